@@ -2,8 +2,11 @@ use std::env;
 
 fn main() {
     println!("cargo::rustc-check-cfg=cfg(ffmpeg_7_0)");
-    for (name, _value) in env::vars() {
-        if name.starts_with("DEP_FFMPEG_") {
+    println!("cargo::rustc-check-cfg=cfg(ffmpeg_7_1)");
+    for (name, value) in env::vars() {
+        // versions that aren't available are passed too, with an empty value. Same as
+        // ffmpeg-next's features, which pick which option::Type variants exist
+        if name.starts_with("DEP_FFMPEG_") && value == "true" {
             println!(
                 r#"cargo::rustc-cfg={}"#,
                 name["DEP_FFMPEG_".len()..name.len()].to_lowercase()
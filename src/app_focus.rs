@@ -0,0 +1,140 @@
+use std::{
+    env,
+    sync::{
+        atomic::{AtomicBool, Ordering::SeqCst},
+        Arc,
+    },
+};
+
+use anyhow::bail;
+use log::{info, warn};
+use serde_json::Value;
+
+use crate::{hyprland, ipc_watch, sway::SwayIpc, window::has_app_id};
+
+// searches a sway tree (GET_TREE) for the focused window
+fn sway_focused(node: &Value) -> Option<&Value> {
+    if node["focused"].as_bool() == Some(true) {
+        return Some(node);
+    }
+    ["nodes", "floating_nodes"]
+        .iter()
+        .filter_map(|key| node[key].as_array())
+        .flatten()
+        .find_map(sway_focused)
+}
+
+// Hyprland's activewindow, which is `{}` when nothing has focus
+fn hyprland_has_focus(active: &Value, class: &str) -> bool {
+    active["class"].as_str() == Some(class) || active["initialClass"].as_str() == Some(class)
+}
+
+// follows focus over the compositor's IPC in the background, the returned flag is true while
+// `app_id` has focus
+pub fn spawn_app_focus_watcher(app_id: String) -> anyhow::Result<Arc<AtomicBool>> {
+    if env::var_os("HYPRLAND_INSTANCE_SIGNATURE").is_some() {
+        let dir = hyprland::socket_dir()?;
+        let mut events = hyprland::event_socket(&dir)?;
+        watch(
+            app_id,
+            move |app_id| {
+                Ok(hyprland_has_focus(
+                    &hyprland::request(&dir, "activewindow")?,
+                    app_id,
+                ))
+            },
+            move || Ok(hyprland::wait_event(&mut events, -1)?),
+        )
+    } else if env::var_os("SWAYSOCK").is_some() {
+        let mut ipc = SwayIpc::connect()?;
+        let mut events = SwayIpc::connect()?;
+        // switching to an empty workspace takes focus away without a window event
+        events.subscribe(r#"["window", "workspace"]"#)?;
+        watch(
+            app_id,
+            move |app_id| Ok(sway_focused(&ipc.tree()?).is_some_and(|n| has_app_id(n, app_id))),
+            move || events.wait_event(-1),
+        )
+    } else {
+        bail!("--only-while-focused only works on sway and Hyprland")
+    }
+}
+
+// keeps the flag set while `focused` says `app_id` has focus
+fn watch(
+    app_id: String,
+    mut focused: impl FnMut(&str) -> anyhow::Result<bool> + Send + 'static,
+    wait: impl FnMut() -> anyhow::Result<()> + Send + 'static,
+) -> anyhow::Result<Arc<AtomicBool>> {
+    let flag = Arc::new(AtomicBool::new(focused(&app_id)?));
+    let flag_weak = Arc::downgrade(&flag);
+
+    let name = app_id.clone();
+    ipc_watch::watch(
+        "app-focus-watcher",
+        move || focused(&name),
+        wait,
+        move |res| {
+            let Some(flag) = flag_weak.upgrade() else {
+                return false;
+            };
+            match res {
+                Ok(focused) => {
+                    if flag.swap(focused, SeqCst) != focused {
+                        info!(
+                            "{app_id} {}",
+                            if focused { "focused" } else { "lost focus" }
+                        );
+                    }
+                    true
+                }
+                Err(e) => {
+                    // keep recording rather than stay paused for good
+                    warn!("lost connection to the compositor, recording regardless of focus: {e}");
+                    flag.store(true, SeqCst);
+                    false
+                }
+            }
+        },
+    );
+
+    Ok(flag)
+}
+
+#[cfg(test)]
+mod test {
+    use serde_json::json;
+
+    use super::{hyprland_has_focus, sway_focused};
+    use crate::window::has_app_id;
+
+    #[test]
+    fn sway() {
+        let tree = json!({"type": "root", "focused": false, "nodes": [
+            {"type": "output", "name": "DP-1", "focused": false, "nodes": [
+                {"type": "con", "app_id": "foot", "focused": false, "nodes": []},
+                {"type": "con", "app_id": null, "focused": false, "nodes": [],
+                 "window_properties": {"class": "Firefox"}}
+            ], "floating_nodes": [
+                {"type": "floating_con", "app_id": "pavucontrol", "focused": true, "nodes": []}
+            ]}
+        ]});
+        let focused = sway_focused(&tree).unwrap();
+        assert!(has_app_id(focused, "pavucontrol"));
+        assert!(!has_app_id(focused, "foot"));
+
+        let mut tree = tree;
+        tree["nodes"][0]["floating_nodes"][0]["focused"] = json!(false);
+        tree["nodes"][0]["nodes"][1]["focused"] = json!(true);
+        assert!(has_app_id(sway_focused(&tree).unwrap(), "Firefox"));
+    }
+
+    #[test]
+    fn hyprland() {
+        assert!(hyprland_has_focus(
+            &json!({"class": "org.gnome.Nautilus", "initialClass": "org.gnome.Nautilus"}),
+            "org.gnome.Nautilus"
+        ));
+        assert!(!hyprland_has_focus(&json!({}), "foot"));
+    }
+}
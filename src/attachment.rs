@@ -0,0 +1,121 @@
+use std::{ffi::CStr, fs, ptr};
+
+use anyhow::Context;
+use ffmpeg::{
+    codec, dict,
+    ffi::{av_mallocz, av_version_info, AVMediaType, AV_INPUT_BUFFER_PADDING_SIZE},
+    format, media,
+};
+use log::{info, warn};
+use serde_json::Value;
+
+use crate::poster::{self, PendingPoster};
+
+// A JSON file attached to mkv recordings, describing how it was recorded (the --print-config
+// output, the markers and a few of the options), so a recording found later can be debugged
+// without knowing the command line. Attachments are written with the header, before any of that
+// is final, so the file is remuxed with it once it's finished, along with the --poster
+
+pub const FILENAME: &str = "wl-screenrec.json";
+
+pub fn supported(octx: &format::context::Output) -> bool {
+    octx.format().name().contains("matroska")
+}
+
+// fields every recording has, the caller adds what it knows about the capture
+pub fn context(mut fields: serde_json::Map<String, Value>) -> Value {
+    let ffmpeg = unsafe { CStr::from_ptr(av_version_info()) }.to_string_lossy();
+    fields.insert("wl_screenrec".to_owned(), env!("CARGO_PKG_VERSION").into());
+    fields.insert("ffmpeg".to_owned(), ffmpeg.into());
+    Value::Object(fields)
+}
+
+// adds `context` and the poster to the finished recording at `path`, waiting for the poster to be
+// encoded. Failures are only warned about, the recording is still fine without them
+pub fn embed(path: &str, context: Option<&Value>, poster: Option<PendingPoster>) {
+    let mut files = Vec::new();
+    if let Some(context) = context {
+        let json = serde_json::to_vec_pretty(context).unwrap();
+        files.push((FILENAME, "application/json", json));
+    }
+    if let Some(png) = poster.and_then(|p| p.png(path)) {
+        files.push((poster::FILENAME, "image/png", png));
+    }
+    if files.is_empty() {
+        return;
+    }
+
+    let names = files
+        .iter()
+        .map(|(name, ..)| *name)
+        .collect::<Vec<_>>()
+        .join(" and ");
+    let tmp = format!("{path}.attaching");
+    match remux_with(path, &tmp, &files) {
+        Ok(()) => info!("attached {names} to {path}"),
+        Err(e) => {
+            warn!("failed to attach {names} to {path}: {e:?}");
+            let _ = fs::remove_file(&tmp);
+        }
+    }
+}
+
+fn remux_with(path: &str, tmp: &str, files: &[(&str, &str, Vec<u8>)]) -> anyhow::Result<()> {
+    let mut ictx = format::input(path).with_context(|| format!("failed to open {path}"))?;
+    let mut octx = format::output_as(tmp, "matroska")
+        .with_context(|| format!("failed to open {tmp} for writing"))?;
+    for ist in ictx.streams() {
+        let mut ost = octx.add_stream(None::<ffmpeg::Codec>)?;
+        ost.set_parameters(ist.parameters());
+        ost.set_metadata(ist.metadata().to_owned());
+        unsafe {
+            (*ost.as_mut_ptr()).disposition = ist.disposition().bits();
+            (*ost.parameters().as_mut_ptr()).codec_tag = 0;
+        }
+    }
+    for (filename, mimetype, data) in files {
+        attach_file(&mut octx, filename, mimetype, data)?;
+    }
+    octx.write_header()?;
+
+    for (ist, mut packet) in ictx.packets() {
+        if ist.parameters().medium() == media::Type::Attachment {
+            continue;
+        }
+        let ost = octx.stream(ist.index()).unwrap();
+        packet.rescale_ts(ist.time_base(), ost.time_base());
+        packet.set_position(-1);
+        packet.write_interleaved(&mut octx)?;
+    }
+    octx.write_trailer()?;
+    drop(octx);
+
+    fs::rename(tmp, path).with_context(|| format!("failed to replace {path}"))?;
+    Ok(())
+}
+
+// before the header is written
+fn attach_file(
+    octx: &mut format::context::Output,
+    filename: &str,
+    mimetype: &str,
+    data: &[u8],
+) -> anyhow::Result<()> {
+    let mut st = octx.add_stream(None::<ffmpeg::Codec>)?;
+    unsafe {
+        let par = (*st.as_mut_ptr()).codecpar;
+        (*par).codec_type = AVMediaType::AVMEDIA_TYPE_ATTACHMENT;
+        (*par).codec_id = codec::Id::None.into();
+        // extradata has to be allocated by ffmpeg, as it frees it
+        let extradata = av_mallocz(data.len() + AV_INPUT_BUFFER_PADDING_SIZE as usize) as *mut u8;
+        anyhow::ensure!(!extradata.is_null(), "failed to allocate attachment");
+        ptr::copy_nonoverlapping(data.as_ptr(), extradata, data.len());
+        (*par).extradata = extradata;
+        (*par).extradata_size = data.len() as i32;
+    }
+    st.set_metadata(dict! {
+        "filename" => filename,
+        "mimetype" => mimetype,
+    });
+    Ok(())
+}
@@ -2,11 +2,12 @@ use std::{
     cmp::max,
     ffi::{CStr, CString},
     sync::{
-        atomic::{AtomicBool, Ordering},
-        mpsc::{channel, Receiver, RecvError, Sender, TryRecvError},
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        mpsc::{sync_channel, Receiver, RecvError, SyncSender, TryRecvError, TrySendError},
         Arc,
     },
     thread::spawn,
+    time::Instant,
 };
 
 use anyhow::{anyhow, bail};
@@ -14,21 +15,31 @@ use ffmpeg::{
     codec::{Context, Id},
     decoder,
     encoder::{self},
-    ffi::{av_channel_layout_describe, av_find_input_format},
+    ffi::{
+        av_channel_layout_describe, av_find_input_format, av_frame_make_writable,
+        av_samples_set_silence,
+    },
     filter,
     format::{self, context::Input, Sample},
-    frame, ChannelLayout, Dictionary, Format, Packet, Rational,
+    frame, ChannelLayout, Dictionary, Format, Packet, Rational, Rescale,
 };
 use human_size::Byte;
+use log::warn;
+use log_once::warn_once;
 
-use crate::{fifo::AudioFifo, Args};
+use crate::{
+    audio_levels::LevelMeter, audio_stats::AudioStats, fifo::AudioFifo, Args, AudioBacklogPolicy,
+    AudioCodec,
+};
 
 struct AudioState {
     enc_audio: encoder::Audio,
     ist_stream_idx: usize,
     ist_time_base: Rational,
     dec_audio: decoder::Audio,
-    frame_sender: Sender<Packet>,
+    frame_sender: SyncSender<Packet>,
+    backlog: Arc<AtomicUsize>, // packets sent to the main thread that it hasn't received yet
+    backlog_policy: AudioBacklogPolicy,
 
     audio_filter: filter::Graph,
 
@@ -40,12 +51,20 @@ struct AudioState {
 
     pts: i64,
     started: Arc<AtomicBool>,
+    paused: Arc<AtomicBool>,
+    paused_samples: i64,
+    muted: Arc<AtomicBool>,
+    levels: Option<LevelMeter>, // --audio-levels
+    stats: AudioStats,
 }
 
 pub struct AudioHandle {
     rec: Receiver<Packet>,
+    backlog: Arc<AtomicUsize>,
     flush_flag: Arc<AtomicBool>,
     started: Arc<AtomicBool>,
+    paused: Arc<AtomicBool>,
+    muted: Arc<AtomicBool>,
 }
 
 pub struct IncompleteAudioState {
@@ -68,6 +87,19 @@ impl AudioState {
             }
 
             if stream.index() == self.ist_stream_idx {
+                self.stats.on_input(packet.duration().rescale(
+                    self.ist_time_base,
+                    Rational::new(1, self.dec_audio.rate() as i32),
+                ));
+            }
+
+            if stream.index() == self.ist_stream_idx && self.paused.load(Ordering::SeqCst) {
+                // drop the audio, but keep the timeline continuous like the video does
+                self.paused_samples += packet.duration().rescale(
+                    self.ist_time_base,
+                    Rational::new(1, self.dec_audio.rate() as i32),
+                );
+            } else if stream.index() == self.ist_stream_idx {
                 packet.rescale_ts(self.ist_time_base, self.dec_audio.time_base());
                 self.dec_audio.send_packet(&packet).unwrap();
                 self.pop_from_decoder();
@@ -84,6 +116,18 @@ impl AudioState {
     fn pop_from_decoder(&mut self) {
         let mut frame = frame::Audio::empty();
         while self.dec_audio.receive_frame(&mut frame).is_ok() {
+            if self.muted.load(Ordering::SeqCst) {
+                // unlike pausing, the track carries on, just silent
+                if let Err(e) = silence(&mut frame) {
+                    warn_once!("failed to mute audio, recording it as it is: {e}");
+                }
+            }
+            if let Some(levels) = &mut self.levels {
+                levels.add_frame(&frame);
+                if let Some(report) = levels.report() {
+                    eprintln!("{report}");
+                }
+            }
             self.audio_filter
                 .get("in")
                 .unwrap()
@@ -105,6 +149,8 @@ impl AudioState {
         {
             if self.fifo.is_some() {
                 self.fifo().unwrap().push(&filtered_frame);
+                let depth = self.fifo().unwrap().size();
+                self.stats.on_fifo(depth);
                 while self.fifo().unwrap().size() > self.enc_audio.frame_size() as usize {
                     let mut frame_into_encoder = frame::Audio::new(
                         self.enc_audio.format(),
@@ -119,6 +165,9 @@ impl AudioState {
                     self.pop_frames_from_encoder();
                 }
             } else {
+                if let Some(pts) = filtered_frame.pts() {
+                    filtered_frame.set_pts(Some(pts - self.paused_samples));
+                }
                 self.enc_audio.send_frame(&filtered_frame).unwrap();
                 self.pop_frames_from_encoder();
             }
@@ -130,6 +179,8 @@ impl AudioState {
     }
 
     fn flush(&mut self) {
+        // the main thread is waiting for the rest of the audio now, none of it should be dropped
+        self.backlog_policy = AudioBacklogPolicy::Block;
         self.dec_audio.send_eof().unwrap();
         self.pop_from_decoder();
         self.audio_filter
@@ -141,6 +192,9 @@ impl AudioState {
         self.pop_from_filter();
         self.enc_audio.send_eof().unwrap();
         self.pop_frames_from_encoder();
+        if let Some(summary) = self.stats.backlog_summary() {
+            warn!("{summary}");
+        }
     }
 
     fn pop_frames_from_encoder(&mut self) {
@@ -151,9 +205,27 @@ impl AudioState {
                 Rational::new(1, self.enc_audio.rate() as i32),
                 self.ost_time_base,
             );
-            self.frame_sender
-                .send(pack)
-                .expect("Strange, main thread exited before issuing flush");
+            let depth = self.backlog.fetch_add(1, Ordering::SeqCst) + 1;
+            self.stats.on_backlog(depth);
+            match self.backlog_policy {
+                AudioBacklogPolicy::Drop => match self.frame_sender.try_send(pack) {
+                    Ok(()) => {}
+                    Err(TrySendError::Full(_)) => {
+                        self.backlog.fetch_sub(1, Ordering::SeqCst);
+                        self.stats.on_backlog_full();
+                    }
+                    Err(TrySendError::Disconnected(_)) => {
+                        panic!("Strange, main thread exited before issuing flush")
+                    }
+                },
+                AudioBacklogPolicy::Block => {
+                    let start = Instant::now();
+                    self.frame_sender
+                        .send(pack)
+                        .expect("Strange, main thread exited before issuing flush");
+                    self.stats.on_blocked(start.elapsed());
+                }
+            }
 
             pack = Packet::empty();
         }
@@ -166,6 +238,18 @@ impl AudioHandle {
         assert!(!was_started, "don't call start more than once");
     }
 
+    pub fn set_paused(&mut self, paused: bool) {
+        self.paused.store(paused, Ordering::SeqCst);
+    }
+
+    pub fn set_muted(&mut self, muted: bool) {
+        self.muted.store(muted, Ordering::SeqCst);
+    }
+
+    pub fn muted(&self) -> bool {
+        self.muted.load(Ordering::SeqCst)
+    }
+
     pub fn create_stream(
         args: &Args,
         octx: &mut format::context::Output,
@@ -178,15 +262,10 @@ impl AudioHandle {
                 .audio()
                 .unwrap()
         } else {
-            let audio_codec_id = match args.audio_codec {
-                crate::AudioCodec::Auto => octx
-                    .format()
-                    .codec(&args.output, ffmpeg::media::Type::Audio),
-                crate::AudioCodec::Aac => Id::AAC,
-                crate::AudioCodec::Mp3 => Id::MP3,
-                crate::AudioCodec::Flac => Id::FLAC,
-                crate::AudioCodec::Opus => Id::OPUS,
-            };
+            let audio_codec_id = codec_id(args.audio_codec).unwrap_or_else(|| {
+                octx.format()
+                    .codec(&args.output, ffmpeg::media::Type::Audio)
+            });
 
             if audio_codec_id == Id::None {
                 bail!(
@@ -269,11 +348,15 @@ impl AudioHandle {
     }
 
     pub fn try_recv(&mut self) -> Result<Packet, TryRecvError> {
-        self.rec.try_recv()
+        let pack = self.rec.try_recv()?;
+        self.backlog.fetch_sub(1, Ordering::SeqCst);
+        Ok(pack)
     }
 
     pub fn recv(&mut self) -> Result<Packet, RecvError> {
-        self.rec.recv()
+        let pack = self.rec.recv()?;
+        self.backlog.fetch_sub(1, Ordering::SeqCst);
+        Ok(pack)
     }
 
     pub fn start_flush(&mut self) {
@@ -282,7 +365,7 @@ impl AudioHandle {
 }
 
 impl IncompleteAudioState {
-    pub fn finish(self, _args: &Args, octx: &format::context::Output) -> AudioHandle {
+    pub fn finish(self, args: &Args, octx: &format::context::Output) -> AudioHandle {
         let ost_time_base = octx.stream(self.ost_stream_idx).unwrap().time_base();
 
         let mut fifo = None;
@@ -302,18 +385,34 @@ impl IncompleteAudioState {
             }
         }
 
-        let (frame_sender, r) = channel();
+        // --audio-backlog is a duration, the channel is sized in packets. Encoders without a
+        // fixed frame size (like PCM) take whatever the decoder gives them, ~1024 samples
+        let frame_size = match self.enc_audio.frame_size() {
+            0 => 1024,
+            n => n,
+        };
+        let backlog_packets = (args.audio_backlog.as_secs_f64() * f64::from(self.enc_audio.rate())
+            / f64::from(frame_size))
+        .ceil()
+        .max(1.) as usize;
+        let (frame_sender, r) = sync_channel(backlog_packets);
+        let backlog = Arc::new(AtomicUsize::new(0));
 
         let audio_filter = audio_filter(
             &self.dec_audio,
             self.dec_audio.rate() as i32,
             self.enc_audio.format(),
             self.enc_audio.channel_layout(),
+            args.noise_gate,
         );
 
         let flush_flag = Arc::new(AtomicBool::new(false));
 
         let started = Arc::new(AtomicBool::new(false));
+        let paused = Arc::new(AtomicBool::new(false));
+        let muted = Arc::new(AtomicBool::new(false));
+
+        let stats = AudioStats::new(self.dec_audio.rate());
 
         let state = AudioState {
             // fifo: None,
@@ -323,6 +422,8 @@ impl IncompleteAudioState {
             ist_time_base: self.ist_time_base,
             dec_audio: self.dec_audio,
             frame_sender,
+            backlog: backlog.clone(),
+            backlog_policy: args.audio_backlog_policy,
             ost_idx: self.ost_stream_idx,
             ost_time_base,
             audio_filter,
@@ -330,24 +431,64 @@ impl IncompleteAudioState {
             fifo,
             pts: 0,
             started: started.clone(),
+            paused: paused.clone(),
+            paused_samples: 0,
+            muted: muted.clone(),
+            levels: args.audio_levels.then(LevelMeter::new),
+            stats,
         };
 
         spawn(|| state.thread(self.input));
 
         AudioHandle {
             rec: r,
+            backlog,
             flush_flag,
             started,
+            paused,
+            muted,
         }
     }
 }
 
+fn silence(frame: &mut frame::Audio) -> Result<(), ffmpeg::Error> {
+    let format = frame.format().into();
+    unsafe {
+        let ptr = frame.as_mut_ptr();
+        // the decoder might still be holding on to the samples
+        let sts = av_frame_make_writable(ptr);
+        if sts < 0 {
+            return Err(ffmpeg::Error::from(sts));
+        }
+        av_samples_set_silence(
+            (*ptr).extended_data,
+            0,
+            (*ptr).nb_samples,
+            (*ptr).ch_layout.nb_channels,
+            format,
+        );
+    }
+    Ok(())
+}
+
+// None for auto, where the container picks
+pub fn codec_id(codec: AudioCodec) -> Option<Id> {
+    Some(match codec {
+        AudioCodec::Auto => return None,
+        AudioCodec::Aac => Id::AAC,
+        AudioCodec::Mp3 => Id::MP3,
+        AudioCodec::Flac => Id::FLAC,
+        AudioCodec::Opus => Id::OPUS,
+    })
+}
+
 fn audio_filter(
     // input: &ffmpeg::Stream,
     input: &decoder::Audio,
     codec_sample_rate: i32,
     codec_sample_format: Sample,
     codec_channel_layout: ChannelLayout,
+    noise_gate: Option<f64>, // threshold in dBFS
 ) -> filter::Graph {
     let mut g = ffmpeg::filter::graph::Graph::new();
 
@@ -386,12 +527,23 @@ fn audio_filter(
     g.add(&filter::find("abuffersink").unwrap(), "out", "")
         .unwrap();
 
+    // --noise-gate. agate's threshold is linear, and the short attack lets the start of speech
+    // through
+    let gate = noise_gate
+        .map(|db| {
+            format!(
+                "agate=threshold={}:attack=5:release=250,",
+                10f64.powf(db / 20.)
+            )
+        })
+        .unwrap_or_default();
+
     g.output("in", 0)
         .unwrap()
         .input("out", 0)
         .unwrap()
         .parse(&format!(
-            "aformat=sample_rates={}:sample_fmts={}:channel_layouts={:#x}",
+            "{gate}aformat=sample_rates={}:sample_fmts={}:channel_layouts={:#x}",
             codec_sample_rate,
             codec_sample_format.name(),
             codec_channel_layout.bits(),
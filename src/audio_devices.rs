@@ -0,0 +1,131 @@
+use std::{
+    ffi::{CStr, CString},
+    fs, ptr,
+};
+
+use anyhow::{bail, Context};
+use ffmpeg::{
+    ffi::{
+        av_find_input_format, avdevice_free_list_devices, avdevice_list_input_sources,
+        AVDeviceInfoList, AVERROR,
+    },
+    Error,
+};
+use libc::{c_char, ENOSYS};
+
+// `wl-screenrec list-audio`: the values --audio-device takes for --audio-backend
+
+struct Device {
+    name: String,
+    description: String,
+    default: bool,
+}
+
+fn list_ffmpeg(backend: &str) -> anyhow::Result<Option<Vec<Device>>> {
+    let backend_c = CString::new(backend).unwrap();
+    let fmt = unsafe { av_find_input_format(backend_c.as_ptr()) };
+    if fmt.is_null() {
+        bail!("ffmpeg has no audio backend called {backend}");
+    }
+
+    let mut list: *mut AVDeviceInfoList = ptr::null_mut();
+    let ret = unsafe { avdevice_list_input_sources(fmt, ptr::null(), ptr::null_mut(), &mut list) };
+    if ret == AVERROR(ENOSYS) {
+        // the backend can't enumerate its devices
+        return Ok(None);
+    }
+    if ret < 0 {
+        return Err(Error::from(ret)).with_context(|| format!("failed to list {backend} devices"));
+    }
+
+    let mut devices = Vec::new();
+    unsafe {
+        for i in 0..(*list).nb_devices {
+            let info = *(*list).devices.offset(i as isize);
+            let string = |s: *mut c_char| {
+                if s.is_null() {
+                    String::new()
+                } else {
+                    CStr::from_ptr(s).to_string_lossy().into_owned()
+                }
+            };
+            devices.push(Device {
+                name: string((*info).device_name),
+                description: string((*info).device_description),
+                default: i == (*list).default_device,
+            });
+        }
+        avdevice_free_list_devices(&mut list);
+    }
+    Ok(Some(devices))
+}
+
+// ffmpeg's oss backend has no device listing, but the kernel lists them in /dev/sndstat like
+// `pcm0: <Realtek ALC269> (play/rec) default`, and pcmN is recorded from /dev/dspN
+fn parse_sndstat(sndstat: &str) -> Vec<Device> {
+    sndstat
+        .lines()
+        .filter_map(|line| {
+            let (pcm, rest) = line.split_once(": ")?;
+            let n = pcm.strip_prefix("pcm")?;
+            if n.is_empty() || !n.bytes().all(|b| b.is_ascii_digit()) {
+                return None;
+            }
+            let description = rest
+                .strip_prefix('<')
+                .and_then(|r| r.split_once('>'))
+                .map_or(rest, |(d, _)| d);
+            Some(Device {
+                name: format!("/dev/dsp{n}"),
+                description: description.to_owned(),
+                default: rest.split_whitespace().last() == Some("default"),
+            })
+        })
+        .collect()
+}
+
+fn to_table(devices: &[Device]) -> String {
+    let width = devices.iter().map(|d| d.name.len()).max().unwrap_or(0);
+    let mut out = String::new();
+    for d in devices {
+        let marker = if d.default { '*' } else { ' ' };
+        out += format!("{marker} {:width$}  {}", d.name, d.description).trim_end();
+        out += "\n";
+    }
+    out
+}
+
+pub fn run(backend: &str) -> anyhow::Result<()> {
+    let devices = match list_ffmpeg(backend)? {
+        Some(devices) => devices,
+        None if backend == "oss" => parse_sndstat(
+            &fs::read_to_string("/dev/sndstat").context("failed to read /dev/sndstat")?,
+        ),
+        None => bail!("the {backend} backend can't list its devices, see https://ffmpeg.org/ffmpeg-devices.html#{backend}"),
+    };
+    if devices.is_empty() {
+        eprintln!("no {backend} devices found");
+    }
+    print!("{}", to_table(&devices));
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::{parse_sndstat, to_table};
+
+    #[test]
+    fn sndstat() {
+        let devices = parse_sndstat(
+            "Installed devices:\n\
+             pcm0: <Realtek ALC269 (Analog)> (play/rec) default\n\
+             pcm1: <Realtek ALC269 (Analog Headphones)> (play)\n\
+             Installed devices from userspace:\n",
+        );
+        assert_eq!(
+            to_table(&devices),
+            "* /dev/dsp0  Realtek ALC269 (Analog)\n  \
+               /dev/dsp1  Realtek ALC269 (Analog Headphones)\n"
+        );
+    }
+}
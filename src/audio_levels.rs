@@ -0,0 +1,112 @@
+use std::time::{Duration, Instant};
+
+use ffmpeg::{format::Sample, frame};
+
+const REPORT_INTERVAL: Duration = Duration::from_secs(1);
+
+fn dbfs(level: f64) -> f64 {
+    20. * level.log10()
+}
+
+// RMS and peak of the decoded audio for --audio-levels, so it's visible that the device is really
+// picking something up before a long recording
+pub struct LevelMeter {
+    peak: f64,
+    sum_sq: f64,
+    count: u64,
+    last_report: Instant,
+}
+
+impl LevelMeter {
+    pub fn new() -> Self {
+        LevelMeter {
+            peak: 0.,
+            sum_sq: 0.,
+            count: 0,
+            last_report: Instant::now(),
+        }
+    }
+
+    fn add(&mut self, sample: f64) {
+        self.peak = self.peak.max(sample.abs());
+        self.sum_sq += sample * sample;
+        self.count += 1;
+    }
+
+    pub fn add_frame(&mut self, frame: &frame::Audio) {
+        let per_plane = if frame.is_planar() {
+            frame.samples()
+        } else {
+            frame.samples() * usize::from(frame.channels())
+        };
+        for plane in 0..frame.planes() {
+            let data = frame.data(plane);
+            // scaled to -1..1
+            match frame.format() {
+                Sample::U8(_) => data[..per_plane]
+                    .iter()
+                    .for_each(|s| self.add((f64::from(*s) - 128.) / 128.)),
+                Sample::I16(_) => data[..per_plane * 2]
+                    .chunks_exact(2)
+                    .map(|s| i16::from_ne_bytes(s.try_into().unwrap()))
+                    .for_each(|s| self.add(f64::from(s) / 32768.)),
+                Sample::I32(_) => data[..per_plane * 4]
+                    .chunks_exact(4)
+                    .map(|s| i32::from_ne_bytes(s.try_into().unwrap()))
+                    .for_each(|s| self.add(f64::from(s) / 2147483648.)),
+                Sample::F32(_) => data[..per_plane * 4]
+                    .chunks_exact(4)
+                    .map(|s| f32::from_ne_bytes(s.try_into().unwrap()))
+                    .for_each(|s| self.add(f64::from(s))),
+                Sample::F64(_) => data[..per_plane * 8]
+                    .chunks_exact(8)
+                    .map(|s| f64::from_ne_bytes(s.try_into().unwrap()))
+                    .for_each(|s| self.add(s)),
+                _ => return,
+            }
+        }
+    }
+
+    // levels since the last report, once every REPORT_INTERVAL
+    pub fn report(&mut self) -> Option<String> {
+        if self.last_report.elapsed() < REPORT_INTERVAL || self.count == 0 {
+            return None;
+        }
+        self.last_report = Instant::now();
+        let text = describe(self.peak, (self.sum_sq / self.count as f64).sqrt());
+        (self.peak, self.sum_sq, self.count) = (0., 0., 0);
+        Some(text)
+    }
+}
+
+fn describe(peak: f64, rms: f64) -> String {
+    if peak == 0. {
+        return "audio: silent".to_owned();
+    }
+    // -60 dBFS and below is an empty meter
+    let bars = ((dbfs(rms) + 60.) / 3.).clamp(0., 20.) as usize;
+    format!(
+        "audio: [{:<20}] RMS {:.1} dBFS, peak {:.1} dBFS",
+        "#".repeat(bars),
+        dbfs(rms),
+        dbfs(peak)
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::describe;
+
+    #[test]
+    fn levels() {
+        assert_eq!(describe(0., 0.), "audio: silent");
+        assert_eq!(
+            describe(1., 0.5),
+            "audio: [#################   ] RMS -6.0 dBFS, peak 0.0 dBFS"
+        );
+        assert_eq!(
+            describe(0.001, 0.0001),
+            "audio: [                    ] RMS -80.0 dBFS, peak -60.0 dBFS"
+        );
+    }
+}
@@ -0,0 +1,167 @@
+use std::time::{Duration, Instant};
+
+use log::{debug, log_enabled, warn, Level};
+
+const REPORT_INTERVAL: Duration = Duration::from_secs(1);
+const WARN_INTERVAL: Duration = Duration::from_secs(30);
+
+// how far the audio read from the device can drift from the wall clock before it's worth a
+// warning. A few packets of jitter is normal
+const SKEW_WARN: f64 = 0.25;
+
+// Latency and drift of the audio thread, like PipelineStats for video. Skew is how far the
+// audio read so far is behind (positive) or ahead of the time since it started, which grows if
+// the thread can't keep up with the device or the device's clock drifts. The backlog is the
+// encoded packets waiting for the main thread to mux them, which --audio-backlog bounds. The
+// encoder FIFO is emptied down to less than a frame every time it's written to, so it can't grow
+// on its own
+pub struct AudioStats {
+    rate: i64, // of the device
+    started: Option<Instant>,
+    samples_in: i64,
+    fifo_depth: usize,
+    fifo_depth_max: usize,
+    backlog: usize,
+    backlog_max: usize,
+    packets_dropped: u64, // --audio-backlog-policy drop
+    blocked: Duration,    // --audio-backlog-policy block
+    last_warning: Option<Instant>,
+    last_report: Instant,
+}
+
+impl AudioStats {
+    pub fn new(rate: u32) -> Self {
+        AudioStats {
+            rate: rate.into(),
+            started: None,
+            samples_in: 0,
+            fifo_depth: 0,
+            fifo_depth_max: 0,
+            backlog: 0,
+            backlog_max: 0,
+            packets_dropped: 0,
+            blocked: Duration::ZERO,
+            last_warning: None,
+            last_report: Instant::now(),
+        }
+    }
+
+    // `samples` were read from the device, including while paused
+    pub fn on_input(&mut self, samples: i64) {
+        let started = *self.started.get_or_insert_with(Instant::now);
+        self.samples_in += samples;
+
+        let skew = self.skew(started.elapsed());
+        if skew.abs() > SKEW_WARN {
+            self.warn(&format!(
+                "audio is {:.0}ms {} the clock, the recording may go out of sync",
+                skew.abs() * 1000.,
+                if skew > 0. { "behind" } else { "ahead of" }
+            ));
+        }
+        self.maybe_report();
+    }
+
+    pub fn on_fifo(&mut self, depth: usize) {
+        self.fifo_depth = depth;
+        self.fifo_depth_max = self.fifo_depth_max.max(depth);
+    }
+
+    pub fn on_backlog(&mut self, depth: usize) {
+        self.backlog = depth;
+        self.backlog_max = self.backlog_max.max(depth);
+    }
+
+    pub fn on_backlog_full(&mut self) {
+        self.packets_dropped += 1;
+        self.warn("audio is being dropped because the recording isn't being written fast enough. A longer --audio-backlog may help");
+    }
+
+    pub fn on_blocked(&mut self, blocked: Duration) {
+        self.blocked += blocked;
+    }
+
+    // for the end of the recording
+    pub fn backlog_summary(&self) -> Option<String> {
+        if self.packets_dropped > 0 {
+            Some(format!(
+                "{} audio packets were dropped because --audio-backlog was full",
+                self.packets_dropped
+            ))
+        } else if self.blocked > Duration::from_secs(1) {
+            Some(format!(
+                "audio waited {:.1}s in total for --audio-backlog to have room",
+                self.blocked.as_secs_f64()
+            ))
+        } else {
+            None
+        }
+    }
+
+    // seconds
+    fn skew(&self, elapsed: Duration) -> f64 {
+        elapsed.as_secs_f64() - self.samples_in as f64 / self.rate as f64
+    }
+
+    fn warn(&mut self, msg: &str) {
+        if self
+            .last_warning
+            .is_some_and(|t| t.elapsed() < WARN_INTERVAL)
+        {
+            return;
+        }
+        self.last_warning = Some(Instant::now());
+        warn!("{msg}");
+    }
+
+    fn maybe_report(&mut self) {
+        if !log_enabled!(Level::Debug) || self.last_report.elapsed() < REPORT_INTERVAL {
+            return;
+        }
+        self.last_report = Instant::now();
+
+        debug!(
+            "audio: skew {:.1}ms, encoder fifo {} samples (max {}), backlog {} packets (max {}, {} dropped, blocked {:.1}s)",
+            self.started.map_or(0., |s| self.skew(s.elapsed()) * 1000.),
+            self.fifo_depth,
+            self.fifo_depth_max,
+            self.backlog,
+            self.backlog_max,
+            self.packets_dropped,
+            self.blocked.as_secs_f64(),
+        );
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use super::AudioStats;
+
+    #[test]
+    fn skew() {
+        let mut stats = AudioStats::new(48000);
+        stats.samples_in = 48000;
+        assert_eq!(stats.skew(Duration::from_secs(1)), 0.);
+        // the thread fell half a second behind the device
+        assert_eq!(stats.skew(Duration::from_millis(1500)), 0.5);
+        stats.samples_in = 96000;
+        assert_eq!(stats.skew(Duration::from_millis(1500)), -0.5);
+    }
+
+    #[test]
+    fn backlog() {
+        let mut stats = AudioStats::new(48000);
+        stats.on_backlog(3);
+        stats.on_backlog(1);
+        assert_eq!(stats.backlog_max, 3);
+        stats.on_blocked(Duration::from_millis(500));
+        assert_eq!(stats.backlog_summary(), None);
+        stats.on_backlog_full();
+        assert_eq!(
+            stats.backlog_summary().as_deref(),
+            Some("1 audio packets were dropped because --audio-backlog was full")
+        );
+    }
+}
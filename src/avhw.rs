@@ -1,35 +1,120 @@
-use std::{ffi::CString, path::Path, ptr::null_mut};
+use std::{
+    ffi::{c_int, CString},
+    path::Path,
+    ptr::null_mut,
+};
 
 use ffmpeg::{
-    dict,
+    codec, dict,
     ffi::{
         av_buffer_ref, av_buffer_unref, av_hwdevice_ctx_create, av_hwframe_ctx_alloc,
-        av_hwframe_ctx_init, av_hwframe_get_buffer, AVHWFramesContext,
+        av_hwframe_ctx_init, av_hwframe_get_buffer, AVHWDeviceContext, AVHWDeviceType,
+        AVHWFramesContext,
     },
     format::Pixel,
     frame,
 };
 use log::error;
 
-use crate::DrmModifier;
+use crate::{
+    vainfo::{
+        VAGenericValue, VASurfaceAttrib, USAGE_HINT_ENCODER, USAGE_HINT_EXPORT,
+        USAGE_HINT_VPP_READ, USAGE_HINT_VPP_WRITE, VA_GENERIC_VALUE_TYPE_INTEGER,
+        VA_SURFACE_ATTRIB_SETTABLE, VA_SURFACE_ATTRIB_USAGE_HINT,
+    },
+    DrmModifier,
+};
+
+// What a frame context's surfaces are for, passed to the driver as a usage hint so it can pick a
+// layout and memory placement that suits them
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Usage {
+    Capture, // the compositor copies into them through an exported dmabuf, the filter reads them
+    Encode,  // written by the filter, read by the encoder
+    Overlay, // uploaded to from the CPU, blended onto other surfaces by the filter
+}
+
+impl Usage {
+    fn va_usage_hint(self) -> i32 {
+        match self {
+            Usage::Capture => USAGE_HINT_EXPORT | USAGE_HINT_VPP_READ,
+            Usage::Encode => USAGE_HINT_VPP_WRITE | USAGE_HINT_ENCODER,
+            Usage::Overlay => USAGE_HINT_VPP_READ,
+        }
+    }
+}
+
+// the start of libavutil's AVVAAPIFramesContext
+#[repr(C)]
+struct AVVAAPIFramesContext {
+    attributes: *mut VASurfaceAttrib,
+    nb_attributes: c_int,
+}
+
+// --hw-backend, which hardware the frames are processed and encoded on. Captured frames are
+// imported, cropped and scaled as vaapi surfaces, so vaapi is the only backend with a pipeline so
+// far. Adding one is a matter of filling in its device, filters and encoders
+#[derive(clap::ValueEnum, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum HwBackend {
+    #[default]
+    Vaapi,
+    None, // software encoding, frames are downloaded from the vaapi surfaces
+}
+
+impl HwBackend {
+    pub fn name(self) -> &'static str {
+        match self {
+            HwBackend::Vaapi => "vaapi",
+            HwBackend::None => "none",
+        }
+    }
+
+    fn device_type(self) -> Option<AVHWDeviceType> {
+        match self {
+            HwBackend::Vaapi => Some(AVHWDeviceType::AV_HWDEVICE_TYPE_VAAPI),
+            HwBackend::None => None,
+        }
+    }
+
+    // ffmpeg's encoder for `codec` on this backend, like hevc_vaapi
+    pub fn encoder_name(self, codec: codec::Id) -> Option<String> {
+        let codec = match codec {
+            codec::Id::H264 => "h264",
+            codec::Id::H265 | codec::Id::HEVC => "hevc",
+            codec::Id::VP8 => "vp8",
+            codec::Id::VP9 => "vp9",
+            codec::Id::AV1 => "av1",
+            _ => return None,
+        };
+        self.device_type()?;
+        Some(format!("{codec}_{}", self.name()))
+    }
+}
 
 pub struct AvHwDevCtx {
     ptr: *mut ffmpeg::sys::AVBufferRef,
 }
 
 impl AvHwDevCtx {
-    pub fn new_libva(dri_device: &Path) -> Result<Self, ffmpeg::Error> {
+    // `device` is the dri render node for vaapi
+    pub fn new(backend: HwBackend, device: &Path) -> Result<Self, ffmpeg::Error> {
+        let Some(device_type) = backend.device_type() else {
+            return Err(ffmpeg::Error::InvalidData);
+        };
         unsafe {
             let mut hw_device_ctx = null_mut();
 
-            let opts = dict! {
-                "connection_type" => "drm"
+            let opts = match backend {
+                HwBackend::Vaapi => dict! {
+                    "connection_type" => "drm"
+                },
+                _ => dict!(),
             };
 
-            let dev_cstr = CString::new(dri_device.to_str().unwrap()).unwrap();
+            let dev_cstr = CString::new(device.to_str().unwrap()).unwrap();
             let sts = av_hwdevice_ctx_create(
                 &mut hw_device_ctx,
-                ffmpeg_next::ffi::AVHWDeviceType::AV_HWDEVICE_TYPE_VAAPI,
+                device_type,
                 dev_cstr.as_ptr(),
                 opts.as_mut_ptr(),
                 0,
@@ -49,6 +134,7 @@ impl AvHwDevCtx {
         width: i32,
         height: i32,
         modifier: DrmModifier,
+        usage: Usage,
     ) -> Result<AvHwFrameCtx, ffmpeg::Error> {
         unsafe {
             let mut hwframe = av_hwframe_ctx_alloc(self.ptr as *mut _);
@@ -65,7 +151,23 @@ impl AvHwDevCtx {
                 error!("unknown how to request non-linear frames in vaapi");
             }
 
+            // ffmpeg copies the attributes when the surfaces are created in init, so they only
+            // need to live until then
+            let mut attributes = [VASurfaceAttrib {
+                type_: VA_SURFACE_ATTRIB_USAGE_HINT,
+                flags: VA_SURFACE_ATTRIB_SETTABLE,
+                value_type: VA_GENERIC_VALUE_TYPE_INTEGER,
+                value: VAGenericValue {
+                    i: usage.va_usage_hint(),
+                },
+            }];
+            let va_frames = (*hwframe_casted).hwctx as *mut AVVAAPIFramesContext;
+            (*va_frames).attributes = attributes.as_mut_ptr();
+            (*va_frames).nb_attributes = attributes.len() as c_int;
+
             let sts = av_hwframe_ctx_init(hwframe);
+            (*va_frames).attributes = null_mut();
+            (*va_frames).nb_attributes = 0;
             if sts != 0 {
                 return Err(ffmpeg::Error::from(sts));
             }
@@ -83,6 +185,19 @@ impl AvHwDevCtx {
     pub fn as_mut_ptr(&mut self) -> *mut ffmpeg::sys::AVBufferRef {
         self.ptr
     }
+
+    pub fn as_ptr(&self) -> *mut ffmpeg::sys::AVBufferRef {
+        self.ptr
+    }
+
+    // the libva VADisplay behind this device
+    pub fn va_display(&self) -> *mut libc::c_void {
+        unsafe {
+            let dev = (*self.ptr).data as *mut AVHWDeviceContext;
+            // hwctx is an AVVAAPIDeviceContext, which starts with the display
+            *((*dev).hwctx as *mut *mut libc::c_void)
+        }
+    }
 }
 
 impl Drop for AvHwDevCtx {
@@ -93,6 +208,18 @@ impl Drop for AvHwDevCtx {
     }
 }
 
+// another reference to the same device
+impl Clone for AvHwDevCtx {
+    fn clone(&self) -> Self {
+        Self {
+            ptr: unsafe { av_buffer_ref(self.ptr) },
+        }
+    }
+}
+
+// the reference count is atomic, and libva displays can be used from any thread
+unsafe impl Send for AvHwDevCtx {}
+
 pub struct AvHwFrameCtx {
     ptr: *mut ffmpeg::sys::AVBufferRef,
 }
@@ -105,7 +232,32 @@ impl Drop for AvHwFrameCtx {
     }
 }
 
+unsafe impl Send for AvHwFrameCtx {}
+
+impl Clone for AvHwFrameCtx {
+    fn clone(&self) -> Self {
+        Self {
+            ptr: unsafe { av_buffer_ref(self.ptr) },
+        }
+    }
+}
+
+// the frame context the references are compared by
+impl PartialEq for AvHwFrameCtx {
+    fn eq(&self, other: &Self) -> bool {
+        unsafe { (*self.ptr).data == (*other.ptr).data }
+    }
+}
+
 impl AvHwFrameCtx {
+    // the context a hardware frame's surface is from
+    pub fn of_frame(frame: &frame::Video) -> Option<Self> {
+        let ctx = unsafe { (*frame.as_ptr()).hw_frames_ctx };
+        (!ctx.is_null()).then(|| Self {
+            ptr: unsafe { av_buffer_ref(ctx) },
+        })
+    }
+
     pub fn alloc(&mut self) -> Result<frame::Video, ffmpeg::Error> {
         let mut frame = ffmpeg_next::frame::video::Video::empty();
         match unsafe { av_hwframe_get_buffer(self.ptr, frame.as_mut_ptr(), 0) } {
@@ -116,4 +268,49 @@ impl AvHwFrameCtx {
     pub fn as_mut_ptr(&mut self) -> *mut ffmpeg::sys::AVBufferRef {
         self.ptr
     }
+
+    pub fn as_ptr(&self) -> *mut ffmpeg::sys::AVBufferRef {
+        self.ptr
+    }
+
+    // the device the surfaces are on
+    pub fn device_ref(&self) -> *mut ffmpeg::sys::AVBufferRef {
+        unsafe { (*((*self.ptr).data as *const AVHWFramesContext)).device_ref }
+    }
+
+    pub fn device(&self) -> AvHwDevCtx {
+        AvHwDevCtx {
+            ptr: unsafe { av_buffer_ref(self.device_ref()) },
+        }
+    }
+
+    pub fn size(&self) -> (i32, i32) {
+        let ctx = unsafe { &*((*self.ptr).data as *const AVHWFramesContext) };
+        (ctx.width, ctx.height)
+    }
+
+    // the pixel format of the surfaces' contents
+    pub fn sw_format(&self) -> Pixel {
+        unsafe {
+            (*((*self.ptr).data as *const AVHWFramesContext))
+                .sw_format
+                .into()
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use ffmpeg::codec;
+
+    use super::HwBackend;
+
+    #[test]
+    fn encoders() {
+        assert_eq!(
+            HwBackend::Vaapi.encoder_name(codec::Id::HEVC).as_deref(),
+            Some("hevc_vaapi")
+        );
+        assert_eq!(HwBackend::None.encoder_name(codec::Id::H264), None);
+    }
 }
@@ -0,0 +1,264 @@
+use std::{
+    ffi::{c_char, c_void, CStr, CString},
+    ptr::null,
+};
+
+use anyhow::bail;
+use ffmpeg::{
+    dictionary,
+    ffi::{
+        av_opt_next, av_opt_set, avcodec_alloc_context3, avcodec_free_context, AVClass, AVOption,
+        AVOptionType, AVERROR_OPTION_NOT_FOUND, AV_OPT_FLAG_ENCODING_PARAM, AV_OPT_SEARCH_CHILDREN,
+    },
+    option,
+};
+
+// --ffmpeg-encoder-options are checked against the encoder's AVOptions before it's opened.
+// Unknown keys would otherwise be ignored without a word, and a bad value only fails the open
+// with "Invalid argument"
+
+struct OptionInfo {
+    name: String,
+    help: String,
+    kind: &'static str,
+    range: Option<(f64, f64)>,
+    default: Option<String>,
+    values: Vec<String>, // named constants, for enums and flags
+}
+
+impl OptionInfo {
+    fn describe(&self) -> String {
+        let mut s = format!("  {} ({}", self.name, self.kind);
+        if let Some((min, max)) = self.range {
+            s += &format!(", {min} to {max}");
+        }
+        if let Some(default) = &self.default {
+            s += &format!(", default {default}");
+        }
+        s += ")";
+        if !self.values.is_empty() {
+            s += &format!(" one of {}", self.values.join(", "));
+        }
+        if !self.help.is_empty() {
+            s += &format!(": {}", self.help);
+        }
+        s
+    }
+}
+
+fn kind(t: option::Type) -> &'static str {
+    match t {
+        option::Type::Flags => "flags",
+        #[cfg(ffmpeg_7_0)]
+        option::Type::FlagArray => "flags",
+        option::Type::Int | option::Type::Int64 => "integer",
+        option::Type::c_ulong => "unsigned integer",
+        #[cfg(ffmpeg_7_1)]
+        option::Type::UInt => "unsigned integer",
+        option::Type::Double | option::Type::Float => "number",
+        option::Type::String => "string",
+        option::Type::Rational => "rational",
+        option::Type::Binary => "binary",
+        option::Type::Dictionary => "dictionary",
+        option::Type::Constant => "constant",
+        option::Type::ImageSize => "image size",
+        option::Type::PixelFormat => "pixel format",
+        option::Type::SampleFormat => "sample format",
+        option::Type::VideoRate => "frame rate",
+        option::Type::Duration => "duration",
+        option::Type::Color => "color",
+        option::Type::ChannelLayout => "channel layout",
+        option::Type::bool => "boolean",
+    }
+}
+
+fn cstr(s: *const c_char) -> Option<String> {
+    (!s.is_null()).then(|| unsafe { CStr::from_ptr(s) }.to_string_lossy().into_owned())
+}
+
+// every option of `class`, constants included
+fn raw_options(class: *const AVClass) -> Vec<&'static AVOption> {
+    // av_opt_next wants an object starting with an AVClass pointer
+    let obj = &class as *const *const AVClass as *mut c_void;
+    let mut options = Vec::new();
+    let mut opt = null();
+    loop {
+        opt = unsafe { av_opt_next(obj, opt) };
+        if opt.is_null() {
+            return options;
+        }
+        options.push(unsafe { &*opt });
+    }
+}
+
+fn info(opt: &AVOption, all: &[&AVOption]) -> OptionInfo {
+    let t = option::Type::from(opt.type_);
+    let unit = cstr(opt.unit);
+    let values: Vec<_> = all
+        .iter()
+        .filter(|o| {
+            o.type_ == AVOptionType::AV_OPT_TYPE_CONST && unit.is_some() && cstr(o.unit) == unit
+        })
+        .map(|o| {
+            (cstr(o.name).unwrap_or_default(), unsafe {
+                o.default_val.i64_
+            })
+        })
+        .collect();
+
+    let default = unsafe {
+        match t {
+            option::Type::Int
+            | option::Type::Int64
+            | option::Type::c_ulong
+            | option::Type::bool
+            | option::Type::Duration => {
+                let v = opt.default_val.i64_;
+                Some(
+                    values
+                        .iter()
+                        .find(|(_, c)| *c == v)
+                        .map_or_else(|| v.to_string(), |(name, _)| name.clone()),
+                )
+            }
+            option::Type::Double | option::Type::Float => Some(opt.default_val.dbl.to_string()),
+            option::Type::Rational => Some(format!(
+                "{}/{}",
+                opt.default_val.q.num, opt.default_val.q.den
+            )),
+            option::Type::String
+            | option::Type::ImageSize
+            | option::Type::VideoRate
+            | option::Type::Color
+            | option::Type::Dictionary => cstr(opt.default_val.str_),
+            _ => None,
+        }
+    };
+    let numeric = matches!(
+        t,
+        option::Type::Int
+            | option::Type::Int64
+            | option::Type::c_ulong
+            | option::Type::Double
+            | option::Type::Float
+            | option::Type::Duration
+    );
+
+    OptionInfo {
+        name: cstr(opt.name).unwrap_or_default(),
+        help: cstr(opt.help).unwrap_or_default(),
+        kind: kind(t),
+        range: (numeric && values.is_empty()).then_some((opt.min, opt.max)),
+        default,
+        values: values.into_iter().map(|(name, _)| name).collect(),
+    }
+}
+
+// the encoder's own options, what `ffmpeg -h encoder=NAME` lists
+fn describe_encoder(codec: ffmpeg::Codec) -> String {
+    let class = unsafe { (*codec.as_ptr()).priv_class };
+    if class.is_null() {
+        return format!("{} has no options of its own", codec.name());
+    }
+    let all = raw_options(class);
+    let listed: Vec<_> = all
+        .iter()
+        .filter(|o| {
+            o.type_ != AVOptionType::AV_OPT_TYPE_CONST
+                && o.flags & AV_OPT_FLAG_ENCODING_PARAM as i32 != 0
+        })
+        .map(|o| info(o, &all).describe())
+        .collect();
+    format!(
+        "{} options are:\n{}\nand the generic ones in `ffmpeg -h full`",
+        codec.name(),
+        listed.join("\n")
+    )
+}
+
+fn find<'a>(codec: ffmpeg::Codec, name: &str) -> Option<(&'a AVOption, Vec<&'a AVOption>)> {
+    let class = unsafe { (*codec.as_ptr()).priv_class };
+    if class.is_null() {
+        return None;
+    }
+    let all = raw_options(class);
+    let opt = *all.iter().find(|o| {
+        o.type_ != AVOptionType::AV_OPT_TYPE_CONST && cstr(o.name).as_deref() == Some(name)
+    })?;
+    Some((opt, all))
+}
+
+pub fn validate(codec: ffmpeg::Codec, options: &dictionary::Owned) -> anyhow::Result<()> {
+    let mut ctx = unsafe { avcodec_alloc_context3(codec.as_ptr()) };
+    if ctx.is_null() {
+        bail!("failed to allocate a context for {}", codec.name());
+    }
+    let mut res = Ok(());
+    for (k, v) in options.iter() {
+        // they came out of a dictionary, so they can't have NULs in them
+        let (key, value) = (CString::new(k).unwrap(), CString::new(v).unwrap());
+        let sts = unsafe {
+            av_opt_set(
+                ctx as *mut c_void,
+                key.as_ptr(),
+                value.as_ptr(),
+                AV_OPT_SEARCH_CHILDREN as i32,
+            )
+        };
+        if sts == AVERROR_OPTION_NOT_FOUND {
+            res = Err(anyhow::anyhow!(
+                "{} has no option {k}. {}",
+                codec.name(),
+                describe_encoder(codec)
+            ));
+        } else if sts < 0 {
+            let about = match find(codec, k) {
+                Some((opt, all)) => format!("\n{}", info(opt, &all).describe()),
+                None => String::new(),
+            };
+            res = Err(anyhow::anyhow!(
+                "invalid value {v} for {k}: {}{about}",
+                ffmpeg::Error::from(sts)
+            ));
+        }
+        if res.is_err() {
+            break;
+        }
+    }
+    unsafe { avcodec_free_context(&mut ctx) };
+    res
+}
+
+#[cfg(test)]
+mod test {
+    use super::OptionInfo;
+
+    #[test]
+    fn describe() {
+        let crf = OptionInfo {
+            name: "crf".to_owned(),
+            help: "Select the quality for constant quality mode".to_owned(),
+            kind: "number",
+            range: Some((-1., 51.)),
+            default: Some("-1".to_owned()),
+            values: Vec::new(),
+        };
+        assert_eq!(
+            crf.describe(),
+            "  crf (number, -1 to 51, default -1): Select the quality for constant quality mode"
+        );
+
+        let usage = OptionInfo {
+            name: "usage".to_owned(),
+            help: String::new(),
+            kind: "integer",
+            range: None,
+            default: Some("good".to_owned()),
+            values: vec!["good".to_owned(), "realtime".to_owned()],
+        };
+        assert_eq!(
+            usage.describe(),
+            "  usage (integer, default good) one of good, realtime"
+        );
+    }
+}
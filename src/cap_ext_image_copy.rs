@@ -1,4 +1,10 @@
-use std::path::PathBuf;
+use std::{
+    io,
+    os::fd::{AsFd, AsRawFd, FromRawFd, OwnedFd},
+    path::PathBuf,
+    ptr::null_mut,
+    slice,
+};
 
 use anyhow::Context;
 use drm::{buffer::DrmFourcc, node::DrmNode};
@@ -6,7 +12,16 @@ use libc::dev_t;
 use log::{debug, warn};
 use log_once::warn_once;
 use wayland_client::{
-    globals::GlobalList, protocol::wl_output::WlOutput, Dispatch, Proxy, QueueHandle,
+    globals::GlobalList,
+    protocol::{
+        wl_buffer::WlBuffer,
+        wl_output::WlOutput,
+        wl_pointer::WlPointer,
+        wl_seat::WlSeat,
+        wl_shm::{self, WlShm},
+        wl_shm_pool::WlShmPool,
+    },
+    Dispatch, Proxy, QueueHandle, WEnum,
 };
 use wayland_protocols::ext::{
     image_capture_source::v1::client::{
@@ -14,13 +29,17 @@ use wayland_protocols::ext::{
         ext_output_image_capture_source_manager_v1::ExtOutputImageCaptureSourceManagerV1,
     },
     image_copy_capture::v1::client::{
-        ext_image_copy_capture_frame_v1::ExtImageCopyCaptureFrameV1,
+        ext_image_copy_capture_cursor_session_v1::{self, ExtImageCopyCaptureCursorSessionV1},
+        ext_image_copy_capture_frame_v1::{self, ExtImageCopyCaptureFrameV1, FailureReason},
         ext_image_copy_capture_manager_v1::{ExtImageCopyCaptureManagerV1, Options},
         ext_image_copy_capture_session_v1::{self, ExtImageCopyCaptureSessionV1},
     },
 };
 
-use crate::{CaptureSource, DmabufPotentialFormat, DrmModifier, State};
+use crate::{
+    cursor_overlay::CursorImage, transform::Rect, CaptureSource, DmabufPotentialFormat,
+    DrmModifier, EncConstructionStage, State,
+};
 
 impl Dispatch<ExtImageCopyCaptureManagerV1, ()> for State<CapExtImageCopy> {
     fn event(
@@ -137,7 +156,12 @@ impl Dispatch<ExtImageCopyCaptureFrameV1, ()> for State<CapExtImageCopy> {
         use wayland_protocols::ext::image_copy_capture::v1::client::ext_image_copy_capture_frame_v1::Event::*;
         match event {
             Transform { .. } => {} // TODO: use this
-            Damage { .. } => {}    // TODO: maybe this is how you implement damage
+            Damage {
+                x,
+                y,
+                width,
+                height,
+            } => state.on_damage(Rect::new((x, y), (width, height))),
             PresentationTime {
                 tv_sec_hi,
                 tv_sec_lo,
@@ -156,6 +180,298 @@ impl Dispatch<ExtImageCopyCaptureFrameV1, ()> for State<CapExtImageCopy> {
     }
 }
 
+// only used to create the cursor session, the pointer never enters any of our surfaces
+impl Dispatch<WlSeat, ()> for State<CapExtImageCopy> {
+    fn event(
+        _state: &mut Self,
+        _proxy: &WlSeat,
+        _event: <WlSeat as Proxy>::Event,
+        _data: &(),
+        _conn: &wayland_client::Connection,
+        _qhandle: &QueueHandle<Self>,
+    ) {
+    }
+}
+impl Dispatch<WlPointer, ()> for State<CapExtImageCopy> {
+    fn event(
+        _state: &mut Self,
+        _proxy: &WlPointer,
+        _event: <WlPointer as Proxy>::Event,
+        _data: &(),
+        _conn: &wayland_client::Connection,
+        _qhandle: &QueueHandle<Self>,
+    ) {
+    }
+}
+
+impl Dispatch<ExtImageCopyCaptureCursorSessionV1, ()> for State<CapExtImageCopy> {
+    fn event(
+        state: &mut Self,
+        _proxy: &ExtImageCopyCaptureCursorSessionV1,
+        event: <ExtImageCopyCaptureCursorSessionV1 as Proxy>::Event,
+        _data: &(),
+        _conn: &wayland_client::Connection,
+        _qhandle: &QueueHandle<Self>,
+    ) {
+        let Some(cap) = capture_source(state) else {
+            return;
+        };
+        match event {
+            ext_image_copy_capture_cursor_session_v1::Event::Position { x, y } => {
+                cap.cursor_position = Some((x, y))
+            }
+            ext_image_copy_capture_cursor_session_v1::Event::Leave => cap.cursor_position = None,
+            ext_image_copy_capture_cursor_session_v1::Event::Hotspot { x, y } => {
+                cap.cursor.hotspot = (x, y);
+                cap.cursor.image_changed = true;
+            }
+            _ => {}
+        }
+    }
+}
+
+// events queued before the output went away can arrive after the capture source is gone
+fn capture_source(state: &mut State<CapExtImageCopy>) -> Option<&mut CapExtImageCopy> {
+    match &mut state.enc {
+        EncConstructionStage::EverythingButFormat { cap, .. } => Some(cap),
+        EncConstructionStage::Complete(c) => Some(&mut c.cap),
+        _ => None,
+    }
+}
+
+// user data of the objects capturing the cursor's image for --composite-cursor, which tells them
+// apart from the ones capturing the output
+pub struct CursorImageCapture;
+
+impl Dispatch<ExtImageCopyCaptureSessionV1, CursorImageCapture> for State<CapExtImageCopy> {
+    fn event(
+        state: &mut Self,
+        _proxy: &ExtImageCopyCaptureSessionV1,
+        event: <ExtImageCopyCaptureSessionV1 as Proxy>::Event,
+        _data: &CursorImageCapture,
+        _conn: &wayland_client::Connection,
+        qhandle: &QueueHandle<Self>,
+    ) {
+        let Some(cap) = capture_source(state) else {
+            return;
+        };
+        let cursor = &mut cap.cursor;
+        match event {
+            ext_image_copy_capture_session_v1::Event::BufferSize { width, height } => {
+                cursor.buffer_size = Some((width as i32, height as i32));
+            }
+            ext_image_copy_capture_session_v1::Event::ShmFormat {
+                format: WEnum::Value(wl_shm::Format::Argb8888),
+            } => cursor.argb8888 = true,
+            ext_image_copy_capture_session_v1::Event::Done => {
+                if !cursor.argb8888 {
+                    warn!(
+                        "the compositor can't copy the cursor to ARGB8888 buffers, it won't be drawn"
+                    );
+                    return;
+                }
+                if cursor.frame.is_none() {
+                    cursor.capture_next(qhandle);
+                }
+            }
+            ext_image_copy_capture_session_v1::Event::Stopped => {
+                debug!("cursor capture session stopped");
+                if let Some(session) = cursor.session.take() {
+                    session.destroy();
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+impl Dispatch<ExtImageCopyCaptureFrameV1, CursorImageCapture> for State<CapExtImageCopy> {
+    fn event(
+        state: &mut Self,
+        _proxy: &ExtImageCopyCaptureFrameV1,
+        event: <ExtImageCopyCaptureFrameV1 as Proxy>::Event,
+        _data: &CursorImageCapture,
+        _conn: &wayland_client::Connection,
+        qhandle: &QueueHandle<Self>,
+    ) {
+        let Some(cap) = capture_source(state) else {
+            return;
+        };
+        let cursor = &mut cap.cursor;
+        match event {
+            ext_image_copy_capture_frame_v1::Event::Ready => {
+                if let Some(frame) = cursor.frame.take() {
+                    frame.destroy();
+                }
+                if let Some(buffer) = &cursor.buffer {
+                    cursor.pixels = buffer.pixels().to_vec();
+                    cursor.image_size = buffer.size;
+                    cursor.image_changed = true;
+                }
+                // only completes once the cursor changes again
+                cursor.capture_next(qhandle);
+            }
+            ext_image_copy_capture_frame_v1::Event::Failed { reason } => {
+                if let Some(frame) = cursor.frame.take() {
+                    frame.destroy();
+                }
+                match reason {
+                    // the session sends the new constraints and done, which captures again
+                    WEnum::Value(FailureReason::BufferConstraints) => {}
+                    WEnum::Value(FailureReason::Stopped) => {
+                        debug!("cursor capture stopped")
+                    }
+                    reason => warn!("failed to capture the cursor, it won't be drawn: {reason:?}"),
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+// the wl_shm pool and buffer are only ever used for the cursor's image
+impl Dispatch<WlShm, ()> for State<CapExtImageCopy> {
+    fn event(
+        _state: &mut Self,
+        _proxy: &WlShm,
+        _event: <WlShm as Proxy>::Event,
+        _data: &(),
+        _conn: &wayland_client::Connection,
+        _qhandle: &QueueHandle<Self>,
+    ) {
+    }
+}
+impl Dispatch<WlShmPool, CursorImageCapture> for State<CapExtImageCopy> {
+    fn event(
+        _state: &mut Self,
+        _proxy: &WlShmPool,
+        _event: <WlShmPool as Proxy>::Event,
+        _data: &CursorImageCapture,
+        _conn: &wayland_client::Connection,
+        _qhandle: &QueueHandle<Self>,
+    ) {
+    }
+}
+impl Dispatch<WlBuffer, CursorImageCapture> for State<CapExtImageCopy> {
+    fn event(
+        _state: &mut Self,
+        _proxy: &WlBuffer,
+        _event: <WlBuffer as Proxy>::Event,
+        _data: &CursorImageCapture,
+        _conn: &wayland_client::Connection,
+        _qhandle: &QueueHandle<Self>,
+    ) {
+    }
+}
+
+// a memfd backed wl_shm buffer the cursor is copied into
+struct ShmBuffer {
+    pool: WlShmPool,
+    buffer: WlBuffer,
+    map: *mut libc::c_void,
+    len: usize,
+    size: (i32, i32),
+}
+
+impl ShmBuffer {
+    fn new(
+        shm: &WlShm,
+        (w, h): (i32, i32),
+        eq: &QueueHandle<State<CapExtImageCopy>>,
+    ) -> io::Result<Self> {
+        let len = w as usize * h as usize * 4;
+        let fd = unsafe { libc::memfd_create(c"wl-screenrec-cursor".as_ptr(), libc::MFD_CLOEXEC) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+        if unsafe { libc::ftruncate(fd.as_raw_fd(), len as libc::off_t) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let map = unsafe {
+            libc::mmap(
+                null_mut(),
+                len,
+                libc::PROT_READ,
+                libc::MAP_SHARED,
+                fd.as_raw_fd(),
+                0,
+            )
+        };
+        if map == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+
+        let pool = shm.create_pool(fd.as_fd(), len as i32, eq, CursorImageCapture);
+        let buffer = pool.create_buffer(
+            0,
+            w,
+            h,
+            w * 4,
+            wl_shm::Format::Argb8888,
+            eq,
+            CursorImageCapture,
+        );
+        Ok(ShmBuffer {
+            pool,
+            buffer,
+            map,
+            len,
+            size: (w, h),
+        })
+    }
+
+    fn pixels(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(self.map as *const u8, self.len) }
+    }
+}
+
+impl Drop for ShmBuffer {
+    fn drop(&mut self) {
+        self.buffer.destroy();
+        self.pool.destroy();
+        unsafe { libc::munmap(self.map, self.len) };
+    }
+}
+
+// --composite-cursor, capturing the pointer's image whenever it changes
+#[derive(Default)]
+struct CursorCapture {
+    shm: Option<WlShm>,
+    session: Option<ExtImageCopyCaptureSessionV1>,
+    frame: Option<ExtImageCopyCaptureFrameV1>,
+    buffer: Option<ShmBuffer>,
+    buffer_size: Option<(i32, i32)>, // from the session's constraints
+    argb8888: bool,
+    pixels: Vec<u8>,
+    image_size: (i32, i32),
+    hotspot: (i32, i32),
+    image_changed: bool,
+}
+
+impl CursorCapture {
+    fn capture_next(&mut self, eq: &QueueHandle<State<CapExtImageCopy>>) {
+        let (Some(session), Some(shm), Some(size)) = (&self.session, &self.shm, self.buffer_size)
+        else {
+            return;
+        };
+        if self.buffer.as_ref().map(|b| b.size) != Some(size) {
+            self.buffer = match ShmBuffer::new(shm, size, eq) {
+                Ok(buffer) => Some(buffer),
+                Err(e) => {
+                    warn!("failed to allocate a buffer for the cursor, it won't be drawn: {e}");
+                    return;
+                }
+            };
+        }
+        let frame = session.create_frame(eq, CursorImageCapture);
+        frame.attach_buffer(&self.buffer.as_ref().unwrap().buffer);
+        frame.damage_buffer(0, 0, size.0, size.1);
+        frame.capture();
+        self.frame = Some(frame);
+    }
+}
+
 /** Struct to collect buffer constraint information as the events arrive */
 #[derive(Default)]
 struct BufferConstraints {
@@ -165,9 +481,14 @@ struct BufferConstraints {
 }
 
 pub struct CapExtImageCopy {
+    capture_src: ExtImageCaptureSourceV1,
+    copy_man: ExtImageCopyCaptureManagerV1,
     output_capture_session: ExtImageCopyCaptureSessionV1,
     time: Option<(u32, u32, u32)>,
     in_progress_constraints: BufferConstraints,
+    cursor_session: Option<ExtImageCopyCaptureCursorSessionV1>,
+    cursor_position: Option<(i32, i32)>, // hotspot, in transformed buffer coordinates
+    cursor: CursorCapture,
 }
 
 impl CaptureSource for CapExtImageCopy {
@@ -177,6 +498,7 @@ impl CaptureSource for CapExtImageCopy {
         gm: &GlobalList,
         eq: &QueueHandle<crate::State<Self>>,
         output: WlOutput,
+        paint_cursor: bool,
     ) -> anyhow::Result<Self> {
         let capture_man: ExtOutputImageCaptureSourceManagerV1 = gm
             .bind(
@@ -198,13 +520,71 @@ impl CaptureSource for CapExtImageCopy {
             )
             .context("Your compositor does not support ext-image-copy-capture-manager-v1")?;
 
-        let output_capture_session =
-            copy_man.create_session(&capture_src, Options::PaintCursors, eq, ());
+        let options = if paint_cursor {
+            Options::PaintCursors
+        } else {
+            Options::empty()
+        };
+        let output_capture_session = copy_man.create_session(&capture_src, options, eq, ());
 
         Ok(Self {
+            capture_src,
+            copy_man,
             output_capture_session,
             time: None,
             in_progress_constraints: BufferConstraints::default(),
+            cursor_session: None,
+            cursor_position: None,
+            cursor: CursorCapture::default(),
+        })
+    }
+
+    fn track_cursor(
+        &mut self,
+        gm: &GlobalList,
+        eq: &QueueHandle<crate::State<Self>>,
+    ) -> anyhow::Result<()> {
+        let seat: WlSeat = gm
+            .bind(eq, 1..=WlSeat::interface().version, ())
+            .context("Your compositor has no wl_seat to follow the cursor of")?;
+        let pointer = seat.get_pointer(eq, ());
+        self.cursor_session =
+            Some(
+                self.copy_man
+                    .create_pointer_cursor_session(&self.capture_src, &pointer, eq, ()),
+            );
+        Ok(())
+    }
+
+    fn cursor_position(&self) -> Option<(i32, i32)> {
+        self.cursor_position
+    }
+
+    fn capture_cursor_image(
+        &mut self,
+        gm: &GlobalList,
+        eq: &QueueHandle<crate::State<Self>>,
+    ) -> anyhow::Result<()> {
+        let cursor_session = self
+            .cursor_session
+            .as_ref()
+            .context("the cursor has to be tracked to capture its image")?;
+        self.cursor.shm = Some(
+            gm.bind(eq, 1..=1, ())
+                .context("Your compositor does not support wl_shm")?,
+        );
+        self.cursor.session = Some(cursor_session.get_capture_session(eq, CursorImageCapture));
+        Ok(())
+    }
+
+    fn take_cursor_image(&mut self) -> Option<CursorImage> {
+        if !std::mem::take(&mut self.cursor.image_changed) || self.cursor.pixels.is_empty() {
+            return None;
+        }
+        Some(CursorImage {
+            pixels: self.cursor.pixels.clone(),
+            size: self.cursor.image_size,
+            hotspot: self.cursor.hotspot,
         })
     }
 
@@ -232,3 +612,17 @@ impl CaptureSource for CapExtImageCopy {
         f.destroy();
     }
 }
+
+impl Drop for CapExtImageCopy {
+    fn drop(&mut self) {
+        if let Some(frame) = self.cursor.frame.take() {
+            frame.destroy();
+        }
+        if let Some(session) = self.cursor.session.take() {
+            session.destroy();
+        }
+        if let Some(cursor_session) = &self.cursor_session {
+            cursor_session.destroy();
+        }
+    }
+}
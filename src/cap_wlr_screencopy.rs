@@ -17,7 +17,7 @@ use wayland_protocols_wlr::screencopy::v1::client::{
     zwlr_screencopy_manager_v1::ZwlrScreencopyManagerV1,
 };
 
-use crate::{CaptureSource, DmabufPotentialFormat, DrmModifier, State};
+use crate::{transform::Rect, CaptureSource, DmabufPotentialFormat, DrmModifier, State};
 
 impl Dispatch<ZwlrScreencopyManagerV1, ()> for State<CapWlrScreencopy> {
     fn event(
@@ -70,7 +70,15 @@ impl Dispatch<ZwlrScreencopyFrameV1, ()> for State<CapWlrScreencopy> {
                 );
                 state.on_frame_allocd(qhandle, capture);
             }
-            zwlr_screencopy_frame_v1::Event::Damage { .. } => {}
+            zwlr_screencopy_frame_v1::Event::Damage {
+                x,
+                y,
+                width,
+                height,
+            } => state.on_damage(Rect::new(
+                (x as i32, y as i32),
+                (width as i32, height as i32),
+            )),
             zwlr_screencopy_frame_v1::Event::Buffer { .. } => {}
             zwlr_screencopy_frame_v1::Event::Flags { .. } => {}
             zwlr_screencopy_frame_v1::Event::Failed => {
@@ -109,12 +117,14 @@ pub struct CapWlrScreencopy {
     screencopy_manager: ZwlrScreencopyManagerV1,
     output: WlOutput,
     drm_device: Option<PathBuf>,
+    paint_cursor: bool,
 }
 impl CaptureSource for CapWlrScreencopy {
     fn new(
         gm: &GlobalList,
         eq: &QueueHandle<State<Self>>,
         output: WlOutput,
+        paint_cursor: bool,
     ) -> anyhow::Result<Self> {
         let man: ZwlrScreencopyManagerV1 = gm
             .bind(eq, 3..=ZwlrScreencopyManagerV1::interface().version, ()).context("your compositor does not support zwlr-screencopy-manager and therefore is not support by wl-screenrec. See the README for supported compositors")?;
@@ -128,6 +138,7 @@ impl CaptureSource for CapWlrScreencopy {
             screencopy_manager: man,
             output,
             drm_device: None,
+            paint_cursor,
         })
     }
 
@@ -142,9 +153,9 @@ impl CaptureSource for CapWlrScreencopy {
     fn alloc_frame(&self, eq: &QueueHandle<State<Self>>) -> Option<Self::Frame> {
         // creating this triggers the linux_dmabuf event, which is where we allocate etc
 
-        let _capture = self
-            .screencopy_manager
-            .capture_output(1, &self.output, eq, ());
+        let _capture =
+            self.screencopy_manager
+                .capture_output(self.paint_cursor.into(), &self.output, eq, ());
 
         None
     }
@@ -0,0 +1,109 @@
+use std::{fmt, str::FromStr, time::Duration};
+
+use thiserror::Error;
+
+// --on-capture-error, what to do when the compositor fails copies for reasons other than the
+// output going away or the format changing
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CaptureErrorPolicy {
+    #[default]
+    Retry, // keep trying, the recording just has a gap
+    Pause,          // keep trying, but cut the time spent failing out of the recording
+    ExitAfter(u32), // give up after this many failures in a row
+}
+
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum ParsePolicyError {
+    #[error("expected retry, pause or exit-after=N, got {0}")]
+    Policy(String),
+    #[error("invalid count for exit-after, expected a number above 0")]
+    Count,
+}
+
+impl FromStr for CaptureErrorPolicy {
+    type Err = ParsePolicyError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "retry" => Ok(CaptureErrorPolicy::Retry),
+            "pause" => Ok(CaptureErrorPolicy::Pause),
+            _ => match s.strip_prefix("exit-after") {
+                Some(n) => match n.trim_start_matches(['=', ' ']).parse() {
+                    Ok(n) if n > 0 => Ok(CaptureErrorPolicy::ExitAfter(n)),
+                    _ => Err(ParsePolicyError::Count),
+                },
+                None => Err(ParsePolicyError::Policy(s.into())),
+            },
+        }
+    }
+}
+
+impl fmt::Display for CaptureErrorPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CaptureErrorPolicy::Retry => write!(f, "retry"),
+            CaptureErrorPolicy::Pause => write!(f, "pause"),
+            CaptureErrorPolicy::ExitAfter(n) => write!(f, "exit-after={n}"),
+        }
+    }
+}
+
+const MIN_BACKOFF: Duration = Duration::from_millis(10);
+const MAX_BACKOFF: Duration = Duration::from_secs(1);
+
+// how long to wait before capturing again after `failures` failed copies in a row. The first
+// retry is immediate, as a single failure is usually a hiccup, then the wait doubles so a
+// compositor that fails every copy isn't hammered with requests
+pub fn backoff(failures: u32) -> Duration {
+    match failures {
+        0 | 1 => Duration::ZERO,
+        n => MIN_BACKOFF
+            .saturating_mul(1 << (n - 2).min(16))
+            .min(MAX_BACKOFF),
+    }
+}
+
+// CLOCK_MONOTONIC, which compositors use for the presentation timestamps of captured frames
+pub fn monotonic_ns() -> i64 {
+    let mut ts = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    unsafe { libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut ts) };
+    ts.tv_sec * 1_000_000_000 + ts.tv_nsec
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use super::{backoff, CaptureErrorPolicy, ParsePolicyError};
+
+    #[test]
+    fn parse() {
+        assert_eq!("retry".parse(), Ok(CaptureErrorPolicy::Retry));
+        assert_eq!("pause".parse(), Ok(CaptureErrorPolicy::Pause));
+        assert_eq!("exit-after=5".parse(), Ok(CaptureErrorPolicy::ExitAfter(5)));
+        assert_eq!("exit-after 3".parse(), Ok(CaptureErrorPolicy::ExitAfter(3)));
+        assert_eq!(
+            "exit-after=0".parse::<CaptureErrorPolicy>(),
+            Err(ParsePolicyError::Count)
+        );
+        assert_eq!(
+            "exit".parse::<CaptureErrorPolicy>(),
+            Err(ParsePolicyError::Policy("exit".into()))
+        );
+        for p in ["retry", "pause", "exit-after=10"] {
+            assert_eq!(p.parse::<CaptureErrorPolicy>().unwrap().to_string(), p);
+        }
+    }
+
+    #[test]
+    fn backoff_grows() {
+        assert_eq!(backoff(1), Duration::ZERO);
+        assert_eq!(backoff(2), Duration::from_millis(10));
+        assert_eq!(backoff(4), Duration::from_millis(40));
+        assert_eq!(backoff(10), Duration::from_secs(1));
+        assert_eq!(backoff(u32::MAX), Duration::from_secs(1));
+    }
+}
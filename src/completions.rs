@@ -0,0 +1,104 @@
+use std::io::Write;
+
+use clap::Command;
+use clap_complete::Shell;
+
+// clap_complete only knows --output takes a string, so on its own it completes file names. For
+// the shells that can run a command while completing, swap that for the names from
+// `wl-screenrec list-outputs --names`, so DP-1 etc come from whatever is connected right now.
+// The other shells are left as clap generates them
+
+pub fn generate(shell: Shell, command: &mut Command, bin_name: String, out: &mut impl Write) {
+    let mut script = Vec::new();
+    clap_complete::generate(shell, command, bin_name.clone(), &mut script);
+    let script = String::from_utf8(script).unwrap();
+    let script = patch(shell, &script, &bin_name);
+    out.write_all(script.as_bytes()).unwrap();
+}
+
+fn patch(shell: Shell, script: &str, bin_name: &str) -> String {
+    let names = format!("{bin_name} list-outputs --names 2>/dev/null");
+    let mut patched = String::new();
+    let mut after_output_case = false;
+    for line in script.lines() {
+        let trimmed = line.trim();
+        let line = match shell {
+            Shell::Bash if after_output_case => line.replace(
+                r#"compgen -f "${cur}""#,
+                &format!(r#"compgen -W "$({names})" -- "${{cur}}""#),
+            ),
+            Shell::Zsh if trimmed.starts_with("'-o+[") || trimmed.starts_with("'--output=[") => {
+                line.replace(
+                    ":OUTPUT:_default'",
+                    &format!(":OUTPUT:{{compadd -- $({names})}}'"),
+                )
+            }
+            Shell::Fish if line.contains(" -s o -l output ") => {
+                format!(r#"{line} -f -a "({names})""#)
+            }
+            _ => line.to_owned(),
+        };
+        after_output_case = trimmed == "--output)" || trimmed == "-o)";
+        patched += &line;
+        patched += "\n";
+    }
+    patched
+}
+
+#[cfg(test)]
+mod test {
+    use clap::{Arg, Command};
+    use clap_complete::Shell;
+
+    fn completions(shell: Shell) -> String {
+        let mut command = Command::new("wl-screenrec")
+            .arg(
+                Arg::new("output")
+                    .long("output")
+                    .short('o')
+                    .value_name("OUTPUT"),
+            )
+            .arg(
+                Arg::new("filename")
+                    .long("filename")
+                    .short('f')
+                    .value_name("FILENAME"),
+            );
+        let mut out = Vec::new();
+        super::generate(shell, &mut command, "wl-screenrec".to_owned(), &mut out);
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn bash() {
+        let script = completions(Shell::Bash);
+        assert_eq!(
+            script
+                .matches(r#"COMPREPLY=($(compgen -W "$(wl-screenrec list-outputs --names 2>/dev/null)" -- "${cur}"))"#)
+                .count(),
+            2
+        );
+        // --filename still completes files
+        assert!(script.contains(r#"COMPREPLY=($(compgen -f "${cur}"))"#));
+    }
+
+    #[test]
+    fn zsh() {
+        let script = completions(Shell::Zsh);
+        assert_eq!(
+            script
+                .matches(":OUTPUT:{compadd -- $(wl-screenrec list-outputs --names 2>/dev/null)}'")
+                .count(),
+            2
+        );
+        assert!(script.contains(":FILENAME:_default'"));
+    }
+
+    #[test]
+    fn fish() {
+        let script = completions(Shell::Fish);
+        assert!(script.contains(
+            r#"-s o -l output -r -f -a "(wl-screenrec list-outputs --names 2>/dev/null)""#
+        ));
+    }
+}
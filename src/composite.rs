@@ -0,0 +1,254 @@
+use std::iter::zip;
+
+use crate::{
+    multi_output::{source_name, OtherOutput},
+    transform::Rect,
+    Layout,
+};
+
+// --geometry passed more than once: each region is cropped out of the capture on its own, scaled
+// so they line up and stacked into one frame, all on the GPU. With --outputs the whole first
+// output is the only region, and the others are stacked after it
+#[derive(Debug, Clone, PartialEq)]
+pub struct Composite {
+    pub regions: Vec<Rect>, // screen coordinates, like the roi
+    pub layout: Layout,
+    pub others: Vec<OtherOutput>,
+    pub zoom: f64, // with others, the regions' scale to the most scaled output's pixel density
+}
+
+// rounded to even sizes, which subsampled pixel formats need
+fn even(x: f64) -> i32 {
+    ((x / 2.).round() as i32).max(1) * 2
+}
+
+impl Composite {
+    // the smallest rect containing every region
+    pub fn bounds(&self) -> Rect {
+        let x = self.regions.iter().map(|r| r.x).min().unwrap();
+        let y = self.regions.iter().map(|r| r.y).min().unwrap();
+        let x2 = self.regions.iter().map(|r| r.x + r.w).max().unwrap();
+        let y2 = self.regions.iter().map(|r| r.y + r.h).max().unwrap();
+        Rect::new((x, y), (x2 - x, y2 - y))
+    }
+
+    // size of each region, then each other output, in the composited frame. Side by side regions
+    // are scaled to the tallest one's height, stacked to the widest one's width. Outputs are all
+    // scaled to the same pixel density instead, so text is the same size on each
+    pub fn tile_sizes(&self) -> Vec<(i32, i32)> {
+        if !self.others.is_empty() {
+            let regions = self.regions.iter().map(|r| ((r.w, r.h), self.zoom));
+            let others = self.others.iter().map(|o| (o.size, o.zoom));
+            return regions
+                .chain(others)
+                .map(|((w, h), zoom)| (even(f64::from(w) * zoom), even(f64::from(h) * zoom)))
+                .collect();
+        }
+        match self.layout {
+            Layout::Horizontal => {
+                let h = self.regions.iter().map(|r| r.h).max().unwrap();
+                let h = even(f64::from(h));
+                self.regions
+                    .iter()
+                    .map(|r| (even(f64::from(r.w) * f64::from(h) / f64::from(r.h)), h))
+                    .collect()
+            }
+            Layout::Vertical => {
+                let w = self.regions.iter().map(|r| r.w).max().unwrap();
+                let w = even(f64::from(w));
+                self.regions
+                    .iter()
+                    .map(|r| (w, even(f64::from(r.h) * f64::from(w) / f64::from(r.w))))
+                    .collect()
+            }
+            Layout::Grid => self
+                .regions
+                .iter()
+                .map(|r| (even(f64::from(r.w)), even(f64::from(r.h))))
+                .collect(),
+        }
+    }
+
+    // where each region ends up in the composited frame
+    pub fn tiles(&self) -> Vec<Rect> {
+        let sizes = self.tile_sizes();
+        // grid cells fit the biggest tile, filled row by row in a square-ish grid
+        let cell_w = sizes.iter().map(|t| t.0).max().unwrap();
+        let cell_h = sizes.iter().map(|t| t.1).max().unwrap();
+        let columns = (sizes.len() as f64).sqrt().ceil() as usize;
+        let mut offset = 0;
+        sizes
+            .into_iter()
+            .enumerate()
+            .map(|(i, (w, h))| match self.layout {
+                Layout::Horizontal => {
+                    offset += w;
+                    Rect::new((offset - w, 0), (w, h))
+                }
+                Layout::Vertical => {
+                    offset += h;
+                    Rect::new((0, offset - h), (w, h))
+                }
+                Layout::Grid => Rect::new(
+                    ((i % columns) as i32 * cell_w, (i / columns) as i32 * cell_h),
+                    (w, h),
+                ),
+            })
+            .collect()
+    }
+
+    pub fn size(&self) -> (i32, i32) {
+        let tiles = self.tiles();
+        (
+            tiles.iter().map(|t| t.x + t.w).max().unwrap(),
+            tiles.iter().map(|t| t.y + t.h).max().unwrap(),
+        )
+    }
+
+    // filtergraph with one output, like a single crop. `tile` is the chain cropping a region and
+    // scaling it to a tile size (both in screen coordinates), `other` the one scaling an other
+    // output. Its input is unlabelled, unless there are other outputs, whose inputs are labelled
+    // by source_name, then it's [in]
+    pub fn filter(
+        &self,
+        tile: impl Fn(Rect, (i32, i32)) -> String,
+        other: impl Fn(&OtherOutput, (i32, i32)) -> String,
+        vaapi: bool,
+    ) -> String {
+        let n = self.regions.len();
+        let sizes = self.tile_sizes();
+
+        let mut f = if self.others.is_empty() {
+            String::new()
+        } else {
+            "[in]".to_owned()
+        };
+        f += &format!("split={n}");
+        for i in 0..n {
+            f += &format!("[c{i}]");
+        }
+        for (i, (region, size)) in zip(&self.regions, &sizes).enumerate() {
+            f += &format!(";[c{i}]{}[t{i}]", tile(*region, *size));
+        }
+        for (i, (o, size)) in zip(&self.others, &sizes[n..]).enumerate() {
+            f += &format!(";[{}]{}[t{}]", source_name(i), other(o, *size), n + i);
+        }
+        f += ";";
+        for i in 0..sizes.len() {
+            f += &format!("[t{i}]");
+        }
+
+        let vaapi = if vaapi { "_vaapi" } else { "" };
+        let inputs = sizes.len();
+        match self.layout {
+            Layout::Horizontal if self.others.is_empty() => {
+                f + &format!("hstack{vaapi}=inputs={inputs}")
+            }
+            Layout::Vertical if self.others.is_empty() => {
+                f + &format!("vstack{vaapi}=inputs={inputs}")
+            }
+            _ => {
+                // tiles of different sizes, placed explicitly with the gaps left black
+                let layout: Vec<_> = self
+                    .tiles()
+                    .iter()
+                    .map(|t| format!("{}_{}", t.x, t.y))
+                    .collect();
+                f + &format!(
+                    "xstack{vaapi}=inputs={inputs}:layout={}:fill=black",
+                    layout.join("|")
+                )
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Composite;
+    use crate::{transform::Rect, Layout};
+
+    #[test]
+    fn layout() {
+        let side_by_side = Composite {
+            regions: vec![
+                Rect::new((100, 100), (800, 600)),
+                Rect::new((1000, 0), (400, 300)),
+            ],
+            layout: Layout::Horizontal,
+            others: Vec::new(),
+            zoom: 1.,
+        };
+        assert_eq!(side_by_side.bounds(), Rect::new((100, 0), (1300, 700)));
+        assert_eq!(side_by_side.tile_sizes(), vec![(800, 600), (800, 600)]);
+        assert_eq!(side_by_side.size(), (1600, 600));
+        assert_eq!(
+            side_by_side.tiles(),
+            vec![
+                Rect::new((0, 0), (800, 600)),
+                Rect::new((800, 0), (800, 600))
+            ]
+        );
+
+        let stacked = Composite {
+            layout: Layout::Vertical,
+            ..side_by_side.clone()
+        };
+        assert_eq!(stacked.tile_sizes(), vec![(800, 600), (800, 600)]);
+        assert_eq!(stacked.size(), (800, 1200));
+        assert_eq!(stacked.tiles()[1], Rect::new((0, 600), (800, 600)));
+
+        // a grid keeps each region's size, in cells as big as the biggest
+        let grid = Composite {
+            regions: vec![
+                Rect::new((0, 0), (800, 600)),
+                Rect::new((0, 0), (400, 300)),
+                Rect::new((0, 0), (640, 480)),
+            ],
+            layout: Layout::Grid,
+            ..side_by_side.clone()
+        };
+        assert_eq!(
+            grid.tiles(),
+            vec![
+                Rect::new((0, 0), (800, 600)),
+                Rect::new((800, 0), (400, 300)),
+                Rect::new((0, 600), (640, 480))
+            ]
+        );
+        assert_eq!(grid.size(), (1200, 1080));
+
+        // odd sizes are rounded to even
+        let odd = Composite {
+            regions: vec![Rect::new((0, 0), (333, 201)), Rect::new((0, 0), (100, 100))],
+            layout: Layout::Horizontal,
+            others: Vec::new(),
+            zoom: 1.,
+        };
+        assert_eq!(odd.tile_sizes(), vec![(334, 202), (202, 202)]);
+    }
+
+    #[test]
+    fn filter() {
+        let c = Composite {
+            regions: vec![Rect::new((0, 0), (10, 10)), Rect::new((10, 0), (10, 10))],
+            layout: Layout::Horizontal,
+            others: Vec::new(),
+            zoom: 1.,
+        };
+        let crop = |r: Rect, (w, h)| format!("crop@{}:{w}x{h}", r.x);
+        assert_eq!(
+            c.filter(crop, |_, _| unreachable!(), true),
+            "split=2[c0][c1];[c0]crop@0:10x10[t0];[c1]crop@10:10x10[t1];[t0][t1]hstack_vaapi=inputs=2"
+        );
+
+        let grid = Composite {
+            layout: Layout::Grid,
+            ..c
+        };
+        assert_eq!(
+            grid.filter(crop, |_, _| unreachable!(), false),
+            "split=2[c0][c1];[c0]crop@0:10x10[t0];[c1]crop@10:10x10[t1];[t0][t1]xstack=inputs=2:layout=0_0|10_0:fill=black"
+        );
+    }
+}
@@ -0,0 +1,270 @@
+use std::{
+    ffi::CString,
+    fs,
+    io::{Seek, SeekFrom},
+    os::unix::fs::FileTypeExt,
+    path::Path,
+    ptr::null,
+};
+
+use ffmpeg::{
+    codec,
+    ffi::{av_guess_format, avformat_query_codec, FF_COMPLIANCE_STRICT},
+    format,
+};
+
+// the container is picked from --filename's extension (or --ffmpeg-muxer), so whether it can hold
+// the codecs asked for is checked while the arguments are processed, before anything is captured
+// or encoded, and the error can say which container would work
+
+// offered instead, in order of preference, by extension and muxer name
+const CONTAINERS: &[(&str, &str)] = &[
+    ("mkv", "matroska"),
+    ("mp4", "mp4"),
+    ("webm", "webm"),
+    ("mov", "mov"),
+];
+
+pub enum Support {
+    Yes,
+    No,
+    Maybe(ffmpeg::Error), // ffmpeg doesn't know, it's up to the muxer when the header is written
+}
+
+// what ffmpeg would write `filename` as, None if it can't tell (like for pipes)
+pub fn guess(filename: &str, muxer: Option<&str>) -> Option<format::Output> {
+    let filename = CString::new(filename).ok()?;
+    let muxer = muxer.map(CString::new).transpose().ok()?;
+    let ptr = unsafe {
+        av_guess_format(
+            muxer.as_ref().map_or(null(), |m| m.as_ptr()),
+            filename.as_ptr(),
+            null(),
+        )
+    };
+    (!ptr.is_null()).then(|| unsafe { format::Output::wrap(ptr as *mut _) })
+}
+
+pub fn supports(format: &format::Output, codec: codec::Id) -> Support {
+    match unsafe { avformat_query_codec(format.as_ptr(), codec.into(), FF_COMPLIANCE_STRICT) } {
+        0 => Support::No,
+        1 => Support::Yes,
+        e => Support::Maybe(ffmpeg::Error::from(e)),
+    }
+}
+
+// the first of CONTAINERS that can hold all of `codecs`, as (extension, muxer name)
+pub fn compatible(codecs: &[codec::Id]) -> Option<(&'static str, &'static str)> {
+    CONTAINERS.iter().copied().find(|(_, muxer)| {
+        guess("", Some(muxer)).is_some_and(|format| {
+            codecs
+                .iter()
+                .all(|c| matches!(supports(&format, *c), Support::Yes))
+        })
+    })
+}
+
+// an error for `format` not holding `codec`, with a suggestion when another container holds all
+// of `codecs`
+pub fn unsupported_error(
+    format: &format::Output,
+    codec: codec::Id,
+    codecs: &[codec::Id],
+    muxer_given: bool,
+) -> String {
+    let mut msg = format!(
+        "{} files can't hold {codec:?}",
+        format
+            .extensions()
+            .first()
+            .copied()
+            .unwrap_or(format.name())
+    );
+    if let Some((ext, muxer)) = compatible(codecs) {
+        let names = codecs
+            .iter()
+            .map(|c| format!("{c:?}"))
+            .collect::<Vec<_>>()
+            .join(" and ");
+        if muxer_given {
+            msg += &format!(". {names} can be written with --ffmpeg-muxer {muxer}");
+        } else {
+            msg += &format!(". {names} can be written to a .{ext} file instead");
+        }
+    }
+    msg
+}
+
+fn is_mov(format: &format::Output) -> bool {
+    matches!(
+        format.name(),
+        "mp4" | "mov" | "ipod" | "3gp" | "3g2" | "ismv" | "f4v" | "psp"
+    )
+}
+
+fn fragmented(muxer_options: Option<&str>) -> bool {
+    muxer_options.is_some_and(|o| o.contains("frag_") || o.contains("empty_moov"))
+}
+
+// mp4 and its relatives go back to write the index at the start of the file when it's finished,
+// unless they're fragmented
+pub fn needs_seekable(format: &format::Output, muxer_options: Option<&str>) -> bool {
+    is_mov(format) && !fragmented(muxer_options)
+}
+
+// whether what was written before a recording was interrupted can be read back. Matroska can be
+// cut off after any cluster, mp4 only has an index before it's finished if it's fragmented
+pub fn recoverable(format: &format::Output, muxer_options: Option<&str>) -> bool {
+    matches!(format.name(), "matroska" | "webm") || (is_mov(format) && fragmented(muxer_options))
+}
+
+// false for pipes, devices and network outputs
+pub fn seekable(filename: &str) -> bool {
+    if filename == "-" || filename.starts_with("pipe:") || filename.contains("://") {
+        return false;
+    }
+    match fs::metadata(filename) {
+        Err(_) => true, // created as a regular file
+        Ok(meta) if meta.is_file() => true,
+        // opening a fifo would block until something reads it
+        Ok(meta) if meta.file_type().is_fifo() || meta.file_type().is_socket() => false,
+        Ok(_) => fs::File::open(filename)
+            .and_then(|mut f| f.seek(SeekFrom::Start(0)))
+            .is_ok(),
+    }
+}
+
+// `filename` with a .mkv extension, for --container-fallback
+pub fn mkv_filename(filename: &str) -> String {
+    Path::new(filename)
+        .with_extension("mkv")
+        .to_string_lossy()
+        .into_owned()
+}
+
+// the parameter sets in a stream's extradata, to tell whether two encoders were configured the
+// same. Encoders give H.264 and HEVC's as Annex B, muxers store them as avcC and hvcC, and either
+// can carry SEI along with them that doesn't matter here
+pub fn codec_config(id: codec::Id, extradata: &[u8]) -> Vec<&[u8]> {
+    let parameter_set = |unit: &&[u8]| match (id, unit.first()) {
+        (codec::Id::H264, Some(b)) => matches!(b & 0x1f, 7 | 8),
+        (codec::Id::HEVC, Some(b)) => matches!((b >> 1) & 0x3f, 32..=34),
+        _ => false,
+    };
+    match id {
+        codec::Id::H264 | codec::Id::HEVC => {
+            let units = match extradata.first() {
+                Some(1) => config_record(id, extradata).unwrap_or_default(),
+                _ => annex_b(extradata),
+            };
+            units.into_iter().filter(parameter_set).collect()
+        }
+        // av1C has 4 bytes of its own before the sequence header
+        codec::Id::AV1 if extradata.first() == Some(&0x81) => {
+            vec![&extradata[4.min(extradata.len())..]]
+        }
+        _ => vec![extradata],
+    }
+}
+
+fn annex_b(data: &[u8]) -> Vec<&[u8]> {
+    let mut units = Vec::new();
+    let mut start = None;
+    let mut i = 0;
+    while i + 3 <= data.len() {
+        if data[i..i + 3] == [0, 0, 1] {
+            if let Some(start) = start {
+                units.push(&data[start..i]);
+            }
+            i += 3;
+            start = Some(i);
+        } else {
+            i += 1;
+        }
+    }
+    if let Some(start) = start {
+        units.push(&data[start..]);
+    }
+    // the zero before a 4 byte start code ends up on the previous unit
+    units
+        .into_iter()
+        .map(|u| &u[..u.iter().rposition(|&b| b != 0).map_or(0, |p| p + 1)])
+        .filter(|u| !u.is_empty())
+        .collect()
+}
+
+// the NAL units of an avcC or hvcC record, None if it's cut short
+fn config_record(id: codec::Id, data: &[u8]) -> Option<Vec<&[u8]>> {
+    let mut rest = data;
+    let mut units = Vec::new();
+    if id == codec::Id::H264 {
+        take(&mut rest, 5)?;
+        let sps = take(&mut rest, 1)?[0] & 0x1f;
+        length_prefixed(&mut rest, sps.into(), &mut units)?;
+        let pps = take(&mut rest, 1)?[0];
+        length_prefixed(&mut rest, pps.into(), &mut units)?;
+    } else {
+        take(&mut rest, 22)?;
+        for _ in 0..take(&mut rest, 1)?[0] {
+            take(&mut rest, 1)?; // NAL unit type of the array
+            let count = u16::from_be_bytes(take(&mut rest, 2)?.try_into().unwrap());
+            length_prefixed(&mut rest, count, &mut units)?;
+        }
+    }
+    Some(units)
+}
+
+fn take<'a>(rest: &mut &'a [u8], n: usize) -> Option<&'a [u8]> {
+    let (taken, left) = rest.split_at_checked(n)?;
+    *rest = left;
+    Some(taken)
+}
+
+// `count` units that each start with their 16 bit length
+fn length_prefixed<'a>(rest: &mut &'a [u8], count: u16, units: &mut Vec<&'a [u8]>) -> Option<()> {
+    for _ in 0..count {
+        let len = u16::from_be_bytes(take(rest, 2)?.try_into().unwrap());
+        units.push(take(rest, len.into())?);
+    }
+    Some(())
+}
+
+#[cfg(test)]
+mod test {
+    use ffmpeg::codec;
+
+    use super::{codec_config, mkv_filename, seekable};
+
+    #[test]
+    fn fallback_names() {
+        assert_eq!(mkv_filename("/tmp/rec.mp4"), "/tmp/rec.mkv");
+        assert_eq!(mkv_filename("rec"), "rec.mkv");
+        assert!(!seekable("-"));
+        assert!(!seekable("rtmp://localhost/live"));
+        assert!(seekable("/dev/null"));
+        assert!(seekable("/tmp/does-not-exist/rec.mp4"));
+    }
+
+    #[test]
+    fn h264_config() {
+        let (sps, pps, sei) = (
+            &[0x67, 0x64, 0x00, 0x1f][..],
+            &[0x68, 0xee][..],
+            &[0x06, 0x05][..],
+        );
+        let annex_b = [&[0, 0, 0, 1][..], sps, &[0, 0, 0, 1], pps, &[0, 0, 1], sei].concat();
+        let avcc = [
+            &[1, 0x64, 0x00, 0x1f, 0xff, 0xe1, 0, 4][..],
+            sps,
+            &[1, 0, 2],
+            pps,
+        ]
+        .concat();
+        assert_eq!(codec_config(codec::Id::H264, &annex_b), [sps, pps]);
+        assert_eq!(codec_config(codec::Id::H264, &avcc), [sps, pps]);
+        assert_ne!(
+            codec_config(codec::Id::H264, &avcc[..avcc.len() - 1]),
+            [sps, pps]
+        );
+    }
+}
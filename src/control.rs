@@ -0,0 +1,493 @@
+use std::{
+    env,
+    fs::{self, File},
+    io::{self, BufRead, BufReader, Write},
+    os::{
+        fd::{FromRawFd, RawFd},
+        unix::net::{UnixListener, UnixStream},
+    },
+    path::{Path, PathBuf},
+    process::Command,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, Ordering::SeqCst},
+        mpsc::{channel, Receiver, Sender},
+        Arc,
+    },
+    thread::{self, sleep},
+    time::{Duration, Instant},
+};
+
+use log::{debug, info, warn};
+use thiserror::Error;
+
+use crate::{parse_geometry, splice::SpliceCue};
+
+// Commands that can be sent to a running instance over its control socket.
+// The protocol is one command per line, and each command gets a one line reply
+// of either `ok` or `error: <reason>`
+#[derive(Debug, Clone, PartialEq)]
+pub enum ControlCommand {
+    Stop,
+    SetRegion((i32, i32, u32, u32)), // same format as --geometry
+    Reselect, // run --selector and use the region it prints. Handled by the control thread
+    SetOutput(String),
+    Marker(String),    // a cue on the --markers track
+    Screenshot,        // save the next recorded frame to --screenshot-filename
+    Split,             // finish the file on the next keyframe and continue in a new one
+    Splice(SpliceCue), // a cue on the --splice-points stream
+    Mute,              // record silence instead of the audio input, until unmuted
+    Unmute,
+    ToggleMute,
+}
+
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum ParseCommandError {
+    #[error("empty command")]
+    Empty,
+    #[error("unknown command {0}")]
+    Unknown(String),
+    #[error("command {0} takes no arguments")]
+    UnexpectedArgs(String),
+    #[error("invalid arguments to {0}: {1}")]
+    InvalidArgs(String, String),
+}
+
+impl FromStr for ControlCommand {
+    type Err = ParseCommandError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        use ParseCommandError::*;
+        let s = s.trim();
+        let (cmd, rest) = s.split_once(' ').unwrap_or((s, ""));
+        let no_args = |c: ControlCommand| {
+            if rest.trim().is_empty() {
+                Ok(c)
+            } else {
+                Err(UnexpectedArgs(cmd.to_string()))
+            }
+        };
+        match cmd {
+            "" => Err(Empty),
+            "stop" => no_args(ControlCommand::Stop),
+            "set-region" => parse_geometry(rest.trim())
+                .map(ControlCommand::SetRegion)
+                .map_err(|e| InvalidArgs(cmd.to_string(), e.to_string())),
+            "reselect" => no_args(ControlCommand::Reselect),
+            "set-output" if rest.trim().is_empty() => Err(InvalidArgs(
+                cmd.to_string(),
+                "expected an output name".to_string(),
+            )),
+            "set-output" => Ok(ControlCommand::SetOutput(rest.trim().to_string())),
+            "marker" if rest.trim().is_empty() => Err(InvalidArgs(
+                cmd.to_string(),
+                "expected the marker's text".to_string(),
+            )),
+            "marker" => Ok(ControlCommand::Marker(rest.trim().to_string())),
+            "screenshot" => no_args(ControlCommand::Screenshot),
+            "split" => no_args(ControlCommand::Split),
+            "mute" => no_args(ControlCommand::Mute),
+            "unmute" => no_args(ControlCommand::Unmute),
+            "toggle-mute" => no_args(ControlCommand::ToggleMute),
+            "splice" => rest
+                .trim()
+                .parse()
+                .map(ControlCommand::Splice)
+                .map_err(|e| InvalidArgs(cmd.to_string(), e)),
+            _ => Err(Unknown(cmd.to_string())),
+        }
+    }
+}
+
+fn socket_dir() -> PathBuf {
+    let mut dir = env::var_os("XDG_RUNTIME_DIR")
+        .map(PathBuf::from)
+        .unwrap_or_else(env::temp_dir);
+    dir.push("wl-screenrec");
+    dir
+}
+
+// there's a socket for each output being recorded, so two instances recording different outputs
+// don't collide and the one recording an output can be found by its name
+fn socket_path(output: &str) -> PathBuf {
+    socket_dir().join(format!("{}.sock", output.replace('/', "_")))
+}
+
+// outputs with an instance listening for commands
+fn running_instances() -> Vec<String> {
+    let Ok(entries) = fs::read_dir(socket_dir()) else {
+        return Vec::new();
+    };
+    let mut outputs: Vec<_> = entries
+        .filter_map(|e| {
+            let path = e.ok()?.path();
+            let name = path
+                .file_name()?
+                .to_str()?
+                .strip_suffix(".sock")?
+                .to_owned();
+            is_running(&path).then_some(name)
+        })
+        .collect();
+    outputs.sort();
+    outputs
+}
+
+// `output` is an --output value, which may be a fallback list. Without one, the only instance
+// running is picked
+fn pick_instance(output: &str, running: &[String]) -> Result<String, String> {
+    if output.is_empty() || output == "any" {
+        return match running {
+            [] => Err("no instance is recording".to_owned()),
+            [only] => Ok(only.clone()),
+            _ => Err(format!(
+                "instances are recording {}, pass --output to pick one",
+                running.join(", ")
+            )),
+        };
+    }
+    let mut names = output.split(',').map(|n| n.trim().replace('/', "_"));
+    names
+        .find(|n| running.contains(n))
+        .ok_or_else(|| format!("no instance is recording {output}"))
+}
+
+// the control socket of the instance recording the output `output` selects
+pub fn find_instance(output: &str) -> Result<PathBuf, String> {
+    pick_instance(output, &running_instances()).map(|name| socket_path(&name))
+}
+
+pub fn is_running(path: &Path) -> bool {
+    UnixStream::connect(path).is_ok()
+}
+
+pub fn send_command(path: &Path, cmd: &str) -> io::Result<String> {
+    let mut stream = UnixStream::connect(path)?;
+    writeln!(stream, "{cmd}")?;
+    let mut reply = String::new();
+    BufReader::new(stream).read_line(&mut reply)?;
+    Ok(reply.trim_end().to_string())
+}
+
+// ask the instance listening on `path` to stop, and wait for it to finalize its file
+pub fn stop_existing(path: &Path, timeout: Duration) -> anyhow::Result<()> {
+    let reply = send_command(path, "stop")?;
+    if reply != "ok" {
+        anyhow::bail!("existing instance refused to stop: {reply}");
+    }
+
+    let start = Instant::now();
+    while is_running(path) {
+        if start.elapsed() > timeout {
+            anyhow::bail!("existing instance did not stop within {timeout:?}");
+        }
+        sleep(Duration::from_millis(50));
+    }
+    Ok(())
+}
+
+#[derive(Error, Debug)]
+pub enum ClaimError {
+    #[error("another instance is already recording {0}")]
+    Taken(String),
+    #[error("failed to stop the instance already recording {0}: {1}")]
+    Replace(String, anyhow::Error),
+}
+
+// listens on one output's socket until dropped
+struct Listener {
+    path: PathBuf,
+    closed: Arc<AtomicBool>,
+}
+
+impl Drop for Listener {
+    fn drop(&mut self) {
+        self.closed.store(true, SeqCst);
+        // wakes the accept thread so it sees it's closed
+        let _ = UnixStream::connect(&self.path);
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+pub struct ControlServer {
+    listeners: Vec<(String, Listener)>,
+    claimed: bool,
+    tx: Sender<ControlCommand>,
+    rx: Receiver<ControlCommand>,
+    selector: String, // run for `reselect`
+    wake: Arc<dyn Fn() + Send + Sync>,
+}
+
+impl ControlServer {
+    // `wake` is called after a command is queued, so the main loop can process it even if it's
+    // blocked waiting for compositor events. Nothing is listened on until `claim`
+    pub fn new(selector: String, wake: impl Fn() + Send + Sync + 'static) -> Self {
+        let (tx, rx) = channel();
+        ControlServer {
+            listeners: Vec::new(),
+            claimed: false,
+            tx,
+            rx,
+            selector,
+            wake: Arc::new(wake),
+        }
+    }
+
+    // listens on the sockets of `outputs`, the ones being recorded, and stops listening on the
+    // others. The first time, an instance already recording one of them is stopped with
+    // `replace`, and an error otherwise. Later, when the recording moves, that's only warned about
+    pub fn claim(&mut self, outputs: &[String], replace: bool) -> Result<(), ClaimError> {
+        if self.claimed && self.listeners.iter().map(|(o, _)| o).eq(outputs) {
+            return Ok(());
+        }
+        let first = !self.claimed;
+        self.claimed = true;
+
+        let mut listeners = Vec::new();
+        for output in outputs {
+            if let Some(i) = self.listeners.iter().position(|(o, _)| o == output) {
+                listeners.push(self.listeners.swap_remove(i));
+                continue;
+            }
+            let path = socket_path(output);
+            if is_running(&path) {
+                if !first {
+                    warn!("another instance is already recording {output}, it keeps the control socket");
+                    continue;
+                }
+                if !replace {
+                    return Err(ClaimError::Taken(output.clone()));
+                }
+                info!("stopping the instance already recording {output}...");
+                stop_existing(&path, Duration::from_secs(30))
+                    .map_err(|e| ClaimError::Replace(output.clone(), e))?;
+            }
+            match self.listen(&path) {
+                Ok(listener) => listeners.push((output.clone(), listener)),
+                Err(e) => warn!("failed to create control socket {}: {e}", path.display()),
+            }
+        }
+        // the sockets of outputs no longer recorded are closed here
+        self.listeners = listeners;
+        Ok(())
+    }
+
+    fn listen(&self, path: &Path) -> io::Result<Listener> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let listener = match UnixListener::bind(path) {
+            Ok(l) => l,
+            Err(e) if e.kind() == io::ErrorKind::AddrInUse && !is_running(path) => {
+                debug!("removing stale control socket {}", path.display());
+                fs::remove_file(path)?;
+                UnixListener::bind(path)?
+            }
+            Err(e) => return Err(e),
+        };
+        info!("control socket listening on {}", path.display());
+
+        let closed = Arc::new(AtomicBool::new(false));
+        let (tx, selector, wake) = (self.tx.clone(), self.selector.clone(), self.wake.clone());
+        let accept_closed = closed.clone();
+        thread::Builder::new()
+            .name("control".to_owned())
+            .spawn(move || {
+                for stream in listener.incoming() {
+                    if accept_closed.load(SeqCst) {
+                        return;
+                    }
+                    let stream = match stream {
+                        Ok(stream) => stream,
+                        Err(e) => {
+                            warn!("failed to accept control connection: {e}");
+                            continue;
+                        }
+                    };
+                    // a client that stays connected, or a selection that's still being made,
+                    // doesn't hold up the others
+                    let (tx, selector, wake) = (tx.clone(), selector.clone(), wake.clone());
+                    let spawned = thread::Builder::new()
+                        .name("control-client".to_owned())
+                        .spawn(move || {
+                            if let Err(e) = handle_client(stream, &tx, &selector, &*wake) {
+                                debug!("control client error: {e}");
+                            }
+                        });
+                    if let Err(e) = spawned {
+                        warn!("failed to handle control connection: {e}");
+                    }
+                }
+            })?;
+
+        Ok(Listener {
+            path: path.to_owned(),
+            closed,
+        })
+    }
+
+    pub fn try_recv(&self) -> Option<ControlCommand> {
+        self.rx.try_recv().ok()
+    }
+}
+
+// runs the region selector (slurp by default), which is interactive, so this blocks until the
+// user picks a region
+fn run_selector(selector: &str) -> Result<ControlCommand, String> {
+    let out = Command::new("sh")
+        .arg("-c")
+        .arg(selector)
+        .output()
+        .map_err(|e| format!("failed to run {selector}: {e}"))?;
+    if !out.status.success() {
+        return Err(format!("{selector} failed: {}", out.status));
+    }
+    let region = String::from_utf8_lossy(&out.stdout);
+    parse_geometry(region.trim())
+        .map(ControlCommand::SetRegion)
+        .map_err(|e| {
+            format!(
+                "{selector} printed an invalid region {:?}: {e}",
+                region.trim()
+            )
+        })
+}
+
+fn handle_client(
+    stream: UnixStream,
+    tx: &Sender<ControlCommand>,
+    selector: &str,
+    wake: &dyn Fn(),
+) -> io::Result<()> {
+    let mut writer = stream.try_clone()?;
+    for line in BufReader::new(stream).lines() {
+        let line = line?;
+        let cmd = match line.parse::<ControlCommand>() {
+            Ok(ControlCommand::Reselect) => run_selector(selector),
+            res => res.map_err(|e| e.to_string()),
+        };
+        match cmd {
+            Ok(cmd) => {
+                debug!("control command: {cmd:?}");
+                if tx.send(cmd).is_err() {
+                    writeln!(writer, "error: shutting down")?;
+                    return Ok(());
+                }
+                wake();
+                writeln!(writer, "ok")?;
+            }
+            Err(e) => writeln!(writer, "error: {e}")?,
+        }
+    }
+    Ok(())
+}
+
+// --marker-fd: every line read from `fd` is a marker. `wake` is called after each one, like for
+// commands from the control socket
+pub fn spawn_marker_reader(fd: RawFd, wake: impl Fn() + Send + 'static) -> Receiver<String> {
+    let file = unsafe { File::from_raw_fd(fd) };
+    let (tx, rx) = channel();
+    thread::Builder::new()
+        .name("markers".to_owned())
+        .spawn(move || {
+            for line in BufReader::new(file).lines() {
+                let line = match line {
+                    Ok(line) => line,
+                    Err(e) => {
+                        warn!("failed to read markers from fd {fd}: {e}");
+                        return;
+                    }
+                };
+                if line.trim().is_empty() {
+                    continue;
+                }
+                if tx.send(line.trim().to_string()).is_err() {
+                    return;
+                }
+                wake();
+            }
+            debug!("marker fd {fd} closed");
+        })
+        .unwrap();
+    rx
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use super::{pick_instance, ControlCommand, ParseCommandError};
+    use crate::splice::SpliceCue;
+
+    #[test]
+    fn parse() {
+        assert_eq!("stop".parse(), Ok(ControlCommand::Stop));
+        assert_eq!(" stop \n".parse(), Ok(ControlCommand::Stop));
+        assert_eq!(
+            "stop now".parse::<ControlCommand>(),
+            Err(ParseCommandError::UnexpectedArgs("stop".into()))
+        );
+        assert_eq!(
+            "set-region 10,20 300x200".parse(),
+            Ok(ControlCommand::SetRegion((10, 20, 300, 200)))
+        );
+        assert!(matches!(
+            "set-region 10,20".parse::<ControlCommand>(),
+            Err(ParseCommandError::InvalidArgs(..))
+        ));
+        assert_eq!(
+            "set-output DP-2".parse(),
+            Ok(ControlCommand::SetOutput("DP-2".into()))
+        );
+        assert!("set-output".parse::<ControlCommand>().is_err());
+        assert_eq!(
+            "marker  bug repro starts here ".parse(),
+            Ok(ControlCommand::Marker("bug repro starts here".into()))
+        );
+        assert!("marker".parse::<ControlCommand>().is_err());
+        assert_eq!("screenshot".parse(), Ok(ControlCommand::Screenshot));
+        assert!("screenshot now".parse::<ControlCommand>().is_err());
+        assert_eq!("split".parse(), Ok(ControlCommand::Split));
+        assert_eq!(
+            "splice out 30s".parse(),
+            Ok(ControlCommand::Splice(SpliceCue::Out(Some(
+                Duration::from_secs(30)
+            ))))
+        );
+        assert_eq!(
+            "splice in".parse(),
+            Ok(ControlCommand::Splice(SpliceCue::In))
+        );
+        assert_eq!(
+            "splice text scene 2".parse(),
+            Ok(ControlCommand::Splice(SpliceCue::Text("scene 2".into())))
+        );
+        assert!(matches!(
+            "splice out soon".parse::<ControlCommand>(),
+            Err(ParseCommandError::InvalidArgs(..))
+        ));
+        assert!("splice".parse::<ControlCommand>().is_err());
+        assert_eq!("mute".parse(), Ok(ControlCommand::Mute));
+        assert_eq!("toggle-mute".parse(), Ok(ControlCommand::ToggleMute));
+        assert_eq!("".parse::<ControlCommand>(), Err(ParseCommandError::Empty));
+        assert_eq!(
+            "explode".parse::<ControlCommand>(),
+            Err(ParseCommandError::Unknown("explode".into()))
+        );
+    }
+    #[test]
+    fn instances() {
+        let running = ["DP-3".to_owned(), "eDP-1".to_owned()];
+        assert_eq!(pick_instance("eDP-1", &running), Ok("eDP-1".into()));
+        // the first in a fallback list that's being recorded
+        assert_eq!(
+            pick_instance("HDMI-A-1,eDP-1", &running),
+            Ok("eDP-1".into())
+        );
+        assert!(pick_instance("HDMI-A-1", &running).is_err());
+        // without --output there has to be just one
+        assert!(pick_instance("", &running).is_err());
+        assert_eq!(pick_instance("", &running[..1]), Ok("DP-3".into()));
+        assert!(pick_instance("any", &[]).is_err());
+    }
+}
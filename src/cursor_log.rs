@@ -0,0 +1,84 @@
+use std::{
+    fs::File,
+    io::{self, BufWriter, Write},
+    path::Path,
+};
+
+use log::warn;
+
+use crate::transform::Rect;
+
+// --cursor-log, one JSON object per line for every frame the pointer moved in, so it can be drawn
+// (or highlighted) in post instead of burnt into the recording
+pub struct CursorLog {
+    w: BufWriter<File>,
+    last: Option<Option<(i32, i32)>>,
+}
+
+// `cursor` from screen coordinates relative to the output to the pixel it's on in the encoded
+// frame, where `roi` is drawn at `dest`. It's logged even when outside of the recorded region, the
+// pointer's image may still be partly visible
+fn to_encoded((x, y): (i32, i32), roi: Rect, dest: Rect) -> (i32, i32) {
+    let scale = |v: i32, origin: i32, from: i32, to: i32| {
+        (i64::from(v - origin) * i64::from(to) / i64::from(from)) as i32
+    };
+    (
+        dest.x + scale(x, roi.x, roi.w, dest.w),
+        dest.y + scale(y, roi.y, roi.h, dest.h),
+    )
+}
+
+impl CursorLog {
+    pub fn create(path: &Path) -> io::Result<Self> {
+        Ok(CursorLog {
+            w: BufWriter::new(File::create(path)?),
+            last: None,
+        })
+    }
+
+    // `cursor` is where the pointer was for the frame with `pts_ns`, None if not over the output
+    pub fn frame(&mut self, pts_ns: i64, cursor: Option<(i32, i32)>, roi: Rect, dest: Rect) {
+        let pos = cursor.map(|c| to_encoded(c, roi, dest));
+        if self.last == Some(pos) {
+            return;
+        }
+        self.last = Some(pos);
+
+        let res = match pos {
+            Some((x, y)) => writeln!(self.w, r#"{{"pts_ns":{pts_ns},"x":{x},"y":{y}}}"#),
+            None => writeln!(self.w, r#"{{"pts_ns":{pts_ns},"x":null,"y":null}}"#),
+        };
+        if let Err(e) = res {
+            warn!("failed to write to cursor log: {e}");
+        }
+    }
+
+    pub fn flush(&mut self) {
+        if let Err(e) = self.w.flush() {
+            warn!("failed to flush cursor log: {e}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::to_encoded;
+    use crate::transform::Rect;
+
+    #[test]
+    fn encoded_position() {
+        let roi = Rect::new((100, 100), (800, 600));
+        let half = Rect::new((0, 0), (400, 300));
+        assert_eq!(to_encoded((500, 400), roi, half), (200, 150));
+        assert_eq!(
+            to_encoded((100, 100), roi, Rect::new((0, 0), (800, 600))),
+            (0, 0)
+        );
+        assert_eq!(to_encoded((50, 100), roi, half), (-25, 0));
+        // letterboxed
+        assert_eq!(
+            to_encoded((500, 400), roi, Rect::new((240, 0), (1440, 1080))),
+            (960, 540)
+        );
+    }
+}
@@ -0,0 +1,218 @@
+use std::{
+    ffi::{CStr, CString},
+    ptr::null_mut,
+};
+
+use ffmpeg::{ffi::avfilter_graph_send_command, filter, format::Pixel, frame};
+use log_once::warn_once;
+
+use crate::transform::Rect;
+
+// --composite-cursor draws the pointer's image from a canvas of this size, so the filter graph
+// doesn't need rebuilding whenever the cursor changes shape. Bigger cursors are cut off
+pub const CANVAS_SIZE: u32 = 256;
+
+// the pointer's image, as captured by an ext-image-copy-capture cursor session
+#[derive(Debug, Clone)]
+pub struct CursorImage {
+    pub pixels: Vec<u8>, // premultiplied ARGB8888 (BGRA in memory), rows packed
+    pub size: (i32, i32),
+    pub hotspot: (i32, i32), // the pixel of the image the pointer position refers to
+}
+
+// how much the cursor image is scaled, when `roi` is scaled to `enc_w` wide
+fn image_scale(cursor_scale: f64, roi: Rect, enc_w: i32) -> f64 {
+    cursor_scale * f64::from(enc_w) / f64::from(roi.w)
+}
+
+// top-left of the cursor image in the encoded frame, for the pointer's hotspot at `position`
+// (screen coordinates). Off the frame if the pointer isn't over the output
+fn origin(
+    position: Option<(i32, i32)>,
+    hotspot: (i32, i32),
+    cursor_scale: f64,
+    roi: Rect,
+    (enc_w, enc_h): (i32, i32),
+) -> (i32, i32) {
+    let s = image_scale(cursor_scale, roi, enc_w);
+    let Some((x, y)) = position else {
+        let hidden = -(f64::from(CANVAS_SIZE) * s).ceil() as i32;
+        return (hidden, hidden);
+    };
+    let scale = |v: i32, origin: i32, from: i32, to: i32| {
+        (i64::from(v - origin) * i64::from(to) / i64::from(from)) as i32
+    };
+    (
+        scale(x, roi.x, roi.w, enc_w) - (f64::from(hotspot.0) * s).round() as i32,
+        scale(y, roi.y, roi.h, enc_h) - (f64::from(hotspot.1) * s).round() as i32,
+    )
+}
+
+pub struct CursorOverlay {
+    canvas: frame::Video,
+    hotspot: (i32, i32),
+    cursor_scale: f64, // --cursor-scale
+    position: Option<(i32, i32)>,
+}
+
+impl CursorOverlay {
+    pub fn new(cursor_scale: f64) -> Self {
+        let mut canvas = frame::Video::new(Pixel::BGRA, CANVAS_SIZE, CANVAS_SIZE);
+        canvas.data_mut(0).fill(0);
+        CursorOverlay {
+            canvas,
+            hotspot: (0, 0),
+            cursor_scale,
+            position: None,
+        }
+    }
+
+    pub fn set_image(&mut self, image: &CursorImage) {
+        let (w, h) = image.size;
+        if w > CANVAS_SIZE as i32 || h > CANVAS_SIZE as i32 {
+            warn_once!(
+                "the cursor is {w}x{h}, bigger than {CANVAS_SIZE}x{CANVAS_SIZE}, it will be cut off"
+            );
+        }
+        let w = w.clamp(0, CANVAS_SIZE as i32) as usize;
+        let h = h.clamp(0, CANVAS_SIZE as i32) as usize;
+        let src_stride = image.size.0 as usize * 4;
+
+        // the graph may still hold a reference to the old canvas, so this is a new one
+        self.canvas = frame::Video::new(Pixel::BGRA, CANVAS_SIZE, CANVAS_SIZE);
+        let stride = self.canvas.stride(0);
+        let data = self.canvas.data_mut(0);
+        data.fill(0);
+        for y in 0..h {
+            data[y * stride..][..w * 4].copy_from_slice(&image.pixels[y * src_stride..][..w * 4]);
+        }
+        self.hotspot = image.hotspot;
+    }
+
+    pub fn set_position(&mut self, position: Option<(i32, i32)>) {
+        self.position = position;
+    }
+
+    // the cursor image's side in the encoded frame
+    fn size(&self, roi: Rect, enc_w: i32) -> i32 {
+        let s = image_scale(self.cursor_scale, roi, enc_w);
+        ((f64::from(CANVAS_SIZE) * s).round() as i32).max(1)
+    }
+
+    // chain drawing the `cursor` source over a software frame in `format`. It starts by naming the
+    // frame it's drawn on, so the graph's input and output need to be labeled `in` and `out`
+    pub fn filter(&self, roi: Rect, (enc_w, _): (i32, i32), format: &str) -> String {
+        let size = self.size(roi, enc_w);
+        format!(
+            "[base];[cursor]scale={size}:{size}[cur];[base][cur]overlay@cursor=x=-{size}:y=-{size}:alpha=premultiplied:eof_action=repeat,format={format}"
+        )
+    }
+
+    // like `filter`, but over a vaapi surface without downloading it. overlay_vaapi can't be
+    // moved once configured, so the uploaded cursor is padded out to a transparent plane twice
+    // the frame's size with the cursor in the middle, and the frame-sized window cropped out of
+    // it is what moves
+    pub fn filter_vaapi(&self, roi: Rect, (enc_w, enc_h): (i32, i32)) -> String {
+        let size = self.size(roi, enc_w);
+        let (plane_w, plane_h) = (2 * enc_w + size, 2 * enc_h + size);
+        let (x, y) = (enc_w + size, enc_h + size);
+        format!(
+            "[base];[cursor]hwupload,scale_vaapi=w={size}:h={size},pad_vaapi=width={plane_w}:height={plane_h}:x={enc_w}:y={enc_h}:color=black@0,crop@cursor=w={enc_w}:h={enc_h}:x={x}:y={y},scale_vaapi=w={enc_w}:h={enc_h}[cur];[base][cur]overlay_vaapi=eof_action=repeat"
+        )
+    }
+
+    // feeds the cursor for the frame with `pts` to `graph`, which has to happen before the frame
+    // itself as overlay waits for both
+    pub fn push(
+        &mut self,
+        graph: &mut filter::Graph,
+        pts: Option<i64>,
+        roi: Rect,
+        enc_size: (i32, i32),
+    ) {
+        let (x, y) = origin(
+            self.position,
+            self.hotspot,
+            self.cursor_scale,
+            roi,
+            enc_size,
+        );
+        if graph.get("crop@cursor").is_some() {
+            let (x, y) = window_origin((x, y), enc_size, self.size(roi, enc_size.0));
+            send_command(graph, c"crop@cursor", x, y);
+        } else {
+            send_command(graph, c"overlay@cursor", x, y);
+        }
+        self.canvas.set_pts(pts);
+        graph
+            .get("cursor")
+            .unwrap()
+            .source()
+            .add(&self.canvas)
+            .unwrap();
+    }
+}
+
+// where the frame-sized window is cropped out of `filter_vaapi`'s plane, so the cursor ends up at
+// `origin`. Cursors off the frame are kept just off it
+fn window_origin((x, y): (i32, i32), (enc_w, enc_h): (i32, i32), size: i32) -> (i32, i32) {
+    (
+        (enc_w - x).clamp(0, enc_w + size),
+        (enc_h - y).clamp(0, enc_h + size),
+    )
+}
+
+fn send_command(graph: &mut filter::Graph, target: &CStr, x: i32, y: i32) {
+    for (opt, v) in [(c"x", x), (c"y", y)] {
+        let v = CString::new(v.to_string()).unwrap();
+        unsafe {
+            avfilter_graph_send_command(
+                graph.as_mut_ptr(),
+                target.as_ptr(),
+                opt.as_ptr(),
+                v.as_ptr(),
+                null_mut(),
+                0,
+                0,
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{origin, window_origin, CANVAS_SIZE};
+    use crate::transform::Rect;
+
+    #[test]
+    fn cursor_origin() {
+        let roi = Rect::new((100, 100), (800, 600));
+        // not scaled
+        assert_eq!(
+            origin(Some((500, 400)), (4, 2), 1., roi, (800, 600)),
+            (396, 298)
+        );
+        // recorded at half size, the image is halved too
+        assert_eq!(
+            origin(Some((500, 400)), (4, 2), 1., roi, (400, 300)),
+            (198, 149)
+        );
+        // --cursor-scale 2
+        assert_eq!(
+            origin(Some((500, 400)), (4, 2), 2., roi, (800, 600)),
+            (392, 296)
+        );
+
+        let hidden = -(CANVAS_SIZE as i32);
+        assert_eq!(origin(None, (4, 2), 1., roi, (800, 600)), (hidden, hidden));
+    }
+    #[test]
+    fn vaapi_window() {
+        // the cursor sits at (800, 600) in the plane
+        assert_eq!(window_origin((396, 298), (800, 600), 32), (404, 302));
+        assert_eq!(window_origin((0, 0), (800, 600), 32), (800, 600));
+        // hidden, or partly off the frame
+        assert_eq!(window_origin((-256, -256), (800, 600), 32), (832, 632));
+        assert_eq!(window_origin((900, 700), (800, 600), 32), (0, 0));
+    }
+}
@@ -0,0 +1,84 @@
+use ffmpeg::{
+    ffi::{av_crc, av_crc_get_table, AVCRCId},
+    filter, frame,
+};
+use log_once::warn_once;
+
+use crate::{avhw::AvHwFrameCtx, multi_output::new_ref, transform::Rect};
+
+// the recorded region is compared at this fraction of its size in each direction
+const DOWNSCALE: i32 = 4;
+
+// --dedupe-frames: some compositors send a new frame (and full damage) on every refresh even when
+// nothing changed, and ext-image-copy-capture has no way to ask for damaged frames only. This
+// compares a CRC of the recorded region with the previous one, so duplicates can be dropped like
+// wlr-screencopy's damage tracking would. The region is scaled down on the GPU first so only a
+// sixteenth of it is downloaded, which means a change too faint to move the average of its 4x4
+// block is taken for a duplicate
+pub struct FrameDedupe {
+    // built for the capture surfaces and region it was last given
+    graph: Option<(filter::Graph, AvHwFrameCtx, Rect)>,
+    downloaded: frame::Video,
+    last_crc: Option<u32>,
+}
+
+fn downscale_graph(frames: &AvHwFrameCtx, region: Rect) -> Result<filter::Graph, ffmpeg::Error> {
+    let Rect { x, y, w, h } = region;
+    let (small_w, small_h) = ((w / DOWNSCALE).max(1), (h / DOWNSCALE).max(1));
+
+    let mut g = filter::Graph::new();
+    crate::add_hw_source(&mut g, c"in", frames, frames.size());
+    g.add(&filter::find("buffersink").unwrap(), "out", "")?;
+    g.output("in", 0)?.input("out", 0)?.parse(&format!(
+        "crop={w}:{h}:{x}:{y}:exact=1,scale_vaapi=w={small_w}:h={small_h}:format=bgr0:mode=hq,hwdownload,format=bgr0"
+    ))?;
+    g.validate()?;
+    Ok(g)
+}
+
+impl FrameDedupe {
+    pub fn new() -> Self {
+        FrameDedupe {
+            graph: None,
+            downloaded: frame::Video::empty(),
+            last_crc: None,
+        }
+    }
+
+    fn downscale(&mut self, surface: &frame::Video, region: Rect) -> Result<(), ffmpeg::Error> {
+        let Some(frames) = AvHwFrameCtx::of_frame(surface) else {
+            return Err(ffmpeg::Error::InvalidData);
+        };
+        if !matches!(&self.graph, Some((_, f, r)) if *f == frames && *r == region) {
+            self.graph = Some((downscale_graph(&frames, region)?, frames, region));
+        }
+        let graph = &mut self.graph.as_mut().unwrap().0;
+        graph.get("in").unwrap().source().add(&new_ref(surface))?;
+        graph.get("out").unwrap().sink().frame(&mut self.downloaded)
+    }
+
+    // `surface` is a vaapi frame, `region` the part of it being recorded (in frame coordinates).
+    // True if that part looks the same as in the last frame checked
+    pub fn is_duplicate(&mut self, surface: &frame::Video, region: Rect) -> bool {
+        if let Err(e) = self.downscale(surface, region) {
+            warn_once!("failed to download frame to check for duplicates: {e}");
+            self.graph = None;
+            self.last_crc = None;
+            return false;
+        }
+
+        let stride = self.downloaded.stride(0);
+        let row_len = self.downloaded.width() as usize * 4;
+        let data = self.downloaded.data(0);
+        let crc = unsafe {
+            let table = av_crc_get_table(AVCRCId::AV_CRC_32_IEEE_LE);
+            (0..self.downloaded.height() as usize).fold(0, |crc, y| {
+                av_crc(table, crc, data[y * stride..].as_ptr(), row_len)
+            })
+        };
+
+        let duplicate = self.last_crc == Some(crc);
+        self.last_crc = Some(crc);
+        duplicate
+    }
+}
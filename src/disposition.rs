@@ -0,0 +1,182 @@
+use std::str::FromStr;
+
+use ffmpeg::{format, format::stream::Disposition, media};
+use log::warn;
+use thiserror::Error;
+
+// --disposition audio=default, markers=comment: how players should treat each track, for when
+// the recording has more than one of a kind or a track shouldn't be picked automatically
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Track {
+    Video,
+    ExtraStream, // --extra-stream
+    Audio,
+    Markers,        // --markers
+    FrameDurations, // --frame-durations
+}
+
+const TRACKS: &[(&str, Track)] = &[
+    ("video", Track::Video),
+    ("extra-stream", Track::ExtraStream),
+    ("audio", Track::Audio),
+    ("markers", Track::Markers),
+    ("frame-durations", Track::FrameDurations),
+];
+
+// the names ffmpeg's -disposition takes, minus the ones that describe how a track was made
+// rather than how to play it
+const FLAGS: &[(&str, Disposition)] = &[
+    ("default", Disposition::DEFAULT),
+    ("dub", Disposition::DUB),
+    ("original", Disposition::ORIGINAL),
+    ("comment", Disposition::COMMENT),
+    ("forced", Disposition::FORCED),
+    ("hearing_impaired", Disposition::HEARING_IMPAIRED),
+    ("visual_impaired", Disposition::VISUAL_IMPAIRED),
+    ("captions", Disposition::CAPTIONS),
+    ("descriptions", Disposition::DESCRIPTIONS),
+    ("metadata", Disposition::METADATA),
+];
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TrackDisposition {
+    pub track: Track,
+    pub disposition: Disposition,
+}
+
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum ParseDispositionError {
+    #[error("expected TRACK=FLAG[+FLAG...]")]
+    Structure,
+    #[error("unknown track {0:?}, expected one of {tracks}", tracks = names(TRACKS))]
+    Track(String),
+    #[error("unknown disposition {0:?}, expected none or one of {flags}", flags = names(FLAGS))]
+    Flag(String),
+}
+
+fn names<T>(list: &[(&str, T)]) -> String {
+    list.iter().map(|(n, _)| *n).collect::<Vec<_>>().join(", ")
+}
+
+impl FromStr for TrackDisposition {
+    type Err = ParseDispositionError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (track, flags) = s.split_once('=').ok_or(ParseDispositionError::Structure)?;
+        let track = TRACKS
+            .iter()
+            .find(|(n, _)| *n == track.trim())
+            .map(|(_, t)| *t)
+            .ok_or_else(|| ParseDispositionError::Track(track.to_owned()))?;
+
+        let mut disposition = Disposition::empty();
+        for flag in flags.split('+').map(str::trim) {
+            if flag == "none" {
+                continue;
+            }
+            disposition |= FLAGS
+                .iter()
+                .find(|(n, _)| *n == flag)
+                .map(|(_, d)| *d)
+                .ok_or_else(|| ParseDispositionError::Flag(flag.to_owned()))?;
+        }
+        Ok(TrackDisposition { track, disposition })
+    }
+}
+
+fn track_of(st: &format::stream::Stream, video_idx: usize) -> Option<Track> {
+    match st.parameters().medium() {
+        media::Type::Video if st.index() == video_idx => Some(Track::Video),
+        media::Type::Video => Some(Track::ExtraStream),
+        media::Type::Audio => Some(Track::Audio),
+        media::Type::Subtitle => match st.metadata().get("title") {
+            Some("markers") => Some(Track::Markers),
+            Some("frame durations") => Some(Track::FrameDurations),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+// before the header is written. A track given a disposition gets exactly that one, and marking
+// a track default takes it away from the others of the same kind
+pub fn apply(
+    octx: &mut format::context::Output,
+    video_idx: usize,
+    dispositions: &[TrackDisposition],
+) {
+    for d in dispositions {
+        let tracks: Vec<_> = octx
+            .streams()
+            .map(|st| {
+                (
+                    st.index(),
+                    st.parameters().medium(),
+                    track_of(&st, video_idx),
+                )
+            })
+            .collect();
+        let Some(&(_, medium, _)) = tracks.iter().find(|(_, _, t)| *t == Some(d.track)) else {
+            let name = TRACKS.iter().find(|(_, t)| *t == d.track).unwrap().0;
+            warn!("ignoring --disposition for {name}, the recording doesn't have that track");
+            continue;
+        };
+
+        for (idx, m, t) in tracks {
+            let mut st = octx.stream_mut(idx).unwrap();
+            unsafe {
+                let av = st.as_mut_ptr();
+                if t == Some(d.track) {
+                    (*av).disposition = d.disposition.bits();
+                } else if m == medium && d.disposition.contains(Disposition::DEFAULT) {
+                    (*av).disposition &= !Disposition::DEFAULT.bits();
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use ffmpeg::format::stream::Disposition;
+
+    use super::{ParseDispositionError, Track, TrackDisposition};
+
+    #[test]
+    fn parse() {
+        assert_eq!(
+            "audio=default".parse(),
+            Ok(TrackDisposition {
+                track: Track::Audio,
+                disposition: Disposition::DEFAULT,
+            })
+        );
+        assert_eq!(
+            "extra-stream=comment+forced".parse(),
+            Ok(TrackDisposition {
+                track: Track::ExtraStream,
+                disposition: Disposition::COMMENT | Disposition::FORCED,
+            })
+        );
+        assert_eq!(
+            "markers=none".parse(),
+            Ok(TrackDisposition {
+                track: Track::Markers,
+                disposition: Disposition::empty(),
+            })
+        );
+        assert_eq!(
+            "audio".parse::<TrackDisposition>(),
+            Err(ParseDispositionError::Structure)
+        );
+        assert_eq!(
+            "mic=default".parse::<TrackDisposition>(),
+            Err(ParseDispositionError::Track("mic".to_owned()))
+        );
+        assert_eq!(
+            "audio=commentary".parse::<TrackDisposition>(),
+            Err(ParseDispositionError::Flag("commentary".to_owned()))
+        );
+    }
+}
@@ -0,0 +1,100 @@
+use std::{
+    fs::File,
+    io::{self, BufWriter, Write},
+    path::Path,
+};
+
+use ffmpeg::{codec::packet::side_data, Packet};
+use log::warn;
+
+// lambda per QP unit, FF_QP2LAMBDA in libavutil
+const QP2LAMBDA: f64 = 118.;
+
+// what the encoder says about a packet, from its AV_PKT_DATA_QUALITY_STATS side data. libx264,
+// nvenc and qsv set it, vaapi doesn't, so both are None there
+#[derive(Debug, PartialEq)]
+pub struct PacketStats {
+    pub size: usize,
+    pub key: bool,
+    pub pict_type: Option<char>,
+    pub qp: Option<f64>,
+}
+
+// the side data is a little endian u32 quality (lambda), then the AVPictureType as a byte
+fn parse_quality_stats(data: &[u8]) -> (Option<char>, Option<f64>) {
+    let Some(quality) = data.get(..4) else {
+        return (None, None);
+    };
+    let quality = u32::from_le_bytes(quality.try_into().unwrap());
+    // same letters as av_get_picture_type_char
+    let pict_type = data
+        .get(4)
+        .and_then(|t| b"?IPBSipb".get(usize::from(*t)))
+        .filter(|t| **t != b'?')
+        .map(|t| char::from(*t));
+    (pict_type, Some(f64::from(quality) / QP2LAMBDA))
+}
+
+impl PacketStats {
+    pub fn of(packet: &Packet) -> Self {
+        let (pict_type, qp) = packet
+            .side_data()
+            .find(|sd| sd.kind() == side_data::Type::QualityStats)
+            .map_or((None, None), |sd| parse_quality_stats(sd.data()));
+        PacketStats {
+            size: packet.size(),
+            key: packet.is_key(),
+            pict_type,
+            qp,
+        }
+    }
+}
+
+// Writes a CSV line for every video packet that comes out of the encoder, for --encode-log
+pub struct EncodeLog {
+    w: BufWriter<File>,
+}
+
+impl EncodeLog {
+    pub fn create(path: &Path) -> io::Result<Self> {
+        let mut w = BufWriter::new(File::create(path)?);
+        writeln!(w, "pts_ns,size,key,pict_type,qp")?;
+        Ok(EncodeLog { w })
+    }
+
+    pub fn packet(&mut self, pts_ns: i64, stats: &PacketStats) {
+        let pict_type = stats.pict_type.map_or(String::new(), String::from);
+        let qp = stats.qp.map_or(String::new(), |qp| format!("{qp:.2}"));
+        if let Err(e) = writeln!(
+            self.w,
+            "{pts_ns},{},{},{pict_type},{qp}",
+            stats.size, stats.key as u8
+        ) {
+            warn!("failed to write to encode log: {e}");
+        }
+    }
+
+    pub fn flush(&mut self) {
+        if let Err(e) = self.w.flush() {
+            warn!("failed to flush encode log: {e}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::parse_quality_stats;
+
+    #[test]
+    fn quality_stats() {
+        // qp 23 P frame, then the error count and reserved bytes
+        let mut data = (23 * 118u32).to_le_bytes().to_vec();
+        data.extend([2, 0, 0, 0]);
+        assert_eq!(parse_quality_stats(&data), (Some('P'), Some(23.)));
+
+        data[4] = 0; // AV_PICTURE_TYPE_NONE
+        assert_eq!(parse_quality_stats(&data), (None, Some(23.)));
+        assert_eq!(parse_quality_stats(&data[..4]), (None, Some(23.)));
+        assert_eq!(parse_quality_stats(&[]), (None, None));
+    }
+}
@@ -0,0 +1,179 @@
+// Maps generic settings onto the option names of individual ffmpeg encoders
+
+use std::thread;
+
+// --encode-speed, from 1 (smallest files, most encoder work) to 7 (least work). Empty if the
+// encoder has no known speed setting
+pub fn speed_options(encoder: &str, speed: u8) -> Vec<(&'static str, String)> {
+    let speed = speed.clamp(1, 7);
+    // how many frames the GPU encoders keep in flight. More keeps the GPU busier but adds latency
+    // and memory, so it goes up with speed
+    let async_depth = [1, 2, 2, 3, 4, 6, 8][usize::from(speed - 1)].to_string();
+    match encoder {
+        // intel's target usage runs from 1 to 7 too, other drivers map it onto their own range
+        "h264_vaapi" | "hevc_vaapi" | "av1_vaapi" | "vp8_vaapi" | "vp9_vaapi" => {
+            vec![("quality", speed.to_string()), ("async_depth", async_depth)]
+        }
+        "h264_qsv" | "hevc_qsv" | "av1_qsv" | "vp9_qsv" => {
+            let preset = [
+                "veryslow", "slower", "slow", "medium", "fast", "faster", "veryfast",
+            ][usize::from(speed - 1)];
+            vec![("preset", preset.to_owned()), ("async_depth", async_depth)]
+        }
+        "libx264" | "libx265" => {
+            let preset = [
+                "medium",
+                "fast",
+                "faster",
+                "veryfast",
+                "superfast",
+                "ultrafast",
+                "ultrafast",
+            ][usize::from(speed - 1)];
+            vec![("preset", preset.to_owned())]
+        }
+        // 7 and up are the presets meant for realtime
+        "libsvtav1" => vec![("preset", (speed + 6).to_string())],
+        "libaom-av1" => vec![
+            ("usage", "realtime".to_owned()),
+            ("cpu-used", (speed + 3).to_string()),
+        ],
+        "libvpx" | "libvpx-vp9" => vec![
+            ("deadline", "realtime".to_owned()),
+            ("cpu-used", (speed + 1).to_string()),
+        ],
+        _ => Vec::new(),
+    }
+}
+
+// options for software encoders. Unless --sw-preset/--sw-tune say otherwise, x264, x265 and
+// SVT-AV1 get presets that keep up with realtime capture, and libaom is put in realtime mode.
+// FFV1 (--preset archive-ffv1) gets its archival settings, with a slice per thread, and DNxHR the
+// 10 bit profile
+pub fn software_options(
+    encoder: &str,
+    preset: Option<&str>,
+    tune: Option<&str>,
+    threads: Option<u32>,
+) -> Vec<(&'static str, String)> {
+    let (default_preset, default_tune) = match encoder {
+        "libx264" | "libx265" => (Some("veryfast"), Some("zerolatency")),
+        "libsvtav1" => (Some("10"), None),
+        _ => (None, None),
+    };
+    let mut options: Vec<_> = [
+        ("preset", preset.or(default_preset).map(str::to_owned)),
+        ("tune", tune.or(default_tune).map(str::to_owned)),
+        ("threads", threads.map(|t| t.to_string())),
+    ]
+    .into_iter()
+    .filter_map(|(k, v)| Some((k, v?)))
+    .collect();
+    match encoder {
+        "libaom-av1" => {
+            options.push(("usage", "realtime".to_owned()));
+            options.push(("cpu-used", "8".to_owned()));
+        }
+        "ffv1" => {
+            // version 3, with a CRC in every slice so damage to the file can be detected
+            options.push(("level", "3".to_owned()));
+            options.push(("slicecrc", "1".to_owned()));
+            // ffmpeg encodes on a single thread unless told otherwise
+            let threads = threads.unwrap_or_else(|| {
+                let threads = thread::available_parallelism().map_or(4, |n| n.get() as u32);
+                options.push(("threads", threads.to_string()));
+                threads
+            });
+            options.push(("slices", ffv1_slices(threads).to_string()));
+        }
+        "dnxhd" => options.push(("profile", "dnxhr_hqx".to_owned())),
+        _ => {}
+    }
+    options
+}
+
+// ffv1 splits frames into a grid of slices, which are encoded in parallel. Only some counts make
+// a grid it accepts, use the smallest one that keeps every thread busy
+fn ffv1_slices(threads: u32) -> u32 {
+    [4, 6, 9, 12, 16, 20, 24, 30]
+        .into_iter()
+        .find(|&s| s >= threads)
+        .unwrap_or(30)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{software_options, speed_options};
+
+    #[test]
+    fn speed() {
+        assert_eq!(
+            speed_options("hevc_vaapi", 4),
+            vec![("quality", "4".to_owned()), ("async_depth", "3".to_owned())]
+        );
+        assert_eq!(
+            speed_options("h264_qsv", 7),
+            vec![
+                ("preset", "veryfast".to_owned()),
+                ("async_depth", "8".to_owned())
+            ]
+        );
+        assert_eq!(
+            speed_options("libx264", 1),
+            vec![("preset", "medium".to_owned())]
+        );
+        assert_eq!(
+            speed_options("libx265", 7),
+            vec![("preset", "ultrafast".to_owned())]
+        );
+        assert_eq!(
+            speed_options("libsvtav1", 7),
+            vec![("preset", "13".to_owned())]
+        );
+        assert_eq!(speed_options("mpeg4", 3), vec![]);
+    }
+
+    #[test]
+    fn software() {
+        assert_eq!(
+            software_options("libx264", None, None, None),
+            vec![
+                ("preset", "veryfast".to_owned()),
+                ("tune", "zerolatency".to_owned())
+            ]
+        );
+        assert_eq!(
+            software_options("libx265", Some("fast"), None, Some(4)),
+            vec![
+                ("preset", "fast".to_owned()),
+                ("tune", "zerolatency".to_owned()),
+                ("threads", "4".to_owned())
+            ]
+        );
+        assert_eq!(
+            software_options("libsvtav1", None, None, None),
+            vec![("preset", "10".to_owned())]
+        );
+        assert_eq!(
+            software_options("libaom-av1", None, None, None),
+            vec![
+                ("usage", "realtime".to_owned()),
+                ("cpu-used", "8".to_owned())
+            ]
+        );
+        assert_eq!(
+            software_options("ffv1", None, None, Some(8)),
+            vec![
+                ("threads", "8".to_owned()),
+                ("level", "3".to_owned()),
+                ("slicecrc", "1".to_owned()),
+                ("slices", "9".to_owned())
+            ]
+        );
+        assert_eq!(
+            software_options("dnxhd", None, None, None),
+            vec![("profile", "dnxhr_hqx".to_owned())]
+        );
+        assert_eq!(software_options("mpeg4", None, None, None), vec![]);
+    }
+}
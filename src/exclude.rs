@@ -0,0 +1,214 @@
+use std::iter::zip;
+
+use ffmpeg::{format::Pixel, frame};
+
+use crate::{
+    avhw::AvHwFrameCtx, composite::Composite, overlay_surface::OverlaySurface, roi,
+    transform::Rect, ExcludeStyle,
+};
+
+// --exclude-region: parts of the screen covered up before they're encoded, so things like
+// notifications never end up in the recording. vaapi has no filter that draws boxes, so on the
+// GPU they're covered with an uploaded black patch, or blurred by scaling them down and back up
+
+// side of the black patch, it's scaled to each box
+const PATCH_SIZE: i32 = 16;
+
+// the black patch for `filter_vaapi`, on the same device as `like`'s surfaces. It only has to be
+// pushed to the graph's `exclude` source once, overlay_vaapi keeps using the last frame of an
+// input that ended
+pub fn black_patch(like: &AvHwFrameCtx) -> Result<OverlaySurface, ffmpeg::Error> {
+    let mut image = frame::Video::new(Pixel::BGRA, PATCH_SIZE as u32, PATCH_SIZE as u32);
+    for px in image.data_mut(0).chunks_exact_mut(4) {
+        px.copy_from_slice(&[0, 0, 0, 0xff]);
+    }
+    let mut patch = OverlaySurface::new(like, Pixel::BGRA, (PATCH_SIZE, PATCH_SIZE))?;
+    patch.upload(&image)?;
+    Ok(patch)
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Exclusions {
+    pub regions: Vec<Rect>, // screen coordinates, like the roi
+    pub style: ExcludeStyle,
+}
+
+impl Exclusions {
+    // where the excluded regions are in the encoded frame, when recording `roi` (or the regions of
+    // `composite`) at `enc_size`
+    pub fn boxes(
+        &self,
+        roi: Rect,
+        composite: Option<&Composite>,
+        (enc_w, enc_h): (i32, i32),
+    ) -> Vec<Rect> {
+        let (placements, (full_w, full_h)) = match composite {
+            Some(c) => (zip(c.regions.clone(), c.tiles()).collect(), c.size()),
+            None => (
+                vec![(roi, Rect::new((0, 0), (enc_w, enc_h)))],
+                (enc_w, enc_h),
+            ),
+        };
+        // composites are scaled as a whole after stacking, if at all
+        let scale =
+            |v: i32, from: i32, to: i32| (i64::from(v) * i64::from(to) / i64::from(from)) as i32;
+
+        let mut boxes = Vec::new();
+        for (src, dest) in placements {
+            for region in &self.regions {
+                let Some(b) = roi::to_encoded(*region, src, (dest.w, dest.h)) else {
+                    continue;
+                };
+                let (x0, y0) = (dest.x + b.x, dest.y + b.y);
+                let (x1, y1) = (x0 + b.w, y0 + b.h);
+                let (x0, x1) = (scale(x0, full_w, enc_w), scale(x1, full_w, enc_w));
+                let (y0, y1) = (scale(y0, full_h, enc_h), scale(y1, full_h, enc_h));
+                if x1 > x0 && y1 > y0 {
+                    boxes.push(Rect::new((x0, y0), (x1 - x0, y1 - y0)));
+                }
+            }
+        }
+        boxes
+    }
+
+    // filter chain covering `boxes` of a software frame. Empty if there's nothing to cover
+    pub fn filter(&self, boxes: &[Rect]) -> String {
+        let filters: Vec<_> = boxes
+            .iter()
+            .enumerate()
+            .map(|(i, b)| match self.style {
+                ExcludeStyle::Black => format!(
+                    "drawbox=x={}:y={}:w={}:h={}:color=black:t=fill",
+                    b.x, b.y, b.w, b.h
+                ),
+                // the radius is kept small enough for the subsampled chroma planes too
+                ExcludeStyle::Blur => format!(
+                    "split=2[ex{i}][exb{i}];[exb{i}]crop={}:{}:{}:{},boxblur=luma_radius=min(w\\,h)/8:luma_power=3[exbl{i}];[ex{i}][exbl{i}]overlay={}:{}",
+                    b.w, b.h, b.x, b.y, b.x, b.y
+                ),
+            })
+            .collect();
+        filters.join(",")
+    }
+
+    // like `filter`, but for a vaapi surface, and appended straight to the chain it's drawn on
+    pub fn filter_vaapi(&self, boxes: &[Rect]) -> String {
+        if boxes.is_empty() {
+            return String::new();
+        }
+        match self.style {
+            ExcludeStyle::Black => {
+                let n = boxes.len();
+                let mut f = format!("[exm0];[exclude]split={n}");
+                for i in 0..n {
+                    f += &format!("[exp{i}]");
+                }
+                for (i, b) in boxes.iter().enumerate() {
+                    let next = if i + 1 < n {
+                        format!("[exm{}]", i + 1)
+                    } else {
+                        String::new()
+                    };
+                    f += &format!(
+                        ";[exp{i}]scale_vaapi=w={}:h={}[exs{i}];[exm{i}][exs{i}]overlay_vaapi=x={}:y={}:eof_action=repeat{next}",
+                        b.w, b.h, b.x, b.y
+                    );
+                }
+                f
+            }
+            // about as blurry as boxblur with a radius of an eighth of the box's smaller side
+            ExcludeStyle::Blur => boxes
+                .iter()
+                .enumerate()
+                .map(|(i, b)| {
+                    let k = (b.w.min(b.h) / 8).max(1);
+                    format!(
+                        ",split=2[ex{i}][exb{i}];[exb{i}]crop={}:{}:{}:{}:exact=1,scale_vaapi=w={}:h={},scale_vaapi=w={}:h={}[exbl{i}];[ex{i}][exbl{i}]overlay_vaapi=x={}:y={}",
+                        b.w,
+                        b.h,
+                        b.x,
+                        b.y,
+                        (b.w / k).max(1),
+                        (b.h / k).max(1),
+                        b.w,
+                        b.h,
+                        b.x,
+                        b.y
+                    )
+                })
+                .collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Exclusions;
+    use crate::{composite::Composite, transform::Rect, ExcludeStyle, Layout};
+
+    #[test]
+    fn boxes() {
+        let exclusions = Exclusions {
+            regions: vec![
+                Rect::new((1800, 0), (400, 200)), // partly outside the roi
+                Rect::new((0, 0), (10, 10)),      // entirely outside
+            ],
+            style: ExcludeStyle::Black,
+        };
+        let roi = Rect::new((1000, 0), (1000, 1000));
+        // recorded at half size
+        assert_eq!(
+            exclusions.boxes(roi, None, (500, 500)),
+            vec![Rect::new((400, 0), (100, 100))]
+        );
+
+        let composite = Composite {
+            regions: vec![
+                Rect::new((0, 0), (100, 100)),
+                Rect::new((1800, 0), (200, 200)),
+            ],
+            layout: Layout::Horizontal,
+            others: Vec::new(),
+            zoom: 1.,
+        };
+        // the second region is scaled down to 100x100 and placed right of the first
+        assert_eq!(
+            exclusions.boxes(composite.bounds(), Some(&composite), (200, 100)),
+            vec![Rect::new((0, 0), (10, 10)), Rect::new((100, 0), (100, 100)),]
+        );
+    }
+
+    #[test]
+    fn filter() {
+        let mut exclusions = Exclusions::default();
+        assert_eq!(exclusions.filter(&[]), "");
+        let boxes = [Rect::new((10, 20), (30, 40))];
+        assert_eq!(
+            exclusions.filter(&boxes),
+            "drawbox=x=10:y=20:w=30:h=40:color=black:t=fill"
+        );
+        exclusions.style = ExcludeStyle::Blur;
+        assert_eq!(
+            exclusions.filter(&boxes),
+            "split=2[ex0][exb0];[exb0]crop=30:40:10:20,boxblur=luma_radius=min(w\\,h)/8:luma_power=3[exbl0];[ex0][exbl0]overlay=10:20"
+        );
+    }
+
+    #[test]
+    fn filter_vaapi() {
+        let mut exclusions = Exclusions::default();
+        assert_eq!(exclusions.filter_vaapi(&[]), "");
+        let boxes = [Rect::new((10, 20), (30, 40)), Rect::new((100, 0), (8, 8))];
+        assert_eq!(
+            exclusions.filter_vaapi(&boxes),
+            "[exm0];[exclude]split=2[exp0][exp1]\
+             ;[exp0]scale_vaapi=w=30:h=40[exs0];[exm0][exs0]overlay_vaapi=x=10:y=20:eof_action=repeat[exm1]\
+             ;[exp1]scale_vaapi=w=8:h=8[exs1];[exm1][exs1]overlay_vaapi=x=100:y=0:eof_action=repeat"
+        );
+        exclusions.style = ExcludeStyle::Blur;
+        assert_eq!(
+            exclusions.filter_vaapi(&boxes[..1]),
+            ",split=2[ex0][exb0];[exb0]crop=30:40:10:20:exact=1,scale_vaapi=w=10:h=13,scale_vaapi=w=30:h=40[exbl0];[ex0][exbl0]overlay_vaapi=x=10:y=20"
+        );
+    }
+}
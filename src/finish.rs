@@ -0,0 +1,92 @@
+use std::{
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc::{self, SendError, Sender},
+        Arc,
+    },
+    thread::{self, JoinHandle},
+};
+
+use log::warn;
+
+// Work on files once they're finished, which for a long recording can take a while: attaching
+// wl-screenrec.json and the --poster, and --trim-start and --trim-end, remux them, --upload-url
+// uploads them. It happens on a background thread so recording (into the next file, with
+// --split-at) doesn't wait for it. Jobs run one after the other in the order they're queued, so a
+// file is only uploaded once it's been trimmed
+
+type Job = Box<dyn FnOnce() + Send>;
+
+#[derive(Default)]
+pub struct Finisher {
+    worker: Option<(Sender<Job>, JoinHandle<()>)>,
+    queued: Arc<AtomicUsize>, // and not done yet
+}
+
+impl Finisher {
+    pub fn run(&mut self, job: impl FnOnce() + Send + 'static) {
+        if self.worker.is_none() {
+            let (tx, rx) = mpsc::channel::<Job>();
+            let spawned = thread::Builder::new()
+                .name("finish".to_owned())
+                .spawn(move || rx.into_iter().for_each(|job| job()));
+            match spawned {
+                Ok(handle) => self.worker = Some((tx, handle)),
+                Err(e) => {
+                    warn!("failed to start a thread to finish files on, doing it now: {e}");
+                    job();
+                    return;
+                }
+            }
+        }
+        let (tx, _) = self.worker.as_ref().unwrap();
+        let queued = Arc::clone(&self.queued);
+        queued.fetch_add(1, Ordering::SeqCst);
+        let job: Job = Box::new(move || {
+            job();
+            queued.fetch_sub(1, Ordering::SeqCst);
+        });
+        // only if an earlier job panicked
+        if let Err(SendError(job)) = tx.send(job) {
+            job();
+        }
+    }
+
+    // blocks until everything queued is done
+    pub fn finish(&mut self) {
+        let Some((tx, handle)) = self.worker.take() else {
+            return;
+        };
+        drop(tx);
+        if self.queued.load(Ordering::SeqCst) > 0 {
+            eprintln!("waiting for the finished files to be processed");
+        }
+        let _ = handle.join();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        sync::{Arc, Mutex},
+        thread::sleep,
+        time::Duration,
+    };
+
+    use super::Finisher;
+
+    #[test]
+    fn in_order() {
+        let done = Arc::new(Mutex::new(Vec::new()));
+        let mut finisher = Finisher::default();
+        for i in 0..5 {
+            let done = Arc::clone(&done);
+            finisher.run(move || {
+                sleep(Duration::from_millis(10 * (5 - i)));
+                done.lock().unwrap().push(i);
+            });
+        }
+        finisher.finish();
+        assert_eq!(*done.lock().unwrap(), [0, 1, 2, 3, 4]);
+    }
+}
@@ -0,0 +1,141 @@
+use std::{
+    env,
+    path::Path,
+    sync::mpsc::{channel, Receiver},
+};
+
+use log::{debug, info, warn};
+use serde_json::Value;
+
+use crate::{hyprland, ipc_watch, sway::SwayIpc};
+
+// Hyprland's `activeworkspace` has the monitor it's on
+fn parse_hyprland(workspace: &Value) -> Option<String> {
+    Some(workspace["monitor"].as_str()?.to_owned())
+}
+
+// sway's GET_OUTPUTS marks the focused output
+fn parse_sway(outputs: &Value) -> Option<String> {
+    let output = outputs
+        .as_array()?
+        .iter()
+        .find(|o| o["focused"].as_bool() == Some(true))?;
+    Some(output["name"].as_str()?.to_owned())
+}
+
+fn hyprland_focused(dir: &Path) -> anyhow::Result<Option<String>> {
+    Ok(parse_hyprland(&hyprland::request(dir, "activeworkspace")?))
+}
+
+fn sway_focused(ipc: &mut SwayIpc) -> anyhow::Result<Option<String>> {
+    Ok(parse_sway(&ipc.outputs()?))
+}
+
+// the output that has focus according to the compositor. Only sway and Hyprland are supported,
+// there is no protocol for this
+pub fn focused_output() -> Option<String> {
+    let focused = if env::var_os("HYPRLAND_INSTANCE_SIGNATURE").is_some() {
+        hyprland::socket_dir().and_then(|dir| hyprland_focused(&dir))
+    } else if env::var_os("SWAYSOCK").is_some() {
+        SwayIpc::connect().and_then(|mut ipc| sway_focused(&mut ipc))
+    } else {
+        return None;
+    };
+    focused
+        .map_err(|e| debug!("failed to ask the compositor for the focused output: {e}"))
+        .ok()
+        .flatten()
+}
+
+// follows focus over the compositor's IPC in the background, sending the name of the focused
+// output whenever it changes. `wake` is called after each change so the event loop notices
+pub fn spawn_focus_watcher(
+    last: Option<String>,
+    wake: impl Fn() + Send + 'static,
+) -> anyhow::Result<Receiver<String>> {
+    if env::var_os("HYPRLAND_INSTANCE_SIGNATURE").is_some() {
+        let dir = hyprland::socket_dir()?;
+        let mut events = hyprland::event_socket(&dir)?;
+        Ok(watch(
+            last,
+            wake,
+            move || hyprland_focused(&dir),
+            move || Ok(hyprland::wait_event(&mut events, -1)?),
+        ))
+    } else if env::var_os("SWAYSOCK").is_some() {
+        let mut ipc = SwayIpc::connect()?;
+        let mut events = SwayIpc::connect()?;
+        // focus moving to another output focuses the workspace shown there
+        events.subscribe(r#"["workspace", "output"]"#)?;
+        Ok(watch(
+            last,
+            wake,
+            move || sway_focused(&mut ipc),
+            move || events.wait_event(-1),
+        ))
+    } else {
+        warn!(
+            "--follow-focus only works on sway and Hyprland, the recording will stay on one output"
+        );
+        Ok(channel().1)
+    }
+}
+
+// sends each output `focused` says has focus, when it's not the `last` one
+fn watch(
+    mut last: Option<String>,
+    wake: impl Fn() + Send + 'static,
+    focused: impl FnMut() -> anyhow::Result<Option<String>> + Send + 'static,
+    wait: impl FnMut() -> anyhow::Result<()> + Send + 'static,
+) -> Receiver<String> {
+    let (tx, rx) = channel();
+    ipc_watch::watch(
+        "focus-watcher",
+        focused,
+        wait,
+        move |focused| match focused {
+            Ok(Some(output)) if last.as_ref() != Some(&output) => {
+                info!("focus moved to output {output}");
+                if tx.send(output.clone()).is_err() {
+                    return false;
+                }
+                wake();
+                last = Some(output);
+                true
+            }
+            Ok(_) => true,
+            Err(e) => {
+                warn!("lost connection to the compositor, no longer following focus: {e}");
+                false
+            }
+        },
+    );
+    rx
+}
+
+#[cfg(test)]
+mod test {
+    use serde_json::json;
+
+    use super::{parse_hyprland, parse_sway};
+
+    #[test]
+    fn parse() {
+        assert_eq!(
+            parse_hyprland(&json!({"id": 2, "name": "2", "monitor": "DP-1", "monitorID": 0}))
+                .as_deref(),
+            Some("DP-1")
+        );
+        assert_eq!(parse_hyprland(&json!({})), None);
+
+        let sway = json!([
+            {"name": "eDP-1", "focused": false},
+            {"name": "DP-2", "focused": true}
+        ]);
+        assert_eq!(parse_sway(&sway).as_deref(), Some("DP-2"));
+        assert_eq!(
+            parse_sway(&json!([{"name": "eDP-1", "focused": false}])),
+            None
+        );
+    }
+}
@@ -0,0 +1,152 @@
+// --max-fps, which of the captured frames are encoded
+
+#[derive(clap::ValueEnum, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum FpsLimitMode {
+    #[default]
+    Drop,
+    Smooth,
+}
+
+// drops frames that arrive sooner than 1/max_fps after the last one kept, for --max-fps. With
+// --max-fps-mode smooth, keeps the frame closest to each tick of a max_fps cadence instead
+pub struct FpsLimit {
+    interval_ns: i64,
+    last_ns: Option<i64>,
+    mode: FpsLimitMode,
+    next_tick_ns: Option<i64>, // smooth
+    capture_interval_ns: i64,  // smooth, average time between captured frames
+    last_seen_ns: Option<i64>, // smooth, kept or not
+}
+
+impl FpsLimit {
+    pub fn new(max_fps: f64, mode: FpsLimitMode) -> Self {
+        let interval_ns = (1e9 / max_fps) as i64;
+        Self {
+            interval_ns,
+            last_ns: None,
+            mode,
+            next_tick_ns: None,
+            capture_interval_ns: interval_ns,
+            last_seen_ns: None,
+        }
+    }
+
+    pub fn interval_ns(&self) -> i64 {
+        self.interval_ns
+    }
+
+    pub fn should_keep(&mut self, pts_ns: i64) -> bool {
+        match self.mode {
+            FpsLimitMode::Drop => match self.last_ns {
+                Some(last) if pts_ns - last < self.interval_ns => false,
+                _ => {
+                    self.last_ns = Some(pts_ns);
+                    true
+                }
+            },
+            FpsLimitMode::Smooth => self.should_keep_smooth(pts_ns),
+        }
+    }
+
+    fn should_keep_smooth(&mut self, pts_ns: i64) -> bool {
+        if let Some(last) = self.last_seen_ns.replace(pts_ns) {
+            // a static screen sends no frames, that's not the capture rate
+            let interval = (pts_ns - last).clamp(0, self.interval_ns);
+            self.capture_interval_ns += (interval - self.capture_interval_ns) / 8;
+        }
+        let Some(tick) = self.next_tick_ns else {
+            self.next_tick_ns = Some(pts_ns + self.interval_ns);
+            return true;
+        };
+        // the next frame is expected capture_interval_ns from now, this one is closer to the tick
+        // unless it's more than half of that early
+        if pts_ns < tick - self.capture_interval_ns / 2 {
+            return false;
+        }
+        self.next_tick_ns = Some(if pts_ns - tick > self.interval_ns / 2 {
+            // more than half a frame late, like after the screen was static: start a new cadence
+            pts_ns + self.interval_ns
+        } else {
+            tick + self.interval_ns
+        });
+        true
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{FpsLimit, FpsLimitMode};
+
+    const MS: i64 = 1_000_000;
+
+    // the pts of the frames kept out of those at pts
+    fn keep(mode: FpsLimitMode, max_fps: f64, pts: &[i64]) -> Vec<i64> {
+        let mut limit = FpsLimit::new(max_fps, mode);
+        pts.iter()
+            .copied()
+            .filter(|p| limit.should_keep(*p))
+            .collect()
+    }
+
+    // pts of frames spaced by intervals, repeating, for length
+    fn frames(intervals: &[i64], length: i64) -> Vec<i64> {
+        let mut pts = vec![0];
+        for i in intervals.iter().cycle() {
+            let next = pts.last().unwrap() + i;
+            if next >= length {
+                return pts;
+            }
+            pts.push(next);
+        }
+        unreachable!()
+    }
+
+    fn gaps(pts: &[i64]) -> Vec<i64> {
+        pts.windows(2).map(|w| w[1] - w[0]).collect()
+    }
+
+    #[test]
+    fn drop() {
+        // 60fps to 30 keeps every other frame
+        let pts = frames(&[16_666_667], 1000 * MS);
+        let kept = keep(FpsLimitMode::Drop, 30., &pts);
+        assert_eq!(kept.len(), 30);
+        assert!(gaps(&kept).iter().all(|g| *g == 33_333_334));
+
+        // but 144fps to 60 keeps every third, 48fps
+        let pts = frames(&[6_944_444], 990 * MS);
+        assert_eq!(keep(FpsLimitMode::Drop, 60., &pts).len(), 48);
+    }
+
+    #[test]
+    fn smooth() {
+        // 144fps to 60 keeps 60 frames a second, alternating 2 and 3 captured frames apart
+        let pts = frames(&[6_944_444], 1000 * MS);
+        let kept = keep(FpsLimitMode::Smooth, 60., &pts);
+        assert!((59..=61).contains(&kept.len()), "{}", kept.len());
+        for g in &gaps(&kept)[8..] {
+            assert!([13_888_888, 20_833_332].contains(g), "{g}");
+        }
+    }
+
+    #[test]
+    fn smooth_uneven() {
+        // jittery capture averaging 60fps, to 30
+        let pts = frames(&[10 * MS, 23 * MS, 14 * MS, 20 * MS], 2000 * MS);
+        let kept = keep(FpsLimitMode::Smooth, 30., &pts);
+        assert!((59..=61).contains(&kept.len()), "{}", kept.len());
+        for g in gaps(&kept) {
+            assert!((20 * MS..=47 * MS).contains(&g), "{g}");
+        }
+
+        // a burst then a static screen: the first frame after it is kept and starts a new cadence
+        let mut pts = frames(&[5 * MS], 100 * MS);
+        pts.extend(frames(&[5 * MS], 100 * MS).iter().map(|p| p + 600 * MS));
+        let kept = keep(FpsLimitMode::Smooth, 50., &pts);
+        assert!(kept.contains(&(600 * MS)));
+        assert!(kept.len() <= 11, "{kept:?}");
+        for g in gaps(&kept) {
+            assert!(g >= 15 * MS, "{g}");
+        }
+    }
+}
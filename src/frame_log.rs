@@ -0,0 +1,44 @@
+use std::{
+    fmt,
+    fs::File,
+    io::{self, BufWriter, Write},
+    path::Path,
+};
+
+use log::warn;
+
+// Writes one JSON object per line for every captured (or dropped) frame, for --frame-log
+pub struct FrameLog {
+    w: BufWriter<File>,
+}
+
+impl FrameLog {
+    pub fn create(path: &Path) -> io::Result<Self> {
+        Ok(FrameLog {
+            w: BufWriter::new(File::create(path)?),
+        })
+    }
+
+    // capture_ns is the compositor's presentation timestamp, pts_ns is relative to the first frame
+    pub fn frame(&mut self, capture_ns: i64, pts_ns: i64, damage: bool) {
+        self.write(format_args!(
+            r#"{{"capture_ns":{capture_ns},"pts_ns":{pts_ns},"damage":{damage}}}"#
+        ));
+    }
+
+    pub fn dropped(&mut self, reason: &str) {
+        self.write(format_args!(r#"{{"dropped":"{reason}"}}"#));
+    }
+
+    fn write(&mut self, line: fmt::Arguments) {
+        if let Err(e) = writeln!(self.w, "{line}") {
+            warn!("failed to write to frame log: {e}");
+        }
+    }
+
+    pub fn flush(&mut self) {
+        if let Err(e) = self.w.flush() {
+            warn!("failed to flush frame log: {e}");
+        }
+    }
+}
@@ -0,0 +1,90 @@
+use thiserror::Error;
+
+// the compositor's presentation timestamp of a frame, in nanoseconds. The seconds come split
+// into two u32s
+pub fn presentation_ns(tv_sec_hi: u32, tv_sec_lo: u32, tv_nsec: u32) -> i64 {
+    let secs = (i64::from(tv_sec_hi) << 32) + i64::from(tv_sec_lo);
+    secs * 1_000_000_000 + i64::from(tv_nsec)
+}
+
+#[derive(Error, Debug, PartialEq, Eq)]
+#[error("frame pts {pts} does not come after the previous frame's {last}")]
+pub struct NonMonotonic {
+    pub pts: i64,
+    pub last: i64,
+}
+
+// Checks the pts of each frame going to the encoder. On a variable refresh rate display the
+// intervals between presentation timestamps vary a lot, and they are kept exactly, but a pts that
+// doesn't advance can't be encoded
+#[derive(Default)]
+pub struct FrameTiming {
+    last_pts: Option<i64>,
+}
+
+impl FrameTiming {
+    // the pts of the previous frame and how long it was shown for, None for the first frame
+    pub fn advance(&mut self, pts: i64) -> Result<Option<(i64, i64)>, NonMonotonic> {
+        match self.last_pts {
+            Some(last) if pts <= last => Err(NonMonotonic { pts, last }),
+            last => {
+                self.last_pts = Some(pts);
+                Ok(last.map(|last| (last, pts - last)))
+            }
+        }
+    }
+
+    pub fn last_pts(&self) -> Option<i64> {
+        self.last_pts
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{presentation_ns, FrameTiming, NonMonotonic};
+
+    #[test]
+    fn presentation_ns_exact() {
+        assert_eq!(presentation_ns(0, 1, 500), 1_000_000_500);
+        assert_eq!(
+            presentation_ns(1, 0, 999_999_999),
+            (1 << 32) * 1_000_000_000 + 999_999_999
+        );
+    }
+
+    #[test]
+    fn vrr_durations_preserved() {
+        // a 48-144Hz VRR display, presenting whenever the content updates
+        let start = presentation_ns(0, 1_700_000_000, 123_456_789);
+        let intervals = [6_944_444, 20_833_333, 7_001_003, 16_666_667, 13_888_889, 1];
+        let mut timing = FrameTiming::default();
+
+        let mut capture_ns = start;
+        assert_eq!(timing.advance(capture_ns - start), Ok(None));
+        for interval in intervals {
+            let pts = capture_ns - start;
+            capture_ns += interval;
+            assert_eq!(
+                timing.advance(capture_ns - start),
+                Ok(Some((pts, interval)))
+            );
+        }
+        assert_eq!(capture_ns - start, intervals.iter().sum::<i64>());
+    }
+
+    #[test]
+    fn non_monotonic() {
+        let mut timing = FrameTiming::default();
+        timing.advance(100).unwrap();
+        assert_eq!(
+            timing.advance(100),
+            Err(NonMonotonic {
+                pts: 100,
+                last: 100
+            })
+        );
+        assert_eq!(timing.advance(50), Err(NonMonotonic { pts: 50, last: 100 }));
+        // rejected frames don't move the last pts
+        assert_eq!(timing.advance(101), Ok(Some((100, 1))));
+    }
+}
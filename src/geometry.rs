@@ -0,0 +1,180 @@
+use std::{fmt, str::FromStr};
+
+use crate::{parse_geometry, ParseGeometryError};
+
+// --geometry, either an absolute region like slurp prints or a size anchored to part of the
+// recorded output, which is resolved once the output's size is known
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Geometry {
+    Absolute((i32, i32, u32, u32)), // x,y WxH in global logical coordinates
+    Anchored(Anchor, Length, Length),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Anchor {
+    TopLeft,
+    Top,
+    TopRight,
+    Left,
+    Center,
+    Right,
+    BottomLeft,
+    Bottom,
+    BottomRight,
+}
+
+const ANCHORS: [(&str, Anchor); 9] = [
+    ("top-left", Anchor::TopLeft),
+    ("top", Anchor::Top),
+    ("top-right", Anchor::TopRight),
+    ("left", Anchor::Left),
+    ("center", Anchor::Center),
+    ("right", Anchor::Right),
+    ("bottom-left", Anchor::BottomLeft),
+    ("bottom", Anchor::Bottom),
+    ("bottom-right", Anchor::BottomRight),
+];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Length {
+    Logical(u32),
+    Percent(f64), // of the output's size
+}
+
+impl Length {
+    fn resolve(self, output_len: i32) -> i32 {
+        match self {
+            Length::Logical(l) => l as i32,
+            Length::Percent(p) => (output_len as f64 * p / 100.).round() as i32,
+        }
+        .clamp(1, output_len.max(1))
+    }
+}
+
+impl FromStr for Length {
+    type Err = ParseGeometryError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.strip_suffix('%') {
+            Some(p) => match p.parse() {
+                Ok(p) if p > 0. && p <= 100. => Ok(Length::Percent(p)),
+                _ => Err(ParseGeometryError::Size),
+            },
+            None => Ok(Length::Logical(s.parse()?)),
+        }
+    }
+}
+
+impl fmt::Display for Length {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Length::Logical(l) => write!(f, "{l}"),
+            Length::Percent(p) => write!(f, "{p}%"),
+        }
+    }
+}
+
+impl Geometry {
+    // the region in global logical coordinates, for an output at `loc` of `logical_size`.
+    // Anchored regions are shrunk to fit
+    pub fn resolve(self, loc: (i32, i32), (out_w, out_h): (i32, i32)) -> (i32, i32, u32, u32) {
+        let (anchor, w, h) = match self {
+            Geometry::Absolute(region) => return region,
+            Geometry::Anchored(anchor, w, h) => (anchor, w.resolve(out_w), h.resolve(out_h)),
+        };
+
+        use Anchor::*;
+        let x = match anchor {
+            TopLeft | Left | BottomLeft => 0,
+            Top | Center | Bottom => (out_w - w) / 2,
+            TopRight | Right | BottomRight => out_w - w,
+        };
+        let y = match anchor {
+            TopLeft | Top | TopRight => 0,
+            Left | Center | Right => (out_h - h) / 2,
+            BottomLeft | Bottom | BottomRight => out_h - h,
+        };
+        (loc.0 + x, loc.1 + y, w as u32, h as u32)
+    }
+}
+
+impl FromStr for Geometry {
+    type Err = ParseGeometryError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let Some((first, size)) = s.split_once(' ') else {
+            return Err(ParseGeometryError::Structure);
+        };
+        let Some(&(_, anchor)) = ANCHORS.iter().find(|(name, _)| *name == first) else {
+            if !first.contains(',') {
+                return Err(ParseGeometryError::Anchor);
+            }
+            return Ok(Geometry::Absolute(parse_geometry(s)?));
+        };
+        let Some((w, h)) = size.split_once('x') else {
+            return Err(ParseGeometryError::Size);
+        };
+        Ok(Geometry::Anchored(anchor, w.parse()?, h.parse()?))
+    }
+}
+
+impl fmt::Display for Geometry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Geometry::Absolute((x, y, w, h)) => write!(f, "{x},{y} {w}x{h}"),
+            Geometry::Anchored(anchor, w, h) => {
+                let name = ANCHORS.iter().find(|(_, a)| a == anchor).unwrap().0;
+                write!(f, "{name} {w}x{h}")
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Anchor, Geometry, Length};
+
+    #[test]
+    fn parse() {
+        assert_eq!(
+            "10,-20 300x200".parse::<Geometry>().unwrap(),
+            Geometry::Absolute((10, -20, 300, 200))
+        );
+        assert_eq!(
+            "center 50%x50%".parse::<Geometry>().unwrap(),
+            Geometry::Anchored(Anchor::Center, Length::Percent(50.), Length::Percent(50.))
+        );
+        assert_eq!(
+            "bottom-right 800x600".parse::<Geometry>().unwrap(),
+            Geometry::Anchored(
+                Anchor::BottomRight,
+                Length::Logical(800),
+                Length::Logical(600)
+            )
+        );
+        assert!("center 150%x50%".parse::<Geometry>().is_err());
+        assert!("middle 50%x50%".parse::<Geometry>().is_err());
+        assert!("center".parse::<Geometry>().is_err());
+
+        for s in ["top-left 12.5%x100", "0,0 1x1"] {
+            assert_eq!(s.parse::<Geometry>().unwrap().to_string(), s);
+        }
+    }
+
+    #[test]
+    fn resolve() {
+        let center: Geometry = "center 50%x50%".parse().unwrap();
+        assert_eq!(
+            center.resolve((1920, 0), (2560, 1440)),
+            (1920 + 640, 360, 1280, 720)
+        );
+
+        let corner: Geometry = "bottom-right 800x600".parse().unwrap();
+        assert_eq!(corner.resolve((0, 0), (1920, 1080)), (1120, 480, 800, 600));
+        // shrunk to fit a small output
+        assert_eq!(corner.resolve((0, 0), (640, 480)), (0, 0, 640, 480));
+
+        let top: Geometry = "top 100%x10%".parse().unwrap();
+        assert_eq!(top.resolve((0, 0), (1366, 768)), (0, 0, 1366, 77));
+    }
+}
@@ -0,0 +1,205 @@
+use std::{
+    fs,
+    path::Path,
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+use anyhow::Context;
+use ffmpeg::{format, media, Packet, Rational};
+use log::{debug, warn};
+
+use crate::{
+    history_buffer::HistoryBuffer,
+    history_transcode::SourceStream,
+    open_output,
+    upload::{self, Destination},
+};
+
+// --history with --continuous: the whole session is recorded to --filename like without
+// --history, and the packets written to it are also kept in a replay buffer. Each SIGUSR1 saves
+// the buffer to a clip of its own (named from --history-filename if given) while recording and
+// buffering carry on
+
+pub struct Highlights {
+    keep: Vec<Duration>, // by stream
+    history: HistoryBuffer,
+    clips: Vec<JoinHandle<()>>,        // being written in the background
+    filename_template: Option<String>, // --history-filename
+    upload: Option<Destination>,       // --upload-url or --s3-url
+}
+
+impl Highlights {
+    pub fn new(
+        keep: Vec<Duration>,
+        history: HistoryBuffer,
+        filename_template: Option<String>,
+        upload: Option<Destination>,
+    ) -> Self {
+        Highlights {
+            keep,
+            history,
+            clips: Vec::new(),
+            filename_template,
+            upload,
+        }
+    }
+
+    pub fn filename_template(&self) -> Option<&str> {
+        self.filename_template.as_deref()
+    }
+
+    pub fn keep(&self) -> &[Duration] {
+        &self.keep
+    }
+
+    pub fn memory_bytes(&self) -> usize {
+        self.history.memory_bytes()
+    }
+
+    // a copy of every packet written to the main recording
+    pub fn push(&mut self, packet: &Packet, time_base: Rational) {
+        let stream = packet.stream();
+        self.history.push(packet.clone());
+        let (packets, bytes) = self.history.trim_to(stream, self.keep[stream], time_base);
+        if packets > 0 {
+            debug!("popped {bytes} bytes across {packets} packets from the replay buffer");
+        }
+    }
+
+    pub fn save(
+        &mut self,
+        filename: String,
+        muxer: Option<String>,
+        muxer_options: Option<String>,
+        streams: Vec<SourceStream>,
+    ) {
+        self.clips.retain(|c| !c.is_finished());
+        let history = match self.history.snapshot() {
+            Ok(history) => history,
+            Err(e) => {
+                warn!("failed to save {filename}, the spilled history can't be read: {e}");
+                return;
+            }
+        };
+        let upload = self.upload.clone();
+        let spawned = thread::Builder::new()
+            .name("highlight".to_owned())
+            .spawn(move || {
+                match write_clip(
+                    &filename,
+                    muxer.as_deref(),
+                    muxer_options.as_deref(),
+                    &streams,
+                    history,
+                ) {
+                    Ok(()) => {
+                        eprintln!("saved the last of the recording to {filename}");
+                        if let Some(destination) = &upload {
+                            upload::upload(destination, &filename);
+                        }
+                    }
+                    Err(e) => warn!("failed to save {filename}: {e:?}"),
+                }
+            });
+        match spawned {
+            Ok(handle) => self.clips.push(handle),
+            Err(e) => warn!("failed to start saving a clip: {e}"),
+        }
+    }
+
+    // waits for the clips still being written
+    pub fn finish(&mut self) {
+        if !self.clips.is_empty() {
+            eprintln!("waiting for clips to be saved");
+        }
+        for clip in self.clips.drain(..) {
+            let _ = clip.join();
+        }
+    }
+}
+
+// `filename` with -clip before the extension, so clips sit next to the recording they're from
+pub fn clip_filename(filename: &str) -> String {
+    let path = Path::new(filename);
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let ext = path
+        .extension()
+        .map(|e| format!(".{}", e.to_string_lossy()))
+        .unwrap_or_default();
+    path.with_file_name(format!("{stem}-clip{ext}"))
+        .to_string_lossy()
+        .into_owned()
+}
+
+fn write_clip(
+    filename: &str,
+    muxer: Option<&str>,
+    muxer_options: Option<&str>,
+    streams: &[SourceStream],
+    history: HistoryBuffer,
+) -> anyhow::Result<()> {
+    if let Some(dir) = Path::new(filename)
+        .parent()
+        .filter(|d| !d.as_os_str().is_empty())
+    {
+        fs::create_dir_all(dir).with_context(|| format!("failed to create {}", dir.display()))?;
+    }
+    let mut octx = open_output(filename, muxer, muxer_options)?;
+    // attachments are last, so leaving them out doesn't change the other streams' indices
+    for st in streams
+        .iter()
+        .filter(|st| st.parameters.medium() != media::Type::Attachment)
+    {
+        let mut ost = octx.add_stream(None::<ffmpeg::Codec>)?;
+        ost.set_parameters(st.parameters.clone());
+        unsafe { (*ost.parameters().as_mut_ptr()).codec_tag = 0 };
+    }
+    octx.write_header()
+        .with_context(|| format!("failed to write the header of {filename}"))?;
+
+    // starts at 0, from the earliest first packet of any stream so nothing is negative
+    let offset_ns = streams
+        .iter()
+        .enumerate()
+        .filter_map(|(i, st)| {
+            let pts = history.packets().find(|p| p.stream == i)?.pts;
+            Some(pts * 1_000_000_000 * i64::from(st.time_base.0) / i64::from(st.time_base.1))
+        })
+        .min()
+        .unwrap_or(0);
+
+    for mut packet in history.into_packets() {
+        let idx = packet.stream();
+        let tb = streams[idx].time_base;
+        let offset = offset_ns * i64::from(tb.1) / i64::from(tb.0) / 1_000_000_000;
+        packet.set_pts(packet.pts().map(|pts| pts - offset));
+        packet.set_dts(packet.dts().map(|dts| dts - offset));
+        packet.rescale_ts(tb, octx.stream(idx).unwrap().time_base());
+        packet.set_position(-1);
+        packet.write_interleaved(&mut octx)?;
+    }
+    octx.write_trailer()?;
+    Ok(())
+}
+
+// the streams of the main recording, for the clips to have the same ones
+pub fn source_streams(octx: &format::context::Output) -> Vec<SourceStream> {
+    octx.streams()
+        .map(|st| SourceStream {
+            parameters: st.parameters().clone(),
+            time_base: st.time_base(),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::clip_filename;
+
+    #[test]
+    fn clip_name() {
+        assert_eq!(clip_filename("/tmp/rec.mkv"), "/tmp/rec-clip.mkv");
+        assert_eq!(clip_filename("rec"), "rec-clip");
+    }
+}
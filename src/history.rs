@@ -0,0 +1,58 @@
+use std::time::Duration;
+
+// With damage-driven capture a GOP of a fixed number of frames can span minutes of a static
+// screen, and history can only be trimmed at keyframes. So while recording --history, keyframes
+// are forced at least this often
+pub fn keyframe_interval(history: Duration) -> Duration {
+    (history / 4).min(Duration::from_secs(2))
+}
+
+// `packets` is one stream's (pts in ns, is keyframe) in history, oldest first. Returns how many
+// to drop from the front: everything before the newest keyframe that still leaves at least
+// `keep_ns` of the stream, so what's kept is within one GOP of the requested duration
+pub fn trim_point(packets: &[(i64, bool)], keep_ns: i64) -> usize {
+    let Some(&(last_pts, _)) = packets.last() else {
+        return 0;
+    };
+    packets
+        .iter()
+        .rposition(|&(pts, key)| key && last_pts - pts >= keep_ns)
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use super::{keyframe_interval, trim_point};
+
+    #[test]
+    fn trim() {
+        assert_eq!(trim_point(&[], 10), 0);
+
+        // keyframe every 3
+        let packets: Vec<_> = (0..10).map(|i| (i * 10, i % 3 == 0)).collect();
+        // last is 90, keep 40 -> the newest keyframe at or before 50 is 30
+        assert_eq!(trim_point(&packets, 40), 3);
+        // keep 30 -> keyframe at 60 is exactly enough
+        assert_eq!(trim_point(&packets, 30), 6);
+        // not enough history yet
+        assert_eq!(trim_point(&packets, 100), 0);
+
+        // audio, every packet is a keyframe
+        let audio: Vec<_> = (0..10).map(|i| (i * 10, true)).collect();
+        assert_eq!(trim_point(&audio, 25), 6);
+    }
+
+    #[test]
+    fn interval() {
+        assert_eq!(
+            keyframe_interval(Duration::from_secs(2)),
+            Duration::from_millis(500)
+        );
+        assert_eq!(
+            keyframe_interval(Duration::from_secs(60)),
+            Duration::from_secs(2)
+        );
+    }
+}
@@ -0,0 +1,372 @@
+use std::{
+    collections::{BTreeMap, VecDeque},
+    fs::{self, File, OpenOptions},
+    io,
+    os::unix::fs::FileExt,
+    path::{Path, PathBuf},
+    process,
+    time::Duration,
+};
+
+use ffmpeg::{ffi::av_packet_ref, packet, Packet, Rational};
+use log::{debug, warn};
+use log_once::warn_once;
+
+use crate::history;
+
+// spilled packets are appended to files of about this size, each one is deleted once everything
+// in it has been trimmed from history
+const SEGMENT_SIZE: u64 = 64 << 20;
+
+#[derive(Clone)]
+pub struct PacketInfo {
+    pub stream: usize,
+    pub pts: i64, // in the stream's time base
+    pub key: bool,
+    pub size: usize,
+}
+
+enum Data {
+    Memory(Packet),
+    Disk { segment: u64, offset: u64 },
+}
+
+struct Buffered {
+    info: PacketInfo,
+    dts: Option<i64>,
+    duration: i64,
+    data: Data,
+}
+
+struct Segment {
+    file: File,
+    len: u64,
+    live: usize, // packets in it that are still in history
+}
+
+// --history-spill. The files are unlinked as soon as they're created, so nothing is left behind
+// if the process is killed
+struct Spill {
+    dir: PathBuf,
+    max_memory: usize,
+    segments: BTreeMap<u64, Segment>,
+    next_segment: u64,
+}
+
+impl Spill {
+    fn new_segment(&mut self) -> io::Result<u64> {
+        let n = self.next_segment;
+        let path = self
+            .dir
+            .join(format!(".wl-screenrec-history-{}-{n}", process::id()));
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(&path)?;
+        fs::remove_file(&path)?;
+        debug!(
+            "spilling history to a new segment in {}",
+            self.dir.display()
+        );
+        self.next_segment += 1;
+        self.segments.insert(
+            n,
+            Segment {
+                file,
+                len: 0,
+                live: 0,
+            },
+        );
+        Ok(n)
+    }
+
+    fn write(&mut self, data: &[u8]) -> io::Result<(u64, u64)> {
+        let current = match self.segments.last_key_value() {
+            Some((n, s)) if s.len < SEGMENT_SIZE && *n + 1 == self.next_segment => *n,
+            _ => self.new_segment()?,
+        };
+        let segment = self.segments.get_mut(&current).unwrap();
+        let offset = segment.len;
+        segment.file.write_all_at(data, offset)?;
+        segment.len += data.len() as u64;
+        segment.live += 1;
+        Ok((current, offset))
+    }
+
+    fn read(&self, segment: u64, offset: u64, size: usize) -> io::Result<Vec<u8>> {
+        let mut data = vec![0; size];
+        self.segments[&segment]
+            .file
+            .read_exact_at(&mut data, offset)?;
+        Ok(data)
+    }
+
+    // the same segments, through handles of its own so they stay readable after they're released
+    fn try_clone(&self) -> io::Result<Spill> {
+        let mut segments = BTreeMap::new();
+        for (n, s) in &self.segments {
+            let segment = Segment {
+                file: s.file.try_clone()?,
+                len: s.len,
+                live: s.live,
+            };
+            segments.insert(*n, segment);
+        }
+        Ok(Spill {
+            dir: self.dir.clone(),
+            max_memory: self.max_memory,
+            segments,
+            next_segment: self.next_segment,
+        })
+    }
+
+    fn release(&mut self, segment: u64) {
+        let s = self.segments.get_mut(&segment).unwrap();
+        s.live -= 1;
+        if s.live == 0 {
+            self.segments.remove(&segment);
+        }
+    }
+}
+
+// the packets kept for --history, oldest first. With --history-spill, the oldest are moved to
+// disk once the rest take more than --history-memory
+pub struct HistoryBuffer {
+    packets: VecDeque<Buffered>,
+    memory_bytes: usize,
+    spill: Option<Spill>,
+}
+
+impl HistoryBuffer {
+    pub fn new(spill: Option<(&Path, usize)>) -> Self {
+        HistoryBuffer {
+            packets: VecDeque::new(),
+            memory_bytes: 0,
+            spill: spill.map(|(dir, max_memory)| Spill {
+                dir: dir.to_owned(),
+                max_memory,
+                segments: BTreeMap::new(),
+                next_segment: 0,
+            }),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.packets.len()
+    }
+
+    // bytes of packets held in memory, spilled ones don't count
+    pub fn memory_bytes(&self) -> usize {
+        self.memory_bytes
+    }
+
+    pub fn packets(&self) -> impl Iterator<Item = &PacketInfo> {
+        self.packets.iter().map(|p| &p.info)
+    }
+
+    pub fn push(&mut self, packet: Packet) {
+        self.memory_bytes += packet.size();
+        self.packets.push_back(Buffered {
+            info: PacketInfo {
+                stream: packet.stream(),
+                pts: packet.pts().unwrap(),
+                key: packet.is_key(),
+                size: packet.size(),
+            },
+            dts: packet.dts(),
+            duration: packet.duration(),
+            data: Data::Memory(packet),
+        });
+        self.spill_oldest();
+    }
+
+    fn spill_oldest(&mut self) {
+        let Some(spill) = &mut self.spill else {
+            return;
+        };
+        for p in &mut self.packets {
+            if self.memory_bytes <= spill.max_memory {
+                return;
+            }
+            let Data::Memory(packet) = &p.data else {
+                continue;
+            };
+            match spill.write(packet.data().unwrap_or_default()) {
+                Ok((segment, offset)) => {
+                    self.memory_bytes -= p.info.size;
+                    p.data = Data::Disk { segment, offset };
+                }
+                Err(e) => {
+                    warn_once!(
+                        "failed to spill history to {}, keeping it in memory: {e}",
+                        spill.dir.display()
+                    );
+                    return;
+                }
+            }
+        }
+    }
+
+    // drops the oldest `count` packets of `stream`, returns how many bytes they were
+    pub fn trim(&mut self, stream: usize, count: usize) -> usize {
+        let mut removed = 0;
+        let mut removed_bytes = 0;
+        let memory_bytes = &mut self.memory_bytes;
+        let spill = &mut self.spill;
+        self.packets.retain(|p| {
+            if p.info.stream != stream || removed == count {
+                return true;
+            }
+            removed += 1;
+            removed_bytes += p.info.size;
+            match p.data {
+                Data::Memory(_) => *memory_bytes -= p.info.size,
+                Data::Disk { segment, .. } => spill.as_mut().unwrap().release(segment),
+            }
+            false
+        });
+        removed_bytes
+    }
+
+    // drops the oldest packets of `stream` while it still has at least `keep` without them,
+    // returns how many packets and bytes were dropped. Streams are trimmed separately, so a stream
+    // with long GOPs doesn't hold on to the others
+    pub fn trim_to(
+        &mut self,
+        stream: usize,
+        keep: Duration,
+        time_base: Rational,
+    ) -> (usize, usize) {
+        let timeline: Vec<_> = self
+            .packets()
+            .filter(|p| p.stream == stream)
+            .map(|p| {
+                (
+                    p.pts * 1_000_000_000 * i64::from(time_base.0) / i64::from(time_base.1),
+                    p.key,
+                )
+            })
+            .collect();
+        let to_remove = history::trim_point(&timeline, keep.as_nanos() as i64);
+        if to_remove == 0 {
+            return (0, 0);
+        }
+        (to_remove, self.trim(stream, to_remove))
+    }
+
+    // every packet, oldest first. Spilled ones are read back as they're needed
+    pub fn into_packets(self) -> impl Iterator<Item = Packet> {
+        let spill = self.spill;
+        self.packets.into_iter().filter_map(move |p| match p.data {
+            Data::Memory(packet) => Some(packet),
+            Data::Disk { segment, offset } => {
+                read_back(spill.as_ref().unwrap(), &p, segment, offset)
+            }
+        })
+    }
+
+    // the history as it is now, to write a clip from on another thread without holding up the
+    // recording. Packets in memory share their data instead of copying it, spilled ones are only
+    // read back when the snapshot's packets are
+    pub fn snapshot(&self) -> io::Result<HistoryBuffer> {
+        let packets = self
+            .packets
+            .iter()
+            .map(|p| Buffered {
+                info: p.info.clone(),
+                dts: p.dts,
+                duration: p.duration,
+                data: match &p.data {
+                    Data::Memory(packet) => Data::Memory(share(packet)),
+                    Data::Disk { segment, offset } => Data::Disk {
+                        segment: *segment,
+                        offset: *offset,
+                    },
+                },
+            })
+            .collect();
+        Ok(HistoryBuffer {
+            packets,
+            memory_bytes: self.memory_bytes,
+            spill: self.spill.as_ref().map(Spill::try_clone).transpose()?,
+        })
+    }
+}
+
+// another reference to the same data, Packet's clone copies it
+fn share(packet: &Packet) -> Packet {
+    let mut shared = Packet::empty();
+    if unsafe { av_packet_ref(shared.as_mut_ptr(), packet.as_ptr()) } < 0 {
+        return packet.clone();
+    }
+    shared
+}
+
+fn read_back(spill: &Spill, p: &Buffered, segment: u64, offset: u64) -> Option<Packet> {
+    let data = match spill.read(segment, offset, p.info.size) {
+        Ok(data) => data,
+        Err(e) => {
+            warn!("failed to read spilled history, a packet is missing: {e}");
+            return None;
+        }
+    };
+    let mut packet = Packet::copy(&data);
+    packet.set_stream(p.info.stream);
+    packet.set_pts(Some(p.info.pts));
+    packet.set_dts(p.dts);
+    packet.set_duration(p.duration);
+    if p.info.key {
+        packet.set_flags(packet::Flags::KEY);
+    }
+    Some(packet)
+}
+
+#[cfg(test)]
+mod test {
+    use std::env;
+
+    use ffmpeg::Packet;
+
+    use super::HistoryBuffer;
+
+    fn packet(stream: usize, pts: i64, key: bool) -> Packet {
+        let mut p = Packet::copy(&[pts as u8; 100]);
+        p.set_stream(stream);
+        p.set_pts(Some(pts));
+        p.set_dts(Some(pts));
+        if key {
+            p.set_flags(ffmpeg::packet::Flags::KEY);
+        }
+        p
+    }
+
+    #[test]
+    fn spill_and_read_back() {
+        let dir = env::temp_dir();
+        let mut history = HistoryBuffer::new(Some((&dir, 250)));
+        for pts in 0..10 {
+            history.push(packet(0, pts, pts % 5 == 0));
+        }
+        assert_eq!(history.len(), 10);
+        assert_eq!(history.memory_bytes(), 200);
+
+        assert_eq!(history.trim(0, 5), 500);
+        assert_eq!(history.memory_bytes(), 200);
+
+        // still readable once the history it was taken from is gone
+        let snapshot = history.snapshot().unwrap();
+        for packets in [
+            history.into_packets().collect::<Vec<_>>(),
+            snapshot.into_packets().collect(),
+        ] {
+            assert_eq!(packets.len(), 5);
+            for (p, pts) in packets.iter().zip(5..) {
+                assert_eq!(p.pts(), Some(pts));
+                assert_eq!(p.dts(), Some(pts));
+                assert_eq!(p.is_key(), pts == 5);
+                assert_eq!(p.data().unwrap(), &[pts as u8; 100]);
+            }
+        }
+    }
+}
@@ -0,0 +1,364 @@
+use std::{
+    mem,
+    str::FromStr,
+    thread::{self, JoinHandle},
+};
+
+use anyhow::{format_err, Context};
+use ffmpeg::{codec, decoder, encoder, format, frame, media, software::scaling, Packet, Rational};
+use human_size::{Byte, Size};
+use log::{info, warn};
+use thiserror::Error;
+
+use crate::supported_formats;
+
+// --history-transcode FILENAME:ENCODER:BITRATE
+#[derive(Debug, Clone, PartialEq)]
+pub struct TranscodeSpec {
+    filename: String,
+    encoder: String,
+    bitrate: Size,
+}
+
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum ParseTranscodeError {
+    #[error("expected FILENAME:ENCODER:BITRATE, for example replay.mp4:libx264:2MB")]
+    Structure,
+    #[error("invalid bitrate")]
+    Bitrate,
+}
+
+impl FromStr for TranscodeSpec {
+    type Err = ParseTranscodeError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        // split from the right, so the filename can contain colons
+        let mut it = s.rsplitn(3, ':');
+        let (Some(bitrate), Some(encoder), Some(filename)) = (it.next(), it.next(), it.next())
+        else {
+            return Err(ParseTranscodeError::Structure);
+        };
+        if filename.is_empty() || encoder.is_empty() {
+            return Err(ParseTranscodeError::Structure);
+        }
+
+        Ok(TranscodeSpec {
+            filename: filename.to_owned(),
+            encoder: encoder.to_owned(),
+            bitrate: bitrate.parse().map_err(|_| ParseTranscodeError::Bitrate)?,
+        })
+    }
+}
+
+impl TranscodeSpec {
+    pub fn check_encoder(&self) -> anyhow::Result<()> {
+        let codec = encoder::find_by_name(&self.encoder)
+            .ok_or_else(|| format_err!("no encoder called {}", self.encoder))?;
+        if codec.medium() != media::Type::Video {
+            return Err(format_err!("{} is not a video encoder", self.encoder));
+        }
+        Ok(())
+    }
+}
+
+// A stream of the main recording, as the history packets were written for it
+pub struct SourceStream {
+    pub parameters: codec::Parameters,
+    pub time_base: Rational,
+}
+
+// The video is decoded in software and encoded again with the clip's encoder, audio is copied.
+// Other streams (--extra-stream, --markers) are left out
+struct Transcoder {
+    spec: TranscodeSpec,
+    octx: format::context::Output,
+    video_in: usize,
+    video_out: usize,
+    source_time_base: Rational,
+    frame_rate: Rational,
+    dec: decoder::Video,
+    // opened on the first decoded frame, once the decoder's pixel format is known
+    enc: Option<(encoder::Video, Option<scaling::Context>)>,
+    audio: Vec<(usize, usize, Rational)>, // (stream in history, stream in clip, time base in history)
+    pts_offset: Vec<i64>,                 // per stream in history, in its time base
+    pending_audio: Vec<Packet>,
+}
+
+impl Transcoder {
+    fn new(
+        spec: TranscodeSpec,
+        streams: &[SourceStream],
+        video_in: usize,
+        frame_rate: Rational,
+        pts_offset_ns: i64,
+    ) -> anyhow::Result<Self> {
+        let mut octx = format::output(&spec.filename)
+            .with_context(|| format!("failed to open {} for writing", spec.filename))?;
+
+        let codec = encoder::find_by_name(&spec.encoder)
+            .ok_or_else(|| format_err!("no encoder called {}", spec.encoder))?;
+        let video_out = octx.add_stream(codec)?.index();
+
+        let mut audio = Vec::new();
+        for (i, st) in streams.iter().enumerate() {
+            if st.parameters.medium() == media::Type::Audio {
+                let mut ost = octx.add_stream(encoder::find(codec::Id::None))?;
+                ost.set_parameters(st.parameters.clone());
+                // the tag belongs to the main recording's container
+                unsafe { (*ost.parameters().as_mut_ptr()).codec_tag = 0 };
+                audio.push((i, ost.index(), st.time_base));
+            }
+        }
+
+        let source = &streams[video_in];
+        let mut dec = codec::Context::from_parameters(source.parameters.clone())?
+            .decoder()
+            .video()
+            .context("failed to open a decoder for the history")?;
+        dec.set_packet_time_base(source.time_base);
+
+        Ok(Transcoder {
+            spec,
+            octx,
+            video_in,
+            video_out,
+            source_time_base: source.time_base,
+            frame_rate,
+            dec,
+            enc: None,
+            audio,
+            pts_offset: streams
+                .iter()
+                .map(|st| {
+                    pts_offset_ns * i64::from(st.time_base.1)
+                        / i64::from(st.time_base.0)
+                        / 1_000_000_000
+                })
+                .collect(),
+            pending_audio: Vec::new(),
+        })
+    }
+
+    fn open_encoder(&mut self, frame: &frame::Video) -> anyhow::Result<()> {
+        let codec = encoder::find_by_name(&self.spec.encoder).unwrap();
+        let formats = supported_formats(&codec);
+        let format = if formats.is_empty() || formats.contains(&frame.format()) {
+            frame.format()
+        } else {
+            formats[0]
+        };
+        let scaler = (format != frame.format())
+            .then(|| {
+                scaling::Context::get(
+                    frame.format(),
+                    frame.width(),
+                    frame.height(),
+                    format,
+                    frame.width(),
+                    frame.height(),
+                    scaling::Flags::BILINEAR,
+                )
+            })
+            .transpose()?;
+
+        let mut enc = codec::Context::new_with_codec(codec).encoder().video()?;
+        enc.set_width(frame.width());
+        enc.set_height(frame.height());
+        enc.set_format(format);
+        enc.set_time_base(self.source_time_base);
+        if self.frame_rate.numerator() != 0 {
+            enc.set_frame_rate(Some(self.frame_rate));
+        }
+        enc.set_bit_rate((self.spec.bitrate.into::<Byte>().value() * 8.) as usize);
+        if self
+            .octx
+            .format()
+            .flags()
+            .contains(format::Flags::GLOBAL_HEADER)
+        {
+            enc.set_flags(codec::Flags::GLOBAL_HEADER);
+        }
+        let enc = enc
+            .open()
+            .with_context(|| format!("failed to open {}", self.spec.encoder))?;
+
+        self.octx
+            .stream_mut(self.video_out)
+            .unwrap()
+            .set_parameters(&enc);
+        self.octx
+            .write_header()
+            .with_context(|| format!("failed to write header of {}", self.spec.filename))?;
+        info!(
+            "transcoding history to {} with {}, {}x{} {:?}",
+            self.spec.filename,
+            self.spec.encoder,
+            frame.width(),
+            frame.height(),
+            format
+        );
+        self.enc = Some((enc, scaler));
+        for packet in mem::take(&mut self.pending_audio) {
+            self.write_audio(packet)?;
+        }
+        Ok(())
+    }
+
+    fn packet(&mut self, mut packet: Packet) -> anyhow::Result<()> {
+        let stream = packet.stream();
+        let offset = self.pts_offset[stream];
+        if packet.pts().unwrap() < offset {
+            return Ok(());
+        }
+        packet.set_pts(packet.pts().map(|pts| pts - offset));
+        packet.set_dts(packet.dts().map(|dts| dts - offset));
+
+        if stream == self.video_in {
+            self.dec.send_packet(&packet)?;
+            self.receive_frames()
+        } else if self.audio.iter().any(|(i, ..)| *i == stream) {
+            if self.enc.is_none() {
+                // the header is only written once the first video frame is decoded
+                self.pending_audio.push(packet);
+                return Ok(());
+            }
+            self.write_audio(packet)
+        } else {
+            Ok(())
+        }
+    }
+
+    fn write_audio(&mut self, mut packet: Packet) -> anyhow::Result<()> {
+        let &(_, out, tb) = self
+            .audio
+            .iter()
+            .find(|(i, ..)| *i == packet.stream())
+            .unwrap();
+        packet.set_stream(out);
+        packet.rescale_ts(tb, self.octx.stream(out).unwrap().time_base());
+        packet.set_position(-1);
+        packet.write_interleaved(&mut self.octx)?;
+        Ok(())
+    }
+
+    fn receive_frames(&mut self) -> anyhow::Result<()> {
+        let mut decoded = frame::Video::empty();
+        while self.dec.receive_frame(&mut decoded).is_ok() {
+            if self.enc.is_none() {
+                self.open_encoder(&decoded)?;
+            }
+            let (enc, scaler) = self.enc.as_mut().unwrap();
+            let pts = decoded.timestamp();
+            match scaler {
+                Some(scaler) => {
+                    let mut converted = frame::Video::empty();
+                    scaler.run(&decoded, &mut converted)?;
+                    converted.set_pts(pts);
+                    enc.send_frame(&converted)?;
+                }
+                None => {
+                    decoded.set_pts(pts);
+                    decoded.set_kind(ffmpeg::picture::Type::None);
+                    enc.send_frame(&decoded)?;
+                }
+            }
+            self.receive_packets()?;
+        }
+        Ok(())
+    }
+
+    fn receive_packets(&mut self) -> anyhow::Result<()> {
+        let Some((enc, _)) = &mut self.enc else {
+            return Ok(());
+        };
+        let mut encoded = Packet::empty();
+        while enc.receive_packet(&mut encoded).is_ok() {
+            encoded.set_stream(self.video_out);
+            encoded.rescale_ts(
+                self.source_time_base,
+                self.octx.stream(self.video_out).unwrap().time_base(),
+            );
+            encoded.write_interleaved(&mut self.octx)?;
+        }
+        Ok(())
+    }
+
+    fn finish(mut self) -> anyhow::Result<()> {
+        self.dec.send_eof()?;
+        self.receive_frames()?;
+        let Some((enc, _)) = &mut self.enc else {
+            return Err(format_err!("no video was decoded from the history"));
+        };
+        enc.send_eof()?;
+        self.receive_packets()?;
+        self.octx.write_trailer()?;
+        Ok(())
+    }
+}
+
+// Re-encodes `packets` (the history that was just flushed, with the main recording's stream
+// indices and time bases) to a separate, smaller clip on a background thread, so capture keeps
+// going while it runs. `pts_offset_ns` is subtracted like it is for the main recording
+pub fn spawn(
+    spec: &TranscodeSpec,
+    streams: Vec<SourceStream>,
+    video_in: usize,
+    frame_rate: Rational,
+    pts_offset_ns: i64,
+    packets: Vec<Packet>,
+) -> Option<JoinHandle<()>> {
+    let spec = spec.clone();
+    let filename = spec.filename.clone();
+    let run = move || -> anyhow::Result<()> {
+        let mut t = Transcoder::new(spec, &streams, video_in, frame_rate, pts_offset_ns)?;
+        for packet in packets {
+            t.packet(packet)?;
+        }
+        t.finish()
+    };
+    let spawned = thread::Builder::new()
+        .name("history-transcode".to_owned())
+        .spawn(move || match run() {
+            Ok(()) => eprintln!("wrote transcoded history to {filename}"),
+            Err(e) => warn!("failed to transcode history to {filename}: {e:?}"),
+        });
+    match spawned {
+        Ok(handle) => Some(handle),
+        Err(e) => {
+            warn!("failed to start transcoding history: {e}");
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use human_size::Size;
+
+    use super::{ParseTranscodeError, TranscodeSpec};
+
+    #[test]
+    fn parse() {
+        let spec: TranscodeSpec = "replay.mp4:libx264:2MB".parse().unwrap();
+        assert_eq!(spec.filename, "replay.mp4");
+        assert_eq!(spec.encoder, "libx264");
+        assert_eq!(spec.bitrate, "2MB".parse::<Size>().unwrap());
+
+        let spec: TranscodeSpec = "a:b.webm:libvpx-vp9:500kB".parse().unwrap();
+        assert_eq!(spec.filename, "a:b.webm");
+        assert_eq!(spec.encoder, "libvpx-vp9");
+
+        assert_eq!(
+            "replay.mp4:2MB".parse::<TranscodeSpec>(),
+            Err(ParseTranscodeError::Structure)
+        );
+        assert_eq!(
+            "replay.mp4::2MB".parse::<TranscodeSpec>(),
+            Err(ParseTranscodeError::Structure)
+        );
+        assert_eq!(
+            "replay.mp4:libx264:fast".parse::<TranscodeSpec>(),
+            Err(ParseTranscodeError::Bitrate)
+        );
+    }
+}
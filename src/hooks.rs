@@ -0,0 +1,50 @@
+use std::process::Command;
+
+use log::{info, warn};
+
+use crate::Args;
+
+// Information about the recording passed to --exec-* hooks as environment variables
+pub struct HookEnv {
+    filename: String,
+    output: String,
+    geometry: String,
+}
+
+impl HookEnv {
+    pub fn new(args: &Args, output: &str) -> Self {
+        HookEnv {
+            filename: args.filename.clone(),
+            output: output.to_string(),
+            geometry: args
+                .geometry
+                .iter()
+                .map(|g| g.to_string())
+                .collect::<Vec<_>>()
+                .join(";"),
+        }
+    }
+}
+
+// runs `cmd` with `sh -c` and waits for it to finish. Failures are only warned about, a broken
+// hook shouldn't stop a recording
+pub fn run(name: &str, cmd: &str, env: &HookEnv, exit_code: Option<i32>) {
+    info!("running {name} hook: {cmd}");
+
+    let mut command = Command::new("sh");
+    command
+        .arg("-c")
+        .arg(cmd)
+        .env("WL_SCREENREC_FILENAME", &env.filename)
+        .env("WL_SCREENREC_OUTPUT", &env.output)
+        .env("WL_SCREENREC_GEOMETRY", &env.geometry);
+    if let Some(code) = exit_code {
+        command.env("WL_SCREENREC_EXIT_CODE", code.to_string());
+    }
+
+    match command.status() {
+        Ok(status) if status.success() => {}
+        Ok(status) => warn!("{name} hook `{cmd}` failed: {status}"),
+        Err(e) => warn!("failed to run {name} hook `{cmd}`: {e}"),
+    }
+}
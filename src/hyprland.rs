@@ -0,0 +1,116 @@
+use std::{
+    env,
+    io::{self, BufRead, BufReader, Read, Write},
+    os::{fd::AsRawFd, unix::net::UnixStream},
+    path::{Path, PathBuf},
+    sync::mpsc::{channel, Receiver},
+};
+
+use anyhow::format_err;
+use log::{debug, trace, warn};
+use serde_json::Value;
+
+use crate::{
+    ipc_watch,
+    window::{hyprland_geometry, WindowGeometry},
+};
+
+// Hyprland doesn't send an event when a window is resized, so the active window is checked this
+// often even when nothing else happens
+pub const RECHECK_MS: i32 = 500;
+
+// where the sockets of the running instance are, see https://wiki.hyprland.org/IPC/
+pub fn socket_dir() -> anyhow::Result<PathBuf> {
+    let sig = env::var_os("HYPRLAND_INSTANCE_SIGNATURE").ok_or_else(|| {
+        format_err!("HYPRLAND_INSTANCE_SIGNATURE is not set, is Hyprland running?")
+    })?;
+    let runtime = env::var_os("XDG_RUNTIME_DIR").map(|d| Path::new(&d).join("hypr").join(&sig));
+    // before 0.40 they were in /tmp
+    [runtime, Some(Path::new("/tmp/hypr").join(&sig))]
+        .into_iter()
+        .flatten()
+        .find(|d| d.join(".socket.sock").exists())
+        .ok_or_else(|| format_err!("Hyprland's sockets weren't found"))
+}
+
+// same as `hyprctl -j <cmd>`
+pub fn request(dir: &Path, cmd: &str) -> anyhow::Result<Value> {
+    let mut stream = UnixStream::connect(dir.join(".socket.sock"))?;
+    stream.write_all(format!("j/{cmd}").as_bytes())?;
+    let mut reply = String::new();
+    stream.read_to_string(&mut reply)?;
+    Ok(serde_json::from_str(&reply)?)
+}
+
+// None if no window has focus, like on an empty workspace
+fn active_window_in(dir: &Path) -> anyhow::Result<Option<WindowGeometry>> {
+    let window = request(dir, "activewindow")?;
+    let monitors = request(dir, "monitors")?;
+    Ok(hyprland_geometry(&window, &monitors))
+}
+
+pub fn active_window() -> anyhow::Result<Option<WindowGeometry>> {
+    active_window_in(&socket_dir()?)
+}
+
+// the socket events are sent on, one per line
+pub fn event_socket(dir: &Path) -> io::Result<BufReader<UnixStream>> {
+    Ok(BufReader::new(UnixStream::connect(
+        dir.join(".socket2.sock"),
+    )?))
+}
+
+// waits up to `timeout_ms` for the next line on the event socket, forever if it's negative
+pub fn wait_event(events: &mut BufReader<UnixStream>, timeout_ms: i32) -> io::Result<()> {
+    if events.buffer().is_empty() {
+        let mut fd = libc::pollfd {
+            fd: events.get_ref().as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        if unsafe { libc::poll(&mut fd, 1, timeout_ms) } <= 0 {
+            return Ok(());
+        }
+    }
+    let mut line = String::new();
+    if events.read_line(&mut line)? == 0 {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    trace!("hyprland event {}", line.trim_end());
+    Ok(())
+}
+
+// follows whichever window has focus, sending its geometry whenever focus changes or the window
+// moves or resizes. While no window has focus the last one is kept. `wake` is called after each
+// change so the event loop notices
+pub fn spawn_active_window_watcher(
+    mut last: Option<WindowGeometry>,
+    wake: impl Fn() + Send + 'static,
+) -> anyhow::Result<Receiver<WindowGeometry>> {
+    let dir = socket_dir()?;
+    let mut events = event_socket(&dir)?;
+
+    let (tx, rx) = channel();
+    ipc_watch::watch(
+        "hyprland-window",
+        move || active_window_in(&dir),
+        move || Ok(wait_event(&mut events, RECHECK_MS)?),
+        move |res| match res {
+            Ok(Some(geometry)) if last.as_ref() != Some(&geometry) => {
+                debug!("active window is now at {geometry:?}");
+                if tx.send(geometry.clone()).is_err() {
+                    return false;
+                }
+                wake();
+                last = Some(geometry);
+                true
+            }
+            Ok(_) => true,
+            Err(e) => {
+                warn!("lost connection to Hyprland, no longer following the active window: {e}");
+                false
+            }
+        },
+    );
+    Ok(rx)
+}
@@ -0,0 +1,162 @@
+use std::time::Duration;
+
+use anyhow::Context;
+use wayland_client::{
+    globals::GlobalList, protocol::wl_seat::WlSeat, Connection, Dispatch, Proxy, QueueHandle,
+};
+use wayland_protocols::ext::idle_notify::v1::client::{
+    ext_idle_notification_v1::{self, ExtIdleNotificationV1},
+    ext_idle_notifier_v1::ExtIdleNotifierV1,
+};
+
+use crate::{CaptureSource, State};
+
+// --pause-after-idle: the recording is idle once there has been no input and no damage (no frames
+// captured) for the timeout. The compositor reports input through ext-idle-notify, but nothing
+// reports the lack of damage, so the event loop polls `IdleTracker::poll` while input is idle
+
+// all times are CLOCK_MONOTONIC ns, like the presentation timestamps of captured frames
+pub struct IdleTracker {
+    timeout: i64,
+    last_input: Option<i64>, // None while there's input
+    last_frame: i64,
+    idle_since: Option<i64>,
+}
+
+impl IdleTracker {
+    pub fn new(timeout: Duration, now: i64) -> Self {
+        IdleTracker {
+            timeout: timeout.as_nanos() as i64,
+            last_input: None,
+            last_frame: now,
+            idle_since: None,
+        }
+    }
+
+    // the compositor says there's been no input for the timeout
+    pub fn input_idle(&mut self, now: i64) {
+        self.last_input = Some(now - self.timeout);
+    }
+
+    // input resumed or a frame was captured. If it was idle, returns for how long
+    pub fn activity(&mut self, now: i64, frame: bool) -> Option<i64> {
+        if frame {
+            self.last_frame = self.last_frame.max(now);
+        } else {
+            self.last_input = None;
+        }
+        Some((now - self.idle_since.take()?).max(0))
+    }
+
+    // true when it has just become idle
+    pub fn poll(&mut self, now: i64) -> bool {
+        let Some(last_input) = self.last_input else {
+            return false;
+        };
+        if self.idle_since.is_some() || now - last_input.max(self.last_frame) < self.timeout {
+            return false;
+        }
+        self.idle_since = Some(now);
+        true
+    }
+}
+
+pub struct IdlePause {
+    _notification: ExtIdleNotificationV1,
+    pub tracker: IdleTracker,
+}
+
+impl IdlePause {
+    pub fn bind<S: CaptureSource + 'static>(
+        gm: &GlobalList,
+        eq: &QueueHandle<State<S>>,
+        timeout: Duration,
+        now: i64,
+    ) -> anyhow::Result<Self> {
+        let notifier: ExtIdleNotifierV1 = gm
+            .bind(eq, 1..=1, ())
+            .context("--pause-after-idle needs a compositor that supports ext-idle-notify-v1")?;
+        let seat: WlSeat = gm
+            .bind(eq, 1..=WlSeat::interface().version, IdleSeat)
+            .context("Your compositor has no wl_seat to watch for input on")?;
+        let timeout_ms = timeout.as_millis().clamp(1, u32::MAX.into()) as u32;
+        Ok(IdlePause {
+            _notification: notifier.get_idle_notification(timeout_ms, &seat, eq, ()),
+            tracker: IdleTracker::new(timeout, now),
+        })
+    }
+}
+
+// distinct from the capture backends' seat user data, so the Dispatch impls don't overlap
+pub struct IdleSeat;
+
+impl<S: CaptureSource> Dispatch<WlSeat, IdleSeat> for State<S> {
+    fn event(
+        _state: &mut Self,
+        _proxy: &WlSeat,
+        _event: <WlSeat as Proxy>::Event,
+        _data: &IdleSeat,
+        _conn: &Connection,
+        _qhandle: &QueueHandle<Self>,
+    ) {
+    }
+}
+
+impl<S: CaptureSource> Dispatch<ExtIdleNotifierV1, ()> for State<S> {
+    fn event(
+        _state: &mut Self,
+        _proxy: &ExtIdleNotifierV1,
+        _event: <ExtIdleNotifierV1 as Proxy>::Event,
+        _data: &(),
+        _conn: &Connection,
+        _qhandle: &QueueHandle<Self>,
+    ) {
+    }
+}
+
+impl<S: CaptureSource> Dispatch<ExtIdleNotificationV1, ()> for State<S> {
+    fn event(
+        state: &mut Self,
+        _proxy: &ExtIdleNotificationV1,
+        event: <ExtIdleNotificationV1 as Proxy>::Event,
+        _data: &(),
+        _conn: &Connection,
+        _qhandle: &QueueHandle<Self>,
+    ) {
+        match event {
+            ext_idle_notification_v1::Event::Idled => state.on_input_idle(),
+            ext_idle_notification_v1::Event::Resumed => state.on_input_resumed(),
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use super::IdleTracker;
+
+    const S: i64 = 1_000_000_000;
+
+    #[test]
+    fn input_and_damage() {
+        let mut t = IdleTracker::new(Duration::from_secs(30), 0);
+        // no input, but frames kept coming until 20s
+        assert_eq!(t.activity(20 * S, true), None);
+        t.input_idle(30 * S);
+        assert!(!t.poll(30 * S));
+        assert!(!t.poll(49 * S));
+        assert!(t.poll(50 * S));
+        assert!(!t.poll(51 * S));
+
+        // a frame ends it, cutting out the time since it became idle
+        assert_eq!(t.activity(80 * S, true), Some(30 * S));
+        assert!(!t.poll(109 * S)); // input still idle, but the frame was recent
+        assert!(t.poll(110 * S));
+
+        // input resuming ends it too
+        assert_eq!(t.activity(115 * S, false), Some(5 * S));
+        assert!(!t.poll(200 * S));
+    }
+}
@@ -0,0 +1,26 @@
+use std::thread;
+
+// the loop behind everything followed over the compositor's IPC (--follow-focus, --follow-window,
+// --workspace, ...). Events only say that something changed, so `query` asks what it is now after
+// every event `wait` returns, and `update` is given the answer. The thread ends when `update`
+// returns false, and after it was given an error, as the connection is gone then
+pub fn watch<T>(
+    name: &str,
+    mut query: impl FnMut() -> anyhow::Result<T> + Send + 'static,
+    mut wait: impl FnMut() -> anyhow::Result<()> + Send + 'static,
+    mut update: impl FnMut(anyhow::Result<T>) -> bool + Send + 'static,
+) {
+    thread::Builder::new()
+        .name(name.to_owned())
+        .spawn(move || {
+            let mut res = query();
+            loop {
+                let failed = res.is_err();
+                if !update(res) || failed {
+                    return;
+                }
+                res = wait().and_then(|()| query());
+            }
+        })
+        .unwrap();
+}
@@ -0,0 +1,300 @@
+use std::{
+    env,
+    fs::{self, OpenOptions},
+    io,
+    path::{Path, PathBuf},
+    process, ptr,
+    time::{Duration, Instant},
+};
+
+use anyhow::{bail, Context};
+use ffmpeg::{
+    codec, encoder,
+    ffi::{av_write_frame, avio_flush, avio_seek},
+    format,
+};
+use log::{debug, info, warn};
+use serde_json::{json, Value};
+
+// Every recording keeps a small journal in $XDG_STATE_HOME/wl-screenrec with the file it's writing
+// and how much of it is known to be complete, and removes it when it finishes cleanly. A journal
+// that outlives its process means the recording was interrupted, and `wl-screenrec recover` or
+// --resume can pick it up from there
+
+// the muxer is flushed and the journal rewritten on a keyframe, at most this often
+const UPDATE_INTERVAL: Duration = Duration::from_secs(1);
+
+fn dir() -> Option<PathBuf> {
+    let state = match env::var_os("XDG_STATE_HOME").filter(|d| !d.is_empty()) {
+        Some(d) => PathBuf::from(d),
+        None => PathBuf::from(env::var_os("HOME")?).join(".local/state"),
+    };
+    Some(state.join("wl-screenrec"))
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Entry {
+    pub pid: u32,
+    pub template: String, // --filename as it was passed, before strftime expansion
+    pub filename: String,
+    pub flushed_bytes: u64, // everything before this was flushed at a keyframe
+}
+
+impl Entry {
+    fn to_json(&self) -> String {
+        json!({
+            "pid": self.pid,
+            "template": self.template,
+            "filename": self.filename,
+            "flushed_bytes": self.flushed_bytes,
+        })
+        .to_string()
+    }
+
+    fn parse(s: &str) -> Option<Entry> {
+        let v: Value = serde_json::from_str(s).ok()?;
+        Some(Entry {
+            pid: v["pid"].as_u64()?.try_into().ok()?,
+            template: v["template"].as_str()?.to_owned(),
+            filename: v["filename"].as_str()?.to_owned(),
+            flushed_bytes: v["flushed_bytes"].as_u64()?,
+        })
+    }
+
+    // cuts off whatever was written after the last flush, it can end in the middle of a packet
+    fn truncate(&self) -> io::Result<()> {
+        let file = OpenOptions::new().write(true).open(&self.filename)?;
+        if file.metadata()?.len() > self.flushed_bytes {
+            file.set_len(self.flushed_bytes)?;
+        }
+        Ok(())
+    }
+}
+
+// flushes what the muxer has buffered (mkv ends the cluster, fragmented mp4 the fragment) and
+// returns the position in the file after it
+pub fn flush(octx: &mut format::context::Output) -> Option<u64> {
+    unsafe {
+        let ctx = octx.as_mut_ptr();
+        av_write_frame(ctx, ptr::null_mut());
+        let pb = (*ctx).pb;
+        if pb.is_null() {
+            return None;
+        }
+        avio_flush(pb);
+        u64::try_from(avio_seek(pb, 0, libc::SEEK_CUR)).ok()
+    }
+}
+
+pub struct Journal {
+    path: PathBuf,
+    entry: Entry,
+    last_update: Instant,
+}
+
+impl Journal {
+    // None if there's no state directory or it can't be written, recording goes on without one
+    pub fn create(template: &str, filename: &str, flushed_bytes: u64) -> Option<Journal> {
+        let dir = dir()?;
+        if let Err(e) = fs::create_dir_all(&dir) {
+            warn!(
+                "failed to create {}, not journaling the recording: {e}",
+                dir.display()
+            );
+            return None;
+        }
+        let mut journal = Journal {
+            path: dir.join(format!("{}.json", process::id())),
+            entry: Entry {
+                pid: process::id(),
+                template: template.to_owned(),
+                filename: filename.to_owned(),
+                flushed_bytes,
+            },
+            last_update: Instant::now(),
+        };
+        journal.write();
+        Some(journal)
+    }
+
+    pub fn due(&self) -> bool {
+        self.last_update.elapsed() >= UPDATE_INTERVAL
+    }
+
+    // the recording continues in another file, it's journaled straight away
+    pub fn set_filename(&mut self, filename: &str) {
+        self.entry.filename = filename.to_owned();
+        self.entry.flushed_bytes = 0;
+        self.write();
+    }
+
+    pub fn update(&mut self, flushed_bytes: u64) {
+        self.entry.flushed_bytes = flushed_bytes;
+        self.last_update = Instant::now();
+        self.write();
+    }
+
+    fn write(&self) {
+        let tmp = self.path.with_extension("tmp");
+        let res = fs::write(&tmp, self.entry.to_json()).and_then(|()| fs::rename(&tmp, &self.path));
+        if let Err(e) = res {
+            warn!("failed to update {}: {e}", self.path.display());
+        }
+    }
+
+    // the recording was finalized
+    pub fn finish(self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+fn alive(pid: u32) -> bool {
+    let ret = unsafe { libc::kill(pid as libc::pid_t, 0) };
+    // EPERM means it's still there, it's just someone else's
+    ret == 0 || io::Error::last_os_error().raw_os_error() != Some(libc::ESRCH)
+}
+
+// journals left behind by recordings that didn't finish, newest first
+pub fn interrupted() -> Vec<(PathBuf, Entry)> {
+    let Some(Ok(dir)) = dir().map(fs::read_dir) else {
+        return Vec::new();
+    };
+    let mut found: Vec<_> = dir
+        .flatten()
+        .filter(|e| e.path().extension().is_some_and(|ext| ext == "json"))
+        .filter_map(|e| {
+            let entry = Entry::parse(&fs::read_to_string(e.path()).ok()?)?;
+            let modified = e.metadata().and_then(|m| m.modified()).ok()?;
+            Some((modified, e.path(), entry))
+        })
+        .filter(|(_, _, entry)| !alive(entry.pid))
+        .collect();
+    found.sort_by(|a, b| b.0.cmp(&a.0));
+    found
+        .into_iter()
+        .map(|(_, path, entry)| (path, entry))
+        .collect()
+}
+
+// --resume: the interrupted recording of `template` is cut back to its last flush, to be appended
+// to. Returns the file to continue
+pub fn resume(template: &str) -> Option<String> {
+    let (path, entry) = interrupted()
+        .into_iter()
+        .find(|(_, e)| e.template == template)?;
+    let _ = fs::remove_file(path);
+    if let Err(e) = entry.truncate() {
+        warn!("can't resume {}: {e}", entry.filename);
+        return None;
+    }
+    info!(
+        "resuming {}, from byte {}",
+        entry.filename, entry.flushed_bytes
+    );
+    Some(entry.filename)
+}
+
+// where `finalize` writes the copy of `filename`, keeping its extension so the container is the same
+fn recovering_path(filename: &str) -> PathBuf {
+    let path = Path::new(filename);
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let name = match path.extension() {
+        Some(ext) => format!(".{stem}.recovering.{}", ext.to_string_lossy()),
+        None => format!(".{stem}.recovering"),
+    };
+    path.with_file_name(name)
+}
+
+// copies the complete part of an interrupted recording into a new file, which writes the index
+// and duration that were never written, and puts it in the original's place. The original is left
+// as it was if anything fails
+fn finalize(entry: &Entry) -> anyhow::Result<()> {
+    let mut ictx = format::input(&entry.filename).with_context(|| {
+        format!(
+            "failed to read {}. An interrupted mp4 can only be recovered if it was fragmented (--ffmpeg-muxer-options movflags=frag_keyframe+empty_moov), mkv always can",
+            entry.filename
+        )
+    })?;
+    let recovering = recovering_path(&entry.filename);
+    let res = copy_flushed(entry, &mut ictx, &recovering).and_then(|()| {
+        fs::rename(&recovering, &entry.filename)
+            .with_context(|| format!("failed to replace {}", entry.filename))
+    });
+    if res.is_err() {
+        let _ = fs::remove_file(&recovering);
+    }
+    res
+}
+
+fn copy_flushed(entry: &Entry, ictx: &mut format::context::Input, to: &Path) -> anyhow::Result<()> {
+    let mut octx = format::output(&to)
+        .with_context(|| format!("failed to open {} for writing", to.display()))?;
+    for ist in ictx.streams() {
+        let mut ost = octx.add_stream(encoder::find(codec::Id::None))?;
+        ost.set_parameters(ist.parameters());
+        ost.set_metadata(ist.metadata().to_owned());
+        unsafe { (*ost.parameters().as_mut_ptr()).codec_tag = 0 };
+    }
+    octx.write_header()?;
+
+    let mut packets = 0;
+    for (ist, mut packet) in ictx.packets() {
+        // whatever was written after the last flush can end in the middle of a packet
+        let end = packet.position() + packet.size() as i64;
+        if packet.position() >= 0 && end > entry.flushed_bytes as i64 {
+            continue;
+        }
+        let ost = octx.stream(ist.index()).unwrap();
+        packet.rescale_ts(ist.time_base(), ost.time_base());
+        packet.set_position(-1);
+        packet.write_interleaved(&mut octx)?;
+        packets += 1;
+    }
+    if packets == 0 {
+        bail!("nothing in {} could be recovered", entry.filename);
+    }
+    octx.write_trailer()?;
+    debug!("copied {packets} packets from {}", entry.filename);
+    Ok(())
+}
+
+// `wl-screenrec recover`
+pub fn recover_all() -> bool {
+    let found = interrupted();
+    if found.is_empty() {
+        eprintln!("no interrupted recordings found");
+        return true;
+    }
+    let mut ok = true;
+    for (path, entry) in found {
+        if !Path::new(&entry.filename).exists() {
+            eprintln!("{} no longer exists, forgetting it", entry.filename);
+        } else if let Err(e) = finalize(&entry) {
+            eprintln!("failed to recover {}: {e:?}", entry.filename);
+            ok = false;
+            continue;
+        } else {
+            eprintln!("recovered {}", entry.filename);
+        }
+        let _ = fs::remove_file(path);
+    }
+    ok
+}
+
+#[cfg(test)]
+mod test {
+    use super::Entry;
+
+    #[test]
+    fn round_trip() {
+        let entry = Entry {
+            pid: 1234,
+            template: "rec-%H%M.mkv".to_owned(),
+            filename: "rec-1200.mkv".to_owned(),
+            flushed_bytes: 1 << 33,
+        };
+        assert_eq!(Entry::parse(&entry.to_json()), Some(entry));
+        assert_eq!(Entry::parse(r#"{"pid": 1234}"#), None);
+        assert_eq!(Entry::parse("not json"), None);
+    }
+}
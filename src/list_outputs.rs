@@ -0,0 +1,313 @@
+use std::path::PathBuf;
+
+use anyhow::Context;
+use drm::node::DrmNode;
+use libc::dev_t;
+use serde_json::{json, Value};
+use wayland_client::{
+    globals::{registry_queue_init, GlobalListContents},
+    protocol::{
+        wl_output::{self, Transform, WlOutput},
+        wl_registry::WlRegistry,
+    },
+    Connection, Dispatch, Proxy, QueueHandle, WEnum,
+};
+use wayland_protocols::{
+    ext::{
+        image_capture_source::v1::client::ext_output_image_capture_source_manager_v1::ExtOutputImageCaptureSourceManagerV1,
+        image_copy_capture::v1::client::ext_image_copy_capture_manager_v1::ExtImageCopyCaptureManagerV1,
+    },
+    wp::linux_dmabuf::zv1::client::{
+        zwp_linux_dmabuf_feedback_v1::{self, ZwpLinuxDmabufFeedbackV1},
+        zwp_linux_dmabuf_v1::ZwpLinuxDmabufV1,
+    },
+};
+use wayland_protocols_wlr::screencopy::v1::client::zwlr_screencopy_manager_v1::ZwlrScreencopyManagerV1;
+
+// `wl-screenrec list-outputs`: what can be passed to --output, and whether it can be captured. The
+// capture protocols are global, so they're the same for every output, but listing them on each
+// line makes the output easy to filter
+
+#[derive(Default)]
+struct OutputInfo {
+    name: Option<String>,
+    description: Option<String>,
+    mode: Option<(i32, i32, i32)>, // width, height, refresh in mHz
+    scale: i32,
+    transform: Option<Transform>,
+}
+
+#[derive(Default)]
+struct Probe {
+    outputs: Vec<OutputInfo>,
+    dmabuf_device: Option<PathBuf>,
+}
+
+impl Dispatch<WlRegistry, GlobalListContents> for Probe {
+    fn event(
+        _state: &mut Self,
+        _proxy: &WlRegistry,
+        _event: <WlRegistry as Proxy>::Event,
+        _data: &GlobalListContents,
+        _conn: &Connection,
+        _qhandle: &QueueHandle<Self>,
+    ) {
+    }
+}
+
+impl Dispatch<WlOutput, usize> for Probe {
+    fn event(
+        state: &mut Self,
+        _proxy: &WlOutput,
+        event: <WlOutput as Proxy>::Event,
+        idx: &usize,
+        _conn: &Connection,
+        _qhandle: &QueueHandle<Self>,
+    ) {
+        let info = &mut state.outputs[*idx];
+        match event {
+            wl_output::Event::Name { name } => info.name = Some(name),
+            wl_output::Event::Description { description } => info.description = Some(description),
+            wl_output::Event::Scale { factor } => info.scale = factor,
+            wl_output::Event::Geometry {
+                transform: WEnum::Value(transform),
+                ..
+            } => info.transform = Some(transform),
+            wl_output::Event::Mode {
+                flags: WEnum::Value(flags),
+                width,
+                height,
+                refresh,
+            } if flags.contains(wl_output::Mode::Current) => {
+                info.mode = Some((width, height, refresh));
+            }
+            _ => {}
+        }
+    }
+}
+
+impl Dispatch<ZwpLinuxDmabufV1, ()> for Probe {
+    fn event(
+        _state: &mut Self,
+        _proxy: &ZwpLinuxDmabufV1,
+        _event: <ZwpLinuxDmabufV1 as Proxy>::Event,
+        _data: &(),
+        _conn: &Connection,
+        _qhandle: &QueueHandle<Self>,
+    ) {
+    }
+}
+
+impl Dispatch<ZwpLinuxDmabufFeedbackV1, ()> for Probe {
+    fn event(
+        state: &mut Self,
+        _proxy: &ZwpLinuxDmabufFeedbackV1,
+        event: <ZwpLinuxDmabufFeedbackV1 as Proxy>::Event,
+        _data: &(),
+        _conn: &Connection,
+        _qhandle: &QueueHandle<Self>,
+    ) {
+        if let zwp_linux_dmabuf_feedback_v1::Event::MainDevice { device } = event {
+            let Ok(dev) = device.try_into().map(dev_t::from_ne_bytes) else {
+                return;
+            };
+            state.dmabuf_device = DrmNode::from_dev_id(dev)
+                .ok()
+                .and_then(|node| node.node_with_type(drm::node::NodeType::Render)?.ok())
+                .and_then(|node| node.dev_path());
+        }
+    }
+}
+
+fn transform_name(transform: Transform) -> &'static str {
+    match transform {
+        Transform::Normal => "normal",
+        Transform::_90 => "90",
+        Transform::_180 => "180",
+        Transform::_270 => "270",
+        Transform::Flipped => "flipped",
+        Transform::Flipped90 => "flipped-90",
+        Transform::Flipped180 => "flipped-180",
+        Transform::Flipped270 => "flipped-270",
+        _ => "unknown",
+    }
+}
+
+struct Capture {
+    screencopy: Option<u32>, // version
+    ext_image_copy: bool,
+    dmabuf_device: Option<PathBuf>,
+}
+
+fn to_json(outputs: &[OutputInfo], capture: &Capture) -> Value {
+    let outputs: Vec<_> = outputs
+        .iter()
+        .map(|o| {
+            json!({
+                "name": o.name,
+                "description": o.description,
+                "width": o.mode.map(|m| m.0),
+                "height": o.mode.map(|m| m.1),
+                "refresh": o.mode.map(|m| f64::from(m.2) / 1000.),
+                "scale": o.scale,
+                "transform": o.transform.map(transform_name),
+                "wlr_screencopy_version": capture.screencopy,
+                "ext_image_copy_capture": capture.ext_image_copy,
+                "dmabuf_device": capture.dmabuf_device.as_ref().map(|d| d.display().to_string()),
+            })
+        })
+        .collect();
+    Value::Array(outputs)
+}
+
+fn to_table(outputs: &[OutputInfo], capture: &Capture) -> String {
+    let mut rows = vec![[
+        "NAME".to_owned(),
+        "MODE".to_owned(),
+        "SCALE".to_owned(),
+        "TRANSFORM".to_owned(),
+        "CAPTURE".to_owned(),
+        "DMABUF DEVICE".to_owned(),
+    ]];
+    let mut protocols = Vec::new();
+    if let Some(v) = capture.screencopy {
+        protocols.push(format!("wlr-screencopy v{v}"));
+    }
+    if capture.ext_image_copy {
+        protocols.push("ext-image-copy".to_owned());
+    }
+    let protocols = if protocols.is_empty() {
+        "none".to_owned()
+    } else {
+        protocols.join(", ")
+    };
+    let device = capture
+        .dmabuf_device
+        .as_ref()
+        .map_or("unknown".to_owned(), |d| d.display().to_string());
+    for o in outputs {
+        rows.push([
+            o.name.clone().unwrap_or_else(|| "?".to_owned()),
+            o.mode.map_or("?".to_owned(), |(w, h, mhz)| {
+                format!("{w}x{h}@{:.2}", f64::from(mhz) / 1000.)
+            }),
+            o.scale.to_string(),
+            o.transform.map_or("?", transform_name).to_owned(),
+            protocols.clone(),
+            device.clone(),
+        ]);
+    }
+
+    let widths: Vec<_> = (0..rows[0].len())
+        .map(|col| rows.iter().map(|r| r[col].len()).max().unwrap_or(0))
+        .collect();
+    let mut table = String::new();
+    for row in rows {
+        let line: Vec<_> = row
+            .iter()
+            .zip(&widths)
+            .map(|(cell, w)| format!("{cell:w$}"))
+            .collect();
+        table += line.join("  ").trim_end();
+        table += "\n";
+    }
+    table
+}
+
+fn probe() -> anyhow::Result<(Vec<OutputInfo>, Capture)> {
+    let conn = Connection::connect_to_env().context("failed to connect to the compositor")?;
+    let (gm, mut queue) = registry_queue_init::<Probe>(&conn)?;
+    let eq = queue.handle();
+    let mut probe = Probe::default();
+
+    let globals = gm.contents().clone_list();
+    let version_of = |interface: &str| {
+        globals
+            .iter()
+            .find(|g| g.interface == interface)
+            .map(|g| g.version)
+    };
+    let capture = Capture {
+        screencopy: version_of(ZwlrScreencopyManagerV1::interface().name),
+        ext_image_copy: version_of(ExtImageCopyCaptureManagerV1::interface().name).is_some()
+            && version_of(ExtOutputImageCaptureSourceManagerV1::interface().name).is_some(),
+        dmabuf_device: None,
+    };
+
+    for g in globals
+        .iter()
+        .filter(|g| g.interface == WlOutput::interface().name)
+    {
+        let idx = probe.outputs.len();
+        probe.outputs.push(OutputInfo {
+            scale: 1,
+            ..Default::default()
+        });
+        // the name event is from version 4
+        let _: WlOutput = gm.registry().bind(g.name, g.version.min(4), &eq, idx);
+    }
+    if let Ok(dma) = gm.bind::<ZwpLinuxDmabufV1, _, _>(&eq, 4..=4, ()) {
+        dma.get_default_feedback(&eq, ());
+    }
+    queue.roundtrip(&mut probe)?;
+    queue.roundtrip(&mut probe)?;
+
+    let capture = Capture {
+        dmabuf_device: probe.dmabuf_device.take(),
+        ..capture
+    };
+    Ok((probe.outputs, capture))
+}
+
+pub fn run(json: bool, names: bool) -> anyhow::Result<()> {
+    let (outputs, capture) = probe()?;
+    if names {
+        // used by the shell completions for --output, so keep it to one bare name per line
+        for name in outputs.iter().filter_map(|o| o.name.as_ref()) {
+            println!("{name}");
+        }
+    } else if json {
+        println!("{}", to_json(&outputs, &capture));
+    } else {
+        print!("{}", to_table(&outputs, &capture));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use wayland_client::protocol::wl_output::Transform;
+
+    use super::{to_table, Capture, OutputInfo};
+
+    #[test]
+    fn table() {
+        let outputs = [
+            OutputInfo {
+                name: Some("eDP-1".to_owned()),
+                description: None,
+                mode: Some((2560, 1600, 165000)),
+                scale: 2,
+                transform: Some(Transform::Normal),
+            },
+            OutputInfo {
+                name: Some("HDMI-A-1".to_owned()),
+                description: None,
+                mode: None,
+                scale: 1,
+                transform: Some(Transform::_90),
+            },
+        ];
+        let capture = Capture {
+            screencopy: Some(3),
+            ext_image_copy: false,
+            dmabuf_device: Some("/dev/dri/renderD128".into()),
+        };
+        assert_eq!(
+            to_table(&outputs, &capture),
+            "NAME      MODE              SCALE  TRANSFORM  CAPTURE            DMABUF DEVICE\n\
+             eDP-1     2560x1600@165.00  2      normal     wlr-screencopy v3  /dev/dri/renderD128\n\
+             HDMI-A-1  ?                 1      90         wlr-screencopy v3  /dev/dri/renderD128\n"
+        );
+    }
+}
@@ -0,0 +1,168 @@
+use std::str::FromStr;
+
+use simplelog::{ColorChoice, ConfigBuilder, LevelFilter, SharedLogger, TermLogger, TerminalMode};
+use thiserror::Error;
+
+// log targets for main.rs, where capture and encoding share a module
+pub const WAYLAND: &str = "wl_screenrec::wayland";
+pub const ENCODE: &str = "wl_screenrec::encode";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Subsystem {
+    Wayland,
+    Encode,
+    Audio,
+}
+
+impl Subsystem {
+    const ALL: [Subsystem; 3] = [Subsystem::Wayland, Subsystem::Encode, Subsystem::Audio];
+
+    fn name(self) -> &'static str {
+        match self {
+            Subsystem::Wayland => "wayland",
+            Subsystem::Encode => "encode",
+            Subsystem::Audio => "audio",
+        }
+    }
+
+    // log targets are matched by prefix
+    fn targets(self) -> &'static [&'static str] {
+        match self {
+            Subsystem::Wayland => &[
+                WAYLAND,
+                "wayland_client",
+                "wayland_backend",
+                "wl_screenrec::cap_",
+                "wl_screenrec::output_management",
+            ],
+            Subsystem::Encode => &[
+                ENCODE, // also encode_log
+                "wl_screenrec::avhw",
+                "wl_screenrec::rendition",
+                "wl_screenrec::history_transcode",
+                "wl_screenrec::screenshot",
+            ],
+            Subsystem::Audio => &["wl_screenrec::audio"], // also audio_levels
+        }
+    }
+}
+
+// --log, for example `info,wayland=debug,audio=trace`. A level on its own is for everything not
+// named, which is otherwise given by -v
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct LogSpec {
+    default: Option<LevelFilter>,
+    subsystems: Vec<(Subsystem, LevelFilter)>,
+}
+
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum ParseLogSpecError {
+    #[error("unknown log level {0}, expected off, error, warn, info, debug or trace")]
+    Level(String),
+    #[error("unknown subsystem {0}, expected wayland, encode or audio")]
+    Subsystem(String),
+}
+
+fn parse_level(s: &str) -> Result<LevelFilter, ParseLogSpecError> {
+    s.parse()
+        .map_err(|_| ParseLogSpecError::Level(s.to_owned()))
+}
+
+impl FromStr for LogSpec {
+    type Err = ParseLogSpecError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut spec = LogSpec::default();
+        for entry in s.split(',').filter(|e| !e.is_empty()) {
+            let Some((name, level)) = entry.split_once('=') else {
+                spec.default = Some(parse_level(entry)?);
+                continue;
+            };
+            let subsystem = Subsystem::ALL
+                .into_iter()
+                .find(|s| s.name() == name)
+                .ok_or_else(|| ParseLogSpecError::Subsystem(name.to_owned()))?;
+            let level = parse_level(level)?;
+            // later entries win
+            spec.subsystems.retain(|(s, _)| *s != subsystem);
+            spec.subsystems.push((subsystem, level));
+        }
+        Ok(spec)
+    }
+}
+
+impl LogSpec {
+    pub fn level(&self, subsystem: Subsystem, verbose: u8) -> LevelFilter {
+        self.subsystems
+            .iter()
+            .find(|(s, _)| *s == subsystem)
+            .map_or_else(|| self.default_level(verbose), |(_, l)| *l)
+    }
+
+    fn default_level(&self, verbose: u8) -> LevelFilter {
+        self.default.unwrap_or(match verbose {
+            0 => LevelFilter::Warn,
+            1 => LevelFilter::Info,
+            2 => LevelFilter::Debug,
+            3.. => LevelFilter::Trace,
+        })
+    }
+
+    // one logger for each subsystem with its own level, and one for everything else
+    pub fn loggers(&self, verbose: u8) -> Vec<Box<dyn SharedLogger>> {
+        let mut rest = ConfigBuilder::new();
+        let mut loggers: Vec<Box<dyn SharedLogger>> = Vec::new();
+        for &(subsystem, level) in &self.subsystems {
+            let mut config = ConfigBuilder::new();
+            for target in subsystem.targets() {
+                config.add_filter_allow_str(target);
+                rest.add_filter_ignore_str(target);
+            }
+            loggers.push(TermLogger::new(
+                level,
+                config.build(),
+                TerminalMode::Stderr,
+                ColorChoice::Auto,
+            ));
+        }
+        loggers.push(TermLogger::new(
+            self.default_level(verbose),
+            rest.build(),
+            TerminalMode::Stderr,
+            ColorChoice::Auto,
+        ));
+        loggers
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use simplelog::LevelFilter;
+
+    use super::{LogSpec, ParseLogSpecError, Subsystem};
+
+    #[test]
+    fn parse() {
+        let spec: LogSpec = "wayland=debug,encode=info,audio=trace".parse().unwrap();
+        assert_eq!(spec.level(Subsystem::Wayland, 0), LevelFilter::Debug);
+        assert_eq!(spec.level(Subsystem::Encode, 3), LevelFilter::Info);
+        assert_eq!(spec.level(Subsystem::Audio, 0), LevelFilter::Trace);
+
+        let spec: LogSpec = "encode=off,info,encode=warn".parse().unwrap();
+        assert_eq!(spec.level(Subsystem::Encode, 0), LevelFilter::Warn);
+        assert_eq!(spec.level(Subsystem::Wayland, 0), LevelFilter::Info);
+        assert_eq!(
+            LogSpec::default().level(Subsystem::Audio, 2),
+            LevelFilter::Debug
+        );
+
+        assert_eq!(
+            "video=debug".parse::<LogSpec>(),
+            Err(ParseLogSpecError::Subsystem("video".to_owned()))
+        );
+        assert_eq!(
+            "wayland=loud".parse::<LogSpec>(),
+            Err(ParseLogSpecError::Level("loud".to_owned()))
+        );
+    }
+}
@@ -1,60 +1,105 @@
 extern crate ffmpeg_next as ffmpeg;
 
 use std::{
-    collections::{HashMap, VecDeque},
-    ffi::{c_int, CStr, CString},
-    fmt,
+    collections::HashMap,
+    ffi::{c_int, CStr, CString, OsString},
+    fmt, fs,
     hash::Hash,
     io,
+    iter::once,
     marker::PhantomData,
-    mem::{self, swap},
+    mem,
+    net::SocketAddr,
     num::ParseIntError,
     os::fd::BorrowedFd,
-    path::Path,
+    path::{Path, PathBuf},
     process::exit,
-    ptr::null_mut,
+    ptr::{null, null_mut},
+    slice,
     str::from_utf8_unchecked,
     sync::{
         atomic::{
             AtomicBool, AtomicU64, AtomicUsize,
             Ordering::{self, SeqCst},
         },
-        Arc,
+        Arc, Mutex,
     },
-    thread::{self, sleep},
-    time::Duration,
+    thread::{self, sleep, JoinHandle},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
 use anyhow::{bail, format_err, Context};
 use audio::AudioHandle;
 use cap_ext_image_copy::CapExtImageCopy;
 use cap_wlr_screencopy::CapWlrScreencopy;
+use capture_error::CaptureErrorPolicy;
 use clap::{command, ArgAction, CommandFactory, Parser};
+use composite::Composite;
+use container::Support;
+use control::{ControlCommand, ControlServer};
+use cursor_log::CursorLog;
+use cursor_overlay::{CursorImage, CursorOverlay};
+use dedupe::FrameDedupe;
+use disposition::TrackDisposition;
 use drm::buffer::DrmFourcc;
+use encode_log::{EncodeLog, PacketStats};
+use exclude::Exclusions;
 use ffmpeg::{
     codec, dict, dictionary, encoder,
     ffi::{
         av_buffer_ref, av_buffersrc_parameters_alloc, av_buffersrc_parameters_set,
         av_dict_parse_string, av_free, av_get_pix_fmt_name, av_hwframe_map, avcodec_alloc_context3,
-        avfilter_graph_alloc_filter, avfilter_init_dict, avformat_query_codec,
-        AVDRMFrameDescriptor, AVPixelFormat, AV_HWFRAME_MAP_WRITE, FF_COMPLIANCE_STRICT,
+        avfilter_graph_alloc_filter, avfilter_init_dict, avformat_alloc_output_context2,
+        avformat_free_context, avformat_init_output, avio_open2, AVDRMFrameDescriptor,
+        AVPixelFormat, AVIO_FLAG_WRITE, AV_HWFRAME_MAP_WRITE,
     },
     filter,
     format::{self, Output, Pixel},
     frame::{self, video},
-    media, Packet, Rational,
+    media, picture, Packet, Rational,
 };
+use finish::Finisher;
+use fps_limit::{FpsLimit, FpsLimitMode};
+use frame_log::FrameLog;
+use frame_timing::{presentation_ns, FrameTiming};
+use geometry::Geometry;
+use highlights::Highlights;
+use history_buffer::HistoryBuffer;
+use history_transcode::{SourceStream, TranscodeSpec};
+use hooks::HookEnv;
 use human_size::{Byte, Megabyte, Size, SpecificSize};
-use log::{debug, error, info, trace, warn};
-use signal_hook::consts::{SIGHUP, SIGINT, SIGTERM, SIGUSR1};
-use simplelog::{ColorChoice, CombinedLogger, LevelFilter, TermLogger, TerminalMode};
+use idle::IdlePause;
+use journal::Journal;
+use log::{debug, error, info, log_enabled, trace, warn, Level};
+use log_spec::{LogSpec, Subsystem};
+use metrics::Metrics;
+use motion::MotionEstimate;
+use multi_output::OtherOutput;
+use output_management::OutputManagement;
+use pause::{PauseReason, Pauses};
+use poster::Poster;
+use power::{BatteryOverrides, BatteryPolicy};
+use rendition::{ProxySpec, Rendition, RenditionSource, RenditionSpec};
+use schedule::{next_boundary, strftime_local, Schedule, TimeOfDay};
+use serde_json::json;
+use signal_hook::consts::{SIGHUP, SIGINT, SIGTERM, SIGUSR1, SIGUSR2};
+use simplelog::{CombinedLogger, LevelFilter};
+use splice::{SpliceCue, SpliceTrack};
+use stats::PipelineStats;
+use systemd::SdNotify;
+use text_overlay::TextOverlay;
+use text_track::TextTrack;
 use thiserror::Error;
+use timer::Timer;
 use transform::{transpose_if_transform_transposed, Rect};
+use trim::Trim;
+use upload::Destination;
 use wayland_client::{
     backend::ObjectId,
     globals::{registry_queue_init, Global, GlobalList, GlobalListContents},
     protocol::{
         wl_buffer::WlBuffer,
+        wl_callback::WlCallback,
         wl_output::{self, Mode, Transform, WlOutput},
         wl_registry::WlRegistry,
     },
@@ -70,53 +115,196 @@ use wayland_protocols::{
         zxdg_output_v1::{self, ZxdgOutputV1},
     },
 };
+use window::WindowGeometry;
 
 mod avhw;
-use avhw::{AvHwDevCtx, AvHwFrameCtx};
+mod avoptions;
+use avhw::{AvHwDevCtx, AvHwFrameCtx, HwBackend, Usage};
 
+mod app_focus;
+mod attachment;
 mod audio;
+mod audio_devices;
+mod audio_levels;
+mod audio_stats;
 mod cap_ext_image_copy;
 mod cap_wlr_screencopy;
+mod capture_error;
+mod completions;
+mod composite;
+mod container;
+mod control;
+mod cursor_log;
+mod cursor_overlay;
+mod dedupe;
+mod disposition;
+mod encode_log;
+mod encoder_options;
+mod exclude;
 mod fifo;
+mod finish;
+mod focus;
+mod fps_limit;
+mod frame_log;
+mod frame_timing;
+mod geometry;
+mod highlights;
+mod history;
+mod history_buffer;
+mod history_transcode;
+mod hooks;
+mod hyprland;
+mod idle;
+mod ipc_watch;
+mod journal;
+mod list_outputs;
+mod log_spec;
+mod metrics;
+mod motion;
+mod multi_output;
+mod output_list;
+mod output_management;
+mod overlay_surface;
+mod pacing;
+mod pause;
+mod poster;
+mod power;
+mod render_node;
+mod rendition;
+mod roi;
+#[cfg(feature = "s3")]
+mod s3;
+mod schedule;
+mod screenshot;
+mod session_lock;
+mod shutdown;
+mod splice;
+mod stats;
+mod sway;
+mod systemd;
+mod test_pattern;
+mod text_overlay;
+mod text_track;
+mod timer;
 mod transform;
+mod trim;
+mod upload;
+mod vainfo;
+mod version;
+mod window;
+mod workspace;
 
 #[cfg(target_os = "linux")]
 mod platform {
     pub const DEFAULT_AUDIO_CAPTURE_DEVICE: &str = "default";
     pub const AUDIO_DEVICE_HELP: &str =
-        "which audio device to record from. list devices with `pactl list short sources`";
+        "which audio device to record from. list devices with `wl-screenrec list-audio`";
     pub const DEFAULT_AUDIO_BACKEND: &str = "pulse";
 }
 #[cfg(any(target_os = "dragonfly", target_os = "freebsd"))]
 mod platform {
     pub const DEFAULT_AUDIO_CAPTURE_DEVICE: &str = "/dev/dsp";
     pub const AUDIO_DEVICE_HELP: &str =
-        "which audio device to record from. list devices with `cat /dev/sndstat` (pcmN -> dspN)";
+        "which audio device to record from. list devices with `wl-screenrec --audio-backend oss list-audio`";
     pub const DEFAULT_AUDIO_BACKEND: &str = "oss";
 }
 use platform::*;
 
-#[derive(Parser, Debug)]
-#[command(author, version, about, long_about = None)]
+#[derive(Parser, Debug, Clone)]
+#[command(author, version, about, long_about = None, disable_version_flag = true)]
 pub struct Args {
-    #[clap(long="no-hw", default_value = "true", action=ArgAction::SetFalse, help="don't use the GPU encoder, download the frames onto the CPU and use a software encoder. Ignored if `encoder` is supplied")]
-    hw: bool,
+    #[clap(
+        long,
+        value_enum,
+        default_value_t,
+        help = "hardware to encode on. vaapi is the only one so far, if ffmpeg has no vaapi encoder for the codec a software encoder is used instead. none downloads the frames onto the CPU and uses a software encoder. Ignored if `encoder` is supplied"
+    )]
+    hw_backend: HwBackend,
+
+    #[clap(
+        long,
+        conflicts_with = "hw_backend",
+        help = "same as --hw-backend none"
+    )]
+    no_hw: bool,
+
+    #[clap(skip)]
+    hw: bool, // from --hw-backend, false when encoding in software
 
     #[clap(
         long,
         short,
         default_value = "screenrecord.mp4",
-        help = "filename to write to. container type is detected from extension"
+        help = "filename to write to. container type is detected from extension. With --split-at or --split-at-midnight, strftime(3) sequences like %Y-%m-%d are expanded with the local time each file is created at"
     )]
     filename: String,
 
-    #[clap(long, short, value_parser=parse_geometry, help="geometry to capture, format x,y WxH. Compatible with the output of `slurp`. Mutually exclusive with --output", allow_hyphen_values=true)]
-    geometry: Option<(i32, i32, u32, u32)>,
+    #[clap(skip)]
+    filename_template: String, // --filename before strftime expansion (% escaped if it isn't expanded), used for new segments
+
+    #[clap(
+        long,
+        overrides_with = "force",
+        help = "if --filename already exists, write to a new file with -1, -2, etc appended to its name instead of overwriting it"
+    )]
+    no_overwrite: bool,
+
+    #[clap(
+        long,
+        overrides_with = "no_overwrite",
+        help = "overwrite --filename if it already exists. This is the default, but can be used to override --no-overwrite"
+    )]
+    force: bool,
+
+    #[clap(
+        long,
+        short,
+        help = "geometry to capture, format x,y WxH. Compatible with the output of `slurp`. Mutually exclusive with --output. Can also be a size anchored to part of the output, like `center 50%x50%` or `bottom-right 800x600`, where sizes are logical pixels or percentages of the output's size. Anchored geometry can be combined with --output. Pass more than once to record several regions of the same output next to each other, see --layout",
+        allow_hyphen_values = true,
+        action = ArgAction::Append
+    )]
+    geometry: Vec<Geometry>,
+
+    #[clap(
+        long,
+        value_enum,
+        default_value_t,
+        help = "how to arrange the regions when --geometry is passed more than once, or the outputs of --outputs. horizontal puts them side by side, vertical above each other and grid in rows of a square-ish grid. Regions are scaled to the same height side by side and the same width above each other, outputs are all scaled to the pixel density of the most scaled one"
+    )]
+    layout: Layout,
+
+    #[clap(
+        long,
+        value_name = "OUTPUTS",
+        conflicts_with_all = [
+            "output", "geometry", "exclude_region", "encode_device", "composite_cursor",
+            "roi_cursor", "damage_hints", "follow_focus", "follow_window", "sway_window",
+            "follow_active_window", "workspace",
+        ],
+        help = "record several outputs (displays) into one video, arranged with --layout. A comma separated list like DP-3,eDP-1, or `all` for every enabled output from left to right. The first one is captured like --output, the others with wlr-screencopy"
+    )]
+    outputs: Option<String>,
+
+    #[clap(
+        long,
+        allow_hyphen_values = true,
+        action = ArgAction::Append,
+        help = "part of the screen to cover up in the recording, like a chat pane or `top-right 400x300` for notifications. Same formats as --geometry, can be passed more than once. Covering is done on the CPU, so with vaapi encoding every frame is downloaded and uploaded again"
+    )]
+    exclude_region: Vec<Geometry>,
+
+    #[clap(
+        long,
+        value_enum,
+        default_value_t,
+        help = "how to cover --exclude-region. black fills it in, blur keeps its shapes and colors but makes text unreadable"
+    )]
+    exclude_style: ExcludeStyle,
 
     #[clap(
         long,
         short,
-        help = "Which output (display) to record. Mutually exclusive with --geometry. Defaults to your only display if you only have one",
+        help = "Which output (display) to record. Mutually exclusive with --geometry. Defaults to your only display if you only have one. Can be a comma separated list like DP-3,eDP-1 to record the first of them that is enabled, where `any` matches any enabled display",
         default_value = ""
     )]
     output: String,
@@ -124,12 +312,41 @@ pub struct Args {
     #[clap(long, short, default_value = "0", action=ArgAction::Count, help = "add very loud logging. can be specified multiple times")]
     verbose: u8,
 
+    #[clap(
+        long,
+        short = 'V',
+        help = "Print version. With --verbose, also the ffmpeg it's linked against, the vaapi encoders it has and the capture backends, for bug reports"
+    )]
+    version: bool,
+
+    #[clap(
+        long,
+        value_name = "FILTERS",
+        default_value = "",
+        hide_default_value = true,
+        help = "log levels per subsystem, for example wayland=debug,encode=info,audio=trace. subsystems are wayland (capture and format negotiation), encode and audio, a level on its own sets everything else and takes the place of -v"
+    )]
+    log: LogSpec,
+
+    #[clap(
+        long,
+        help = "once the encoder is set up, print the negotiated capture format and modifier, the formats the compositor offered, the dri device(s), encoder, pixel formats and filter graph to stdout as one line of JSON. for bug reports and scripts"
+    )]
+    print_config: bool,
+
     #[clap(
         long,
         help = "which dri device to use for vaapi. by default, this is obtained from the linux-dmabuf-v1 protocol when using wlr-screencopy, and from ext-image-copy-capture-session if using ext-image-copy-capture, if present. if not present, /dev/dri/renderD128 is guessed"
     )]
     dri_device: Option<String>,
 
+    #[clap(
+        long,
+        value_name = "DRI_DEVICE",
+        help = "encode on this dri device instead of the one the compositor's buffers are on (--dri-device), for hybrid graphics where the better encoder is on the other GPU. Frames are captured and scaled on the compositor's GPU, then copied through system memory to this one, which costs some CPU time and memory bandwidth. Only for vaapi encoders"
+    )]
+    encode_device: Option<PathBuf>,
+
     #[clap(long, value_enum, default_value_t)]
     low_power: LowPowerMode,
 
@@ -137,10 +354,18 @@ pub struct Args {
         long,
         value_enum,
         default_value_t,
-        help = "which video codec to use. Ignored if `--ffmpeg-encoder` is supplied"
+        help = "which video codec to use. prores and dnxhr are intra-only codecs for editing, encoded in 10 bit 4:2:2 on the CPU. Write them to a mov (or mxf for dnxhr). Ignored if `--ffmpeg-encoder` is supplied"
     )]
     codec: Codec,
 
+    #[clap(
+        long,
+        value_enum,
+        conflicts_with_all = ["codec", "ffmpeg_encoder"],
+        help = "a set of encode settings for a particular use. archive-ffv1 records lossless FFV1 into mkv, keeping the captured RGB pixels exactly (unless --encode-resolution scales them). Files are very large"
+    )]
+    preset: Option<Preset>,
+
     #[clap(
         long,
         help = "Which ffmpeg muxer to use. Guessed from output filename by default"
@@ -153,6 +378,50 @@ pub struct Args {
     )]
     ffmpeg_muxer_options: Option<String>,
 
+    #[clap(
+        long,
+        help = "PUT each finished file to this http(s) url (https needs the tls feature), every --split-at file and --continuous clip too. It has to end in / or contain {filename}, which is where the file's name goes, so files don't overwrite each other. Anything but a 2xx response is retried a few times, then only warned about, the files are kept either way"
+    )]
+    upload_url: Option<String>,
+
+    #[cfg(feature = "s3")]
+    #[clap(
+        long,
+        conflicts_with = "upload_url",
+        help = "upload each finished file to an S3 compatible bucket, like https://s3.us-east-1.amazonaws.com/bucket. Every --split-at file and --continuous clip is uploaded, and deleted once it's there unless --s3-keep-local is passed. Credentials are read from AWS_ACCESS_KEY_ID, AWS_SECRET_ACCESS_KEY and AWS_SESSION_TOKEN"
+    )]
+    s3_url: Option<String>,
+
+    #[cfg(feature = "s3")]
+    #[clap(
+        long,
+        default_value = "{filename}",
+        help = "object key to upload to with --s3-url. strftime-style, and {filename} is the name of the file, like cameras/%Y-%m-%d/{filename}"
+    )]
+    s3_key: String,
+
+    #[cfg(feature = "s3")]
+    #[clap(
+        long,
+        default_value = "us-east-1",
+        help = "region to sign --s3-url requests for"
+    )]
+    s3_region: String,
+
+    #[cfg(feature = "s3")]
+    #[clap(
+        long,
+        requires = "s3_url",
+        help = "keep files after they're uploaded with --s3-url"
+    )]
+    s3_keep_local: bool,
+
+    #[clap(
+        long,
+        help = "write matroska instead of failing when the container can't hold the codecs asked for, or needs to seek and the output is a pipe or a stream. A regular file's extension is changed to .mkv"
+    )]
+    container_fallback: bool,
+
     #[clap(
         long,
         value_enum,
@@ -163,10 +432,29 @@ pub struct Args {
     #[clap(
         long,
         value_enum,
-        help = "Options to pass to the encoder. Format looks like key=val,key2=val2"
+        help = "Options to pass to the encoder. Format looks like key=val,key2=val2. Unknown options and invalid values are an error, listing the options the encoder has"
     )]
     ffmpeg_encoder_options: Option<String>,
 
+    #[clap(
+        long,
+        conflicts_with = "encode_speed",
+        help = "preset for the software encoder used with --no-hw. Defaults to veryfast for x264/x265 and 10 for SVT-AV1, which keep up with realtime capture on most CPUs"
+    )]
+    sw_preset: Option<String>,
+
+    #[clap(
+        long,
+        help = "tune for the software encoder used with --no-hw. Defaults to zerolatency for x264/x265"
+    )]
+    sw_tune: Option<String>,
+
+    #[clap(
+        long,
+        help = "number of threads for the software encoder used with --no-hw. Defaults to ffmpeg's choice"
+    )]
+    sw_threads: Option<u32>,
+
     #[clap(
         long,
         value_enum,
@@ -194,18 +482,129 @@ pub struct Args {
     )]
     encode_pixfmt: Option<Pixel>,
 
+    #[clap(
+        long,
+        help = "vaapi encode profile, passed to the encoder as its `profile` option. For example `main10` for hevc or `high` for avc. The entrypoint is picked by --low-power. If the encoder can't be opened, the profiles and entrypoints the driver supports are listed"
+    )]
+    vaapi_profile: Option<String>,
+
+    #[clap(
+        long,
+        value_parser=clap::value_parser!(u8).range(1..=7),
+        help = "trade encoder work for file size, from 1 (smallest files, most GPU/CPU time) to 7 (least overhead, for example while gaming). Mapped onto the encoder's own settings: vaapi quality and async_depth, qsv presets and async_depth, x264/x265 presets, SVT-AV1 presets, libaom/libvpx cpu-used. Options given with --ffmpeg-encoder-options take precedence"
+    )]
+    encode_speed: Option<u8>,
+
     #[clap(long, value_parser=parse_size, help="what resolution to encode at. example: 1920x1080. Default is the resolution of the captured region. If your goal is reducing filesize, it's suggested to try --bitrate/-b first")]
     encode_resolution: Option<(u32, u32)>,
 
+    #[clap(
+        long,
+        value_parser = parse_scale,
+        conflicts_with = "encode_resolution",
+        help = "scale the captured region by this factor before encoding, for example 0.5 to halve the resolution of a HiDPI display. Applied after the region to record is picked"
+    )]
+    scale: Option<f64>,
+
     #[clap(long, short, default_value_t=SpecificSize::new(5, Megabyte).unwrap().into(), help="bitrate to encode at. Unit is bytes per second, so 5 MB is 40 Mbps")]
     bitrate: Size,
 
+    #[clap(
+        long,
+        help = "drop frames so at most this many are encoded per second. By default every frame the compositor gives is encoded"
+    )]
+    max_fps: Option<f64>,
+
+    #[clap(
+        long,
+        value_enum,
+        default_value_t,
+        help = "how --max-fps picks the frames to keep. drop keeps a frame once 1/max-fps has passed since the last one kept, which can judder when the refresh rate isn't a multiple of max-fps. smooth keeps the frame closest to each tick of an even max-fps cadence, predicting when the next frames will arrive from the capture rate, for smoother motion at rates like 144Hz to 30fps"
+    )]
+    max_fps_mode: FpsLimitMode,
+
+    #[clap(
+        long,
+        help = "copy at most this many frames per second from the compositor. Frames are still dropped for --max-fps after they're captured, so with --capture-fps at the refresh rate and a lower --max-fps the encoder gets a decimated stream of frames captured with as little latency as possible. By default a frame is copied whenever the compositor has a new one"
+    )]
+    capture_fps: Option<f64>,
+
     #[clap(long,
         help="run in a mode where the screen is recorded, but nothing is written to the output file until SIGUSR1 is sent to the process. Then, it writes the most recent N seconds to a file and continues recording", 
         value_parser=parse_duration
     )]
     history: Option<Duration>,
 
+    #[clap(
+        long,
+        requires = "history",
+        value_parser = parse_duration_with_unit,
+        help = "with --history, keep this much more audio than video (for example 0.5s or 500ms), so a clip doesn't start in the middle of a word"
+    )]
+    history_preroll: Option<Duration>,
+
+    #[clap(
+        long,
+        value_name = "DIR",
+        requires = "history",
+        help = "with --history, move the oldest buffered packets to files in DIR once they take more memory than --history-memory, so a long replay buffer doesn't need all of it in RAM. The files are deleted right away and only take space while the packets are in history. Pick a directory on disk, /tmp is often in memory"
+    )]
+    history_spill: Option<PathBuf>,
+
+    #[clap(
+        long,
+        default_value_t = SpecificSize::new(256, Megabyte).unwrap().into(),
+        requires = "history_spill",
+        help = "with --history-spill, how much history to keep in memory"
+    )]
+    history_memory: Size,
+
+    #[clap(
+        long,
+        value_name = "FILENAME:ENCODER:BITRATE",
+        requires = "history",
+        help = "with --history, also re-encode the history to a separate, smaller clip when SIGUSR1 is received, for example replay.mp4:libx264:2MB. This runs in the background while recording continues at full quality. ENCODER is an ffmpeg software encoder, audio is copied as is. Waits for the clip to finish before exiting"
+    )]
+    history_transcode: Option<TranscodeSpec>,
+
+    #[clap(
+        long,
+        requires = "history",
+        conflicts_with = "history_transcode",
+        help = "with --history, record the whole session to --filename as if --history wasn't given, and make each SIGUSR1 save the last --history seconds to a clip of its own, named like --filename with -clip added (and -1, -2, etc if it exists). The replay buffer keeps going, so any number of clips can be saved"
+    )]
+    continuous: bool,
+
+    #[clap(
+        long,
+        value_name = "TEMPLATE",
+        requires = "continuous",
+        help = "with --continuous, name clips from this instead of --filename, like ~/Videos/highlights/clip-%s.mp4. strftime(3) sequences are expanded when the clip is saved, missing directories are created, and the container is picked from the extension"
+    )]
+    history_filename: Option<String>,
+
+    #[clap(
+        long,
+        conflicts_with = "history",
+        help = "if --filename already exists, continue the recording at the end of it instead of overwriting it. The existing file must have been recorded with the same codec and audio settings. Works best with mkv"
+    )]
+    append: bool,
+
+    #[clap(
+        long,
+        conflicts_with_all = ["history", "split_at", "split_at_midnight", "proxy"],
+        help = "if the last recording with this --filename was interrupted (wl-screenrec was killed or crashed), continue it like --append instead of starting a new file. It's cut back to the last point it was flushed at, about a second before it stopped. mkv and fragmented mp4 recordings are tracked, in $XDG_STATE_HOME/wl-screenrec"
+    )]
+    resume: bool,
+
+    #[clap(
+        long,
+        value_parser = parse_duration_with_unit,
+        default_value = "10s",
+        help = "how long finishing the recording can take after it's stopped before giving up on it, for example when a network --filename stopped accepting data. After this, what hasn't been written is dropped and the file is closed as well as it can be, and if that doesn't return within 5 seconds either, wl-screenrec exits without closing it. Pressing Ctrl-C a second time gives up straight away, and a third time exits"
+    )]
+    shutdown_timeout: Duration,
+
     #[clap(long, default_value = "false", action=ArgAction::SetTrue, help="record audio with the stream. Defaults to the default audio capture device")]
     audio: bool,
 
@@ -215,6 +614,54 @@ pub struct Args {
     #[clap(long, default_value_t = DEFAULT_AUDIO_BACKEND.to_string(), help = "which ffmpeg audio capture backend (see https://ffmpeg.org/ffmpeg-devices.html`) to use. you almost certainally want to specify --audio-device if you use this, as the values depend on the backend used")]
     audio_backend: String,
 
+    #[clap(
+        long,
+        requires = "audio",
+        help = "print the RMS and peak level of the recorded audio to stderr every second, to check the right device is being recorded before a long session"
+    )]
+    audio_levels: bool,
+
+    #[clap(
+        long,
+        requires = "audio",
+        value_parser = parse_gate_threshold,
+        num_args = 0..=1,
+        default_missing_value = "-40",
+        allow_negative_numbers = true,
+        value_name = "THRESHOLD_DB",
+        help = "turn the audio right down while it's quieter than this many dBFS (-60 to 0, -40 if not given), so keyboard and fan noise between speech is left out. Pass it like --noise-gate=-50"
+    )]
+    noise_gate: Option<f64>,
+
+    #[clap(
+        long,
+        value_parser = parse_duration_with_unit,
+        default_value = "10s",
+        help = "how much encoded audio can wait to be written before --audio-backlog-policy applies, so a stalled output can't use unbounded memory"
+    )]
+    audio_backlog: Duration,
+
+    #[clap(
+        long,
+        value_enum,
+        default_value_t = AudioBacklogPolicy::Drop,
+        help = "what to do with new audio while --audio-backlog is full. drop leaves a gap in the audio, block stops reading the device until there's room, which usually loses the audio in the device instead but keeps the timestamps continuous"
+    )]
+    audio_backlog_policy: AudioBacklogPolicy,
+
+    #[clap(
+        long,
+        value_parser = parse_duration_with_unit,
+        help = "hold each copied frame back this long (for example 50ms) and only encode it if no newer one came in meanwhile, so damage that comes in quick succession, like a terminal printing, ends up as one frame of where it settled instead of many nearly identical ones. While the screen keeps changing, a frame is still encoded every 4 times this long"
+    )]
+    damage_debounce: Option<Duration>,
+
+    #[clap(
+        long,
+        help = "ask for each frame just after the output refreshes, instead of as soon as the last one was copied. Refreshes are predicted from the output's refresh rate and the compositor's timestamps. Can help with compositors where copies catch a frame halfway through being drawn, which looks like tearing"
+    )]
+    align_to_vblank: bool,
+
     #[clap(long="no-damage", default_value = "true", action=ArgAction::SetFalse, help="copy every frame, not just unique frames. This can be helpful to get a non-variable framerate video, but is generally discouraged as it uses much more resources. Useful for testing")]
     damage: bool,
 
@@ -233,61 +680,512 @@ pub struct Args {
         default_value = "false"
     )]
     ext_image_copy_capture: bool,
-}
 
-trait CaptureSource: Sized {
-    type Frame: Clone;
-
-    fn new(
-        gm: &GlobalList,
-        eq: &QueueHandle<State<Self>>,
-        output: WlOutput,
-    ) -> anyhow::Result<Self>;
+    #[clap(
+        long,
+        value_enum,
+        default_value_t,
+        help = "where frames come from. wayland captures the screen with wlr-screencopy, or ext-image-copy-capture if --experimental-ext-image-copy-capture is passed. test-pattern encodes 1920x1080 60fps SMPTE bars with a running timestamp instead, without connecting to a compositor (a vaapi device is still needed). --geometry is relative to the pattern. For development, CI and benchmarks"
+    )]
+    capture_backend: CaptureBackend,
 
-    // allocates a frame, either sync or async
-    // if async, return None and call `on_frame_allocd` at a later moment
-    // if sync, just return the allocated stuff
-    fn alloc_frame(&self, eq: &QueueHandle<State<Self>>) -> Option<Self::Frame>;
+    #[clap(
+        long,
+        conflicts_with = "no_replace",
+        help = "if another instance is already recording this output, tell it to stop and wait for it to finish its file before starting. Without it, wl-screenrec refuses to start"
+    )]
+    replace: bool,
 
-    // queue a copy of the screen into `buf`
-    // call `on_copy_complete` or `on_copy_fail` when the copy has completed
-    fn queue_copy(&self, damage: bool, buf: &WlBuffer, cap: &Self::Frame);
+    #[clap(
+        long,
+        help = "if another instance is already recording this output, exit with an error instead of starting. This is the default, the flag is kept for scripts that pass it"
+    )]
+    no_replace: bool,
 
-    // destroy the `frame` object
-    fn on_done_with_frame(&self, f: Self::Frame);
-}
+    #[clap(
+        long,
+        help = "shell command to run (and wait for) once the output to record has been selected, before recording starts. WL_SCREENREC_FILENAME, WL_SCREENREC_OUTPUT, and WL_SCREENREC_GEOMETRY are set in its environment"
+    )]
+    exec_before: Option<String>,
 
-#[derive(clap::ValueEnum, Debug, Clone, Default, PartialEq, Eq)]
-enum Codec {
-    #[default]
-    Auto,
-    Avc,
-    Hevc,
-    VP8,
-    VP9,
-    AV1,
-}
+    #[clap(
+        long,
+        help = "shell command to run after the recording has been successfully finalized. Has the same environment as --exec-before"
+    )]
+    exec_after: Option<String>,
 
-#[derive(clap::ValueEnum, Debug, Default, Clone, PartialEq, Eq)]
-enum AudioCodec {
-    #[default]
-    Auto,
-    Aac,
-    Mp3,
-    Flac,
-    Opus,
-}
+    #[clap(
+        long,
+        help = "shell command to run if wl-screenrec exits with an error. Has the same environment as --exec-before, plus WL_SCREENREC_EXIT_CODE"
+    )]
+    exec_on_error: Option<String>,
 
-#[derive(clap::ValueEnum, Debug, Clone, Default)]
-enum LowPowerMode {
-    #[default]
-    Auto,
-    On,
-    Off,
-}
+    #[clap(
+        long,
+        help = "write a JSON line for every captured or dropped frame to this file, with its capture timestamp, PTS, and if the compositor reported damage for it"
+    )]
+    frame_log: Option<PathBuf>,
 
-#[derive(Error, Debug)]
-enum ParseGeometryError {
+    #[clap(
+        long,
+        help = "write a CSV line for every video packet the encoder outputs to this file, with its PTS, size, if it's a keyframe, and the frame type and QP when the encoder reports them (vaapi encoders don't). For finding bitrate spikes and quality pulsing. Each packet is also logged at trace level"
+    )]
+    encode_log: Option<PathBuf>,
+
+    #[clap(long, help = "don't draw the cursor into the recording")]
+    no_cursor: bool,
+
+    #[clap(
+        long,
+        requires = "ext_image_copy_capture",
+        conflicts_with = "no_cursor",
+        help = "capture the cursor separately and draw it into the recording ourselves, for compositors that leave it out of captured frames, or to resize it with --cursor-scale. The cursor isn't drawn into --proxy or --extra-stream renditions. Requires --experimental-ext-image-copy-capture"
+    )]
+    composite_cursor: bool,
+
+    #[clap(
+        long,
+        default_value_t = 1.,
+        requires = "composite_cursor",
+        help = "with --composite-cursor, draw the cursor this many times bigger, so it's easier to follow in a screencast"
+    )]
+    cursor_scale: f64,
+
+    #[clap(
+        long,
+        requires = "ext_image_copy_capture",
+        help = "write a JSON line to this file whenever the pointer moves, with the PTS of the frame and the pointer's position in the video (null when it's off the output), so it can be drawn in later. Useful with --no-cursor. Clicks aren't logged, Wayland doesn't tell other clients about them. Requires --experimental-ext-image-copy-capture"
+    )]
+    cursor_log: Option<PathBuf>,
+
+    #[clap(
+        long,
+        value_name = "FILE",
+        help = "draw the text in FILE in the top left corner of the recording, updating it whenever FILE changes, for a status line or scoreboard kept up to date by a script. Needs ffmpeg built with the drawtext filter (libfreetype). Not drawn into --proxy or --extra-stream renditions"
+    )]
+    overlay_text: Option<PathBuf>,
+
+    #[clap(
+        long,
+        help = "add a subtitle track with how long each frame was on screen. On variable refresh rate displays frames arrive at irregular intervals, this lets analysis tools reconstruct the exact pacing. Requires a mkv or mp4 output"
+    )]
+    frame_durations: bool,
+
+    #[clap(
+        long,
+        help = "add a subtitle track of markers, to annotate the recording while it runs. Each `wl-screenrec ctl marker <TEXT>` (or line written to --marker-fd) shows TEXT from the current frame on. Requires a mkv or mp4 output"
+    )]
+    markers: bool,
+
+    #[clap(
+        long,
+        value_name = "FD",
+        help = "read markers from this file descriptor, one per line, for a program logging keystrokes or test steps as it drives the recorded session. Implies --markers"
+    )]
+    marker_fd: Option<i32>,
+
+    #[clap(
+        long,
+        help = "add a timed ID3 metadata stream of splice points, for tools downstream of a live stream to cut it at. `wl-screenrec ctl splice out [DURATION]` and `splice in` insert SCTE-35 splice_insert commands (in PRIV frames owned by urn:scte:scte35:2013:bin), `splice text <TEXT>` a TXXX frame. The frame a cue is inserted at is made a keyframe. Requires a mpegts or hls output"
+    )]
+    splice_points: bool,
+
+    #[clap(
+        long,
+        help = "don't attach wl-screenrec.json to mkv recordings. By default it records the output, geometry, encoder settings, markers and versions the recording was made with, added once each file is finished, for debugging it later (`mkvextract attachments FILE 1:wl-screenrec.json`)"
+    )]
+    no_context_attachment: bool,
+
+    #[clap(
+        long,
+        value_name = "TRACK=FLAG[+FLAG...]",
+        help = "how players should treat a track, like `audio=default` or `extra-stream=comment`. TRACK is video, extra-stream, audio, markers or frame-durations, FLAG is default, dub, original, comment, forced, hearing_impaired, visual_impaired, captions, descriptions, metadata or none, as for ffmpeg's -disposition. Marking a track default takes it away from the other tracks of the same kind. Can be passed more than once",
+        action = ArgAction::Append
+    )]
+    disposition: Vec<TrackDisposition>,
+
+    #[clap(
+        long,
+        default_value = "screenshot-%Y-%m-%d_%H-%M-%S.png",
+        help = "where `wl-screenrec ctl screenshot` or SIGUSR2 save a PNG of the next recorded frame, as it is encoded. strftime(3) sequences are expanded like for --filename, and an existing file isn't overwritten"
+    )]
+    screenshot_filename: String,
+
+    #[clap(
+        long,
+        value_name = "TIME",
+        num_args = 0..=1,
+        default_missing_value = "0s",
+        value_parser = parse_duration_with_unit,
+        help = "attach the frame TIME into the recording (the first frame if no TIME is given) as cover art, so file managers show it as the thumbnail. The file is rewritten with it once finished. Requires a mkv output"
+    )]
+    poster: Option<Duration>,
+
+    #[clap(
+        long,
+        value_name = "DURATION",
+        value_parser = parse_duration_with_unit,
+        default_value = "0s",
+        help = "cut this much off the start of the recording once it's finished, like 2s to lose fumbling with the terminal. The cut moves forward to the next keyframe, so nothing is re-encoded"
+    )]
+    trim_start: Duration,
+
+    #[clap(
+        long,
+        value_name = "DURATION",
+        value_parser = parse_duration_with_unit,
+        default_value = "0s",
+        help = "cut this much off the end of the recording once it's finished"
+    )]
+    trim_end: Duration,
+
+    #[clap(
+        long,
+        value_name = "DURATION",
+        value_parser = parse_duration_with_unit,
+        help = "delete the recording when it's stopped if it's shorter than this, like 3s, so a keybinding pressed twice by accident doesn't leave a file behind. Recordings that were appended to or split into several files are always kept"
+    )]
+    discard_shorter_than: Option<Duration>,
+
+    #[clap(
+        long,
+        value_parser=parse_size,
+        num_args=0..=1,
+        default_missing_value="480x270",
+        requires="ext_image_copy_capture",
+        help="spend more bits on a WxH box (in pixels, 480x270 if not given) around the pointer, so what's being pointed at stays sharp at low bitrates. Uses the encoder's region of interest support, which vaapi drivers may not have. Requires --experimental-ext-image-copy-capture to know where the pointer is"
+    )]
+    roi_cursor: Option<(u32, u32)>,
+
+    #[clap(
+        long,
+        value_parser=parse_qoffset,
+        num_args=0..=1,
+        default_missing_value="0.3",
+        conflicts_with="damage",
+        help="lower the quality while little of the screen is changing, judged from the damage the compositor reports, so mostly static recordings are much smaller. The value (0 to 1, 0.3 if not given) is how much to lower it by for a completely static screen. Uses the encoder's region of interest support, which vaapi drivers may not have"
+    )]
+    adaptive_quality: Option<f64>,
+
+    #[clap(
+        long,
+        conflicts_with = "damage",
+        help = "pass the regions of each frame the compositor reports as changed to the encoder, which spends a few more bits on them and fewer on the rest of the frame. Uses the encoder's region of interest support, which vaapi drivers may not have"
+    )]
+    damage_hints: bool,
+
+    #[clap(
+        long,
+        conflicts_with = "damage",
+        help = "drop frames that are the same as the previous one. For compositors that send unchanged frames (ext-image-copy-capture ones without damage tracking), this gets the small files damage tracking would. Each frame is compared at a quarter of its size, so very faint changes can be missed"
+    )]
+    dedupe_frames: bool,
+
+    #[clap(
+        long,
+        help = "embed a SMPTE timecode derived from the local wall-clock time of the first frame in each file, so recordings from multiple machines can be aligned in an editor. Written as a tmcd track in mp4/mov and as a tag in mkv"
+    )]
+    timecode: bool,
+
+    #[clap(
+        long,
+        help = "set everything up, but don't start recording until this local time of day. Format is HH:MM or HH:MM:SS"
+    )]
+    start_at: Option<TimeOfDay>,
+
+    #[clap(
+        long,
+        help = "stop recording and finalize the file at this local time of day. Format is HH:MM or HH:MM:SS. If --start-at is passed, this is the first occurrence after the start time"
+    )]
+    stop_at: Option<TimeOfDay>,
+
+    #[clap(
+        long,
+        value_delimiter = ',',
+        conflicts_with_all = ["history", "append"],
+        help = "start a new file at these local times of day (comma separated, HH:MM or HH:MM:SS). The split happens on a keyframe, so no frames are lost. Use strftime sequences in --filename to give each file a unique name, otherwise -1, -2, etc are appended"
+    )]
+    split_at: Vec<TimeOfDay>,
+
+    #[clap(
+        long,
+        conflicts_with_all = ["history", "append"],
+        help = "shorthand for --split-at 00:00"
+    )]
+    split_at_midnight: bool,
+
+    #[clap(
+        long,
+        conflicts_with = "geometry",
+        help = "move the recording to whichever output has focus. Only works on sway and Hyprland. Starts on the focused output unless --output is passed"
+    )]
+    follow_focus: bool,
+
+    #[clap(
+        long,
+        value_name = "APP_ID",
+        conflicts_with_all = ["geometry", "follow_focus"],
+        help = "record only the window with this app id (or X11 class, for Xwayland windows and on Hyprland), following it as it moves and resizes, including to other outputs. Only works on sway and Hyprland. Resizing the window letterboxes it in the size the recording started with"
+    )]
+    follow_window: Option<String>,
+
+    #[clap(
+        long,
+        value_name = "CRITERIA",
+        conflicts_with_all = ["geometry", "follow_focus", "follow_window"],
+        help = "record only the sway window matching these criteria, like 'app_id=\"foot\" title=\"vim\"' (see sway(5)), following it as it moves and resizes, including to other outputs. If several windows match, the first is kept until it closes, and it's marked with _wl-screenrec-PID until the recording ends. Dragging the window with the mouse is followed within half a second, as sway sends no events for it. Resizing the window letterboxes it in the size the recording started with"
+    )]
+    sway_window: Option<String>,
+
+    #[clap(
+        long,
+        conflicts_with_all = ["geometry", "follow_focus", "follow_window", "sway_window"],
+        help = "record only whichever window has focus, following it as focus changes and as it moves and resizes, including to other outputs. Only works on Hyprland. Each window is letterboxed in the size the recording started with"
+    )]
+    follow_active_window: bool,
+
+    #[clap(
+        long,
+        help = "if the connection to the compositor is lost (it crashed or was restarted), finish the current file, wait for the compositor to come back and continue recording into a new file. The new file is named from --filename again, with a number appended"
+    )]
+    reconnect: bool,
+
+    #[clap(
+        long,
+        value_name = "ADDR:PORT",
+        help = "serve Prometheus metrics on http://ADDR:PORT/metrics: frames captured, dropped and encoded, bytes written, bytes held for --history and restarts. For alerting when a long running instance (like a --history replay buffer) silently stops producing frames. Use 127.0.0.1:9185 to only allow local connections"
+    )]
+    metrics_listen: Option<SocketAddr>,
+
+    #[clap(
+        long,
+        default_value = "slurp",
+        help = "command to run for the reselect control command. It should print a region in the same format as --geometry"
+    )]
+    selector: String,
+
+    #[clap(
+        long,
+        help = "settings to use while running on battery power, switching back when plugged in. Format looks like max-fps=30,bitrate=2MB"
+    )]
+    on_battery: Option<BatteryOverrides>,
+
+    #[clap(
+        long,
+        value_enum,
+        help = "what to do while the session is locked (requires locking through logind, with `loginctl lock-session` or a screen locker that sets its LockedHint). pause cuts the locked time out of the recording, slate keeps a one second still of the lock screen to mark the gap. By default, recording continues as normal"
+    )]
+    on_lock: Option<LockAction>,
+
+    #[clap(
+        long,
+        value_name = "WORKSPACE",
+        help = "only record while the recorded output shows this workspace (by name or number), cutting everything else out of the recording. Only works on sway and Hyprland"
+    )]
+    workspace: Option<String>,
+
+    #[clap(
+        long,
+        value_name = "APP_ID",
+        help = "only record while a window with this app id (or X11 class) has focus, cutting everything else out of the recording. Only works on sway and Hyprland"
+    )]
+    only_while_focused: Option<String>,
+
+    #[clap(
+        long,
+        value_parser = parse_duration_with_unit,
+        value_name = "DURATION",
+        help = "cut time out of the recording once there has been no input and nothing on screen has changed for this long (like 30s), resuming as soon as either happens. Needs a compositor that supports ext-idle-notify"
+    )]
+    pause_after_idle: Option<Duration>,
+
+    #[clap(
+        long,
+        default_value_t,
+        help = "what to do when the compositor fails to copy frames. retry keeps trying, leaving a gap in the recording. pause keeps trying too, but cuts the failing time out of the recording. exit-after=N gives up after N failures in a row. Retries back off up to once a second while copies keep failing"
+    )]
+    on_capture_error: CaptureErrorPolicy,
+
+    #[clap(
+        long,
+        conflicts_with_all = ["history", "append"],
+        help = "also write a low resolution copy of the recording, for use as an editing proxy. Format is FILENAME:SIZE:BITRATE, where SIZE is a height like 720p or an exact size like 1280x720. For example proxy.mp4:720p:1MB. The proxy has no audio"
+    )]
+    proxy: Option<ProxySpec>,
+
+    #[clap(
+        long,
+        help = "also encode a lower resolution copy of the video as a second stream in the same file, for simple adaptive bitrate streaming. Format is SIZE:BITRATE, where SIZE is a height like 360p or an exact size like 640x360. Use a container that supports multiple video streams, like mkv"
+    )]
+    extra_stream: Option<RenditionSpec>,
+
+    #[command(subcommand)]
+    command: Option<SubCommand>,
+}
+
+#[derive(clap::Subcommand, Debug, Clone)]
+enum SubCommand {
+    #[command(
+        about = "if an instance is already recording the output selected by [ARGS], stop it. Otherwise, start recording with [ARGS]"
+    )]
+    Toggle {
+        #[clap(trailing_var_arg = true, allow_hyphen_values = true)]
+        args: Vec<OsString>,
+    },
+    #[command(
+        about = "send a command to the instance recording the output selected by the options before ctl. Commands are stop, set-region <x,y WxH>, reselect, set-output <OUTPUT>, marker <TEXT>, screenshot, split, splice <out [DURATION]|in|text TEXT>, mute, unmute and toggle-mute. Without --output, the only instance running gets it"
+    )]
+    Ctl {
+        #[clap(required = true, trailing_var_arg = true)]
+        command: Vec<String>,
+    },
+    #[command(
+        about = "finish recordings that were interrupted (wl-screenrec was killed or crashed), so they have an index and a duration. Data written in the last second or so before it stopped is lost. Only mkv and fragmented mp4 recordings are tracked, a regular mp4 can't be read until it's finished"
+    )]
+    Recover,
+    #[command(
+        about = "list outputs that can be passed to --output, with their mode, scale, transform and which capture protocols and dmabuf device are available"
+    )]
+    ListOutputs {
+        #[clap(long, help = "print as JSON instead of a table")]
+        json: bool,
+        #[clap(
+            long,
+            conflicts_with = "json",
+            help = "only print output names, one per line. This is what the shell completions for --output use"
+        )]
+        names: bool,
+    },
+    #[command(
+        about = "list the devices --audio-backend can record from, to pass to --audio-device. The default device is marked with *"
+    )]
+    ListAudio,
+}
+
+trait CaptureSource: Sized {
+    type Frame: Clone;
+
+    fn new(
+        gm: &GlobalList,
+        eq: &QueueHandle<State<Self>>,
+        output: WlOutput,
+        paint_cursor: bool,
+    ) -> anyhow::Result<Self>;
+
+    // allocates a frame, either sync or async
+    // if async, return None and call `on_frame_allocd` at a later moment
+    // if sync, just return the allocated stuff
+    fn alloc_frame(&self, eq: &QueueHandle<State<Self>>) -> Option<Self::Frame>;
+
+    // queue a copy of the screen into `buf`
+    // call `on_copy_complete` or `on_copy_fail` when the copy has completed
+    fn queue_copy(&self, damage: bool, buf: &WlBuffer, cap: &Self::Frame);
+
+    // destroy the `frame` object
+    fn on_done_with_frame(&self, f: Self::Frame);
+
+    // start following the pointer, so `cursor_position` knows where it is
+    fn track_cursor(
+        &mut self,
+        _gm: &GlobalList,
+        _eq: &QueueHandle<State<Self>>,
+    ) -> anyhow::Result<()> {
+        bail!("following the cursor requires --experimental-ext-image-copy-capture")
+    }
+
+    // the pointer's hotspot in screen coordinates relative to the output, None if it isn't over
+    // the output or isn't tracked
+    fn cursor_position(&self) -> Option<(i32, i32)> {
+        None
+    }
+
+    // after `track_cursor`, also capture the pointer's image for `take_cursor_image`
+    fn capture_cursor_image(
+        &mut self,
+        _gm: &GlobalList,
+        _eq: &QueueHandle<State<Self>>,
+    ) -> anyhow::Result<()> {
+        bail!("capturing the cursor requires --experimental-ext-image-copy-capture")
+    }
+
+    // the pointer's image, if it changed since the last call
+    fn take_cursor_image(&mut self) -> Option<CursorImage> {
+        None
+    }
+}
+
+#[derive(clap::ValueEnum, Debug, Clone, Default, PartialEq, Eq)]
+enum Codec {
+    #[default]
+    Auto,
+    Avc,
+    Hevc,
+    VP8,
+    VP9,
+    AV1,
+    Prores,
+    Dnxhr,
+}
+
+#[derive(clap::ValueEnum, Debug, Default, Clone, PartialEq, Eq)]
+enum AudioCodec {
+    #[default]
+    Auto,
+    Aac,
+    Mp3,
+    Flac,
+    Opus,
+}
+
+#[derive(clap::ValueEnum, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Layout {
+    #[default]
+    Horizontal,
+    Vertical,
+    Grid,
+}
+
+#[derive(clap::ValueEnum, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ExcludeStyle {
+    #[default]
+    Black,
+    Blur,
+}
+
+#[derive(clap::ValueEnum, Debug, Clone, Copy, Default, PartialEq, Eq)]
+enum CaptureBackend {
+    #[default]
+    Wayland,
+    TestPattern,
+}
+
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+enum Preset {
+    ArchiveFfv1,
+}
+
+#[derive(clap::ValueEnum, Debug, Clone, Default)]
+enum LowPowerMode {
+    #[default]
+    Auto,
+    On,
+    Off,
+}
+
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+enum AudioBacklogPolicy {
+    Drop,
+    Block,
+}
+
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+enum LockAction {
+    Pause,
+    Slate,
+}
+
+#[derive(Error, Debug)]
+pub enum ParseGeometryError {
     #[error("invalid integer")]
     Int(#[from] ParseIntError),
     #[error("invalid geometry string")]
@@ -296,6 +1194,8 @@ enum ParseGeometryError {
     Location,
     #[error("invalid size string")]
     Size,
+    #[error("invalid anchor, expected top-left, top, top-right, left, center, right, bottom-left, bottom or bottom-right")]
+    Anchor,
 }
 
 fn parse_geometry(s: &str) -> Result<(i32, i32, u32, u32), ParseGeometryError> {
@@ -331,45 +1231,133 @@ fn parse_size(size: &str) -> Result<(u32, u32), ParseGeometryError> {
     Ok((sizex, sizey))
 }
 
-fn parse_duration(arg: &str) -> Result<std::time::Duration, std::num::ParseIntError> {
-    let seconds = arg.parse()?;
-    Ok(std::time::Duration::from_secs(seconds))
+fn parse_scale(arg: &str) -> Result<f64, String> {
+    match arg.parse() {
+        Ok(s) if s > 0. && s <= 1. => Ok(s),
+        _ => Err(format!(
+            "expected a number greater than 0 and at most 1, got {arg}"
+        )),
+    }
 }
 
-struct FpsCounter {
-    ct: Arc<AtomicU64>,
+fn parse_qoffset(arg: &str) -> Result<f64, String> {
+    match arg.parse() {
+        Ok(q) if (0. ..=1.).contains(&q) => Ok(q),
+        _ => Err(format!("expected a number from 0 to 1, got {arg}")),
+    }
 }
 
-impl FpsCounter {
-    fn new() -> Self {
-        let ct = Arc::new(AtomicU64::new(0));
-        let ct_weak = Arc::<AtomicU64>::downgrade(&ct);
+// dBFS, optionally with a dB suffix. ffmpeg's agate can't gate below -60dB
+fn parse_gate_threshold(arg: &str) -> Result<f64, String> {
+    let db = arg.strip_suffix("dB").unwrap_or(arg);
+    match db.trim().parse() {
+        Ok(db) if (-60. ..=0.).contains(&db) => Ok(db),
+        _ => Err(format!("expected a level from -60 to 0 dB, got {arg}")),
+    }
+}
 
-        thread::Builder::new()
-            .name("FpsCounter".to_owned())
-            .spawn(move || {
-                let mut last_ct = 0;
-                loop {
-                    sleep(Duration::from_millis(1000));
+// a duration with a unit, like 500ms, 0.5s or 2m
+fn parse_duration_with_unit(arg: &str) -> Result<Duration, String> {
+    let (num, unit_secs) = if let Some(n) = arg.strip_suffix("ms") {
+        (n, 1e-3)
+    } else if let Some(n) = arg.strip_suffix('s') {
+        (n, 1.)
+    } else if let Some(n) = arg.strip_suffix('m') {
+        (n, 60.)
+    } else {
+        return Err(format!(
+            "expected a duration with a unit (ms, s or m), got {arg}"
+        ));
+    };
+    num.trim()
+        .parse::<f64>()
+        .ok()
+        .filter(|n| n.is_finite() && *n >= 0.)
+        .map(|n| Duration::from_secs_f64(n * unit_secs))
+        .ok_or_else(|| format!("invalid duration {arg}"))
+}
 
-                    if let Some(ct_ptr) = ct_weak.upgrade() {
-                        let ct = ct_ptr.load(Ordering::SeqCst);
-                        println!("{} fps", ct - last_ct);
-                        last_ct = ct;
-                    } else {
-                        return;
-                    }
-                }
-            })
-            .unwrap();
+fn parse_duration(arg: &str) -> Result<std::time::Duration, std::num::ParseIntError> {
+    let seconds = arg.parse()?;
+    Ok(std::time::Duration::from_secs(seconds))
+}
 
-        Self { ct }
+// holds back requesting the next copy so at most capture_fps are copied per second, for
+// --capture-fps. Runs ahead of FpsLimit, which decides which of the captured frames are encoded. The copy
+// is scheduled on State::timer rather than waited for, the event loop has other work.
+// With --align-to-vblank the copy is then put off to the next refresh of the output. Screencopy
+// has no surface to get frame callbacks for, so refreshes are predicted from the timestamp the
+// compositor gave the last frame and the output's refresh rate
+struct CapturePacing {
+    interval_ns: i64,
+    last_ns: Option<i64>,
+    align_to_vblank: bool,
+    last_presented_ns: Option<i64>,
+    refresh_ns: i64,
+}
+
+impl CapturePacing {
+    fn new(capture_fps: Option<f64>, align_to_vblank: bool) -> Option<Self> {
+        let interval_ns = capture_fps.map(|fps| (1e9 / fps) as i64);
+        if interval_ns.is_none() && !align_to_vblank {
+            return None;
+        }
+        Some(Self {
+            interval_ns: interval_ns.unwrap_or(0),
+            last_ns: None,
+            align_to_vblank,
+            last_presented_ns: None,
+            refresh_ns: 0,
+        })
+    }
+
+    // every copied frame, with the compositor's timestamp for it
+    fn presented(&mut self, pts_ns: i64, refresh: Rational) {
+        self.last_presented_ns = Some(pts_ns);
+        if refresh.numerator() > 0 && refresh.denominator() > 0 {
+            self.refresh_ns = (1e9 / f64::from(refresh)) as i64;
+        }
+    }
+
+    // when the next copy can be requested, None if right away
+    fn next_capture(&self, now: i64) -> Option<i64> {
+        let mut at = self
+            .last_ns
+            .map_or(now, |last| last + self.interval_ns)
+            .max(now);
+        if let (true, Some(presented)) = (self.align_to_vblank, self.last_presented_ns) {
+            if self.refresh_ns > 0 {
+                let refreshes = (at - presented).max(0) / self.refresh_ns + 1;
+                at = presented + refreshes * self.refresh_ns;
+            }
+        }
+        (at > now).then_some(at)
     }
-    fn on_frame(&mut self) {
-        self.ct.fetch_add(1, Ordering::SeqCst);
+
+    // a copy is being requested
+    fn requested(&mut self, now: i64) {
+        self.last_ns = Some(now);
     }
 }
 
+// with --damage-debounce, the last copied frame is held back and only encoded once no newer one
+// has come in for the debounce period. Under steady change, it's encoded anyway after
+// DEBOUNCE_MAX_WAIT periods so the recording doesn't freeze
+struct Debounced {
+    surf: frame::Video,
+    pts_abs: i64,
+    damage: Vec<Rect>, // of the frames it replaced too, they were never encoded
+    since_ns: i64,     // the first of those came in
+    due_ns: i64,
+}
+
+const DEBOUNCE_MAX_WAIT: i64 = 4;
+
+// the VASurfaceID of a vaapi frame
+fn surface_id(frame: &frame::Video) -> u32 {
+    unsafe { (*frame.as_ptr()).data[3] as usize as u32 }
+}
+
 fn map_drm(frame: &frame::Video) -> (AVDRMFrameDescriptor, video::Video) {
     let mut dst = video::Video::empty();
     dst.set_format(Pixel::DRM_PRIME);
@@ -440,16 +1428,65 @@ struct OutputInfo {
 
 impl OutputInfo {
     fn logical_to_pixel(&self, logical: i32) -> i32 {
-        (f64::from(logical) * self.fractional_scale()).round() as i32
+        transform::logical_to_pixel(logical, self.scale_120())
     }
 
-    fn fractional_scale(&self) -> f64 {
-        f64::from(self.size_pixels.0) / f64::from(self.logical_size.0)
+    // the logical size is in screen space, so compare it against the transposed pixel size
+    fn scale_120(&self) -> i64 {
+        transform::scale_120(self.size_screen_space().0, self.logical_size.0)
     }
 
     fn size_screen_space(&self) -> (i32, i32) {
         transpose_if_transform_transposed(self.size_pixels, self.transform)
     }
+
+    // converts a --geometry region (logical, global coordinates) to pixels relative to this
+    // output. None if the region isn't entirely within this output
+    fn region_to_roi(&self, (x, y, w, h): (i32, i32, u32, u32)) -> Option<Rect> {
+        let w = w as i32;
+        let h = h as i32;
+        let within = x >= self.loc.0 && x + w <= self.loc.0 + self.logical_size.0 && // x within
+            y >= self.loc.1 && y + h <= self.loc.1 + self.logical_size.1; // y within
+
+        // convert both edges rather than the size so adjacent regions share a pixel boundary
+        within.then(|| {
+            let (x0, y0) = (
+                self.logical_to_pixel(x - self.loc.0),
+                self.logical_to_pixel(y - self.loc.1),
+            );
+            let (x1, y1) = (
+                self.logical_to_pixel(x + w - self.loc.0),
+                self.logical_to_pixel(y + h - self.loc.1),
+            );
+            Rect::new((x0, y0), (x1 - x0, y1 - y0))
+        })
+    }
+}
+
+impl OutputInfo {
+    // like region_to_roi, but only the part of the region on this output. None if none of it is
+    fn clip_region_to_roi(&self, (x, y, w, h): (i32, i32, u32, u32)) -> Option<Rect> {
+        let x0 = x.max(self.loc.0);
+        let y0 = y.max(self.loc.1);
+        let x1 = (x + w as i32).min(self.loc.0 + self.logical_size.0);
+        let y1 = (y + h as i32).min(self.loc.1 + self.logical_size.1);
+        if x1 <= x0 || y1 <= y0 {
+            return None;
+        }
+        self.region_to_roi((x0, y0, (x1 - x0) as u32, (y1 - y0) as u32))
+    }
+
+    // --exclude-region, on this output
+    fn exclusions(&self, args: &Args) -> Exclusions {
+        Exclusions {
+            regions: args
+                .exclude_region
+                .iter()
+                .filter_map(|g| self.clip_region_to_roi(g.resolve(self.loc, self.logical_size)))
+                .collect(),
+            style: args.exclude_style,
+        }
+    }
 }
 
 #[derive(Hash, PartialEq, Eq, Clone)]
@@ -531,16 +1568,39 @@ extern "C" {
 }
 
 struct State<S: CaptureSource> {
+    conn: Connection,
     in_flight_surface: InFlightSurface<S>,
     dma: ZwpLinuxDmabufV1,
     enc: EncConstructionStage<S>,
     starting_timestamp: Option<i64>,
-    fps_counter: FpsCounter,
+    pacing: Pacing,
     args: Args,
     quit_flag: Arc<AtomicUsize>,
     sigusr1_flag: Arc<AtomicBool>,
+    metrics: Arc<Metrics>,
     gm: GlobalList,
     xdg_output_manager: ZxdgOutputManagerV1,
+    output_management: Option<OutputManagement>,
+    frame_log: Option<FrameLog>,
+    cursor_log: Option<CursorLog>,
+    frame_damage: Vec<Rect>, // damage the compositor sent for the in-flight frame, in buffer coordinates
+    motion: Option<MotionEstimate>,
+    dedupe: Option<FrameDedupe>,
+    schedule: Schedule,
+    fps_limit: Option<FpsLimit>,
+    capture_pacing: Option<CapturePacing>,
+    timer: Timer,
+    capture_due: Option<i64>, // the next copy is scheduled on the timer
+    debounced: Option<Debounced>,
+    battery: Option<BatteryPolicy>,
+    session_locked: Option<Arc<AtomicBool>>,
+    pending_output: Option<String>, // set-output was received, but a frame is being allocated
+    workspace_output: Option<Arc<Mutex<Option<String>>>>, // output showing --workspace
+    app_focused: Option<Arc<AtomicBool>>, // --only-while-focused app has focus
+    idle: Option<IdlePause>,
+    pauses: Pauses,
+    capture_errors: u32,                // copies failed in a row
+    capture_failing_since: Option<i64>, // CLOCK_MONOTONIC time of the first of them
 }
 
 enum InFlightSurface<S: CaptureSource> {
@@ -549,9 +1609,7 @@ enum InFlightSurface<S: CaptureSource> {
     Allocd(S::Frame),
     CopyQueued {
         av_surface: frame::Video,
-        av_mapping: video::Video,
         wl_frame: S::Frame,
-        wl_buffer: WlBuffer,
     },
 }
 impl<S: CaptureSource> InFlightSurface<S> {
@@ -575,6 +1633,7 @@ struct CompleteState<S> {
 struct OutputWentAwayState {
     enc: EncState,
     waiting_for_output_name: String,
+    switching: bool, // moving to a different output because of set-output, rather than waiting for the old one to come back
     partial_outputs: HashMap<TypedObjectId<WlOutput>, PartialOutputInfo>, // key is xdg-output name (wayland object ID)
 }
 
@@ -582,6 +1641,7 @@ enum EncConstructionStage<S> {
     ProbingOutputs(ProbingOutputsState),
     EverythingButFormat {
         roi: Rect,
+        composite: Option<Composite>,
         cap: S,
         output: OutputInfo,
     },
@@ -617,7 +1677,7 @@ impl<S> EncConstructionStage<S> {
 }
 
 enum HistoryState {
-    RecordingHistory(Duration, VecDeque<Packet>), // --history specified, but SIGUSR1 not received yet. State is (duration of history, history)
+    RecordingHistory(Vec<Duration>, HistoryBuffer), // --history specified, but SIGUSR1 not received yet. State is (how much of each stream to keep, indexed by stream, history)
     Recording(i64), // --history not specified OR (--history specified and SIGUSR1 has been sent). Data is the PTS offset (in nanoseconds), which is required when using history. If a stream is not present, then assume 0 offset
 }
 
@@ -672,7 +1732,7 @@ impl<S: CaptureSource + 'static> Dispatch<WlRegistry, GlobalListContents> for St
         qhandle: &QueueHandle<Self>,
     ) {
         use wayland_client::protocol::wl_registry::Event;
-        debug!("wl-registry event: {:?}", event);
+        debug!(target: log_spec::WAYLAND, "wl-registry event: {:?}", event);
         match event {
             Event::GlobalRemove { name } => {
                 if let EncConstructionStage::Complete(c) = &mut state.enc {
@@ -706,6 +1766,19 @@ impl<S: CaptureSource + 'static> Dispatch<WlRegistry, GlobalListContents> for St
     }
 }
 
+// used to wake up the event loop from other threads, see `ControlServer::start`
+impl<S: CaptureSource> Dispatch<WlCallback, ()> for State<S> {
+    fn event(
+        _state: &mut Self,
+        _proxy: &WlCallback,
+        _event: <WlCallback as Proxy>::Event,
+        _data: &(),
+        _conn: &Connection,
+        _qhandle: &QueueHandle<Self>,
+    ) {
+    }
+}
+
 impl<S: CaptureSource> Dispatch<ZwpLinuxDmabufV1, ()> for State<S> {
     fn event(
         _state: &mut Self,
@@ -739,7 +1812,7 @@ impl<S: CaptureSource + 'static> Dispatch<ZxdgOutputV1, TypedObjectId<WlOutput>>
         _conn: &Connection,
         qhandle: &QueueHandle<Self>,
     ) {
-        debug!("zxdg-output event: {:?} {event:?}", proxy.id());
+        debug!(target: log_spec::WAYLAND, "zxdg-output event: {:?} {event:?}", proxy.id());
         match event {
             zxdg_output_v1::Event::Name { name } => {
                 state.update_output_info_wl_output(out_id, |info| info.name = Some(name));
@@ -769,7 +1842,7 @@ impl<S: CaptureSource + 'static> Dispatch<WlOutput, ()> for State<S> {
         _conn: &Connection,
         qhandle: &QueueHandle<Self>,
     ) {
-        debug!("wl-output event: {:?} {event:?}", proxy.id());
+        debug!(target: log_spec::WAYLAND, "wl-output event: {:?} {event:?}", proxy.id());
         let id = TypedObjectId::new(proxy);
         match event {
             wl_output::Event::Mode {
@@ -839,6 +1912,7 @@ impl<S: CaptureSource + 'static> State<S> {
         args: Args,
         quit_flag: Arc<AtomicUsize>,
         sigusr1_flag: Arc<AtomicBool>,
+        metrics: Arc<Metrics>,
     ) -> anyhow::Result<(Self, EventQueue<Self>)> {
         let display = conn.display();
 
@@ -855,6 +1929,8 @@ impl<S: CaptureSource + 'static> State<S> {
             .bind(&eq, 3..=ZxdgOutputManagerV1::interface().version, ())
             .context("your compositor does not support zxdg-output-manager and therefore is not support by wl-screenrec. See the README for supported compositors")?;
 
+        let output_management = OutputManagement::bind(&gm, &eq);
+
         let mut partial_outputs = HashMap::new();
         for g in gm.contents().clone_list() {
             if g.interface == WlOutput::interface().name {
@@ -882,8 +1958,51 @@ impl<S: CaptureSource + 'static> State<S> {
             }
         }
 
+        let frame_log = match &args.frame_log {
+            Some(path) => Some(
+                FrameLog::create(path)
+                    .with_context(|| format!("failed to create frame log {}", path.display()))?,
+            ),
+            None => None,
+        };
+        let cursor_log = match &args.cursor_log {
+            Some(path) => Some(
+                CursorLog::create(path)
+                    .with_context(|| format!("failed to create cursor log {}", path.display()))?,
+            ),
+            None => None,
+        };
+
+        let schedule = Schedule::new(args.start_at, args.stop_at);
+        let fps_limit = args
+            .max_fps
+            .map(|fps| FpsLimit::new(fps, args.max_fps_mode));
+        let capture_pacing = CapturePacing::new(args.capture_fps, args.align_to_vblank);
+        let motion = args.adaptive_quality.map(MotionEstimate::new);
+        let dedupe = args.dedupe_frames.then(FrameDedupe::new);
+        let battery = args
+            .on_battery
+            .clone()
+            .map(|o| BatteryPolicy::new(o, args.max_fps, args.bitrate));
+        let session_locked = args.on_lock.map(|_| session_lock::spawn_lock_watcher());
+        let workspace_output = args
+            .workspace
+            .clone()
+            .map(workspace::spawn_workspace_watcher)
+            .transpose()?;
+        let app_focused = args
+            .only_while_focused
+            .clone()
+            .map(app_focus::spawn_app_focus_watcher)
+            .transpose()?;
+        let idle = args
+            .pause_after_idle
+            .map(|timeout| IdlePause::bind(&gm, &eq, timeout, capture_error::monotonic_ns()))
+            .transpose()?;
+
         Ok((
             State {
+                conn: conn.clone(),
                 in_flight_surface: InFlightSurface::None,
                 dma,
                 enc: EncConstructionStage::ProbingOutputs(ProbingOutputsState {
@@ -891,12 +2010,34 @@ impl<S: CaptureSource + 'static> State<S> {
                     outputs: HashMap::new(),
                 }),
                 starting_timestamp: None,
-                fps_counter: FpsCounter::new(),
+                pacing: Pacing::new(),
                 args,
                 quit_flag,
                 sigusr1_flag,
+                metrics,
                 gm,
                 xdg_output_manager,
+                output_management,
+                frame_log,
+                cursor_log,
+                frame_damage: Vec::new(),
+                motion,
+                dedupe,
+                schedule,
+                fps_limit,
+                capture_pacing,
+                timer: Timer::new(event_loop_waker(conn, eq.clone())),
+                capture_due: None,
+                debounced: None,
+                battery,
+                session_locked,
+                workspace_output,
+                app_focused,
+                idle,
+                pending_output: None,
+                pauses: Pauses::default(),
+                capture_errors: 0,
+                capture_failing_since: None,
             },
             queue,
         ))
@@ -908,6 +2049,10 @@ impl<S: CaptureSource + 'static> State<S> {
             InFlightSurface::AllocQueued
         ));
         self.in_flight_surface = InFlightSurface::Allocd(frame.clone());
+        if self.pending_output.is_some() {
+            self.switch_output_if_pending(qhandle);
+            return;
+        }
 
         match &mut self.enc {
             EncConstructionStage::ProbingOutputs { .. } => unreachable!(
@@ -933,39 +2078,50 @@ impl<S: CaptureSource + 'static> State<S> {
         };
 
         let av_surface = enc.frames_rgb.alloc().unwrap();
+        enc.stats.surfaces_allocd += 1;
+
+        // the pool hands out the same few surfaces over and over, so each one is only mapped and
+        // imported by the compositor the first time
+        let wl_buffer = enc
+            .capture_buffers
+            .entry(surface_id(&av_surface))
+            .or_insert_with(|| {
+                let (desc, av_mapping) = map_drm(&av_surface);
+
+                let modifier = desc.objects[0].format_modifier.to_be_bytes();
+                let stride = desc.layers[0].planes[0].pitch as u32;
+                let fd = unsafe { BorrowedFd::borrow_raw(desc.objects[0].fd) };
+
+                let wl_buffer_params = self.dma.create_params(qhandle, ());
+                wl_buffer_params.add(
+                    fd,
+                    0,
+                    0,
+                    stride,
+                    u32::from_be_bytes(modifier[..4].try_into().unwrap()),
+                    u32::from_be_bytes(modifier[4..].try_into().unwrap()),
+                );
 
-        let (desc, av_mapping) = map_drm(&av_surface);
-
-        let modifier = desc.objects[0].format_modifier.to_be_bytes();
-        let stride = desc.layers[0].planes[0].pitch as u32;
-        let fd = unsafe { BorrowedFd::borrow_raw(desc.objects[0].fd) };
-
-        let wl_buffer_params = self.dma.create_params(qhandle, ());
-        wl_buffer_params.add(
-            fd,
-            0,
-            0,
-            stride,
-            u32::from_be_bytes(modifier[..4].try_into().unwrap()),
-            u32::from_be_bytes(modifier[4..].try_into().unwrap()),
-        );
-
-        let wl_buffer = wl_buffer_params.create_immed(
-            enc.selected_format.width,
-            enc.selected_format.height,
-            enc.selected_format.fourcc as u32,
-            zwp_linux_buffer_params_v1::Flags::empty(),
-            qhandle,
-            (),
-        );
+                let wl_buffer = wl_buffer_params.create_immed(
+                    enc.selected_format.width,
+                    enc.selected_format.height,
+                    enc.selected_format.fourcc as u32,
+                    zwp_linux_buffer_params_v1::Flags::empty(),
+                    qhandle,
+                    (),
+                );
+                // the fd is sent with the request, the compositor keeps its own import of it
+                drop(av_mapping);
+                wl_buffer
+            });
 
-        cap.queue_copy(self.args.damage, &wl_buffer, frame);
+        self.frame_damage.clear();
+        cap.queue_copy(self.args.damage, wl_buffer, frame);
+        enc.stats.copies_queued += 1;
 
         self.in_flight_surface = InFlightSurface::CopyQueued {
             av_surface,
-            av_mapping,
             wl_frame: frame.clone(),
-            wl_buffer,
         };
     }
 
@@ -977,19 +2133,25 @@ impl<S: CaptureSource + 'static> State<S> {
         if new_format == cs.enc.selected_format {
             return Ok(cs);
         }
-        info!("compositor gave new format {new_format:?}");
+        info!(target: log_spec::WAYLAND, "compositor gave new format {new_format:?}");
 
         // destroy old frames
+        if self.debounced.take().is_some() {
+            self.metrics.frame_dropped();
+            if let Some(log) = &mut self.frame_log {
+                log.dropped("format_change");
+            }
+        }
         match &self.in_flight_surface {
             InFlightSurface::Allocd(_) => {} // these frames are format independent, the previously allocated one is fine
-            InFlightSurface::CopyQueued {
-                wl_frame,
-                wl_buffer,
-                ..
-            } => {
+            InFlightSurface::CopyQueued { wl_frame, .. } => {
                 cs.cap.on_done_with_frame(wl_frame.clone());
-                wl_buffer.destroy();
                 self.in_flight_surface = InFlightSurface::None;
+                cs.enc.stats.copies_failed += 1;
+                self.metrics.frame_dropped();
+                if let Some(log) = &mut self.frame_log {
+                    log.dropped("format_change");
+                }
             }
             InFlightSurface::None => {}
             InFlightSurface::AllocQueued => {}
@@ -1006,74 +2168,28 @@ impl<S: CaptureSource + 'static> State<S> {
         if cs.enc.roi_screen_coord.w == 0 || cs.enc.roi_screen_coord.h == 0 {
             bail!("new capture surface is zero-sized, bailing");
         }
+        if let Some(composite) = &mut cs.enc.composite {
+            for region in &mut composite.regions {
+                *region = region.fit_inside_bounds(new_format.width, new_format.height);
+                if region.w == 0 || region.h == 0 {
+                    bail!("a --geometry region is outside the new capture surface, bailing");
+                }
+            }
+        }
 
-        cs.enc.frames_rgb = cs.enc.hw_device_ctx
-            .create_frame_ctx(capture_pixfmt, new_format.width, new_format.height, new_format.modifier)
+        cs.enc.clear_capture_buffers();
+        cs.enc.frames_rgb = cs.enc.capture_device.as_mut().unwrap_or(&mut cs.enc.hw_device_ctx)
+            .create_frame_ctx(capture_pixfmt, new_format.width, new_format.height, new_format.modifier, Usage::Capture)
             .with_context(|| format!("Failed to create vaapi frame context for capture surfaces of format {capture_pixfmt:?} {new_format:?}"))?;
 
-        // todo: proper size here
-        let enc_pixfmt_av = match cs.enc.enc_pixfmt {
-            EncodePixelFormat::Vaapi(fmt) => fmt,
-            EncodePixelFormat::Sw(fmt) => fmt,
-        };
-
         cs.enc.selected_format = new_format;
-
-        // flush old filter & encoder
-        cs.enc
-            .video_filter
-            .get("in")
-            .unwrap()
-            .source()
-            .flush()
-            .unwrap();
-        cs.enc.process_ready();
-        if cs.enc.enc_video_has_been_fed_any_frames {
-            // ffmpeg bug--if you call send_eof before feeding any frames it will crash
-            cs.enc.enc_video.send_eof().unwrap();
+        if mem::take(&mut cs.enc.keep_encode_size) {
+            // the file started at another size, the new output is fit into that
+            let roi = cs.enc.roi_screen_coord;
+            cs.enc.set_roi(roi)?;
+        } else {
+            cs.enc.rebuild_encoder(&self.args)?;
         }
-        cs.enc.process_ready();
-
-        // create a new encoder
-        // TODO: correct scaling
-        let mut frames_yuv = cs.enc.hw_device_ctx
-            .create_frame_ctx(enc_pixfmt_av, cs.enc.roi_screen_coord.w, cs.enc.roi_screen_coord.h, DrmModifier::LINEAR)
-            .with_context(|| {
-                format!("Failed to create a vaapi frame context for encode surfaces of format {enc_pixfmt_av:?} {}x{}", cs.enc.roi_screen_coord.w, cs.enc.roi_screen_coord.h)
-            })?;
-
-        let encoder = cs.enc.enc_video.codec().unwrap();
-        let framerate = cs.enc.enc_video.frame_rate();
-        let global_header = cs
-            .enc
-            .octx
-            .format()
-            .flags()
-            .contains(format::Flags::GLOBAL_HEADER);
-        let enc = make_video_params(
-            &self.args,
-            cs.enc.enc_pixfmt,
-            &encoder,
-            (cs.enc.roi_screen_coord.w, cs.enc.roi_screen_coord.h),
-            framerate,
-            global_header,
-            &mut cs.enc.hw_device_ctx,
-            &mut frames_yuv,
-        )?;
-
-        cs.enc.enc_video = enc.open_with(cs.enc.enc_video_options.clone())?;
-        cs.enc.enc_video_has_been_fed_any_frames = false;
-
-        let (filter, filter_timebase) = video_filter(
-            &mut cs.enc.frames_rgb,
-            cs.enc.enc_pixfmt,
-            (new_format.width, new_format.height),
-            cs.enc.roi_screen_coord,
-            (cs.enc.roi_screen_coord.w, cs.enc.roi_screen_coord.h),
-            cs.enc.transform,
-        );
-        cs.enc.video_filter = filter;
-        cs.enc.filter_output_timebase = filter_timebase;
         cs.enc.format_change = true;
 
         Ok(cs)
@@ -1125,6 +2241,7 @@ impl<S: CaptureSource + 'static> State<S> {
 
         if output.name.is_none() {
             warn!(
+                target: log_spec::WAYLAND,
                 "compositor did not provide name for wl_output {}, strange",
                 id.0.protocol_id()
             );
@@ -1143,12 +2260,25 @@ impl<S: CaptureSource + 'static> State<S> {
                 if let Some(info) = complete_output {
                     if info.name == output_went_away_state.waiting_for_output_name {
                         info!(
+                            target: log_spec::WAYLAND,
                             "output {} came back, continuing screenrecording..",
                             info.name
                         );
-                        let enc = mem::replace(&mut self.enc, EncConstructionStage::Intermediate)
-                            .take_enc();
-                        let cap = S::new(&self.gm, qhandle, info.output.clone()).unwrap();
+                        let switching = output_went_away_state.switching;
+                        let mut enc =
+                            mem::replace(&mut self.enc, EncConstructionStage::Intermediate)
+                                .take_enc();
+                        if switching {
+                            if let Err(e) = enc.move_to_output(&info, &self.args) {
+                                error!(
+                                    target: log_spec::WAYLAND,
+                                    "failed to move recording to {}: {e:?}", info.name
+                                );
+                                self.quit_flag.store(1, SeqCst);
+                                return;
+                            }
+                        }
+                        let cap = self.new_capture(info.output.clone(), qhandle).unwrap();
                         self.enc = EncConstructionStage::Complete(CompleteState {
                             enc,
                             cap,
@@ -1173,6 +2303,7 @@ impl<S: CaptureSource + 'static> State<S> {
         if p.outputs.len() != p.partial_outputs.len() {
             // probe not complete
             debug!(
+                target: log_spec::WAYLAND,
                 "output probe not yet complete, still waiting for {}",
                 p.partial_outputs
                     .iter()
@@ -1184,11 +2315,71 @@ impl<S: CaptureSource + 'static> State<S> {
             return;
         }
 
-        info!("output probe complete: {:?}", p.outputs);
+        info!(target: log_spec::WAYLAND, "output probe complete: {:?}", p.outputs);
 
         let enabled_outputs: Vec<_> = p.outputs.iter().flat_map(|(_, o)| o).collect();
 
-        let (output, roi) = match (self.args.geometry, self.args.output.as_str()) {
+        // --outputs: the first is recorded like --output, the others are stacked after it
+        let mut others = Vec::new();
+        let mut zoom = 1.;
+        if let Some(list) = &self.args.outputs {
+            let selected: Option<Vec<_>> = if list == "all" {
+                let mut all = enabled_outputs.clone();
+                all.sort_by_key(|o| o.loc);
+                Some(all)
+            } else {
+                list.split(',')
+                    .map(|name| enabled_outputs.iter().find(|o| o.name == name).copied())
+                    .collect()
+            };
+            let Some(selected) = selected.filter(|s| !s.is_empty()) else {
+                eprintln!(
+                    "not all of --outputs {list} are enabled (enabled displays are {}), bailing",
+                    enabled_outputs
+                        .iter()
+                        .map(|o| o.name.as_str())
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                );
+                self.quit_flag.store(1, Ordering::SeqCst);
+                return;
+            };
+            let scale_120 = selected.iter().map(|o| o.scale_120()).max().unwrap();
+            zoom = scale_120 as f64 / selected[0].scale_120() as f64;
+            others = selected[1..]
+                .iter()
+                .map(|o| {
+                    OtherOutput::new(
+                        o.name.clone(),
+                        o.output.clone(),
+                        o.size_screen_space(),
+                        o.transform,
+                        scale_120 as f64 / o.scale_120() as f64,
+                    )
+                })
+                .collect();
+            self.args.output = selected[0].name.clone();
+        }
+
+        if output_list::is_list(&self.args.output) {
+            let names: Vec<_> = enabled_outputs.iter().map(|o| o.name.as_str()).collect();
+            let Some(name) = output_list::pick(&self.args.output, &names) else {
+                eprintln!(
+                    "none of --output {} are enabled (enabled displays are {}), bailing",
+                    self.args.output,
+                    names.join(", ")
+                );
+                self.quit_flag.store(1, Ordering::SeqCst);
+                return;
+            };
+            info!(target: log_spec::WAYLAND, "--output {} picked {name}", self.args.output);
+            self.args.output = name.to_owned();
+        }
+
+        let (output, roi) = match (
+            self.args.geometry.first().copied(),
+            self.args.output.as_str(),
+        ) {
             (None, "") => {
                 // default case, capture whole monitor
                 if enabled_outputs.len() != 1 {
@@ -1212,24 +2403,36 @@ impl<S: CaptureSource + 'static> State<S> {
                     return;
                 }
             }
-            (Some((x, y, w, h)), "") => {
-                let w = w as i32;
-                let h = h as i32;
+            (Some(geometry @ Geometry::Anchored(..)), disp) => {
+                // anchored --geometry, on --output or the only display
+                let output = match disp {
+                    "" if enabled_outputs.len() == 1 => Some(enabled_outputs[0]),
+                    "" => {
+                        eprintln!("multiple enabled displays and an anchored --geometry, pass --output to pick one. bailing");
+                        self.quit_flag.store(1, Ordering::SeqCst);
+                        return;
+                    }
+                    disp => enabled_outputs.iter().find(|i| i.name == disp).copied(),
+                };
+                let Some(output) = output else {
+                    eprintln!("display {} not found, bailing", disp);
+                    self.quit_flag.store(1, Ordering::SeqCst);
+                    return;
+                };
+                let region = geometry.resolve(output.loc, output.logical_size);
+                info!(
+                    target: log_spec::WAYLAND,
+                    "--geometry {geometry} is {region:?} on {}", output.name
+                );
+                (output, output.region_to_roi(region).unwrap())
+            }
+            (Some(Geometry::Absolute(geometry @ (x, y, w, h))), "") => {
                 // --geometry but no --output
-                if let Some(&output) = enabled_outputs.iter().find(|i| {
-                    x >= i.loc.0 && x + w <= i.loc.0 + i.logical_size.0 && // x within
-                        y >= i.loc.1 && y + h <= i.loc.1 + i.logical_size.1 // y within
-                }) {
-                    (
-                        output,
-                        Rect::new(
-                            (
-                                output.logical_to_pixel(x - output.loc.0),
-                                output.logical_to_pixel(y - output.loc.1),
-                            ),
-                            (output.logical_to_pixel(w), output.logical_to_pixel(h)),
-                        ),
-                    )
+                if let Some((output, roi)) = enabled_outputs
+                    .iter()
+                    .find_map(|i| Some((*i, i.region_to_roi(geometry)?)))
+                {
+                    (output, roi)
                 } else {
                     eprintln!(
                         "region {},{} {}x{} is not entirely within one output, bailing",
@@ -1248,9 +2451,52 @@ impl<S: CaptureSource + 'static> State<S> {
             }
         };
 
-        info!("Using output {}", output.name);
+        // the other --geometry regions are resolved on the same output as the first
+        let composite = if self.args.geometry.len() > 1 {
+            let regions: Option<Vec<_>> = self
+                .args
+                .geometry
+                .iter()
+                .map(|g| output.region_to_roi(g.resolve(output.loc, output.logical_size)))
+                .collect();
+            let Some(regions) = regions else {
+                eprintln!(
+                    "every --geometry region must be entirely within {}, the output of the first one, bailing",
+                    output.name
+                );
+                self.quit_flag.store(1, Ordering::SeqCst);
+                return;
+            };
+            Some(Composite {
+                regions,
+                layout: self.args.layout,
+                others: Vec::new(),
+                zoom: 1.,
+            })
+        } else if !others.is_empty() {
+            Some(Composite {
+                regions: vec![roi],
+                layout: self.args.layout,
+                others,
+                zoom,
+            })
+        } else {
+            None
+        };
+        let roi = composite.as_ref().map_or(roi, Composite::bounds);
+
+        info!(target: log_spec::WAYLAND, "Using output {}", output.name);
 
-        let cap = match S::new(&self.gm, qhandle, output.output.clone()) {
+        if let Some(cmd) = &self.args.exec_before {
+            hooks::run(
+                "exec-before",
+                cmd,
+                &HookEnv::new(&self.args, &output.name),
+                None,
+            );
+        }
+
+        let cap = match self.new_capture(output.output.clone(), qhandle) {
             Ok(cap) => cap,
             Err(err) => {
                 eprintln!("failed to create capture state: {}", err);
@@ -1260,6 +2506,7 @@ impl<S: CaptureSource + 'static> State<S> {
         };
         self.enc = EncConstructionStage::EverythingButFormat {
             roi,
+            composite,
             cap,
             output: output.clone(),
         };
@@ -1267,6 +2514,21 @@ impl<S: CaptureSource + 'static> State<S> {
         self.queue_alloc_frame(qhandle);
     }
 
+    fn new_capture(&self, output: WlOutput, qhandle: &QueueHandle<Self>) -> anyhow::Result<S> {
+        let paint_cursor = !self.args.no_cursor && !self.args.composite_cursor;
+        let mut cap = S::new(&self.gm, qhandle, output, paint_cursor)?;
+        if self.args.roi_cursor.is_some()
+            || self.args.cursor_log.is_some()
+            || self.args.composite_cursor
+        {
+            cap.track_cursor(&self.gm, qhandle)?;
+        }
+        if self.args.composite_cursor {
+            cap.capture_cursor_image(&self.gm, qhandle)?;
+        }
+        Ok(cap)
+    }
+
     fn on_copy_complete(
         &mut self,
         qhandle: &QueueHandle<Self>,
@@ -1274,47 +2536,293 @@ impl<S: CaptureSource + 'static> State<S> {
         tv_sec_lo: u32,
         tv_nsec: u32,
     ) {
-        let CompleteState { enc, cap, .. } = self.enc.unwrap();
+        let CompleteState {
+            enc, cap, output, ..
+        } = self.enc.unwrap();
 
-        self.fps_counter.on_frame();
+        enc.stats.copies_completed += 1;
+        self.metrics.frame_captured();
 
-        let mut surf = if let InFlightSurface::CopyQueued {
+        let surf = if let InFlightSurface::CopyQueued {
             av_surface,
-            av_mapping,
             wl_frame,
-            wl_buffer,
         } = self.in_flight_surface.take()
         {
-            drop(av_mapping);
             cap.on_done_with_frame(wl_frame);
-            wl_buffer.destroy();
             av_surface
         } else {
             panic!("on_copy_complete called in a strange state")
         };
 
-        let secs = (i64::from(tv_sec_hi) << 32) + i64::from(tv_sec_lo);
-        let pts_abs = secs * 1_000_000_000 + i64::from(tv_nsec);
+        let pts_abs = presentation_ns(tv_sec_hi, tv_sec_lo, tv_nsec);
+        self.pacing.on_capture(pts_abs);
+        if let Some(pacing) = &mut self.capture_pacing {
+            pacing.presented(pts_abs, enc.framerate);
+        }
+
+        if self.capture_failing_since.take().is_some() {
+            info!(
+                target: log_spec::WAYLAND,
+                "capture recovered after {} failed copies",
+                self.capture_errors
+            );
+            self.pauses
+                .resume(PauseReason::CaptureError, pts_abs, enc.audio.as_mut());
+        }
+        self.capture_errors = 0;
+
+        if !self.schedule.started() {
+            trace!(target: log_spec::ENCODE, "before --start-at time, dropping frame");
+            self.queue_alloc_frame(qhandle);
+            return;
+        }
+
+        if let Some((max_fps, bitrate)) = self.battery.as_mut().and_then(|b| b.poll()) {
+            self.fps_limit = max_fps.map(|fps| FpsLimit::new(fps, self.args.max_fps_mode));
+            if bitrate != self.args.bitrate {
+                self.args.bitrate = bitrate;
+                if let Err(e) = enc.rebuild_encoder(&self.args) {
+                    error!(
+                        target: log_spec::ENCODE,
+                        "failed to reopen encoder with new bitrate: {e:?}"
+                    );
+                    self.quit_flag.store(1, Ordering::SeqCst);
+                    return;
+                }
+            }
+        }
+
+        if let Some(limit) = &mut self.fps_limit {
+            if !limit.should_keep(pts_abs) {
+                trace!(target: log_spec::ENCODE, "dropping frame for --max-fps");
+                self.queue_alloc_frame(qhandle);
+                return;
+            }
+        }
+
+        if let Some(dedupe) = &mut self.dedupe {
+            let region = enc.roi_screen_coord.screen_to_frame(
+                enc.selected_format.width,
+                enc.selected_format.height,
+                enc.transform,
+            );
+            if dedupe.is_duplicate(&surf, region) {
+                trace!(target: log_spec::ENCODE, "dropping duplicate frame");
+                self.metrics.frame_dropped();
+                if let Some(log) = &mut self.frame_log {
+                    log.dropped("duplicate");
+                }
+                self.queue_alloc_frame(qhandle);
+                return;
+            }
+        }
 
         if self.starting_timestamp.is_none() {
             self.starting_timestamp = Some(pts_abs);
+            self.pauses.started(pts_abs);
 
             // start audio when we get the first timestamp so it's properly sync'd
             if let Some(audio) = &mut enc.audio {
                 audio.start();
             }
         }
-        let pts = pts_abs - self.starting_timestamp.unwrap();
+
+        // a captured frame means the screen changed
+        if self
+            .idle
+            .as_mut()
+            .is_some_and(|i| i.tracker.activity(pts_abs, true).is_some())
+        {
+            info!(target: log_spec::ENCODE, "screen changed, resuming after idle");
+            self.pauses
+                .resume(PauseReason::Idle, pts_abs, enc.audio.as_mut());
+        }
+
+        if let Some(locked) = &self.session_locked {
+            if locked.load(Ordering::SeqCst) {
+                // with --on-lock slate, this frame is kept and shown for SLATE_DURATION
+                let slate_ns = match self.args.on_lock {
+                    Some(LockAction::Slate) => session_lock::SLATE_DURATION.as_nanos() as i64,
+                    _ => 0,
+                };
+                let started =
+                    self.pauses
+                        .pause(PauseReason::Locked, pts_abs + slate_ns, enc.audio.as_mut());
+                if !started || slate_ns == 0 {
+                    self.metrics.frame_dropped();
+                    if let Some(log) = &mut self.frame_log {
+                        log.dropped("session_locked");
+                    }
+                    self.queue_alloc_frame(qhandle);
+                    return;
+                }
+            } else {
+                self.pauses
+                    .resume(PauseReason::Locked, pts_abs, enc.audio.as_mut());
+            }
+        }
+
+        if let Some(shown_on) = &self.workspace_output {
+            if shown_on.lock().unwrap().as_deref() != Some(output.name.as_str()) {
+                self.pauses
+                    .pause(PauseReason::OtherWorkspace, pts_abs, enc.audio.as_mut());
+                self.metrics.frame_dropped();
+                if let Some(log) = &mut self.frame_log {
+                    log.dropped("other_workspace");
+                }
+                self.queue_alloc_frame(qhandle);
+                return;
+            }
+            self.pauses
+                .resume(PauseReason::OtherWorkspace, pts_abs, enc.audio.as_mut());
+        }
+
+        if let Some(focused) = &self.app_focused {
+            if !focused.load(Ordering::SeqCst) {
+                self.pauses
+                    .pause(PauseReason::Unfocused, pts_abs, enc.audio.as_mut());
+                self.metrics.frame_dropped();
+                if let Some(log) = &mut self.frame_log {
+                    log.dropped("unfocused");
+                }
+                self.queue_alloc_frame(qhandle);
+                return;
+            }
+            self.pauses
+                .resume(PauseReason::Unfocused, pts_abs, enc.audio.as_mut());
+        }
+
+        let mut damage = mem::take(&mut self.frame_damage);
+        if let Some(debounce) = self.args.damage_debounce {
+            let debounce = debounce.as_nanos() as i64;
+            let now = capture_error::monotonic_ns();
+            let since_ns = match self.debounced.take() {
+                Some(held) => {
+                    trace!(target: log_spec::ENCODE, "dropping frame for --damage-debounce");
+                    self.metrics.frame_dropped();
+                    if let Some(log) = &mut self.frame_log {
+                        log.dropped("debounced");
+                    }
+                    damage.extend(held.damage);
+                    held.since_ns
+                }
+                None => now,
+            };
+            let due_ns = (now + debounce).min(since_ns + DEBOUNCE_MAX_WAIT * debounce);
+            self.debounced = Some(Debounced {
+                surf,
+                pts_abs,
+                damage,
+                since_ns,
+                due_ns,
+            });
+            self.timer.wake_at(due_ns);
+            self.queue_alloc_frame(qhandle);
+            return;
+        }
+
+        if let Err(e) = self.encode_frame(surf, pts_abs, &damage) {
+            error!(target: log_spec::ENCODE, "{e:?}");
+            self.quit_flag.store(1, SeqCst);
+            return;
+        }
+
+        self.queue_alloc_frame(qhandle);
+    }
+
+    // the part of on_copy_complete after it's decided to keep the frame
+    fn encode_frame(
+        &mut self,
+        mut surf: frame::Video,
+        pts_abs: i64,
+        damage: &[Rect],
+    ) -> anyhow::Result<()> {
+        let started = Instant::now();
+        let CompleteState { enc, cap, .. } = self.enc.unwrap();
+
+        let pts = pts_abs - self.starting_timestamp.unwrap() - self.pauses.cut_ns();
+        match enc.frame_timing.advance(pts) {
+            Ok(Some((prev_pts, duration))) => enc.on_frame_duration(prev_pts, duration),
+            Ok(None) => {}
+            Err(e) => {
+                warn!(target: log_spec::ENCODE, "dropping frame: {e}");
+                self.metrics.frame_dropped();
+                if let Some(log) = &mut self.frame_log {
+                    log.dropped("non_monotonic_pts");
+                }
+                return Ok(());
+            }
+        }
         surf.set_pts(Some(pts));
 
+        if let Some(box_size) = self.args.roi_cursor {
+            enc.set_cursor(cap.cursor_position(), box_size);
+        }
+        if let Some(cursor) = &mut enc.cursor_overlay {
+            if let Some(image) = cap.take_cursor_image() {
+                cursor.set_image(&image);
+            }
+            cursor.set_position(cap.cursor_position());
+        }
+        if let Some(log) = &mut self.cursor_log {
+            log.frame(
+                pts,
+                cap.cursor_position(),
+                enc.roi_screen_coord,
+                enc.content(),
+            );
+        }
+        if self.args.damage_hints {
+            enc.set_damage(damage);
+        }
+        if let Some(motion) = &mut self.motion {
+            // overlapping damage is counted twice, close enough for an estimate
+            let damaged_px: i64 = damage.iter().map(|r| i64::from(r.w) * i64::from(r.h)).sum();
+            let frame_px =
+                i64::from(enc.selected_format.width) * i64::from(enc.selected_format.height);
+            let qoffset = motion.on_frame(damaged_px as f64 / frame_px as f64);
+            enc.frame_qoffset = (qoffset > 0.).then(|| Rational::from(qoffset));
+        }
+
+        if let Some(log) = &mut self.frame_log {
+            log.frame(pts_abs, pts, !damage.is_empty());
+        }
+
         unsafe {
             (*surf.as_mut_ptr()).time_base.num = 1;
             (*surf.as_mut_ptr()).time_base.den = 1_000_000_000;
         }
 
+        if enc.timecode_rate.is_some() {
+            let age = Duration::from_nanos((capture_error::monotonic_ns() - pts_abs).max(0) as u64);
+            enc.frame_clock = Some((pts, SystemTime::now() - age));
+        }
+        self.pacing.on_encode(pts);
         enc.push(surf);
 
-        self.queue_alloc_frame(qhandle);
+        // the next frame isn't captured until this one's encoded, so taking longer than the time
+        // between frames means missing some
+        let interval_ns = (1_000_000_000 * i64::from(enc.framerate.denominator()))
+            .checked_div(i64::from(enc.framerate.numerator()))
+            .unwrap_or(0)
+            .max(self.fps_limit.as_ref().map_or(0, FpsLimit::interval_ns));
+        enc.stats
+            .frame_encoded(started.elapsed(), Duration::from_nanos(interval_ns as u64));
+        enc.recover_encoder(&self.args)
+    }
+
+    // encodes the frame held back for --damage-debounce, now rather than when it's due
+    fn encode_debounced(&mut self) {
+        let Some(held) = self.debounced.take() else {
+            return;
+        };
+        if !matches!(self.enc, EncConstructionStage::Complete(_)) {
+            return;
+        }
+        if let Err(e) = self.encode_frame(held.surf, held.pts_abs, &held.damage) {
+            error!(target: log_spec::ENCODE, "{e:?}");
+            self.quit_flag.store(1, SeqCst);
+        }
     }
 
     fn on_copy_fail(&mut self, qhandle: &QueueHandle<Self>) {
@@ -1327,45 +2835,74 @@ impl<S: CaptureSource + 'static> State<S> {
 
         if let InFlightSurface::CopyQueued {
             av_surface,
-            av_mapping,
             wl_frame,
-            wl_buffer,
         } = self.in_flight_surface.take()
         {
-            drop(av_mapping);
             cap.on_done_with_frame(wl_frame);
-            wl_buffer.destroy();
-            drop(av_surface);
+            // in case the buffer is what the compositor didn't like, it's imported again next time
+            if let Some(wl_buffer) = enc.capture_buffers.remove(&surface_id(&av_surface)) {
+                wl_buffer.destroy();
+            }
         } else {
             panic!("on_copy_fail called in strange state");
         }
+        enc.stats.copies_failed += 1;
+        self.metrics.frame_dropped();
+
+        if let Some(log) = &mut self.frame_log {
+            log.dropped(if *output_went_away {
+                "output_went_away"
+            } else if enc.format_change {
+                "format_change"
+            } else {
+                "copy_failed"
+            });
+        }
 
         if *output_went_away {
             info!(
+                target: log_spec::WAYLAND,
                 "copy failed because output {} went away. Waiting for it to come back...",
                 output.name
             );
-            let waiting_for_output_name = output.name.clone();
-            let enc = mem::replace(&mut self.enc, EncConstructionStage::Intermediate).take_enc();
-
-            let mut owa = OutputWentAwayState {
-                enc,
-                waiting_for_output_name,
-                partial_outputs: Default::default(),
-            };
-            for g in self.gm.contents().clone_list() {
-                if g.interface == WlOutput::interface().name {
-                    owa.new_wl_output(self.gm.registry(), &self.xdg_output_manager, g, qhandle);
-                }
-            }
-            self.enc = EncConstructionStage::OutputWentAway(owa);
+            let name = output.name.clone();
+            self.wait_for_output(name, false, qhandle);
         } else if enc.format_change {
             enc.format_change = false;
-            debug!("failed transfer, but just did a format change so not surprising. trying to capture a new frame...");
+            debug!(
+                target: log_spec::WAYLAND,
+                "failed transfer, but just did a format change so not surprising. trying to capture a new frame..."
+            );
             self.queue_alloc_frame(qhandle);
         } else {
-            error!("unknown copy fail reason, trying to capture a new frame...");
-            self.queue_alloc_frame(qhandle);
+            self.capture_errors += 1;
+            if self.capture_failing_since.is_none() {
+                let now = capture_error::monotonic_ns();
+                self.capture_failing_since = Some(now);
+                if self.args.on_capture_error == CaptureErrorPolicy::Pause {
+                    self.pauses
+                        .pause(PauseReason::CaptureError, now, enc.audio.as_mut());
+                }
+            }
+
+            if let CaptureErrorPolicy::ExitAfter(n) = self.args.on_capture_error {
+                if self.capture_errors >= n {
+                    error!(target: log_spec::WAYLAND, "{n} copies failed in a row, giving up");
+                    self.quit_flag.store(1, Ordering::SeqCst);
+                    return;
+                }
+            }
+
+            let wait = capture_error::backoff(self.capture_errors);
+            error!(
+                target: log_spec::WAYLAND,
+                "unknown copy fail reason, trying to capture a new frame in {}ms...",
+                wait.as_millis()
+            );
+            // on the timer, see on_timer
+            let at = capture_error::monotonic_ns() + wait.as_nanos() as i64;
+            self.capture_due = Some(at);
+            self.timer.wake_at(at);
         }
     }
 
@@ -1376,13 +2913,19 @@ impl<S: CaptureSource + 'static> State<S> {
         dri_device: Option<&Path>,
         eq: &QueueHandle<State<S>>,
     ) {
-        debug!("Supported capture formats are {w}x{h} {capture_formats:?}");
+        debug!(
+            target: log_spec::WAYLAND,
+            "Supported capture formats are {w}x{h} {capture_formats:?}"
+        );
         let dri_device = if let Some(dev) = &self.args.dri_device {
             Path::new(dev)
         } else if let Some(dev) = dri_device {
             dev
         } else {
-            warn!("dri device could not be auto-detected, using /dev/dri/renderD128. Pass --dri-device if this isn't correct or to suppress this warning");
+            warn!(
+                target: log_spec::WAYLAND,
+                "dri device could not be auto-detected, using /dev/dri/renderD128. Pass --dri-device if this isn't correct or to suppress this warning"
+            );
             Path::new("/dev/dri/renderD128")
         };
 
@@ -1422,29 +2965,64 @@ impl<S: CaptureSource + 'static> State<S> {
         let selected_format = match negotiate_format_impl(w as i32, h as i32, capture_formats) {
             Ok(f) => f,
             Err(e) => {
-                error!("Failed to negotiate format: {e}");
+                error!(target: log_spec::WAYLAND, "Failed to negotiate format: {e}");
                 return;
             }
         };
 
         match mem::replace(&mut self.enc, EncConstructionStage::Intermediate) {
-            EncConstructionStage::EverythingButFormat { output, roi, cap } => {
+            EncConstructionStage::EverythingButFormat {
+                mut output,
+                roi,
+                composite,
+                cap,
+            } => {
+                if let Some(head) = self
+                    .output_management
+                    .as_ref()
+                    .and_then(|om| om.head(&output.name))
+                {
+                    if let Some(refresh) = head.refresh {
+                        output.refresh = refresh;
+                    }
+                    if head.adaptive_sync {
+                        info!(
+                            target: log_spec::WAYLAND,
+                            "adaptive sync is enabled on {}, frames will arrive at up to {} fps",
+                            output.name,
+                            f64::from(output.refresh)
+                        );
+                    }
+                }
+
                 let enc = match EncState::new(
                     &self.args,
                     selected_format,
                     output.refresh,
                     output.transform,
                     roi,
+                    composite,
+                    output.exclusions(&self.args),
                     Arc::clone(&self.sigusr1_flag),
+                    Arc::clone(&self.metrics),
                     dri_device,
+                    capture_formats,
                 ) {
                     Ok(enc) => enc,
                     Err(e) => {
-                        error!("failed to create encoder(s): {e}");
+                        error!(target: log_spec::ENCODE, "failed to create encoder(s): {e}");
                         self.quit_flag.store(1, SeqCst);
                         return;
                     }
                 };
+                if let Err(e) = self.start_other_outputs(&enc, eq) {
+                    error!(target: log_spec::WAYLAND, "{e:?}");
+                    self.quit_flag.store(1, SeqCst);
+                    return;
+                }
+                if self.args.print_config {
+                    println!("{}", enc.config());
+                }
 
                 self.enc = EncConstructionStage::Complete(CompleteState {
                     enc,
@@ -1458,11 +3036,15 @@ impl<S: CaptureSource + 'static> State<S> {
                 c = match self.on_new_capture_format(c, selected_format) {
                     Ok(enc) => enc,
                     Err(e) => {
-                        error!("failed to renegotiate new format {selected_format:?}: {e}");
+                        error!(
+                            target: log_spec::WAYLAND,
+                            "failed to renegotiate new format {selected_format:?}: {e}"
+                        );
                         self.quit_flag.store(1, SeqCst);
                         return;
                     }
                 };
+                c.enc.capture_formats = capture_formats.to_vec();
                 self.enc = EncConstructionStage::Complete(c);
             }
             _ => panic!("called negotiate_format in a strange state"),
@@ -1481,14 +3063,381 @@ impl<S: CaptureSource + 'static> State<S> {
         }
     }
 
+    fn on_damage(&mut self, damage: Rect) {
+        self.frame_damage.push(damage);
+    }
+
+    // called when wlr-output-management reports a new configuration. wl_output doesn't say when an
+    // output is disabled without being removed, and mode changes that keep the same size don't
+    // change the capture format, so neither would be noticed otherwise
+    fn on_output_configuration_changed(&mut self) {
+        let (Some(om), EncConstructionStage::Complete(c)) =
+            (&self.output_management, &mut self.enc)
+        else {
+            return;
+        };
+        let Some(head) = om.head(&c.output.name) else {
+            return;
+        };
+
+        if !head.enabled {
+            info!(target: log_spec::WAYLAND, "output {} was disabled", c.output.name);
+            c.output_went_away = true;
+            return;
+        }
+
+        if let Some(refresh) = head.refresh.filter(|r| *r != c.output.refresh) {
+            info!(
+                target: log_spec::WAYLAND,
+                "refresh rate of {} changed to {} Hz, reopening encoder",
+                c.output.name,
+                f64::from(refresh)
+            );
+            c.output.refresh = refresh;
+            c.enc.framerate = refresh;
+            if let Err(e) = c.enc.rebuild_encoder(&self.args) {
+                error!(
+                    target: log_spec::ENCODE,
+                    "failed to reopen encoder for the new refresh rate: {e}"
+                );
+                self.quit_flag.store(1, SeqCst);
+            }
+        }
+    }
+
+    // --pause-after-idle: the compositor reported no input for the timeout
+    fn on_input_idle(&mut self) {
+        if let Some(idle) = &mut self.idle {
+            idle.tracker.input_idle(capture_error::monotonic_ns());
+        }
+        self.check_idle();
+    }
+
+    fn on_input_resumed(&mut self) {
+        let now = capture_error::monotonic_ns();
+        if !self
+            .idle
+            .as_mut()
+            .is_some_and(|i| i.tracker.activity(now, false).is_some())
+        {
+            return;
+        }
+        info!(target: log_spec::ENCODE, "input resumed, resuming after idle");
+        let audio = match &mut self.enc {
+            EncConstructionStage::Complete(CompleteState { enc, .. }) => enc.audio.as_mut(),
+            _ => None,
+        };
+        self.pauses.resume(PauseReason::Idle, now, audio);
+    }
+
+    // called from the event loop, as the screen not changing has no event of its own
+    fn check_idle(&mut self) {
+        let Some(idle) = &mut self.idle else {
+            return;
+        };
+        let now = capture_error::monotonic_ns();
+        if !idle.tracker.poll(now) {
+            return;
+        }
+        info!(
+            target: log_spec::ENCODE,
+            "no input or screen changes for {:?}, pausing",
+            self.args.pause_after_idle.unwrap()
+        );
+        let audio = match &mut self.enc {
+            EncConstructionStage::Complete(CompleteState { enc, .. }) => enc.audio.as_mut(),
+            _ => None,
+        };
+        self.pauses.pause(PauseReason::Idle, now, audio);
+    }
+
+    // audio is otherwise only muxed after a video frame, which don't come while the screen is static
+    fn drain_audio(&mut self) {
+        if let EncConstructionStage::Complete(CompleteState { enc, .. }) = &mut self.enc {
+            enc.drain_audio();
+        }
+    }
+
+    // the outputs being recorded, once they're known
+    fn recorded_outputs(&self) -> Option<Vec<String>> {
+        let (output, composite) = match &self.enc {
+            EncConstructionStage::EverythingButFormat {
+                output, composite, ..
+            } => (&output.name, composite.as_ref()),
+            EncConstructionStage::Complete(c) => (&c.output.name, c.enc.composite.as_ref()),
+            EncConstructionStage::OutputWentAway(owa) => {
+                (&owa.waiting_for_output_name, owa.enc.composite.as_ref())
+            }
+            _ => return None,
+        };
+        let others = composite.into_iter().flat_map(|c| &c.others);
+        Some(
+            once(output.clone())
+                .chain(others.map(|o| o.name.clone()))
+                .collect(),
+        )
+    }
+
+    fn hook_env(&self) -> HookEnv {
+        let output = match &self.enc {
+            EncConstructionStage::EverythingButFormat { output, .. } => &output.name,
+            EncConstructionStage::Complete(c) => &c.output.name,
+            EncConstructionStage::OutputWentAway(owa) => &owa.waiting_for_output_name,
+            _ => &self.args.output,
+        };
+        HookEnv::new(&self.args, output)
+    }
+
+    // drops the capture source and waits for an output called `name` to be announced, then
+    // continues recording on it
+    fn wait_for_output(&mut self, name: String, switching: bool, qhandle: &QueueHandle<Self>) {
+        let enc = mem::replace(&mut self.enc, EncConstructionStage::Intermediate).take_enc();
+
+        let mut owa = OutputWentAwayState {
+            enc,
+            waiting_for_output_name: name,
+            switching,
+            partial_outputs: Default::default(),
+        };
+        for g in self.gm.contents().clone_list() {
+            if g.interface == WlOutput::interface().name {
+                owa.new_wl_output(self.gm.registry(), &self.xdg_output_manager, g, qhandle);
+            }
+        }
+        self.enc = EncConstructionStage::OutputWentAway(owa);
+    }
+
+    fn on_control_command(&mut self, cmd: ControlCommand, qhandle: &QueueHandle<Self>) {
+        match cmd {
+            ControlCommand::Stop => {
+                info!("stop requested over control socket");
+                self.quit_flag.store(0, SeqCst);
+            }
+            ControlCommand::SetRegion(geometry) => self.set_region(geometry),
+            ControlCommand::Reselect => unreachable!("reselect is handled by the control thread"),
+            ControlCommand::SetOutput(name) => self.set_output(name, qhandle),
+            ControlCommand::Marker(text) => match &mut self.enc {
+                EncConstructionStage::Complete(c) => c.enc.add_marker(&text),
+                _ => warn!("not recording yet, ignoring marker {text:?}"),
+            },
+            ControlCommand::Screenshot => match &mut self.enc {
+                EncConstructionStage::Complete(c) => c.enc.screenshot_requested = true,
+                _ => warn!("not recording yet, ignoring screenshot"),
+            },
+            ControlCommand::Split => match &mut self.enc {
+                EncConstructionStage::Complete(c) => c.enc.request_split(),
+                _ => warn!("not recording yet, ignoring split"),
+            },
+            ControlCommand::Splice(cue) => match &mut self.enc {
+                EncConstructionStage::Complete(c) => c.enc.add_splice(cue),
+                _ => warn!("not recording yet, ignoring splice"),
+            },
+            ControlCommand::Mute => self.set_muted(Some(true)),
+            ControlCommand::Unmute => self.set_muted(Some(false)),
+            ControlCommand::ToggleMute => self.set_muted(None),
+        }
+    }
+
+    // None toggles it
+    fn set_muted(&mut self, muted: Option<bool>) {
+        let EncConstructionStage::Complete(CompleteState { enc, .. }) = &mut self.enc else {
+            warn!("not recording yet, ignoring mute");
+            return;
+        };
+        let Some(audio) = &mut enc.audio else {
+            warn!("not recording audio, ignoring mute");
+            return;
+        };
+        let muted = muted.unwrap_or(!audio.muted());
+        audio.set_muted(muted);
+        info!("audio {}", if muted { "muted" } else { "unmuted" });
+    }
+
+    fn set_output(&mut self, name: String, qhandle: &QueueHandle<Self>) {
+        let EncConstructionStage::Complete(CompleteState { output, .. }) = &self.enc else {
+            warn!("not recording yet, ignoring set-output");
+            return;
+        };
+        if output.name == name {
+            info!("already recording {name}, ignoring set-output");
+            return;
+        }
+        self.pending_output = Some(name);
+        self.switch_output_if_pending(qhandle);
+    }
+
+    // stops capturing the current output, and waits for the one requested by set-output. If a
+    // frame allocation is in flight, this is retried once it completes
+    fn switch_output_if_pending(&mut self, qhandle: &QueueHandle<Self>) {
+        if matches!(self.in_flight_surface, InFlightSurface::AllocQueued) {
+            return;
+        }
+        let Some(name) = self.pending_output.take() else {
+            return;
+        };
+
+        let CompleteState { cap, .. } = self.enc.unwrap();
+        match self.in_flight_surface.take() {
+            InFlightSurface::Allocd(frame) => cap.on_done_with_frame(frame),
+            InFlightSurface::CopyQueued { wl_frame, .. } => cap.on_done_with_frame(wl_frame),
+            InFlightSurface::None | InFlightSurface::AllocQueued => {}
+        }
+
+        info!(target: log_spec::WAYLAND, "moving recording to output {name}");
+        self.args.output = name.clone();
+        self.args.geometry.clear();
+        self.wait_for_output(name, true, qhandle);
+    }
+
+    // --follow-window, --sway-window and --follow-active-window. Called after every dispatch rather
+    // than when the window moves, as moving to another output first records all of it and the
+    // region can only be set once that's done
+    fn follow_window(&mut self, window: &WindowGeometry, qhandle: &QueueHandle<Self>) {
+        let EncConstructionStage::Complete(CompleteState { enc, output, .. }) = &mut self.enc
+        else {
+            return;
+        };
+        if self.pending_output.is_some() {
+            return;
+        }
+        if output.name != window.output {
+            self.set_output(window.output.clone(), qhandle);
+            return;
+        }
+        let geometry = Geometry::Absolute(window.region);
+        if self.args.geometry == [geometry] {
+            return;
+        }
+        // on failure this isn't retried until the window changes again
+        self.args.geometry = vec![geometry];
+        let Some(roi) = output.clip_region_to_roi(window.region) else {
+            return;
+        };
+        debug!("following window to {:?}", window.region);
+        if let Err(e) = enc.set_roi(roi) {
+            warn!("failed to follow window: {e:?}");
+        }
+    }
+
+    fn set_region(&mut self, geometry @ (x, y, w, h): (i32, i32, u32, u32)) {
+        let EncConstructionStage::Complete(CompleteState { enc, output, .. }) = &mut self.enc
+        else {
+            warn!("not recording yet, ignoring set-region");
+            return;
+        };
+        let Some(roi) = output.region_to_roi(geometry) else {
+            warn!(
+                "region {x},{y} {w}x{h} is not entirely within {}, ignoring set-region",
+                output.name
+            );
+            return;
+        };
+
+        info!("changing region to {x},{y} {w}x{h}");
+        if let Err(e) = enc.set_roi(roi) {
+            warn!("failed to change region: {e:?}");
+            return;
+        }
+        self.args.geometry = vec![Geometry::Absolute(geometry)];
+    }
+
     fn queue_alloc_frame(&mut self, eq: &QueueHandle<State<S>>) {
         assert!(matches!(self.in_flight_surface, InFlightSurface::None));
+        let now = capture_error::monotonic_ns();
+        if let Some(at) = self
+            .capture_pacing
+            .as_ref()
+            .and_then(|p| p.next_capture(now))
+        {
+            self.capture_due = Some(at);
+            self.timer.wake_at(at);
+            return;
+        }
+        self.alloc_frame(eq);
+    }
+
+    fn alloc_frame(&mut self, eq: &QueueHandle<State<S>>) {
+        self.capture_due = None;
+        if let Some(pacing) = &mut self.capture_pacing {
+            pacing.requested(capture_error::monotonic_ns());
+        }
         let f = self.enc.unwrap_cap().alloc_frame(eq);
         self.in_flight_surface = InFlightSurface::AllocQueued;
         if let Some(f) = f {
             self.on_frame_allocd(eq, &f);
         }
     }
+
+    // called after every dispatch, does what was scheduled on the timer once it's due
+    // --outputs: the other outputs are captured onto the same device as the first
+    fn start_other_outputs(
+        &self,
+        enc: &EncState,
+        eq: &QueueHandle<State<S>>,
+    ) -> anyhow::Result<()> {
+        for other in enc.composite.iter().flat_map(|c| &c.others) {
+            other.start(
+                &self.conn,
+                &self.gm,
+                enc.capture_device.as_ref().unwrap_or(&enc.hw_device_ctx),
+                self.args.damage,
+                !self.args.no_cursor,
+                event_loop_waker(&self.conn, eq.clone()),
+            )?;
+        }
+        Ok(())
+    }
+
+    // --outputs: another output changed while the first didn't, so the first's last frame is
+    // encoded again along with it
+    fn on_other_outputs(&mut self) {
+        let EncConstructionStage::Complete(CompleteState { enc, .. }) = &mut self.enc else {
+            return;
+        };
+        let mut fresh = false;
+        for other in enc.composite.iter().flat_map(|c| &c.others) {
+            fresh |= other.take_fresh();
+        }
+        if !fresh
+            || self.starting_timestamp.is_none()
+            || self.pauses.is_paused()
+            || self.debounced.is_some()
+        {
+            return;
+        }
+        let Some(surf) = enc.last_surface.as_ref().map(multi_output::new_ref) else {
+            return;
+        };
+        let now = capture_error::monotonic_ns();
+        if self.fps_limit.as_mut().is_some_and(|l| !l.should_keep(now)) {
+            return;
+        }
+        if let Err(e) = self.encode_frame(surf, now, &[]) {
+            error!(target: log_spec::ENCODE, "{e:?}");
+            self.quit_flag.store(1, SeqCst);
+        }
+    }
+
+    fn on_timer(&mut self, eq: &QueueHandle<State<S>>) {
+        let now = capture_error::monotonic_ns();
+        if self.debounced.as_ref().is_some_and(|d| d.due_ns <= now) {
+            self.encode_debounced();
+        }
+        let Some(at) = self.capture_due else {
+            return;
+        };
+        if at > now {
+            return;
+        }
+        // switching outputs in the meantime queues a copy of its own once the new one is set up
+        if matches!(self.in_flight_surface, InFlightSurface::None)
+            && matches!(self.enc, EncConstructionStage::Complete(_))
+        {
+            self.alloc_frame(eq);
+        } else {
+            self.capture_due = None;
+        }
+    }
 }
 
 struct EncState {
@@ -1496,19 +3445,78 @@ struct EncState {
     enc_video: encoder::Video,
     enc_video_has_been_fed_any_frames: bool,
     octx: format::context::Output,
+    header_written: bool, // with --timecode, the first file's header waits for its first frame
+    timecode_rate: Option<Rational>, // --timecode
+    frame_clock: Option<(i64, SystemTime)>, // pts and wall-clock time of the last captured frame
     frames_rgb: AvHwFrameCtx,
     filter_output_timebase: Rational,
     vid_stream_idx: usize,
     history_state: HistoryState,
+    history_transcode: Option<TranscodeSpec>,
+    history_clip: Option<JoinHandle<()>>, // --history-transcode running in the background
+    highlights: Option<Highlights>,       // --continuous
+    upload: Option<Destination>,          // --upload-url or --s3-url
+    finisher: Finisher,                   // attaches posters to, trims and uploads finished files
     sigusr1_flag: Arc<AtomicBool>,
+    metrics: Arc<Metrics>,
     audio: Option<AudioHandle>,
     selected_format: DmabufFormat,
-    hw_device_ctx: AvHwDevCtx,
+    hw_device_ctx: AvHwDevCtx, // the encoder's, and capture's unless capture_device is set
+    capture_device: Option<AvHwDevCtx>, // with --encode-device
+    capture_buffers: HashMap<u32, WlBuffer>, // for frames_rgb's surfaces, by VASurfaceID
     enc_pixfmt: EncodePixelFormat,
     roi_screen_coord: Rect,
+    composite: Option<Composite>,
+    exclusions: Exclusions,
+    letterbox: bool, // set_roi changed the region's shape, it's fit into the encode size
+    keep_encode_size: bool, // moved to an output of another size, letterbox it on the new format
+    cursor_overlay: Option<CursorOverlay>,
+    text_overlay: Option<TextOverlay>,
     transform: Transform,
+    framerate: Rational, // hint given to the encoder, the output refresh rate
     enc_video_options: dictionary::Owned<'static>,
     format_change: bool,
+    stats: PipelineStats,
+    sd_notify: SdNotify,
+    bytes_written: u64,
+    filename: String, // file currently being written to
+    filename_template: String,
+    journal: Option<Journal>, // None while recording --history, or if it's not writing to a file
+    muxer: Option<String>,
+    muxer_options: Option<String>,
+    split_at: Vec<TimeOfDay>,
+    next_split: Option<SystemTime>,
+    split: SplitState,
+    proxy: Option<Rendition>,
+    extra_stream: Option<Rendition>,
+    frame_timing: FrameTiming,
+    frame_durations: Option<TextTrack>, // --frame-durations
+    markers: Option<TextTrack>,         // --markers
+    splice: Option<SpliceTrack>,        // --splice-points
+    screenshot_filename: String,        // before strftime expansion
+    screenshot_requested: bool,         // save the next frame out of the filter
+    poster: Option<Poster>,             // --poster
+    trim: Option<Trim>,                 // --trim-start, --trim-end
+    discard_shorter_than: Option<i64>,  // ns, None once there's more than this file to keep
+    encode_log: Option<EncodeLog>,
+    cursor_roi: Option<Rect>, // --roi-cursor, in encoded frame coordinates
+    frame_qoffset: Option<Rational>, // --adaptive-quality, for the whole frame
+    damage_roi: Option<Vec<Rect>>, // --damage-hints, in encoded frame coordinates
+    last_forced_keyframe: Option<i64>, // pts of the last keyframe forced to keep --history trimmable
+    dri_device: PathBuf,
+    encode_device: Option<PathBuf>,              // --encode-device
+    capture_formats: Vec<DmabufPotentialFormat>, // what the compositor offered
+    context: Option<serde_json::Map<String, serde_json::Value>>, // wl-screenrec.json's options
+    file_markers: Vec<(i64, String)>,            // --markers in the current file, ns from its start
+    encoder_error: Option<ffmpeg::Error>,        // send_frame failed, the encoder needs restarting
+    packets_at_restart: Option<u64>,             // packets_from_encoder when it was last restarted
+    last_surface: Option<frame::Video>, // with --outputs, encoded again when only another output changed
+}
+
+enum SplitState {
+    None,
+    ForceKeyframe,           // the next frame sent to the encoder should be a keyframe
+    WaitingForKeyframe(i64), // waiting for the keyframe with at least this PTS to start a new file with
 }
 
 #[derive(Copy, Clone, Debug)]
@@ -1517,17 +3525,39 @@ enum EncodePixelFormat {
     Sw(Pixel),
 }
 
-fn vaapi_codec_id(codec: codec::Id) -> Option<&'static str> {
-    match codec {
-        codec::Id::H264 => Some("h264_vaapi"),
-        codec::Id::H265 | codec::Id::HEVC => Some("hevc_vaapi"),
-        codec::Id::VP8 => Some("vp8_vaapi"),
-        codec::Id::VP9 => Some("vp9_vaapi"),
-        codec::Id::AV1 => Some("av1_vaapi"),
-        _ => None,
+// 10 bit planar formats, used by prores and dnxhr, that vaapi has no surfaces for
+const CPU_CONVERTED_FORMATS: [Pixel; 2] = [Pixel::YUV422P10LE, Pixel::YUV444P10LE];
+
+// how long a --markers cue stays on screen
+const MARKER_DURATION_NS: i64 = 3_000_000_000;
+
+impl EncodePixelFormat {
+    // software encoders that take RGB or a format vaapi can't convert to get the captured frames
+    // downloaded as they are, everything else is converted on the GPU first
+    fn downloads_capture(self) -> bool {
+        match self {
+            EncodePixelFormat::Sw(fmt) => is_rgb(fmt) || CPU_CONVERTED_FORMATS.contains(&fmt),
+            EncodePixelFormat::Vaapi(_) => false,
+        }
+    }
+
+    // format of the vaapi surfaces the converted frames are in
+    fn surface_format(self) -> Pixel {
+        match self {
+            // not used, but a frame context still has to be made
+            _ if self.downloads_capture() => Pixel::NV12,
+            EncodePixelFormat::Vaapi(fmt) | EncodePixelFormat::Sw(fmt) => fmt,
+        }
     }
 }
 
+fn is_rgb(fmt: Pixel) -> bool {
+    // AV_PIX_FMT_FLAG_RGB, bindgen doesn't pick it up
+    const FLAG_RGB: u64 = 1 << 5;
+    fmt.descriptor()
+        .is_some_and(|d| unsafe { (*d.as_ptr()).flags } & FLAG_RGB != 0)
+}
+
 fn make_video_params(
     args: &Args,
     enc_pix_fmt: EncodePixelFormat,
@@ -1593,6 +3623,35 @@ fn parse_dict<'a>(dict: &str) -> Result<dictionary::Owned<'a>, ffmpeg::Error> {
     }
 }
 
+// None for auto, where the container picks
+fn video_codec_id(codec: Codec) -> Option<codec::Id> {
+    Some(match codec {
+        Codec::Auto => return None,
+        Codec::Avc => codec::Id::H264,
+        Codec::Hevc => codec::Id::HEVC,
+        Codec::VP8 => codec::Id::VP8,
+        Codec::VP9 => codec::Id::VP9,
+        Codec::AV1 => codec::Id::AV1,
+        Codec::Prores => codec::Id::PRORES,
+        Codec::Dnxhr => codec::Id::DNXHD,
+    })
+}
+
+// the codecs asked for with --codec/--ffmpeg-encoder and --audio-codec/--ffmpeg-audio-encoder, for
+// checking them against the container. Left out when the container picks them
+fn requested_codecs(args: &Args) -> Vec<codec::Id> {
+    let video = match &args.ffmpeg_encoder {
+        Some(name) => encoder::find_by_name(name).map(|c| c.id()),
+        None => video_codec_id(args.codec),
+    };
+    let audio = match &args.ffmpeg_audio_encoder {
+        _ if !args.audio => None,
+        Some(name) => encoder::find_by_name(name).map(|c| c.id()),
+        None => audio::codec_id(args.audio_codec),
+    };
+    video.into_iter().chain(audio).collect()
+}
+
 fn get_encoder(args: &Args, format: &Output) -> anyhow::Result<ffmpeg::Codec> {
     Ok(if let Some(encoder_name) = &args.ffmpeg_encoder {
         ffmpeg_next::encoder::find_by_name(encoder_name).ok_or_else(|| {
@@ -1601,25 +3660,27 @@ fn get_encoder(args: &Args, format: &Output) -> anyhow::Result<ffmpeg::Codec> {
             )
         })?
     } else {
-        let codec_id = match args.codec {
-            Codec::Auto => format.codec(&args.filename, media::Type::Video),
-            Codec::Avc => codec::Id::H264,
-            Codec::Hevc => codec::Id::HEVC,
-            Codec::VP8 => codec::Id::VP8,
-            Codec::VP9 => codec::Id::VP9,
-            Codec::AV1 => codec::Id::AV1,
-        };
+        let codec_id = video_codec_id(args.codec)
+            .unwrap_or_else(|| format.codec(&args.filename, media::Type::Video));
 
         let maybe_hw_codec = if args.hw {
-            if let Some(hw_codec_name) = vaapi_codec_id(codec_id) {
-                if let Some(codec) = ffmpeg_next::encoder::find_by_name(hw_codec_name) {
+            if let Some(hw_codec_name) = args.hw_backend.encoder_name(codec_id) {
+                if let Some(codec) = ffmpeg_next::encoder::find_by_name(&hw_codec_name) {
                     Some(codec)
                 } else {
-                    warn!("there is a known vaapi codec ({hw_codec_name}) for codec {codec_id:?}, but it's not available. Using a generic encoder...");
+                    warn!(
+                        target: log_spec::ENCODE,
+                        "there is a known {} codec ({hw_codec_name}) for codec {codec_id:?}, but it's not available. Using a generic encoder...",
+                        args.hw_backend.name()
+                    );
                     None
                 }
             } else {
-                warn!("hw flag is specified, but there's no known vaapi codec for {codec_id:?}. Using a generic encoder...");
+                warn!(
+                    target: log_spec::ENCODE,
+                    "there's no known {} codec for {codec_id:?}. Using a generic encoder...",
+                    args.hw_backend.name()
+                );
                 None
             }
         } else {
@@ -1628,6 +3689,12 @@ fn get_encoder(args: &Args, format: &Output) -> anyhow::Result<ffmpeg::Codec> {
 
         match maybe_hw_codec {
             Some(codec) => codec,
+            None if codec_id == codec::Id::AV1 => realtime_av1_encoder()?,
+            // ffmpeg's default prores encoder is prores_aw, prores_ks looks better at the same size
+            None if codec_id == codec::Id::PRORES => {
+                ffmpeg_next::encoder::find_by_name("prores_ks")
+                    .ok_or_else(|| format_err!("ffmpeg was built without the prores_ks encoder"))?
+            }
             None => match ffmpeg_next::encoder::find(codec_id) {
                 Some(codec) => codec,
                 None => {
@@ -1638,13 +3705,235 @@ fn get_encoder(args: &Args, format: &Output) -> anyhow::Result<ffmpeg::Codec> {
     })
 }
 
-fn get_enc_pixfmt(args: &Args, encoder: &ffmpeg::Codec) -> anyhow::Result<EncodePixelFormat> {
+// ffmpeg's default AV1 encoder is libaom in its offline mode, which manages a couple of frames per
+// second. Pick one that can keep up with capture instead
+fn realtime_av1_encoder() -> anyhow::Result<ffmpeg::Codec> {
+    let codec = ["libsvtav1", "libaom-av1"]
+        .into_iter()
+        .find_map(ffmpeg_next::encoder::find_by_name)
+        .ok_or_else(|| {
+            format_err!("no realtime capable software AV1 encoder is available, ffmpeg needs to be built with libsvtav1 or libaom. Pick another --codec, or use a GPU that can encode AV1")
+        })?;
+    info!(target: log_spec::ENCODE, "using {} for software AV1", codec.name());
+    Ok(codec)
+}
+
+// the timecode an earlier recording was started with
+fn existing_timecode(src: &Path) -> Option<String> {
+    let ictx = format::input(src).ok()?;
+    let video = ictx.streams().best(media::Type::Video)?;
+    video.metadata().get("timecode").map(str::to_owned)
+}
+
+// why packets encoded for `new` can't continue the existing stream, None if they can. `new_format`
+// is the software format of the frames the video encoder is given
+fn stream_mismatch(
+    existing: codec::Parameters,
+    new: codec::Parameters,
+    new_format: Pixel,
+) -> Option<String> {
+    let id = existing.id();
+    if id != new.id() {
+        return Some(format!(
+            "is {id:?}, but this recording would write {:?}",
+            new.id()
+        ));
+    }
+    let extradata = |p: &codec::Parameters| unsafe {
+        let p = &*p.as_ptr();
+        match p.extradata.is_null() {
+            true => &[][..],
+            false => slice::from_raw_parts(p.extradata, p.extradata_size as usize),
+        }
+    };
+    // muxers don't all keep it, so there's only something to compare if both have it
+    let (existing_config, new_config) = (extradata(&existing), extradata(&new));
+    let config_differs = !existing_config.is_empty()
+        && !new_config.is_empty()
+        && container::codec_config(id, existing_config) != container::codec_config(id, new_config);
+
+    let ctx = |p: codec::Parameters| codec::Context::from_parameters(p).ok();
+    let (existing, new) = (ctx(existing)?.decoder(), ctx(new)?.decoder());
+    match existing.medium() {
+        media::Type::Video => {
+            let (existing, new) = (existing.video().ok()?, new.video().ok()?);
+            let (ew, eh, nw, nh) = (
+                existing.width(),
+                existing.height(),
+                new.width(),
+                new.height(),
+            );
+            if (ew, eh) != (nw, nh) {
+                return Some(format!(
+                    "is {ew}x{eh}, but this recording would be {nw}x{nh}"
+                ));
+            }
+            // NV12 and YUV420P encode the same way, it's the subsampling and depth that matter
+            let layout = |fmt: Pixel| {
+                let desc = fmt.descriptor()?;
+                let depth = unsafe { (*desc.as_ptr()).comp[0].depth };
+                Some((desc.log2_chroma_w(), desc.log2_chroma_h(), depth))
+            };
+            if layout(existing.format()) != layout(new_format) {
+                return Some(format!(
+                    "is {:?}, but this recording would be {new_format:?}",
+                    existing.format()
+                ));
+            }
+        }
+        media::Type::Audio => {
+            let (existing, new) = (existing.audio().ok()?, new.audio().ok()?);
+            let (er, ec, nr, nc) = (
+                existing.rate(),
+                existing.channels(),
+                new.rate(),
+                new.channels(),
+            );
+            if (er, ec) != (nr, nc) {
+                return Some(format!(
+                    "is {ec} channels at {er}Hz, but this recording would be {nc} channels at {nr}Hz"
+                ));
+            }
+        }
+        _ => {}
+    }
+    if config_differs {
+        return Some(
+            "was encoded with different settings (its codec parameters differ), like another profile, quality or encoder".to_owned(),
+        );
+    }
+    None
+}
+
+// copies every packet of `src` into `octx`, which must already have the same streams. Returns the
+// end timestamp of the copied data (in nanoseconds), which is where newly encoded packets start
+fn copy_existing_recording(
+    src: &Path,
+    octx: &mut format::context::Output,
+    video_format: Pixel,
+) -> anyhow::Result<i64> {
+    let mut ictx = format::input(src)
+        .with_context(|| format!("failed to open {} to append to", src.display()))?;
+
+    // attachments have no packets, and --poster adds one once the file is finished
+    let ist_count = ictx
+        .streams()
+        .filter(|st| st.parameters().medium() != media::Type::Attachment)
+        .count();
+    let ost_count = octx
+        .streams()
+        .filter(|st| st.parameters().medium() != media::Type::Attachment)
+        .count();
+    if ist_count != ost_count {
+        bail!(
+            "existing file has {ist_count} streams, but this recording has {ost_count}. Pass the same --audio settings as the original recording",
+        );
+    }
+    for (ist, ost) in ictx.streams().zip(octx.streams()).take(ost_count) {
+        if let Some(mismatch) = stream_mismatch(ist.parameters(), ost.parameters(), video_format) {
+            bail!(
+                "stream {} of the existing file {mismatch}. Pass the same settings as the original recording",
+                ist.index()
+            );
+        }
+    }
+
+    let out_time_bases: Vec<_> = octx.streams().map(|st| st.time_base()).collect();
+    let mut end_ns = 0;
+    for (ist, mut packet) in ictx.packets() {
+        let idx = ist.index();
+        let tb = ist.time_base();
+        if let Some(pts) = packet.pts() {
+            let end = pts + packet.duration();
+            end_ns = end_ns.max(end * 1_000_000_000 * tb.0 as i64 / tb.1 as i64);
+        }
+
+        packet.rescale_ts(tb, out_time_bases[idx]);
+        packet.set_position(-1);
+        packet.set_stream(idx);
+        packet.write_interleaved(octx)?;
+    }
+
+    Ok(end_ns)
+}
+
+// non drop-frame SMPTE timecode (HH:MM:SS:FF) for the local time of day at `at`
+fn wallclock_timecode(framerate: Rational, at: SystemTime) -> String {
+    let now = at.duration_since(UNIX_EPOCH).unwrap();
+    let secs = now.as_secs() as libc::time_t;
+    let tm = unsafe {
+        let mut tm = mem::zeroed::<libc::tm>();
+        libc::localtime_r(&secs, &mut tm);
+        tm
+    };
+
+    let fps = f64::from(framerate).round().max(1.) as u64;
+    let frame = u64::from(now.subsec_nanos()) * fps / 1_000_000_000;
+    format!(
+        "{:02}:{:02}:{:02}:{:02}",
+        tm.tm_hour, tm.tm_min, tm.tm_sec, frame
+    )
+}
+
+fn open_output(
+    filename: &str,
+    muxer: Option<&str>,
+    muxer_options: Option<&str>,
+) -> anyhow::Result<format::context::Output> {
+    let muxer_options = match muxer_options {
+        Some(muxer_options) => parse_dict(muxer_options)
+            .with_context(|| format!("failed to parse muxer options {muxer_options}"))?,
+        None => dict!(),
+    };
+
+    // like format::output_as_with, but the file is opened with --shutdown-timeout's interrupt
+    // callback, blocking protocols (network sinks) only check the one they were opened with
+    let path = CString::new(filename)?;
+    let muxer = muxer.map(CString::new).transpose()?;
+    unsafe {
+        let mut ps = null_mut();
+        let sts = avformat_alloc_output_context2(
+            &mut ps,
+            null_mut(),
+            muxer.as_ref().map_or(null(), |m| m.as_ptr()),
+            path.as_ptr(),
+        );
+        if sts < 0 {
+            return Err(ffmpeg::Error::from(sts))
+                .with_context(|| format!("failed to open {filename} for writing"));
+        }
+        (*ps).interrupt_callback = shutdown::interrupt_callback();
+
+        let mut opts = muxer_options.disown();
+        let sts = avio_open2(
+            &mut (*ps).pb,
+            path.as_ptr(),
+            AVIO_FLAG_WRITE,
+            &(*ps).interrupt_callback,
+            &mut opts,
+        );
+        dictionary::Owned::own(opts);
+        if sts < 0 {
+            avformat_free_context(ps);
+            return Err(ffmpeg::Error::from(sts))
+                .with_context(|| format!("failed to open {filename} for writing"));
+        }
+        Ok(format::context::Output::wrap(ps))
+    }
+}
+
+fn get_enc_pixfmt(
+    args: &Args,
+    encoder: &ffmpeg::Codec,
+    capture_pixfmt: Pixel,
+) -> anyhow::Result<EncodePixelFormat> {
     let supported_formats = supported_formats(encoder);
     Ok(if supported_formats.is_empty() {
         match args.encode_pixfmt {
             Some(fmt) => EncodePixelFormat::Sw(fmt),
             None => {
                 warn!(
+                    target: log_spec::ENCODE,
                     "codec \"{}\" does not advertize supported pixel formats, just using NV12. Pass --encode-pixfmt to suppress this warning",
                     encoder.name()
                 );
@@ -1655,6 +3944,26 @@ fn get_enc_pixfmt(args: &Args, encoder: &ffmpeg::Codec) -> anyhow::Result<Encode
         EncodePixelFormat::Vaapi(args.encode_pixfmt.unwrap_or(Pixel::NV12))
     } else {
         match args.encode_pixfmt {
+            // keep the captured pixels. ffv1 takes 8 bit captures as they are, 10 bit ones are
+            // repacked into planar RGB
+            None if args.preset == Some(Preset::ArchiveFfv1) => EncodePixelFormat::Sw(
+                [capture_pixfmt, Pixel::GBRP10LE]
+                    .into_iter()
+                    .find(|f| supported_formats.contains(f))
+                    .ok_or_else(|| {
+                        format_err!(
+                            "{} can't encode {capture_pixfmt:?} losslessly",
+                            encoder.name()
+                        )
+                    })?,
+            ),
+            // the first format dnxhd lists is 8 bit, which only the lower quality DNxHR profiles
+            // take
+            None if matches!(encoder.id(), codec::Id::PRORES | codec::Id::DNXHD)
+                && supported_formats.contains(&Pixel::YUV422P10LE) =>
+            {
+                EncodePixelFormat::Sw(Pixel::YUV422P10LE)
+            }
             None => EncodePixelFormat::Sw(supported_formats[0]),
             Some(fmt) if supported_formats.contains(&fmt) => EncodePixelFormat::Sw(fmt),
             Some(fmt) => bail!("Encoder does not support pixel format {fmt:?}"),
@@ -1662,102 +3971,150 @@ fn get_enc_pixfmt(args: &Args, encoder: &ffmpeg::Codec) -> anyhow::Result<Encode
     })
 }
 
-impl EncState {
-    // assumed that capture_{w,h}
-    fn new(
+// size (pixels) of what's recorded before scaling: the roi, or the regions composited together
+fn source_size(roi: Rect, composite: Option<&Composite>) -> (i32, i32) {
+    composite.map_or((roi.w, roi.h), Composite::size)
+}
+
+// size (pixels) to encode a source of `(w, h)` at
+fn encode_size(args: &Args, (w, h): (i32, i32)) -> (i32, i32) {
+    match (args.encode_resolution, args.scale) {
+        (Some((w, h)), _) => (w as i32, h as i32),
+        // rounded to even sizes, which subsampled pixel formats need
+        (None, Some(scale)) => (
+            ((f64::from(w) * scale / 2.).round() as i32).max(1) * 2,
+            ((f64::from(h) * scale / 2.).round() as i32).max(1) * 2,
+        ),
+        (None, None) => (w, h),
+    }
+}
+
+// "No usable encoding profile found" in the log isn't much to go on, so list what the driver can do
+fn vaapi_open_error(e: ffmpeg::Error, hw_device_ctx: &AvHwDevCtx) -> anyhow::Error {
+    format_err!(
+        "failed to open vaapi encoder: {e}. {}",
+        vainfo::describe(&vainfo::encode_profiles(hw_device_ctx))
+    )
+}
+
+impl EncState {
+    // assumed that capture_{w,h}
+    fn new(
         args: &Args,
         capture_format: DmabufFormat,
         refresh: Rational,
         transform: Transform,
         roi_screen_coord: Rect, // roi in screen coordinates (0, 0 is screen upper left, which is not necessarily captured frame upper left)
+        composite: Option<Composite>, // several --geometry regions, roi_screen_coord is their bounds
+        exclusions: Exclusions,
         sigusr1_flag: Arc<AtomicBool>,
+        metrics: Arc<Metrics>,
         dri_device: &Path,
+        capture_formats: &[DmabufPotentialFormat],
     ) -> anyhow::Result<Self> {
-        let muxer_options = if let Some(muxer_options) = &args.ffmpeg_muxer_options {
-            parse_dict(muxer_options).unwrap()
+        // the muxer truncates the file when it's opened, so move the existing recording out of
+        // the way first and copy it back in once the header is written
+        let append_src = if args.append && Path::new(&args.filename).exists() {
+            let src = PathBuf::from(format!("{}.append-src", args.filename));
+            fs::rename(&args.filename, &src)
+                .with_context(|| format!("failed to move {} to append to it", args.filename))?;
+            Some(src)
         } else {
-            dict!()
+            None
         };
 
-        let mut octx = if let Some(muxer) = &args.ffmpeg_muxer {
-            ffmpeg_next::format::output_as_with(&args.filename, muxer, muxer_options).unwrap()
-        } else {
-            ffmpeg_next::format::output_with(&args.filename, muxer_options).unwrap()
-        };
+        let mut octx = open_output(
+            &args.filename,
+            args.ffmpeg_muxer.as_deref(),
+            args.ffmpeg_muxer_options.as_deref(),
+        )?;
+
+        if args.poster.is_some() && !attachment::supported(&octx) {
+            bail!("--poster can only be attached to a mkv recording");
+        }
 
         let encoder = get_encoder(args, &octx.format())?;
 
         // format selection: naive version, should actually see what the ffmpeg filter supports...
-        info!("capture pixel format is {}", capture_format.fourcc);
+        info!(target: log_spec::ENCODE, "capture pixel format is {}", capture_format.fourcc);
 
-        let enc_pixfmt = get_enc_pixfmt(args, &encoder)?;
-        info!("encode pixel format is {enc_pixfmt:?}");
-
-        let codec_id = encoder.id();
-        match unsafe {
-            avformat_query_codec(
-                octx.format().as_ptr(),
-                codec_id.into(),
-                FF_COMPLIANCE_STRICT,
-            )
-        } {
-            0 => bail!(
-                "Format {} does not support {:?} codec",
-                octx.format().name(),
-                codec_id
-            ),
-            1 => (),
-            e => {
-                warn!(
-                    "Format {} might not support {:?} codec ({})",
-                    octx.format().name(),
-                    codec_id,
-                    ffmpeg::Error::from(e)
-                )
-            }
+        let enc_pixfmt = get_enc_pixfmt(args, &encoder, dmabuf_to_av(capture_format.fourcc))?;
+        info!(target: log_spec::ENCODE, "encode pixel format is {enc_pixfmt:?}");
+        if enc_pixfmt.downloads_capture()
+            && composite.as_ref().is_some_and(|c| !c.others.is_empty())
+        {
+            bail!("--outputs stacks the outputs on the GPU, so it needs a vaapi encoder");
         }
 
         let global_header = octx.format().flags().contains(format::Flags::GLOBAL_HEADER);
 
+        let dri_device = render_node::usable(dri_device, args.dri_device.is_none())?;
         eprintln!(
             "Opening libva device from DRM device {}",
             dri_device.display()
         );
 
-        let mut hw_device_ctx = match AvHwDevCtx::new_libva(dri_device) {
+        let mut hw_device_ctx = match AvHwDevCtx::new(HwBackend::Vaapi, &dri_device) {
             Ok(hdc) => hdc,
             Err(e) => bail!("Failed to load vaapi device: {e}. This is likely *not* a bug in wl-screenrec, but an issue with your vaapi installation. Follow your distribution's instructions. If you're pretty sure you've done this correctly, create a new issue with the output of `vainfo` and if `wf-recorder -c h264_vaapi -d {}` works.", dri_device.display()),
         };
 
-        let mut frames_rgb = hw_device_ctx
-            .create_frame_ctx(dmabuf_to_av(capture_format.fourcc), capture_format.width, capture_format.height, capture_format.modifier)
+        let mut capture_device = None;
+        if let Some(encode_device) = &args.encode_device {
+            eprintln!(
+                "Encoding on {}, copying frames from {}",
+                encode_device.display(),
+                dri_device.display()
+            );
+            let encode_device = AvHwDevCtx::new(HwBackend::Vaapi, encode_device).map_err(|e| {
+                format_err!(
+                    "Failed to load vaapi device {}: {e}",
+                    encode_device.display()
+                )
+            })?;
+            capture_device = Some(mem::replace(&mut hw_device_ctx, encode_device));
+        }
+
+        let mut frames_rgb = capture_device.as_mut().unwrap_or(&mut hw_device_ctx)
+            .create_frame_ctx(dmabuf_to_av(capture_format.fourcc), capture_format.width, capture_format.height, capture_format.modifier, Usage::Capture)
             .with_context(|| format!("Failed to create vaapi frame context for capture surfaces of format {capture_format:?}"))?;
 
-        let (enc_w_screen_coord, enc_h_screen_coord) = match args.encode_resolution {
-            Some((x, y)) => (x as i32, y as i32),
-            None => (roi_screen_coord.w, roi_screen_coord.h),
-        };
+        let (enc_w_screen_coord, enc_h_screen_coord) =
+            encode_size(args, source_size(roi_screen_coord, composite.as_ref()));
 
+        let cursor_overlay = args
+            .composite_cursor
+            .then(|| CursorOverlay::new(args.cursor_scale));
+        let mut text_overlay = match &args.overlay_text {
+            Some(file) => Some(
+                TextOverlay::spawn(file)
+                    .with_context(|| format!("Failed to read {}", file.display()))?,
+            ),
+            None => None,
+        };
         let (video_filter, filter_timebase) = video_filter(
             &mut frames_rgb,
             enc_pixfmt,
+            capture_device.is_some().then_some(&hw_device_ctx),
             (capture_format.width, capture_format.height),
             roi_screen_coord,
+            composite.as_ref(),
+            &exclusions,
+            cursor_overlay.as_ref(),
+            text_overlay.as_mut(),
             (enc_w_screen_coord, enc_h_screen_coord),
+            false,
             transform,
         );
 
-        let enc_pixfmt_av = match enc_pixfmt {
-            EncodePixelFormat::Vaapi(fmt) => fmt,
-            EncodePixelFormat::Sw(fmt) => fmt,
-        };
+        let enc_pixfmt_av = enc_pixfmt.surface_format();
         let mut frames_yuv = hw_device_ctx
-            .create_frame_ctx(enc_pixfmt_av, enc_w_screen_coord, enc_h_screen_coord, DrmModifier::LINEAR)
+            .create_frame_ctx(enc_pixfmt_av, enc_w_screen_coord, enc_h_screen_coord, DrmModifier::LINEAR, Usage::Encode)
             .with_context(|| {
                 format!("Failed to create a vaapi frame context for encode surfaces of format {enc_pixfmt_av:?} {enc_w_screen_coord}x{enc_h_screen_coord}")
             })?;
 
-        info!("{}", video_filter.dump());
+        info!(target: log_spec::ENCODE, "{}", video_filter.dump());
 
         let enc = make_video_params(
             args,
@@ -1770,10 +4127,33 @@ impl EncState {
             &mut frames_yuv,
         )?;
 
-        let passed_enc_options = match &args.ffmpeg_encoder_options {
-            Some(enc_options) => parse_dict(enc_options).unwrap(),
+        let mut passed_enc_options = match &args.ffmpeg_encoder_options {
+            Some(enc_options) => {
+                let options = parse_dict(enc_options).with_context(|| {
+                    format!("failed to parse --ffmpeg-encoder-options {enc_options}")
+                })?;
+                avoptions::validate(encoder, &options).context("--ffmpeg-encoder-options")?;
+                options
+            }
             None => dict!(),
         };
+        if let (true, Some(profile)) = (args.hw, &args.vaapi_profile) {
+            passed_enc_options.set("profile", profile);
+        }
+        if let Some(speed) = args.encode_speed {
+            let speed_options = encoder_options::speed_options(encoder.name(), speed);
+            if speed_options.is_empty() {
+                warn!(
+                    target: log_spec::ENCODE,
+                    "--encode-speed has no effect on {}", encoder.name()
+                );
+            }
+            for (k, v) in speed_options {
+                if passed_enc_options.get(k).is_none() {
+                    passed_enc_options.set(k, &v);
+                }
+            }
+        }
 
         let (enc_video, enc_video_options) = if args.hw {
             let low_power_opts = {
@@ -1806,18 +4186,34 @@ impl EncState {
                                 &mut hw_device_ctx,
                                 &mut frames_yuv,
                             )?
-                            .open_with(regular_opts.clone())?,
+                            .open_with(regular_opts.clone())
+                            .map_err(|e| vaapi_open_error(e, &hw_device_ctx))?,
                             regular_opts,
                         )
                     }
                 },
-                LowPowerMode::On => (enc.open_with(low_power_opts.clone())?, low_power_opts),
-                LowPowerMode::Off => (enc.open_with(regular_opts.clone())?, regular_opts),
+                LowPowerMode::On => (
+                    enc.open_with(low_power_opts.clone())
+                        .map_err(|e| vaapi_open_error(e, &hw_device_ctx))?,
+                    low_power_opts,
+                ),
+                LowPowerMode::Off => (
+                    enc.open_with(regular_opts.clone())
+                        .map_err(|e| vaapi_open_error(e, &hw_device_ctx))?,
+                    regular_opts,
+                ),
             }
         } else {
             let mut enc_options = passed_enc_options.clone();
-            if enc_options.get("preset").is_none() {
-                enc_options.set("preset", "ultrafast");
+            for (k, v) in encoder_options::software_options(
+                encoder.name(),
+                args.sw_preset.as_deref(),
+                args.sw_tune.as_deref(),
+                args.sw_threads,
+            ) {
+                if enc_options.get(k).is_none() {
+                    enc_options.set(k, &v);
+                }
             }
             (enc.open_with(enc_options.clone()).unwrap(), enc_options)
         };
@@ -1827,46 +4223,287 @@ impl EncState {
         let vid_stream_idx = ost_video.index();
         ost_video.set_parameters(&enc_video);
 
+        if args.timecode {
+            ost_video.set_avg_frame_rate(refresh); // muxers derive the timecode rate from this
+                                                   // the muxer only adds a timecode track if there's one when it's initialized, the real
+                                                   // one is set once the file's first frame is known
+            ost_video.set_metadata(dict! { "timecode" => "00:00:00:00" });
+        }
+
+        let extra_stream = match &args.extra_stream {
+            Some(spec) => Some(Rendition::new_stream(
+                args,
+                spec,
+                &mut octx,
+                RenditionSource {
+                    hw_device_ctx: &mut hw_device_ctx,
+                    cross_device: capture_device.is_some(),
+                    frames_rgb: &mut frames_rgb,
+                    capture_format,
+                    roi_screen_coord,
+                    composite: composite.as_ref(),
+                    exclusions: &exclusions,
+                    letterbox: false,
+                    transform,
+                    enc_pixfmt,
+                    enc_video: &enc_video,
+                    enc_video_options: &enc_video_options,
+                },
+            )?),
+            None => None,
+        };
+
+        let frame_durations = if args.frame_durations {
+            Some(TextTrack::new(&mut octx, "frame durations")?)
+        } else {
+            None
+        };
+        let encode_log = match &args.encode_log {
+            Some(path) => Some(
+                EncodeLog::create(path)
+                    .with_context(|| format!("failed to create encode log {}", path.display()))?,
+            ),
+            None => None,
+        };
+        let markers = if args.markers || args.marker_fd.is_some() {
+            Some(TextTrack::new(&mut octx, "markers")?)
+        } else {
+            None
+        };
+        let splice = if args.splice_points {
+            Some(SpliceTrack::new(&mut octx)?)
+        } else {
+            None
+        };
+
         let incomplete_audio_state = if args.audio {
             Some(AudioHandle::create_stream(args, &mut octx)?)
         } else {
             None
         };
 
-        octx.write_header().unwrap();
+        disposition::apply(&mut octx, vid_stream_idx, &args.disposition);
+
+        // wl-screenrec.json, the rest of it is added when each file is finished
+        let context = (!args.no_context_attachment && attachment::supported(&octx)).then(|| {
+            serde_json::Map::from_iter([
+                (
+                    "started".to_owned(),
+                    json!(strftime_local("%FT%T%z", SystemTime::now())),
+                ),
+                ("output".to_owned(), json!(args.output)),
+                ("transform".to_owned(), json!(format!("{transform:?}"))),
+                (
+                    "geometry".to_owned(),
+                    json!(args
+                        .geometry
+                        .iter()
+                        .map(|g| g.to_string())
+                        .collect::<Vec<_>>()),
+                ),
+                (
+                    "region".to_owned(),
+                    json!({
+                        "x": roi_screen_coord.x,
+                        "y": roi_screen_coord.y,
+                        "width": roi_screen_coord.w,
+                        "height": roi_screen_coord.h,
+                    }),
+                ),
+                (
+                    "audio".to_owned(),
+                    json!(args.audio.then(|| json!({
+                        "device": args.audio_device,
+                        "backend": args.audio_backend,
+                        "codec": format!("{:?}", args.audio_codec),
+                    }))),
+                ),
+            ])
+        });
+
+        // the timecode is the time of the file's first frame, so the header has to wait for it.
+        // Appending keeps the original recording's first frame, and its timecode
+        let header_written = if args.timecode && append_src.is_none() {
+            let sts = unsafe { avformat_init_output(octx.as_mut_ptr(), null_mut()) };
+            if sts < 0 {
+                return Err(ffmpeg::Error::from(sts))
+                    .with_context(|| format!("failed to initialize muxer for {}", args.filename));
+            }
+            false
+        } else {
+            if let Some(src) = append_src.as_deref().filter(|_| args.timecode) {
+                let timecode = existing_timecode(src);
+                let mut ost = octx.stream_mut(vid_stream_idx).unwrap();
+                match timecode {
+                    Some(timecode) => ost.set_metadata(dict! { "timecode" => &timecode }),
+                    None => ost.set_metadata(dict!()),
+                }
+            }
+            octx.write_header().unwrap();
+            true
+        };
         let audio = incomplete_audio_state.map(|ias| ias.finish(args, &octx));
 
-        if args.verbose >= 1 {
+        let append_offset_ns = if let Some(src) = &append_src {
+            let (EncodePixelFormat::Vaapi(video_format) | EncodePixelFormat::Sw(video_format)) =
+                enc_pixfmt;
+            let end_ns =
+                copy_existing_recording(src, &mut octx, video_format).with_context(|| {
+                    format!(
+                        "failed to append, original recording is at {}",
+                        src.display()
+                    )
+                })?;
+            fs::remove_file(src)?;
+            info!(
+                target: log_spec::ENCODE,
+                "appending to existing recording, which is {:?} long",
+                Duration::from_nanos(end_ns as u64)
+            );
+            end_ns
+        } else {
+            0
+        };
+
+        // flushing every second is only worth it if the file can be recovered from
+        let journal = if (args.history.is_none() || args.continuous)
+            && Path::new(&args.filename).is_file()
+            && container::recoverable(&octx.format(), args.ffmpeg_muxer_options.as_deref())
+        {
+            let flushed = if header_written {
+                journal::flush(&mut octx).unwrap_or_default()
+            } else {
+                0
+            };
+            Journal::create(&args.filename_template, &args.filename, flushed)
+        } else {
+            None
+        };
+
+        if args.log.level(Subsystem::Encode, args.verbose) >= LevelFilter::Info {
             ffmpeg_next::format::context::output::dump(&octx, 0, Some(&args.filename));
         }
 
-        let history_state = match args.history {
-            Some(history) => HistoryState::RecordingHistory(history, VecDeque::new()),
-            None => HistoryState::Recording(0), // recording since the beginnging, no PTS offset
+        let mut split_at = args.split_at.clone();
+        if args.split_at_midnight {
+            split_at.push("00:00".parse().unwrap());
+        }
+
+        let replay_buffer = args.history.map(|history| {
+            let preroll = args.history_preroll.unwrap_or_default();
+            let keep = octx
+                .streams()
+                .map(|st| match st.parameters().medium() {
+                    media::Type::Audio => history + preroll,
+                    _ => history,
+                })
+                .collect();
+            let spill = args
+                .history_spill
+                .as_deref()
+                .map(|dir| (dir, args.history_memory.into::<Byte>().value() as usize));
+            (keep, HistoryBuffer::new(spill))
+        });
+        let (history_state, highlights) = match replay_buffer {
+            Some((keep, history)) if args.continuous => (
+                HistoryState::Recording(0),
+                Some(Highlights::new(
+                    keep,
+                    history,
+                    args.history_filename.clone(),
+                    Destination::from_args(args)?,
+                )),
+            ),
+            Some((keep, history)) => (HistoryState::RecordingHistory(keep, history), None),
+            None => (HistoryState::Recording(-append_offset_ns), None), // recording since the beginnging, only offset if appending
         };
 
-        Ok(EncState {
+        let mut enc = EncState {
             video_filter,
             enc_video,
             enc_video_has_been_fed_any_frames: false,
             filter_output_timebase: filter_timebase,
             octx,
+            header_written,
+            timecode_rate: args.timecode.then_some(refresh),
+            frame_clock: None,
             vid_stream_idx,
             hw_device_ctx,
+            capture_device,
+            capture_buffers: HashMap::new(),
             enc_pixfmt,
             roi_screen_coord,
+            composite,
+            exclusions,
+            letterbox: false,
+            cursor_overlay,
+            text_overlay,
             transform,
+            framerate: refresh,
             enc_video_options,
             frames_rgb,
             history_state,
+            history_transcode: args.history_transcode.clone(),
+            history_clip: None,
+            highlights,
+            upload: Destination::from_args(args)?,
+            finisher: Finisher::default(),
             sigusr1_flag,
+            metrics,
             audio,
             selected_format: capture_format,
             format_change: false,
-        })
+            keep_encode_size: false,
+            stats: PipelineStats::new(),
+            sd_notify: SdNotify::from_env(),
+            bytes_written: 0,
+            filename: args.filename.clone(),
+            filename_template: args.filename_template.clone(),
+            journal,
+            muxer: args.ffmpeg_muxer.clone(),
+            muxer_options: args.ffmpeg_muxer_options.clone(),
+            next_split: next_boundary(&split_at, SystemTime::now()),
+            split_at,
+            split: SplitState::None,
+            proxy: None,
+            extra_stream,
+            frame_timing: FrameTiming::default(),
+            frame_durations,
+            markers,
+            splice,
+            screenshot_filename: args.screenshot_filename.clone(),
+            screenshot_requested: false,
+            poster: args.poster.map(Poster::new),
+            trim: Trim::new(args.trim_start, args.trim_end),
+            discard_shorter_than: args
+                .discard_shorter_than
+                .filter(|_| append_src.is_none())
+                .map(|d| d.as_nanos() as i64),
+            encode_log,
+            cursor_roi: None,
+            frame_qoffset: None,
+            damage_roi: None,
+            last_forced_keyframe: None,
+            dri_device,
+            encode_device: args.encode_device.clone(),
+            capture_formats: capture_formats.to_vec(),
+            context,
+            file_markers: Vec::new(),
+            encoder_error: None,
+            packets_at_restart: None,
+            last_surface: None,
+        };
+        if let Some(spec) = &args.proxy {
+            enc.proxy = Some(Rendition::new_file(args, spec, enc.rendition_source())?);
+        }
+        Ok(enc)
     }
 
     fn process_ready(&mut self) {
+        if self.highlights.is_some() && self.sigusr1_flag.swap(false, Ordering::SeqCst) {
+            self.save_highlight();
+        }
+
         // if we were recording history and got the SIGUSR1 flag
         if let (HistoryState::RecordingHistory(_, hist), true) = (
             &mut self.history_state,
@@ -1879,27 +4516,52 @@ impl EncState {
             let pts_offset_ns = self
                 .octx
                 .streams()
-                .filter_map(|st| hist.iter().find(|p| p.stream() == st.index()))
+                .filter_map(|st| hist.packets().find(|p| p.stream == st.index()))
                 .map(|packet| {
-                    let tb = self.octx.stream(packet.stream()).unwrap().time_base();
-                    packet.pts().unwrap() * 1_000_000_000 * tb.0 as i64 / tb.1 as i64
+                    let tb = self.octx.stream(packet.stream).unwrap().time_base();
+                    packet.pts * 1_000_000_000 * tb.0 as i64 / tb.1 as i64
                 })
                 .min()
                 .unwrap_or(0);
 
             eprintln!("SIGUSR1 received, flushing history");
-            info!("pts offset is {:?}ns", pts_offset_ns);
-
-            // grab this before we set history_state
-            let mut hist_moved = VecDeque::new();
-            swap(hist, &mut hist_moved);
-
-            // transition history state
-            self.history_state = HistoryState::Recording(pts_offset_ns);
+            info!(target: log_spec::ENCODE, "pts offset is {:?}ns", pts_offset_ns);
+
+            // transition history state, taking the history with it
+            let HistoryState::RecordingHistory(_, hist) = mem::replace(
+                &mut self.history_state,
+                HistoryState::Recording(pts_offset_ns),
+            ) else {
+                unreachable!()
+            };
+            self.metrics.set_history_bytes(0);
 
-            for packet in hist_moved.drain(..) {
+            let mut clip_packets = Vec::new();
+            for packet in hist.into_packets() {
+                if self.history_transcode.is_some() {
+                    clip_packets.push(packet.clone());
+                }
                 self.on_encoded_packet(packet);
             }
+
+            if let Some(spec) = &self.history_transcode {
+                let streams = self
+                    .octx
+                    .streams()
+                    .map(|st| SourceStream {
+                        parameters: st.parameters().clone(),
+                        time_base: st.time_base(),
+                    })
+                    .collect();
+                self.history_clip = history_transcode::spawn(
+                    spec,
+                    streams,
+                    self.vid_stream_idx,
+                    self.enc_video.frame_rate(),
+                    pts_offset_ns,
+                    clip_packets,
+                );
+            }
         }
 
         let mut yuv_frame = frame::Video::empty();
@@ -1911,13 +4573,104 @@ impl EncState {
             .frame(&mut yuv_frame)
             .is_ok()
         {
+            if let SplitState::ForceKeyframe = self.split {
+                yuv_frame.set_kind(picture::Type::I);
+                self.split = SplitState::WaitingForKeyframe(yuv_frame.pts().unwrap());
+            }
+            if let Some(splice) = self.splice.as_mut().filter(|s| s.has_pending()) {
+                yuv_frame.set_kind(picture::Type::I);
+                let cue = splice.packet(&self.octx, yuv_frame.pts().unwrap());
+                self.on_encoded_packet(cue);
+            }
+            let keep = match (&self.history_state, &self.highlights) {
+                (HistoryState::RecordingHistory(keep, _), _) => Some(&keep[..]),
+                (_, Some(highlights)) => Some(highlights.keep()),
+                _ => None,
+            };
+            if let Some(keep) = keep {
+                let pts = yuv_frame.pts().unwrap();
+                let interval =
+                    history::keyframe_interval(keep[self.vid_stream_idx]).as_nanos() as i64;
+                if self
+                    .last_forced_keyframe
+                    .is_none_or(|last| pts - last >= interval)
+                {
+                    yuv_frame.set_kind(picture::Type::I);
+                    self.last_forced_keyframe = Some(pts);
+                    if let Some(extra) = &mut self.extra_stream {
+                        extra.force_keyframe();
+                    }
+                }
+            }
+            if mem::take(&mut self.screenshot_requested) {
+                let mut path = strftime_local(&self.screenshot_filename, SystemTime::now());
+                if Path::new(&path).exists() {
+                    path = non_clobbering_filename(&path);
+                }
+                screenshot::save(&yuv_frame, path.into());
+            }
+            if let Some(poster) = &mut self.poster {
+                poster.push(&yuv_frame);
+            }
+            let damaged = self.damage_roi.iter().flatten();
+            let frame_qoffset = self
+                .frame_qoffset
+                .or(self.damage_roi.is_some().then_some(roi::UNDAMAGED_QOFFSET));
+            let regions: Vec<_> = self
+                .cursor_roi
+                .map(|r| (r, roi::CURSOR_QOFFSET))
+                .into_iter()
+                .chain(damaged.map(|r| (*r, roi::DAMAGED_QOFFSET)))
+                .chain(frame_qoffset.map(|q| {
+                    (
+                        Rect::new(
+                            (0, 0),
+                            (yuv_frame.width() as i32, yuv_frame.height() as i32),
+                        ),
+                        q,
+                    )
+                }))
+                .collect();
+            roi::attach(&mut yuv_frame, &regions);
+
             // encoder has same time base as the filter, so don't do any time scaling
-            self.enc_video.send_frame(&yuv_frame).unwrap();
+            if let Err(e) = self.enc_video.send_frame(&yuv_frame) {
+                error!(target: log_spec::ENCODE, "failed to encode frame: {e}");
+                self.encoder_error = Some(e);
+                break;
+            }
             self.enc_video_has_been_fed_any_frames = true;
+            self.stats.frames_into_encoder += 1;
         }
 
         let mut encoded = Packet::empty();
         while self.enc_video.receive_packet(&mut encoded).is_ok() {
+            self.stats.packets_from_encoder += 1;
+            self.metrics.frame_encoded();
+            self.sd_notify.ready();
+
+            if self.encode_log.is_some() || log_enabled!(target: log_spec::ENCODE, Level::Trace) {
+                let stats = PacketStats::of(&encoded);
+                let pts = encoded.pts().unwrap_or(0);
+                trace!(target: log_spec::ENCODE, "encoded video packet at {pts} ns: {stats:?}");
+                if let Some(log) = &mut self.encode_log {
+                    log.packet(pts, &stats);
+                }
+            }
+
+            // packet is still in the filter timebase (ns) here, same as the split PTS
+            if let SplitState::WaitingForKeyframe(split_pts) = self.split {
+                if encoded.is_key() && encoded.pts().unwrap() >= split_pts {
+                    self.split = SplitState::None;
+                    if let Err(e) = self.start_new_file(split_pts) {
+                        error!(
+                            target: log_spec::ENCODE,
+                            "failed to start new file, continuing to write to {}: {e:?}",
+                            self.filename
+                        );
+                    }
+                }
+            }
             encoded.set_stream(self.vid_stream_idx);
             encoded.rescale_ts(
                 self.filter_output_timebase,
@@ -1928,21 +4681,555 @@ impl EncState {
             encoded = Packet::empty();
         }
 
+        let extra_packets = self
+            .extra_stream
+            .as_mut()
+            .map(|e| e.take_packets(&self.octx))
+            .unwrap_or_default();
+        for pack in extra_packets {
+            self.on_encoded_packet(pack);
+        }
+
+        self.drain_audio();
+    }
+
+    fn drain_audio(&mut self) {
         while let Some(pack) = self.audio.as_mut().and_then(|ar| ar.try_recv().ok()) {
             self.on_encoded_packet(pack);
         }
     }
 
+    fn set_cursor(&mut self, cursor: Option<(i32, i32)>, box_size: (u32, u32)) {
+        let content = self.content();
+        self.cursor_roi = cursor
+            .and_then(|c| {
+                roi::cursor_region(c, box_size, self.roi_screen_coord, (content.w, content.h))
+            })
+            .map(|r| r.translate((content.x, content.y)));
+    }
+
+    // `damage` is in buffer coordinates, as the compositor reports it
+    fn set_damage(&mut self, damage: &[Rect]) {
+        let (capture_w, capture_h) = (self.selected_format.width, self.selected_format.height);
+        let damage: Vec<_> = damage
+            .iter()
+            .map(|d| d.frame_to_screen(capture_w, capture_h, self.transform))
+            .collect();
+        let content = self.content();
+        self.damage_roi = Some(
+            roi::damage_regions(&damage, self.roi_screen_coord, (content.w, content.h))
+                .into_iter()
+                .map(|r| r.translate((content.x, content.y)))
+                .collect(),
+        );
+    }
+
+    // pts_ns is the pts of a frame that was on screen for duration_ns, known once the next frame
+    // arrives
+    fn on_frame_duration(&mut self, pts_ns: i64, duration_ns: i64) {
+        if let Some(track) = &self.frame_durations {
+            let text = format!("{:.3} ms", duration_ns as f64 / 1e6);
+            let cue = track.cue(&self.octx, &text, pts_ns, duration_ns);
+            self.on_encoded_packet(cue);
+        }
+    }
+
+    // a --markers cue from the last frame encoded on
+    fn add_marker(&mut self, text: &str) {
+        let Some(track) = &self.markers else {
+            warn!(
+                target: log_spec::ENCODE,
+                "ignoring marker {text:?}, start the recording with --markers to add them"
+            );
+            return;
+        };
+        let pts = self.frame_timing.last_pts().unwrap_or(0);
+        let cue = track.cue(&self.octx, text, pts, MARKER_DURATION_NS);
+        self.on_encoded_packet(cue);
+        if let HistoryState::Recording(file_start) = self.history_state {
+            self.file_markers.push((pts - file_start, text.to_owned()));
+        }
+    }
+
+    // the split control command: the next frame is forced to be a keyframe, and the file is
+    // switched on it, so the new file starts exactly where the old one ends
+    fn request_split(&mut self) {
+        if let HistoryState::RecordingHistory(..) = self.history_state {
+            warn!(
+                target: log_spec::ENCODE,
+                "ignoring split, nothing is being written to a file until --history is triggered"
+            );
+            return;
+        }
+        if !matches!(self.split, SplitState::None) {
+            info!(target: log_spec::ENCODE, "already splitting, ignoring split");
+            return;
+        }
+        info!(
+            target: log_spec::ENCODE,
+            "split requested, starting a new file at the next keyframe"
+        );
+        self.force_split();
+    }
+
+    fn force_split(&mut self) {
+        self.split = SplitState::ForceKeyframe;
+        if let Some(extra) = &mut self.extra_stream {
+            extra.force_keyframe();
+        }
+    }
+
+    // --splice-points, written with the next frame out of the filter
+    fn add_splice(&mut self, cue: SpliceCue) {
+        let Some(track) = &mut self.splice else {
+            warn!(
+                target: log_spec::ENCODE,
+                "ignoring splice {cue:?}, start the recording with --splice-points to add them"
+            );
+            return;
+        };
+        info!(target: log_spec::ENCODE, "inserting splice point {cue:?}");
+        track.push(cue);
+        if let Some(extra) = &mut self.extra_stream {
+            extra.force_keyframe();
+        }
+    }
+
+    // capture all of `output` instead of the current output
+    fn move_to_output(&mut self, output: &OutputInfo, args: &Args) -> anyhow::Result<()> {
+        self.transform = output.transform;
+        // --exclude-region is in global coordinates, so it's somewhere else on this output
+        self.exclusions = output.exclusions(args);
+        let roi = Rect::new((0, 0), output.size_screen_space());
+        if (self.selected_format.width, self.selected_format.height) == output.size_pixels {
+            self.set_roi(roi)
+        } else {
+            // the capture format will change too, on_new_capture_format letterboxes the new size
+            // into the file's
+            self.roi_screen_coord = roi;
+            self.keep_encode_size = true;
+            Ok(())
+        }
+    }
+
+    // crop a different part of the capture, scaled to fit the same encode size
+    fn set_roi(&mut self, roi: Rect) -> anyhow::Result<()> {
+        let roi = roi.fit_inside_bounds(self.selected_format.width, self.selected_format.height);
+        if roi.w == 0 || roi.h == 0 {
+            bail!("region is zero-sized");
+        }
+        let (enc_w, enc_h) = (
+            self.enc_video.width() as i32,
+            self.enc_video.height() as i32,
+        );
+        if i64::from(roi.w) * i64::from(enc_h) != i64::from(roi.h) * i64::from(enc_w) {
+            info!(
+                target: log_spec::ENCODE,
+                "new region is {}x{}, which will be letterboxed to the encode size of {enc_w}x{enc_h}",
+                roi.w, roi.h
+            );
+        }
+
+        // flush frames out of the old filter, the encoder keeps going
+        multi_output::flush_sources(&mut self.video_filter);
+        self.process_ready();
+
+        self.roi_screen_coord = roi;
+        if self.composite.take().is_some() {
+            info!(
+                target: log_spec::ENCODE,
+                "recording the new region alone instead of the composited --geometry regions"
+            );
+        }
+        let (filter, filter_timebase) = video_filter(
+            &mut self.frames_rgb,
+            self.enc_pixfmt,
+            self.capture_device.is_some().then_some(&self.hw_device_ctx),
+            (self.selected_format.width, self.selected_format.height),
+            roi,
+            None,
+            &self.exclusions,
+            self.cursor_overlay.as_ref(),
+            self.text_overlay.as_mut(),
+            (enc_w, enc_h),
+            true,
+            self.transform,
+        );
+        self.video_filter = filter;
+        self.filter_output_timebase = filter_timebase;
+        self.letterbox = true;
+
+        let mut renditions = [self.proxy.take(), self.extra_stream.take()];
+        for r in renditions.iter_mut().flatten() {
+            r.rebuild_filter(self.rendition_source());
+        }
+        [self.proxy, self.extra_stream] = renditions;
+        self.process_ready();
+
+        Ok(())
+    }
+
+    // --outputs: the surfaces of another output's frames changed (its first frame is in, or it
+    // was resized), so the filters are built again to take them. The encoders keep going
+    fn rebuild_filters(&mut self) {
+        multi_output::flush_sources(&mut self.video_filter);
+        self.process_ready();
+
+        let (filter, filter_timebase) = video_filter(
+            &mut self.frames_rgb,
+            self.enc_pixfmt,
+            self.capture_device.is_some().then_some(&self.hw_device_ctx),
+            (self.selected_format.width, self.selected_format.height),
+            self.roi_screen_coord,
+            self.composite.as_ref(),
+            &self.exclusions,
+            self.cursor_overlay.as_ref(),
+            self.text_overlay.as_mut(),
+            (
+                self.enc_video.width() as i32,
+                self.enc_video.height() as i32,
+            ),
+            self.letterbox,
+            self.transform,
+        );
+        self.video_filter = filter;
+        self.filter_output_timebase = filter_timebase;
+
+        let mut renditions = [self.proxy.take(), self.extra_stream.take()];
+        for r in renditions.iter_mut().flatten() {
+            r.rebuild_filter(self.rendition_source());
+        }
+        [self.proxy, self.extra_stream] = renditions;
+        self.process_ready();
+    }
+
+    // where the region is in the encoded frame, all of it unless set_roi letterboxed it
+    fn content(&self) -> Rect {
+        let enc_size = (
+            self.enc_video.width() as i32,
+            self.enc_video.height() as i32,
+        );
+        if self.letterbox {
+            transform::letterbox(
+                source_size(self.roi_screen_coord, self.composite.as_ref()),
+                enc_size,
+            )
+        } else {
+            Rect::new((0, 0), enc_size)
+        }
+    }
+
+    // before frames_rgb is replaced, the surface ids of the new one can be the same
+    fn clear_capture_buffers(&mut self) {
+        for (_, wl_buffer) in self.capture_buffers.drain() {
+            wl_buffer.destroy();
+        }
+    }
+
+    // --print-config, everything that was decided from what the compositor and driver support
+    fn config(&self) -> serde_json::Value {
+        let format = self.selected_format;
+        let (encode_pixfmt, encode_surfaces) = match self.enc_pixfmt {
+            EncodePixelFormat::Vaapi(fmt) => (fmt, "vaapi"),
+            EncodePixelFormat::Sw(fmt) => (fmt, "memory"),
+        };
+        json!({
+            "capture": {
+                "width": format.width,
+                "height": format.height,
+                "fourcc": format.fourcc.to_string(),
+                "modifier": format!("{:?}", format.modifier),
+                "pixel_format": format!("{:?}", dmabuf_to_av(format.fourcc)),
+            },
+            "offered_formats": self
+                .capture_formats
+                .iter()
+                .map(|f| json!({
+                    "fourcc": f.fourcc.to_string(),
+                    "modifiers": f.modifiers.iter().map(|m| format!("{m:?}")).collect::<Vec<_>>(),
+                }))
+                .collect::<Vec<_>>(),
+            "dri_device": self.dri_device.display().to_string(),
+            "encode_device": self.encode_device.as_ref().map(|d| d.display().to_string()),
+            "encoder": self.enc_video.codec().map(|c| c.name().to_owned()),
+            "encoder_options": self
+                .enc_video_options
+                .iter()
+                .map(|(k, v)| (k.to_owned(), json!(v)))
+                .collect::<serde_json::Map<_, _>>(),
+            "encode": {
+                "width": self.enc_video.width(),
+                "height": self.enc_video.height(),
+                "pixel_format": format!("{encode_pixfmt:?}"),
+                "surfaces": encode_surfaces,
+            },
+            "framerate": format!("{}/{}", self.framerate.numerator(), self.framerate.denominator()),
+            "filter_graph": self.video_filter.dump(),
+        })
+    }
+
+    fn rendition_source(&mut self) -> RenditionSource<'_> {
+        RenditionSource {
+            hw_device_ctx: &mut self.hw_device_ctx,
+            cross_device: self.capture_device.is_some(),
+            frames_rgb: &mut self.frames_rgb,
+            capture_format: self.selected_format,
+            roi_screen_coord: self.roi_screen_coord,
+            composite: self.composite.as_ref(),
+            exclusions: &self.exclusions,
+            letterbox: self.letterbox,
+            transform: self.transform,
+            enc_pixfmt: self.enc_pixfmt,
+            enc_video: &self.enc_video,
+            enc_video_options: &self.enc_video_options,
+        }
+    }
+
+    // flushes the current encoder and opens a new one, for when the capture format or encode
+    // settings change mid-recording
+    fn rebuild_encoder(&mut self, args: &Args) -> anyhow::Result<()> {
+        // flush old filter & encoder
+        multi_output::flush_sources(&mut self.video_filter);
+        self.process_ready();
+        if self.enc_video_has_been_fed_any_frames {
+            // ffmpeg bug--if you call send_eof before feeding any frames it will crash
+            self.enc_video.send_eof().unwrap();
+        }
+        self.process_ready();
+
+        self.open_encoder(args)
+    }
+
+    // replaces the filter and encoder with new ones, dropping whatever is still in the old ones
+    fn open_encoder(&mut self, args: &Args) -> anyhow::Result<()> {
+        let enc_pixfmt_av = self.enc_pixfmt.surface_format();
+        let (enc_w, enc_h) = encode_size(
+            args,
+            source_size(self.roi_screen_coord, self.composite.as_ref()),
+        );
+
+        let mut frames_yuv = self.hw_device_ctx
+            .create_frame_ctx(enc_pixfmt_av, enc_w, enc_h, DrmModifier::LINEAR, Usage::Encode)
+            .with_context(|| {
+                format!("Failed to create a vaapi frame context for encode surfaces of format {enc_pixfmt_av:?} {enc_w}x{enc_h}")
+            })?;
+
+        let encoder = self.enc_video.codec().unwrap();
+        let global_header = self
+            .octx
+            .format()
+            .flags()
+            .contains(format::Flags::GLOBAL_HEADER);
+        let enc = make_video_params(
+            args,
+            self.enc_pixfmt,
+            &encoder,
+            (enc_w, enc_h),
+            self.framerate,
+            global_header,
+            &mut self.hw_device_ctx,
+            &mut frames_yuv,
+        )?;
+
+        self.enc_video = enc.open_with(self.enc_video_options.clone())?;
+        self.enc_video_has_been_fed_any_frames = false;
+        self.last_surface = None;
+
+        let (filter, filter_timebase) = video_filter(
+            &mut self.frames_rgb,
+            self.enc_pixfmt,
+            self.capture_device.is_some().then_some(&self.hw_device_ctx),
+            (self.selected_format.width, self.selected_format.height),
+            self.roi_screen_coord,
+            self.composite.as_ref(),
+            &self.exclusions,
+            self.cursor_overlay.as_ref(),
+            self.text_overlay.as_mut(),
+            (enc_w, enc_h),
+            false,
+            self.transform,
+        );
+        self.video_filter = filter;
+        self.filter_output_timebase = filter_timebase;
+        self.letterbox = false;
+
+        let mut renditions = [self.proxy.take(), self.extra_stream.take()];
+        let res = renditions
+            .iter_mut()
+            .flatten()
+            .try_for_each(|r| r.rebuild(self.rendition_source()));
+        [self.proxy, self.extra_stream] = renditions;
+        res?;
+
+        Ok(())
+    }
+
+    // Drivers can start failing to encode after a GPU reset. The old device and everything on it
+    // can't be relied on (not even to flush), so open the device again and build new capture
+    // surfaces, filter and encoder on it. Frames still in the old ones are lost, the file carries
+    // on from the next captured frame
+    fn recover_encoder(&mut self, args: &Args) -> anyhow::Result<()> {
+        let Some(e) = self.encoder_error.take() else {
+            return Ok(());
+        };
+        if self.packets_at_restart == Some(self.stats.packets_from_encoder) {
+            bail!("encoder failed again before producing anything since it was restarted: {e}");
+        }
+        warn!(target: log_spec::ENCODE, "encoder failed ({e}), restarting it");
+
+        let capture_device = AvHwDevCtx::new(HwBackend::Vaapi, &self.dri_device)
+            .map_err(|e| format_err!("Failed to reopen vaapi device: {e}"))?;
+        match &args.encode_device {
+            Some(encode_device) => {
+                self.hw_device_ctx = AvHwDevCtx::new(HwBackend::Vaapi, encode_device)
+                    .map_err(|e| format_err!("Failed to reopen vaapi device: {e}"))?;
+                self.capture_device = Some(capture_device);
+            }
+            None => self.hw_device_ctx = capture_device,
+        }
+        for other in self.composite.iter().flat_map(|c| &c.others) {
+            other.set_device(self.capture_device.as_ref().unwrap_or(&self.hw_device_ctx));
+        }
+        let format = self.selected_format;
+        let capture_pixfmt = dmabuf_to_av(format.fourcc);
+        self.clear_capture_buffers();
+        self.frames_rgb = self.capture_device.as_mut().unwrap_or(&mut self.hw_device_ctx)
+            .create_frame_ctx(capture_pixfmt, format.width, format.height, format.modifier, Usage::Capture)
+            .with_context(|| format!("Failed to create vaapi frame context for capture surfaces of format {capture_pixfmt:?} {format:?}"))?;
+        self.open_encoder(args)?;
+        self.packets_at_restart = Some(self.stats.packets_from_encoder);
+        self.metrics.restarted();
+        Ok(())
+    }
+
+    // attaches wl-screenrec.json and the poster to self.filename once it's written, trims it and
+    // uploads it, in the background. `last` if the recording is over
+    fn finish_file(&mut self, last: bool) {
+        let markers = mem::take(&mut self.file_markers);
+        let context = self.context.as_ref().map(|fields| {
+            let mut fields = fields.clone();
+            if let serde_json::Value::Object(config) = self.config() {
+                fields.extend(config);
+            }
+            fields.insert(
+                "markers".to_owned(),
+                json!(markers
+                    .iter()
+                    .map(|(pts, text)| json!({ "pts_ns": pts, "text": text }))
+                    .collect::<Vec<_>>()),
+            );
+            attachment::context(fields)
+        });
+        let poster = (self.poster.as_mut()).and_then(|p| p.take(&self.filename, last));
+        if context.is_some() || poster.is_some() {
+            let path = self.filename.clone();
+            self.finisher
+                .run(move || attachment::embed(&path, context.as_ref(), poster));
+        }
+        if let Some(trim) = &mut self.trim {
+            trim.finish_file(
+                &mut self.finisher,
+                &self.filename,
+                self.muxer.as_deref(),
+                self.muxer_options.as_deref(),
+                last,
+            );
+        }
+        if let Some(destination) = &self.upload {
+            let (destination, path) = (destination.clone(), self.filename.clone());
+            self.finisher
+                .run(move || upload::upload(&destination, &path));
+        }
+    }
+
+    // wall-clock time of `pts` (ns, in the encoder's time base), worked out from the last frame
+    fn wallclock_at(&self, pts: i64) -> SystemTime {
+        let Some((frame_pts, at)) = self.frame_clock else {
+            return SystemTime::now();
+        };
+        let offset = Duration::from_nanos(pts.abs_diff(frame_pts));
+        if pts >= frame_pts {
+            at + offset
+        } else {
+            at - offset
+        }
+    }
+
+    // --timecode of a file that starts with the frame at `start_pts`
+    fn timecode_at(&self, start_pts: i64) -> Option<String> {
+        let rate = self.timecode_rate?;
+        Some(wallclock_timecode(rate, self.wallclock_at(start_pts)))
+    }
+
+    fn write_pending_header(&mut self, start_pts: i64) {
+        if let Some(timecode) = self.timecode_at(start_pts) {
+            info!(target: log_spec::ENCODE, "timecode of {} is {timecode}", self.filename);
+            let mut ost = self.octx.stream_mut(self.vid_stream_idx).unwrap();
+            ost.set_metadata(dict! { "timecode" => &timecode });
+        }
+        self.octx.write_header().unwrap();
+        self.header_written = true;
+    }
+
+    // finish the current file and continue in a new one, starting from `start_pts` (ns)
+    fn start_new_file(&mut self, start_pts: i64) -> anyhow::Result<()> {
+        let mut filename = strftime_local(&self.filename_template, SystemTime::now());
+        if filename == self.filename || Path::new(&filename).exists() {
+            filename = non_clobbering_filename(&filename);
+        }
+
+        let mut octx = open_output(
+            &filename,
+            self.muxer.as_deref(),
+            self.muxer_options.as_deref(),
+        )?;
+        for stream in self.octx.streams() {
+            let mut ost = octx.add_stream(None::<ffmpeg::Codec>)?;
+            ost.set_parameters(stream.parameters());
+            ost.set_metadata(stream.metadata().to_owned());
+            unsafe { (*ost.as_mut_ptr()).disposition = stream.disposition().bits() };
+        }
+        if let Some(timecode) = self.timecode_at(start_pts) {
+            info!(target: log_spec::ENCODE, "timecode of {filename} is {timecode}");
+            let mut ost = octx.stream_mut(self.vid_stream_idx).unwrap();
+            ost.set_metadata(dict! { "timecode" => &timecode });
+        }
+        octx.write_header()?;
+
+        let mut old = mem::replace(&mut self.octx, octx);
+        old.write_trailer()?;
+        self.discard_shorter_than = None;
+        self.finish_file(false);
+        info!(target: log_spec::ENCODE, "finished {}, continuing in {filename}", self.filename);
+
+        self.history_state = HistoryState::Recording(start_pts);
+        if let Some(journal) = &mut self.journal {
+            journal.set_filename(&filename);
+        }
+        self.filename = filename;
+        Ok(())
+    }
+
     fn on_encoded_packet(&mut self, mut encoded: Packet) {
         let stream = self.octx.stream(encoded.stream()).unwrap();
 
         match &mut self.history_state {
             HistoryState::Recording(pts_offset) => {
                 let tb = stream.time_base();
-                let pts_offset = *pts_offset * i64::from(tb.1) / i64::from(tb.0) / 1_000_000_000;
-
+                let start_pts = *pts_offset;
+                let pts_offset = start_pts * i64::from(tb.1) / i64::from(tb.0) / 1_000_000_000;
+
+                if encoded.pts().unwrap() < pts_offset {
+                    // packets from before the start of a new file (audio lags behind video a bit)
+                    trace!(
+                        target: log_spec::ENCODE,
+                        "dropping packet from before the start of {}", self.filename
+                    );
+                    return;
+                }
                 encoded.set_pts(Some(encoded.pts().unwrap() - pts_offset));
                 trace!(
+                    target: log_spec::ENCODE,
                     "writing pts={} on {:?} is_key={}",
                     encoded.pts().unwrap(),
                     self.octx
@@ -1953,123 +5240,529 @@ impl EncState {
                     encoded.is_key()
                 );
                 encoded.set_dts(encoded.dts().map(|dts| dts - pts_offset));
-                encoded.write_interleaved(&mut self.octx).unwrap();
-            }
-            HistoryState::RecordingHistory(history_dur, history) => {
-                history.push_back(encoded);
 
-                // discard old history if necessary
-                while let Some(front) = history.front() {
-                    let last_in_stream = history
-                        .iter()
-                        .rev()
-                        .find(|p| p.stream() == front.stream())
-                        .unwrap()
-                        .clone();
+                self.bytes_written += encoded.size() as u64;
+                self.metrics.wrote(encoded.size());
+                let bytes_written = self.bytes_written;
+                let pts_secs = encoded.pts().unwrap() as f64 * f64::from(tb);
+                self.sd_notify.status(|| {
+                    format!(
+                        "Recording, {:.0}s encoded, {:.1} MB written",
+                        pts_secs,
+                        bytes_written as f64 / 1e6
+                    )
+                });
 
-                    if let Some((key_idx, _)) = history
-                        .iter()
-                        .enumerate()
-                        .filter(|(_, a)| a.stream() == front.stream() && a.is_key())
-                        .nth(1)
-                    {
-                        let key_pts = history[key_idx].pts().unwrap();
-
-                        let current_history_size_pts =
-                            u64::try_from(last_in_stream.pts().unwrap() - key_pts).unwrap();
-                        let current_history_size = Duration::from_nanos(
-                            current_history_size_pts * stream.time_base().0 as u64 * 1_000_000_000
-                                / stream.time_base().1 as u64,
-                        );
+                if let Some(highlights) = &mut self.highlights {
+                    highlights.push(&encoded, tb);
+                    self.metrics.set_history_bytes(highlights.memory_bytes());
+                }
 
-                        if current_history_size > *history_dur {
-                            // erase everything in that stream <= key_idx
-                            let mut removed_bytes = 0;
-                            let mut removed_packets = 0;
-
-                            let mut final_idx = key_idx;
-                            let mut i = 0;
-                            while i < final_idx {
-                                if history[i].stream() == last_in_stream.stream() {
-                                    removed_bytes += history[i].size();
-                                    removed_packets += 1;
-
-                                    history.remove(i);
-                                    final_idx -= 1;
-                                } else {
-                                    i += 1;
-                                }
-                            }
+                if !self.header_written {
+                    self.write_pending_header(start_pts);
+                }
+                let key_frame = encoded.is_key() && encoded.stream() == self.vid_stream_idx;
+                match encoded.write_interleaved(&mut self.octx) {
+                    // --shutdown-timeout passed, the rest is dropped
+                    Err(_) if shutdown::aborted() => return,
+                    res => res.unwrap(),
+                }
 
-                            debug!(
-                                "history is {:?} > {:?}, popping from history buffer {} bytes across {} packets on stream {:?}", 
-                                current_history_size, history_dur,
-                                removed_bytes,
-                                removed_packets,
-                                self.octx.stream(last_in_stream.stream()).unwrap().parameters().medium()
-                            );
-                        } else {
-                            break; // there is a second keyframe in the stream, but it isn't old enough yet
-                        }
-                    } else {
-                        break; // no second keyframe in the stream
+                if let Some(journal) = self.journal.as_mut().filter(|j| key_frame && j.due()) {
+                    if let Some(flushed) = journal::flush(&mut self.octx) {
+                        journal.update(flushed);
                     }
                 }
             }
+            HistoryState::RecordingHistory(keep, history) => {
+                let stream_idx = encoded.stream();
+                let history_dur = keep[stream_idx];
+                history.push(encoded);
+                let history_len = history.len();
+                self.sd_notify.status(|| {
+                    format!(
+                        "Recording history, {history_len} packets buffered, waiting for SIGUSR1"
+                    )
+                });
+
+                // discard old history of this stream if necessary
+                let (to_remove, removed_bytes) =
+                    history.trim_to(stream_idx, history_dur, stream.time_base());
+                if to_remove > 0 {
+                    debug!(
+                        target: log_spec::ENCODE,
+                        "history is longer than {:?}, popped {} bytes across {} packets on stream {:?}",
+                        history_dur,
+                        removed_bytes,
+                        to_remove,
+                        stream.parameters().medium()
+                    );
+                }
+                self.metrics.set_history_bytes(history.memory_bytes());
+            }
+        }
+    }
+
+    fn flush_audio(&mut self) {
+        if let Some(audio) = &mut self.audio {
+            audio.start_flush();
+        }
+        while let Some(pack) = self.audio.as_mut().and_then(|a| a.recv().ok()) {
+            self.on_encoded_packet(pack);
+        }
+    }
+
+    // --continuous, on SIGUSR1
+    fn save_highlight(&mut self) {
+        let highlights = self.highlights.as_mut().unwrap();
+        let (mut filename, muxer, muxer_options) = match highlights.filename_template() {
+            // its own container, guessed from its name
+            Some(template) => (strftime_local(template, SystemTime::now()), None, None),
+            None => (
+                highlights::clip_filename(&strftime_local(
+                    &self.filename_template,
+                    SystemTime::now(),
+                )),
+                self.muxer.clone(),
+                self.muxer_options.clone(),
+            ),
+        };
+        if Path::new(&filename).exists() {
+            filename = non_clobbering_filename(&filename);
+        }
+        eprintln!("SIGUSR1 received, saving the last of the recording to {filename}");
+        let streams = highlights::source_streams(&self.octx);
+        highlights.save(filename, muxer, muxer_options, streams);
+    }
+
+    // --discard-shorter-than, once the file is finished
+    fn discard_if_short(&self) -> bool {
+        let Some(min) = self.discard_shorter_than else {
+            return false;
+        };
+        let len = self.frame_timing.last_pts().unwrap_or(0);
+        // pipes, devices and network outputs have nothing to delete
+        if len >= min || !Path::new(&self.filename).is_file() {
+            return false;
+        }
+        match fs::remove_file(&self.filename) {
+            Ok(()) => eprintln!(
+                "the recording was only {:.1}s long, deleted {}",
+                len as f64 / 1e9,
+                self.filename
+            ),
+            Err(e) => warn!("failed to delete {}: {e}", self.filename),
+        }
+        true
+    }
+
+    fn flush(&mut self) {
+        self.sd_notify.stopping();
+        self.flush_audio();
+        multi_output::flush_sources(&mut self.video_filter);
+        self.process_ready();
+        self.enc_video.send_eof().unwrap();
+        if let Some(extra) = &mut self.extra_stream {
+            extra.flush();
+        }
+        self.process_ready();
+        if !self.header_written {
+            // nothing was ever written, still leave a valid (empty) file
+            let start_pts = match self.history_state {
+                HistoryState::Recording(start_pts) => start_pts,
+                HistoryState::RecordingHistory(..) => 0,
+            };
+            self.write_pending_header(start_pts);
+        }
+        match self.octx.write_trailer() {
+            Err(e) if shutdown::aborted() => {
+                // the journal is kept, so it can be recovered
+                error!("failed to finish {}: {e}", self.filename);
+            }
+            res => {
+                res.unwrap();
+                if let Some(journal) = self.journal.take() {
+                    journal.finish();
+                }
+                if !self.discard_if_short() {
+                    self.finish_file(true);
+                }
+            }
+        }
+        if let Some(proxy) = &mut self.proxy {
+            proxy.flush();
+        }
+        if let Some(log) = &mut self.encode_log {
+            log.flush();
+        }
+        if let Some(summary) = self.stats.backpressure_summary() {
+            warn!(target: log_spec::ENCODE, "{summary}");
+        }
+    }
+
+    // waits for what's still running in the background once the recording is finished, after the
+    // shutdown watchdog so a long upload doesn't get the recording cut short. Another Ctrl-C gives
+    // up on the uploads
+    fn finish_background(&mut self) {
+        if let Some(clip) = self.history_clip.take() {
+            eprintln!("waiting for the transcoded history to finish");
+            let _ = clip.join();
+        }
+        if let Some(highlights) = &mut self.highlights {
+            highlights.finish();
+        }
+        self.finisher.finish();
+    }
+
+    fn push(&mut self, surf: frame::Video) {
+        if self.next_split.is_some_and(|t| SystemTime::now() >= t) {
+            info!(
+                target: log_spec::ENCODE,
+                "reached --split-at time, starting a new file at the next keyframe"
+            );
+            self.force_split();
+            self.next_split = next_boundary(&self.split_at, SystemTime::now());
+        }
+
+        let mut rebuild = false;
+        for other in self.composite.iter_mut().flat_map(|c| &mut c.others) {
+            rebuild |= other.update();
+        }
+        if rebuild {
+            self.rebuild_filters();
+        }
+        if self
+            .composite
+            .as_ref()
+            .is_some_and(|c| !c.others.is_empty())
+        {
+            self.last_surface = Some(multi_output::new_ref(&surf));
+        }
+
+        for rendition in [&mut self.proxy, &mut self.extra_stream]
+            .into_iter()
+            .flatten()
+        {
+            rendition.push(&surf, self.composite.as_ref());
+        }
+        let content = self.content();
+        if let Some(cursor) = &mut self.cursor_overlay {
+            // it's drawn before the frame is padded out to a letterbox
+            cursor.push(
+                &mut self.video_filter,
+                surf.pts(),
+                self.roi_screen_coord,
+                (content.w, content.h),
+            );
+        }
+        if let Some(text) = &mut self.text_overlay {
+            text.push(&mut self.video_filter, surf.pts());
+        }
+        multi_output::add_frames(&mut self.video_filter, &surf, self.composite.as_ref());
+        self.stats.frames_into_filter += 1;
+
+        self.process_ready();
+        self.stats.maybe_report();
+    }
+}
+
+fn video_filter(
+    inctx: &mut AvHwFrameCtx,
+    pix_fmt: EncodePixelFormat,
+    encode_device: Option<&AvHwDevCtx>, // --encode-device, when it isn't inctx's device
+    (capture_width, capture_height): (i32, i32),
+    roi_screen_coord: Rect,        // size (pixels)
+    composite: Option<&Composite>, // regions to stack instead of cropping to roi_screen_coord
+    exclusions: &Exclusions,
+    cursor: Option<&CursorOverlay>, // --composite-cursor, not with composites
+    text: Option<&mut TextOverlay>, // --overlay-text
+    enc_size_screen_coord: (i32, i32), // size (pixels) to encode. if not same as roi_{w,h}, the image will be scaled.
+    letterbox: bool, // scale to fit the encode size without stretching, padding out the rest
+    transform: Transform,
+) -> (filter::Graph, Rational) {
+    let mut g = ffmpeg::filter::graph::Graph::new();
+
+    // where the region ends up in the encoded frame, overlays are drawn on it before it's padded
+    let content = if letterbox {
+        transform::letterbox(
+            source_size(roi_screen_coord, composite),
+            enc_size_screen_coord,
+        )
+    } else {
+        Rect::new((0, 0), enc_size_screen_coord)
+    };
+    let (enc_w_screen_coord, enc_h_screen_coord) = (content.w, content.h);
+
+    // src
+    add_hw_source(&mut g, c"in", inctx, (capture_width, capture_height));
+    let others = composite.map_or(&[][..], |c| &c.others[..]);
+    for (i, other) in others.iter().enumerate() {
+        other.add_source(&mut g, &multi_output::source_name(i));
+    }
+
+    // sink
+    g.add(&filter::find("buffersink").unwrap(), "out", "")
+        .unwrap();
+
+    let output_real_pixfmt_name = unsafe {
+        from_utf8_unchecked(
+            CStr::from_ptr(av_get_pix_fmt_name(
+                match pix_fmt {
+                    EncodePixelFormat::Vaapi(fmt) => fmt,
+                    EncodePixelFormat::Sw(fmt) => fmt,
+                }
+                .into(),
+            ))
+            .to_bytes(),
+        )
+    };
+
+    let transpose_filter = transpose_vaapi(transform);
+
+    // it seems intel's vaapi driver doesn't support transpose in RGB space, so we have to transpose
+    // after the format conversion
+    // which means we have to transform the crop to be in the *pre* transpose space
+    //
+    // exact=1 should not be necessary, as the input is not chroma-subsampled
+    // however, there is a bug in ffmpeg that makes it required: https://trac.ffmpeg.org/ticket/10669
+    // it is harmless to add though, so keep it as a workaround
+    let downloads = pix_fmt.downloads_capture();
+    let crop_and_scale = |roi_screen_coord: Rect, enc_size_screen_coord: (i32, i32)| {
+        let Rect {
+            x: roi_x,
+            y: roi_y,
+            w: roi_w,
+            h: roi_h,
+        } = roi_screen_coord.screen_to_frame(capture_width, capture_height, transform);
+
+        // sanity check
+        assert!(roi_x >= 0, "{roi_x} < 0");
+        assert!(roi_y >= 0, "{roi_y} < 0");
+
+        let (enc_w, enc_h) = transpose_if_transform_transposed(enc_size_screen_coord, transform);
+
+        if downloads {
+            // everything happens on the CPU, so the pixels are only touched if they need to be
+            // scaled, transposed or converted
+            let scale_filter = if (enc_w, enc_h) == (roi_w, roi_h) {
+                String::new()
+            } else {
+                format!(",scale={enc_w}:{enc_h}")
+            };
+            let transpose_filter = match transform {
+                Transform::_90 => ",transpose=dir=clock",
+                Transform::_180 => ",hflip,vflip",
+                Transform::_270 => ",transpose=dir=cclock",
+                Transform::Flipped => ",hflip",
+                Transform::Flipped90 => ",transpose=dir=cclock_flip",
+                Transform::Flipped180 => ",vflip",
+                Transform::Flipped270 => ",transpose=dir=clock_flip",
+                _ => "",
+            };
+            format!(
+                "crop={roi_w}:{roi_h}:{roi_x}:{roi_y}:exact=1{scale_filter}{transpose_filter},format={output_real_pixfmt_name}"
+            )
+        } else {
+            format!(
+                "crop={roi_w}:{roi_h}:{roi_x}:{roi_y}:exact=1,scale_vaapi=format={output_real_pixfmt_name}:w={enc_w}:h={enc_h}{transpose_filter}"
+            )
+        }
+    };
+
+    let regions = match composite {
+        Some(composite) => {
+            let mut f = composite.filter(
+                crop_and_scale,
+                |other, size| other.filter(size, output_real_pixfmt_name),
+                !downloads,
+            );
+            // the stacked frame only needs scaling if --encode-resolution or --scale say so
+            let (w, h) = (enc_w_screen_coord, enc_h_screen_coord);
+            if (w, h) != composite.size() {
+                f += &if downloads {
+                    format!(",scale={w}:{h}")
+                } else {
+                    format!(",scale_vaapi=w={w}:h={h}")
+                };
+            }
+            f
+        }
+        None => crop_and_scale(roi_screen_coord, (enc_w_screen_coord, enc_h_screen_coord)),
+    };
+    let boxes = exclusions.boxes(
+        roi_screen_coord,
+        composite,
+        (enc_w_screen_coord, enc_h_screen_coord),
+    );
+
+    // the cursor, excluded regions and text are blended on the GPU when the frames are encoded
+    // there. Builds of ffmpeg without overlay_vaapi fall back to downloading
+    let mut vaapi_overlays = !downloads
+        && matches!(pix_fmt, EncodePixelFormat::Vaapi(_))
+        && encode_device.is_none()
+        && filter::find("overlay_vaapi").is_some();
+    let black_patch = match exclusions.style {
+        ExcludeStyle::Black if vaapi_overlays && !boxes.is_empty() => {
+            match exclude::black_patch(inctx) {
+                Ok(patch) => Some(patch),
+                Err(e) => {
+                    warn!("failed to upload --exclude-region's black, covering it on the CPU: {e}");
+                    vaapi_overlays = false;
+                    None
+                }
+            }
+        }
+        _ => None,
+    };
+    let mut text = text;
+    if let Some(t) = text.as_deref_mut().filter(|_| vaapi_overlays) {
+        if let Err(e) = t.prepare_vaapi(inctx, (enc_w_screen_coord, enc_h_screen_coord)) {
+            warn!("failed to draw --overlay-text for the GPU, drawing it on the CPU: {e}");
+            vaapi_overlays = false;
+        }
+    }
+    let vaapi_text = text.as_deref().filter(|_| vaapi_overlays);
+    let black_patch = black_patch.filter(|_| vaapi_overlays);
+
+    let exclude = if vaapi_overlays {
+        String::new()
+    } else {
+        match exclusions.filter(&boxes) {
+            f if f.is_empty() => f,
+            f => format!(",{f}"),
+        }
+    };
+    let vaapi_filters = if vaapi_overlays {
+        format!(
+            "{}{}{}",
+            cursor.map_or(String::new(), |c| c.filter_vaapi(
+                roi_screen_coord,
+                (enc_w_screen_coord, enc_h_screen_coord)
+            )),
+            exclusions.filter_vaapi(&boxes),
+            vaapi_text.map_or("", |t| t.filter_vaapi()),
+        )
+    } else {
+        String::new()
+    };
+    // the cursor is drawn before the exclusions, so it's covered up by them too
+    let cursor_filter = match cursor {
+        Some(c) if !vaapi_overlays => c.filter(
+            roi_screen_coord,
+            (enc_w_screen_coord, enc_h_screen_coord),
+            output_real_pixfmt_name,
+        ),
+        _ => String::new(),
+    };
+    // text is drawn last, over everything else
+    let text_filter = match &text {
+        Some(t) if !vaapi_overlays => format!(",{}", t.filter()),
+        _ => String::new(),
+    };
+    let overlays = format!("{cursor_filter}{exclude}{text_filter}");
+
+    let pad = if (content.w, content.h) == enc_size_screen_coord {
+        String::new()
+    } else {
+        let (w, h) = enc_size_screen_coord;
+        format!("w={w}:h={h}:x={}:y={}", content.x, content.y)
+    };
+    let sw_pad = if pad.is_empty() {
+        String::new()
+    } else {
+        format!(",pad={pad}")
+    };
+
+    let filters = if downloads {
+        let capture_pixfmt_name = inctx.sw_format().descriptor().unwrap().name();
+        format!("hwdownload,format={capture_pixfmt_name},{regions}{overlays}{sw_pad}")
+    } else if let EncodePixelFormat::Vaapi(_) = pix_fmt {
+        if overlays.is_empty() && encode_device.is_none() {
+            if pad.is_empty() {
+                format!("{regions}{vaapi_filters}")
+            } else {
+                format!("{regions}{vaapi_filters},pad_vaapi={pad}")
+            }
+        } else {
+            format!(
+                "{regions},hwdownload,format={output_real_pixfmt_name}{overlays}{sw_pad},hwupload"
+            )
         }
+    } else {
+        format!("{regions}, hwdownload{overlays}{sw_pad}")
+    };
+
+    // the cursor's image comes in through a source of its own. The canvas is in wl_shm's ARGB8888,
+    // which is bgra in ffmpeg's byte order
+    if cursor.is_some() {
+        let size = cursor_overlay::CANVAS_SIZE;
+        g.add(
+            &filter::find("buffer").unwrap(),
+            "cursor",
+            &format!("video_size={size}x{size}:pix_fmt=bgra:time_base=1/1000000000"),
+        )
+        .unwrap();
+    }
+    if let Some(patch) = &black_patch {
+        patch.add_source(&mut g, c"exclude");
+    }
+    if let Some(t) = vaapi_text {
+        t.add_source(&mut g);
+    }
+    let filters = if !others.is_empty() {
+        // the composite's filter labels the sources itself
+        format!("{filters}[out]")
+    } else if cursor.is_some() || black_patch.is_some() || vaapi_text.is_some() {
+        format!("[in]{filters}[out]")
+    } else {
+        filters
+    };
+    let mut parser = g.output("in", 0).unwrap();
+    if cursor.is_some() {
+        parser = parser.output("cursor", 0).unwrap();
+    }
+    if black_patch.is_some() {
+        parser = parser.output("exclude", 0).unwrap();
+    }
+    if vaapi_text.is_some() {
+        parser = parser.output("text", 0).unwrap();
     }
-
-    fn flush_audio(&mut self) {
-        if let Some(audio) = &mut self.audio {
-            audio.start_flush();
-        }
-        while let Some(pack) = self.audio.as_mut().and_then(|a| a.recv().ok()) {
-            self.on_encoded_packet(pack);
-        }
+    for i in 0..others.len() {
+        parser = parser.output(&multi_output::source_name(i), 0).unwrap();
     }
+    parser.input("out", 0).unwrap().parse(&filters).unwrap();
 
-    fn flush(&mut self) {
-        self.flush_audio();
-        self.video_filter
-            .get("in")
-            .unwrap()
-            .source()
-            .flush()
-            .unwrap();
-        self.process_ready();
-        self.enc_video.send_eof().unwrap();
-        self.process_ready();
-        self.octx.write_trailer().unwrap();
+    // hwupload (for overlays, --encode-device and the cursor) takes the device to upload to from its filter
+    // context, which can't be set from the filtergraph string
+    let upload_device = encode_device.map_or(inctx.device_ref(), |d| d.as_ptr());
+    unsafe {
+        let graph = g.as_mut_ptr();
+        for i in 0..(*graph).nb_filters as usize {
+            let f = *(*graph).filters.add(i);
+            if CStr::from_ptr((*(*f).filter).name) == c"hwupload" {
+                (*f).hw_device_ctx = av_buffer_ref(upload_device);
+            }
+        }
     }
 
-    fn push(&mut self, surf: frame::Video) {
-        self.video_filter
-            .get("in")
-            .unwrap()
-            .source()
-            .add(&surf)
-            .unwrap();
+    g.validate().unwrap();
 
-        self.process_ready();
+    // the patch never changes, overlay_vaapi repeats it once its source has ended
+    if let Some(patch) = &black_patch {
+        patch.push(&mut g, "exclude", Some(0)).unwrap();
+        g.get("exclude").unwrap().source().flush().unwrap();
     }
-}
 
-fn video_filter(
-    inctx: &mut AvHwFrameCtx,
-    pix_fmt: EncodePixelFormat,
-    (capture_width, capture_height): (i32, i32),
-    roi_screen_coord: Rect,                               // size (pixels)
-    (enc_w_screen_coord, enc_h_screen_coord): (i32, i32), // size (pixels) to encode. if not same as roi_{w,h}, the image will be scaled.
-    transform: Transform,
-) -> (filter::Graph, Rational) {
-    let mut g = ffmpeg::filter::graph::Graph::new();
+    (g, Rational::new(1, 1_000_000_000))
+}
 
-    // src
+// adds a buffersrc named `name` taking vaapi surfaces from `frames`, of `size` (pixels)
+fn add_hw_source(g: &mut filter::Graph, name: &CStr, frames: &AvHwFrameCtx, (w, h): (i32, i32)) {
     unsafe {
         let buffersrc_ctx = avfilter_graph_alloc_filter(
             g.as_mut_ptr(),
             filter::find("buffer").unwrap().as_mut_ptr(),
-            c"in".as_ptr() as _,
+            name.as_ptr() as _,
         );
         if buffersrc_ctx.is_null() {
             panic!("faield to alloc buffersrc filter");
@@ -2077,12 +5770,12 @@ fn video_filter(
 
         let p = &mut *av_buffersrc_parameters_alloc();
 
-        p.width = capture_width;
-        p.height = capture_height;
+        p.width = w;
+        p.height = h;
         p.format = AVPixelFormat::AV_PIX_FMT_VAAPI as c_int;
         p.time_base.num = 1;
         p.time_base.den = 1_000_000_000;
-        p.hw_frames_ctx = inctx.as_mut_ptr();
+        p.hw_frames_ctx = frames.as_ptr();
 
         let sts = av_buffersrc_parameters_set(buffersrc_ctx, p as *mut _);
         assert_eq!(sts, 0);
@@ -2091,25 +5784,11 @@ fn video_filter(
         let sts = avfilter_init_dict(buffersrc_ctx, null_mut());
         assert_eq!(sts, 0);
     }
+}
 
-    // sink
-    g.add(&filter::find("buffersink").unwrap(), "out", "")
-        .unwrap();
-
-    let output_real_pixfmt_name = unsafe {
-        from_utf8_unchecked(
-            CStr::from_ptr(av_get_pix_fmt_name(
-                match pix_fmt {
-                    EncodePixelFormat::Vaapi(fmt) => fmt,
-                    EncodePixelFormat::Sw(fmt) => fmt,
-                }
-                .into(),
-            ))
-            .to_bytes(),
-        )
-    };
-
-    let transpose_filter = match transform {
+// turns frames in capture orientation upright, after they're converted out of RGB
+fn transpose_vaapi(transform: Transform) -> &'static str {
+    match transform {
         Transform::_90 => ",transpose_vaapi=dir=clock",
         Transform::_180 => ",transpose_vaapi=dir=reversal",
         Transform::_270 => ",transpose_vaapi=dir=cclock",
@@ -2118,45 +5797,7 @@ fn video_filter(
         Transform::Flipped180 => ",transpose_vaapi=dir=vflip",
         Transform::Flipped270 => ",transpose_vaapi=dir=clock_flip",
         _ => "",
-    };
-
-    // it seems intel's vaapi driver doesn't support transpose in RGB space, so we have to transpose
-    // after the format conversion
-    // which means we have to transform the crop to be in the *pre* transpose space
-    let Rect {
-        x: roi_x,
-        y: roi_y,
-        w: roi_w,
-        h: roi_h,
-    } = roi_screen_coord.screen_to_frame(capture_width, capture_height, transform);
-
-    // sanity check
-    assert!(roi_x >= 0, "{roi_x} < 0");
-    assert!(roi_y >= 0, "{roi_y} < 0");
-
-    let (enc_w, enc_h) =
-        transpose_if_transform_transposed((enc_w_screen_coord, enc_h_screen_coord), transform);
-
-    // exact=1 should not be necessary, as the input is not chroma-subsampled
-    // however, there is a bug in ffmpeg that makes it required: https://trac.ffmpeg.org/ticket/10669
-    // it is harmless to add though, so keep it as a workaround
-    g.output("in", 0)
-        .unwrap()
-        .input("out", 0)
-        .unwrap()
-        .parse(&format!(
-            "crop={roi_w}:{roi_h}:{roi_x}:{roi_y}:exact=1,scale_vaapi=format={output_real_pixfmt_name}:w={enc_w}:h={enc_h}{transpose_filter}{}",
-            if let EncodePixelFormat::Vaapi(_) = pix_fmt {
-                ""
-            } else {
-                ", hwdownload"
-            },
-        ))
-        .unwrap();
-
-    g.validate().unwrap();
-
-    (g, Rational::new(1, 1_000_000_000))
+    }
 }
 
 fn supported_formats(codec: &ffmpeg::Codec) -> Vec<Pixel> {
@@ -2174,7 +5815,71 @@ fn supported_formats(codec: &ffmpeg::Codec) -> Vec<Pixel> {
 }
 
 fn main() {
-    let args = Args::parse();
+    let mut args = Args::parse();
+    if args.version {
+        println!("{} {}", env!("CARGO_BIN_NAME"), env!("CARGO_PKG_VERSION"));
+        if args.verbose > 0 {
+            ffmpeg_next::init().unwrap();
+            print!("{}", version::verbose());
+        }
+        exit(0);
+    }
+    if let Some(SubCommand::Ctl { command }) = &args.command {
+        let control_path = match control::find_instance(&args.output) {
+            Ok(path) => path,
+            Err(e) => {
+                eprintln!("{e}");
+                exit(1);
+            }
+        };
+        match control::send_command(&control_path, &command.join(" ")) {
+            Ok(reply) if reply == "ok" => exit(0),
+            Ok(reply) => {
+                eprintln!("{reply}");
+                exit(1);
+            }
+            Err(e) => {
+                eprintln!("failed to connect to {}: {e}", control_path.display());
+                exit(1);
+            }
+        }
+    }
+    if let Some(SubCommand::Recover) = &args.command {
+        ffmpeg_next::init().unwrap();
+        exit(if journal::recover_all() { 0 } else { 1 });
+    }
+    if let Some(SubCommand::ListAudio) = &args.command {
+        ffmpeg_next::init().unwrap();
+        if let Err(e) = audio_devices::run(&args.audio_backend) {
+            eprintln!("{e:?}");
+            exit(1);
+        }
+        exit(0);
+    }
+    if let Some(SubCommand::ListOutputs { json, names }) = &args.command {
+        if let Err(e) = list_outputs::run(*json, *names) {
+            eprintln!("{e:?}");
+            exit(1);
+        }
+        exit(0);
+    }
+    if let Some(SubCommand::Toggle { args: toggle_args }) = args.command.take() {
+        args = Args::parse_from(once(OsString::from(env!("CARGO_BIN_NAME"))).chain(toggle_args));
+
+        if let Ok(control_path) = control::find_instance(&args.output) {
+            match control::stop_existing(&control_path, Duration::from_secs(30)) {
+                Ok(()) => {
+                    eprintln!("stopped existing recording");
+                    exit(0);
+                }
+                Err(e) => {
+                    eprintln!("failed to stop existing recording: {e}");
+                    exit(1);
+                }
+            }
+        }
+    }
+
     if args.ext_image_copy_capture {
         execute::<CapExtImageCopy>(args);
     } else {
@@ -2182,34 +5887,120 @@ fn main() {
     }
 }
 
-fn execute<S: CaptureSource + 'static>(args: Args) {
+// returns a function that can be called from any thread to make `blocking_dispatch` return, by
+// doing a roundtrip to the compositor
+fn event_loop_waker<S: CaptureSource + 'static>(
+    conn: &Connection,
+    qh: QueueHandle<State<S>>,
+) -> impl Fn() + Send + 'static {
+    let conn = conn.clone();
+    move || {
+        conn.display().sync(&qh, ());
+        let _ = conn.flush();
+    }
+}
+
+const TICK_INTERVAL: Duration = Duration::from_millis(250);
+
+type SharedWaker = Arc<Mutex<Box<dyn Fn() + Send>>>;
+
+fn shared_wake(waker: &SharedWaker) -> impl Fn() + Send + 'static {
+    let waker = Arc::clone(waker);
+    move || (waker.lock().unwrap())()
+}
+
+// wakes the event loop regularly, for the things that have to be checked without a Wayland event
+// to wake it: --pause-after-idle noticing the screen hasn't changed, and audio packets waiting for
+// the muxer while the screen is static
+fn spawn_ticker(wake: impl Fn() + Send + 'static) {
+    thread::Builder::new()
+        .name("ticker".to_owned())
+        .spawn(move || loop {
+            sleep(TICK_INTERVAL);
+            wake();
+        })
+        .unwrap();
+}
+
+// --reconnect: polls for the compositor to accept connections again. None if told to quit first
+fn wait_for_compositor(quit_flag: &AtomicUsize) -> Option<Connection> {
+    while quit_flag.load(Ordering::SeqCst) == usize::MAX {
+        match Connection::connect_to_env() {
+            Ok(conn) => return Some(conn),
+            Err(e) => trace!("compositor not back yet: {e}"),
+        }
+        sleep(Duration::from_secs(1));
+    }
+    None
+}
+
+// foo.mp4 -> foo-1.mp4, foo-2.mp4, ... for the first name that doesn't exist yet
+fn non_clobbering_filename(filename: &str) -> String {
+    let path = Path::new(filename);
+    // only regular files, don't rename devices like v4l2 sinks
+    if !path.is_file() {
+        return filename.to_string();
+    }
+
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let ext = path
+        .extension()
+        .map(|e| format!(".{}", e.to_string_lossy()))
+        .unwrap_or_default();
+    (1..)
+        .map(|i| path.with_file_name(format!("{stem}-{i}{ext}")))
+        .find(|candidate| !candidate.exists())
+        .unwrap()
+        .to_string_lossy()
+        .into_owned()
+}
+
+// --container-fallback: matroska takes nearly any codec and doesn't need to seek. Regular files
+// get a .mkv extension, pipes, devices and urls keep their name
+fn fall_back_to_mkv(args: &mut Args) {
+    if args.ffmpeg_muxer.is_none() && container::seekable(&args.filename) {
+        args.filename = container::mkv_filename(&args.filename);
+        args.filename_template = container::mkv_filename(&args.filename_template);
+        if args.no_overwrite && !args.force && !args.append {
+            args.filename = non_clobbering_filename(&args.filename);
+        }
+    } else {
+        args.ffmpeg_muxer = Some("matroska".to_owned());
+    }
+    if args.ffmpeg_muxer_options.take().is_some() {
+        warn!("--ffmpeg-muxer-options were for the original container, ignoring them");
+    }
+}
+
+fn execute<S: CaptureSource + 'static>(mut args: Args) {
     if let Some(generator) = args.completions_generator {
         let mut command = Args::command();
         let bin_name = command.get_name().to_string();
-        clap_complete::generate(generator, &mut command, bin_name, &mut io::stdout());
+        completions::generate(generator, &mut command, bin_name, &mut io::stdout());
         return;
     }
 
     let quit_flag = Arc::new(AtomicUsize::new(usize::MAX)); // ::MAX means still running, otherwise it's an exit value
     let sigusr1_flag = Arc::new(AtomicBool::new(false));
+    let screenshot_flag = Arc::new(AtomicBool::new(false));
 
-    signal_hook::flag::register_usize(SIGINT, Arc::clone(&quit_flag), 0).unwrap();
+    let sigint_quit_flag = Arc::clone(&quit_flag);
+    // a second Ctrl-C while the recording is being finished stops waiting for it
+    unsafe {
+        signal_hook::low_level::register(SIGINT, move || shutdown::interrupted(&sigint_quit_flag))
+    }
+    .unwrap();
     signal_hook::flag::register_usize(SIGTERM, Arc::clone(&quit_flag), 0).unwrap();
     signal_hook::flag::register_usize(SIGHUP, Arc::clone(&quit_flag), 0).unwrap();
     signal_hook::flag::register(SIGUSR1, Arc::clone(&sigusr1_flag)).unwrap();
+    signal_hook::flag::register(SIGUSR2, Arc::clone(&screenshot_flag)).unwrap();
 
-    CombinedLogger::init(vec![TermLogger::new(
-        match args.verbose {
-            0 => LevelFilter::Warn,
-            1 => LevelFilter::Info,
-            2 => LevelFilter::Debug,
-            3.. => LevelFilter::Trace,
-        },
-        simplelog::Config::default(),
-        TerminalMode::Stderr,
-        ColorChoice::Auto,
-    )])
-    .unwrap();
+    CombinedLogger::init(args.log.loggers(args.verbose)).unwrap();
+
+    if args.no_hw {
+        args.hw_backend = HwBackend::None;
+    }
+    args.hw = args.hw_backend != HwBackend::None;
 
     if !args.audio && args.audio_backend != DEFAULT_AUDIO_BACKEND {
         warn!("--audio-backend passed without --audio, will be ignored");
@@ -2229,18 +6020,225 @@ fn execute<S: CaptureSource + 'static>(args: Args) {
     if args.ffmpeg_encoder.is_some() && args.codec != Codec::Auto {
         warn!("--ffmpeg-encoder passed with --codec, --codec will be ignored");
     }
+    // strftime sequences are only expanded when the recording is split into several files,
+    // otherwise a % in --filename is kept as it is, in every file it's written to
+    args.filename_template = if !args.split_at.is_empty() || args.split_at_midnight {
+        args.filename.clone()
+    } else {
+        args.filename.replace('%', "%%")
+    };
+    if args.filename_template.contains('%') {
+        args.filename = strftime_local(&args.filename_template, SystemTime::now());
+    }
+    if args.resume {
+        match journal::resume(&args.filename_template) {
+            Some(filename) => {
+                args.filename = filename;
+                args.append = true;
+            }
+            None => info!(
+                "no interrupted recording of {} to resume, starting a new one",
+                args.filename_template
+            ),
+        }
+    }
+    if let Some(capture_fps) = args.capture_fps {
+        if capture_fps <= 0. {
+            eprintln!("--capture-fps must be positive");
+            exit(1);
+        }
+        if args.max_fps.is_some_and(|max_fps| max_fps > capture_fps) {
+            warn!("--max-fps is higher than --capture-fps, at most {capture_fps} frames per second will be encoded");
+        }
+    }
+    if args.pause_after_idle.is_some() && !args.damage {
+        warn!("--pause-after-idle with --no-damage never pauses, every frame counts as the screen changing");
+    }
+    if args.damage_debounce.is_some() && !args.damage {
+        warn!("--damage-debounce with --no-damage only encodes a frame every 4 debounce periods, every copy counts as the screen changing");
+    }
+    if args.no_overwrite && !args.force && !args.append {
+        let filename = non_clobbering_filename(&args.filename);
+        if filename != args.filename {
+            warn!("{} already exists, writing to {filename}", args.filename);
+            args.filename = filename;
+        }
+    }
+    if matches!(args.codec, Codec::Prores | Codec::Dnxhr) && args.ffmpeg_encoder.is_none() {
+        args.hw = false;
+    }
+    if args.geometry.len() > 1 {
+        // both map a part of the screen onto the encoded frame, which doesn't work once regions
+        // are moved around to be composited
+        if args.damage_hints {
+            warn!("--damage-hints does not work with more than one --geometry, ignoring it");
+            args.damage_hints = false;
+        }
+        if args.roi_cursor.is_some() {
+            warn!("--roi-cursor does not work with more than one --geometry, ignoring it");
+            args.roi_cursor = None;
+        }
+        if args.composite_cursor {
+            warn!("--composite-cursor does not work with more than one --geometry, letting the compositor draw the cursor");
+            args.composite_cursor = false;
+        }
+    }
+    if args.preset == Some(Preset::ArchiveFfv1) {
+        args.ffmpeg_encoder = Some("ffv1".to_owned());
+        args.hw = false;
+        // every frame a keyframe, so any part of the archive can be cut out or recovered on its own
+        args.gop_size.get_or_insert(1);
+        if args.ffmpeg_muxer.is_none() {
+            if !args.filename.ends_with(".mkv") {
+                warn!(
+                    "--preset archive-ffv1 writes mkv, but --filename {} does not end in .mkv",
+                    args.filename
+                );
+            }
+            args.ffmpeg_muxer = Some("matroska".to_owned());
+        }
+    }
+    if let Some(format) = container::guess(&args.filename, args.ffmpeg_muxer.as_deref()) {
+        let codecs = requested_codecs(&args);
+        let mut problem = None;
+        for codec in &codecs {
+            match container::supports(&format, *codec) {
+                Support::Yes => {}
+                Support::No => {
+                    problem = Some(container::unsupported_error(
+                        &format,
+                        *codec,
+                        &codecs,
+                        args.ffmpeg_muxer.is_some(),
+                    ));
+                    break;
+                }
+                Support::Maybe(e) => warn!(
+                    target: log_spec::ENCODE,
+                    "Format {} might not support {codec:?} codec ({e})",
+                    format.name()
+                ),
+            }
+        }
+        if problem.is_none()
+            && container::needs_seekable(&format, args.ffmpeg_muxer_options.as_deref())
+            && !container::seekable(&args.filename)
+        {
+            problem = Some(format!(
+                "{} can't be written to {}, it needs to seek back to the start of the file when the recording is finished. Fragmented mp4 works with --ffmpeg-muxer-options movflags=frag_keyframe+empty_moov",
+                format.name(),
+                args.filename
+            ));
+        }
+        match problem {
+            Some(problem) if args.container_fallback => {
+                fall_back_to_mkv(&mut args);
+                warn!("{problem}. Writing matroska to {} instead", args.filename);
+            }
+            Some(problem) => {
+                eprintln!("{problem}. Pass --container-fallback to write matroska instead");
+                exit(1);
+            }
+            None => {}
+        }
+    }
     if args.encode_pixfmt == Some(Pixel::VAAPI) {
         error!("`--encode-pixfmt vaapi` passed, this is nonsense. It will automatically be transformed into a vaapi pixel format if the selected encoder supports vaapi memory input");
         exit(1);
     }
 
+    let initial_focus = if args.follow_focus {
+        focus::focused_output()
+    } else {
+        None
+    };
+    if let (Some(focused), "") = (&initial_focus, args.output.as_str()) {
+        args.output = focused.clone();
+    }
+    if let Some(dir) = &args.history_spill {
+        if !dir.is_dir() {
+            eprintln!("--history-spill {} is not a directory", dir.display());
+            exit(1);
+        }
+    }
+    if let Some(fd) = args.marker_fd {
+        if unsafe { libc::fcntl(fd, libc::F_GETFD) } < 0 {
+            eprintln!("--marker-fd {fd} is not an open file descriptor");
+            exit(1);
+        }
+    }
+    if args.overlay_text.is_some() && filter::find("drawtext").is_none() {
+        eprintln!(
+            "--overlay-text needs the drawtext filter, which this build of ffmpeg doesn't have"
+        );
+        exit(1);
+    }
+    if args.workspace.is_some() && !workspace::supported() {
+        eprintln!("--workspace only works on sway and Hyprland");
+        exit(1);
+    }
+    if args.only_while_focused.is_some() && !workspace::supported() {
+        eprintln!("--only-while-focused only works on sway and Hyprland");
+        exit(1);
+    }
+    let initial_window = if let Some(app_id) = &args.follow_window {
+        let window = window::window_geometry(app_id);
+        if window.is_none() {
+            warn!("window {app_id} not found, recording the whole output until it shows up");
+        }
+        window
+    } else if args.follow_active_window {
+        match hyprland::active_window() {
+            Ok(window) => window,
+            Err(e) => {
+                eprintln!("failed to talk to Hyprland: {e}");
+                exit(1);
+            }
+        }
+    } else if let Some(criteria) = &args.sway_window {
+        match sway::SwayIpc::connect().and_then(|mut ipc| sway::window_geometry(&mut ipc, criteria))
+        {
+            Ok(None) => {
+                warn!(
+                    "no window matches {criteria}, recording the whole output until one shows up"
+                );
+                None
+            }
+            Ok(window) => window,
+            Err(e) => {
+                eprintln!("failed to talk to sway: {e}");
+                exit(1);
+            }
+        }
+    } else {
+        None
+    };
+    if let (Some(window), "") = (&initial_window, args.output.as_str()) {
+        args.output = window.output.clone();
+    }
+
     ffmpeg_next::init().unwrap();
 
-    if args.verbose >= 3 {
+    if args.log.level(Subsystem::Encode, args.verbose) >= LevelFilter::Trace {
         ffmpeg_next::log::set_level(ffmpeg::log::Level::Trace);
     }
 
-    let conn = match Connection::connect_to_env() {
+    if let Some(spec) = &args.history_transcode {
+        if let Err(e) = spec.check_encoder() {
+            eprintln!("--history-transcode: {e}");
+            exit(1);
+        }
+    }
+
+    if args.capture_backend == CaptureBackend::TestPattern {
+        if args.outputs.is_some() {
+            eprintln!("--outputs records displays, it can't be combined with the test pattern");
+            exit(1);
+        }
+        exit(test_pattern::run(&args, quit_flag, sigusr1_flag));
+    }
+
+    let mut conn = match Connection::connect_to_env() {
         Ok(conn) => conn,
         Err(e @ ConnectError::NoCompositor) => {
             eprintln!("WAYLAND_DISPLAY or XDG_RUNTIME_DIR environment variables are not set or are set to an invalid value: {e}");
@@ -2252,22 +6250,182 @@ fn execute<S: CaptureSource + 'static>(args: Args) {
         }
     };
 
-    let (mut state, mut queue) = match State::<S>::new(&conn, args, quit_flag.clone(), sigusr1_flag)
+    // the background threads outlive a connection with --reconnect, so they wake whichever event
+    // loop is current
+    let waker: SharedWaker = Arc::new(Mutex::new(Box::new(|| {})));
+    let mut control = ControlServer::new(args.selector.clone(), shared_wake(&waker));
+    let focus = match args
+        .follow_focus
+        .then(|| focus::spawn_focus_watcher(Some(args.output.clone()), shared_wake(&waker)))
+        .transpose()
     {
-        Ok(res) => res,
+        Ok(rx) => rx,
         Err(e) => {
-            eprintln!("{e}");
+            eprintln!("failed to subscribe to the compositor's focus events: {e}");
             exit(1);
         }
     };
+    let window = if let Some(app_id) = args.follow_window.clone() {
+        match window::spawn_window_watcher(app_id, initial_window.clone(), shared_wake(&waker)) {
+            Ok(rx) => Some(rx),
+            Err(e) => {
+                eprintln!("failed to subscribe to the compositor's window events: {e}");
+                exit(1);
+            }
+        }
+    } else if args.follow_active_window {
+        match hyprland::spawn_active_window_watcher(initial_window.clone(), shared_wake(&waker)) {
+            Ok(rx) => Some(rx),
+            Err(e) => {
+                eprintln!("failed to subscribe to Hyprland events: {e}");
+                exit(1);
+            }
+        }
+    } else if let Some(criteria) = args.sway_window.clone() {
+        match sway::spawn_window_watcher(criteria, initial_window.clone(), shared_wake(&waker)) {
+            Ok(rx) => Some(rx),
+            Err(e) => {
+                eprintln!("failed to subscribe to sway window events: {e}");
+                sway::unmark();
+                exit(1);
+            }
+        }
+    } else {
+        None
+    };
+    let mut followed = initial_window;
+    let metrics = Arc::new(Metrics::default());
+    if let Some(addr) = args.metrics_listen {
+        if let Err(e) = metrics::serve(addr, metrics.clone()) {
+            eprintln!("failed to serve metrics on {addr}: {e}");
+            exit(1);
+        }
+    }
+    let markers = args
+        .marker_fd
+        .map(|fd| control::spawn_marker_reader(fd, shared_wake(&waker)));
+    Schedule::new(args.start_at, args.stop_at)
+        .spawn_stop_timer(quit_flag.clone(), shared_wake(&waker));
+    if args.pause_after_idle.is_some() || args.audio {
+        spawn_ticker(shared_wake(&waker));
+    }
 
-    while quit_flag.load(Ordering::SeqCst) == usize::MAX {
-        queue.blocking_dispatch(&mut state).unwrap();
+    let state = loop {
+        let (mut state, mut queue) = match State::<S>::new(
+            &conn,
+            args.clone(),
+            quit_flag.clone(),
+            sigusr1_flag.clone(),
+            metrics.clone(),
+        ) {
+            Ok(res) => res,
+            Err(e) => {
+                eprintln!("{e}");
+                if args.sway_window.is_some() {
+                    sway::unmark();
+                }
+                exit(1);
+            }
+        };
+        *waker.lock().unwrap() = Box::new(event_loop_waker(&conn, queue.handle()));
+
+        let mut disconnected = None;
+        while quit_flag.load(Ordering::SeqCst) == usize::MAX {
+            if let Err(e) = queue.blocking_dispatch(&mut state) {
+                disconnected = Some(e);
+                break;
+            }
+
+            // the control sockets follow the outputs being recorded
+            if let Some(outputs) = state.recorded_outputs() {
+                if let Err(e) = control.claim(&outputs, args.replace) {
+                    eprintln!("{e}. Pass --replace to stop it, bailing");
+                    quit_flag.store(1, Ordering::SeqCst);
+                }
+            }
+            while let Some(cmd) = control.try_recv() {
+                state.on_control_command(cmd, &queue.handle());
+            }
+            while let Some(output) = focus.as_ref().and_then(|f| f.try_recv().ok()) {
+                state.on_control_command(ControlCommand::SetOutput(output), &queue.handle());
+            }
+            while let Some(text) = markers.as_ref().and_then(|m| m.try_recv().ok()) {
+                state.on_control_command(ControlCommand::Marker(text), &queue.handle());
+            }
+            if screenshot_flag.swap(false, Ordering::SeqCst) {
+                state.on_control_command(ControlCommand::Screenshot, &queue.handle());
+            }
+            while let Some(geometry) = window.as_ref().and_then(|w| w.try_recv().ok()) {
+                followed = Some(geometry);
+            }
+            if let Some(geometry) = &followed {
+                state.follow_window(geometry, &queue.handle());
+            }
+            state.on_other_outputs();
+            state.on_timer(&queue.handle());
+            state.check_idle();
+            state.drain_audio();
+        }
+
+        let watchdog = shutdown::Watchdog::start(
+            args.shutdown_timeout,
+            &args.filename,
+            match quit_flag.load(Ordering::SeqCst) {
+                usize::MAX => 1, // disconnected
+                code => code as i32,
+            },
+        );
+        state.encode_debounced();
+        if let EncConstructionStage::Complete(c) = &mut state.enc {
+            c.enc.flush();
+        }
+        for line in state.pacing.summary() {
+            eprintln!("{line}");
+        }
+        if let Some(log) = &mut state.frame_log {
+            log.flush();
+        }
+        if let Some(log) = &mut state.cursor_log {
+            log.flush();
+        }
+        drop(watchdog);
+        if let EncConstructionStage::Complete(c) = &mut state.enc {
+            c.enc.finish_background();
+        }
+
+        let Some(e) = disconnected else {
+            break state;
+        };
+        if !args.reconnect {
+            error!("lost connection to the compositor: {e}");
+            quit_flag.store(1, Ordering::SeqCst);
+            break state;
+        }
+        warn!("lost connection to the compositor ({e}), waiting for it to come back...");
+        conn = match wait_for_compositor(&quit_flag) {
+            Some(conn) => conn,
+            None => break state,
+        };
+        args.filename = strftime_local(&args.filename_template, SystemTime::now());
+        if Path::new(&args.filename).exists() {
+            args.filename = non_clobbering_filename(&args.filename);
+        }
+        info!("reconnected, continuing in {}", args.filename);
+        metrics.restarted();
+    };
+    drop(control); // exit doesn't run destructors, make sure the socket is cleaned up
+    if args.sway_window.is_some() {
+        sway::unmark();
     }
 
-    if let EncConstructionStage::Complete(c) = &mut state.enc {
-        c.enc.flush();
+    let exit_code = quit_flag.load(Ordering::SeqCst) as i32;
+    if exit_code == 0 {
+        if let Some(cmd) = &state.args.exec_after {
+            hooks::run("exec-after", cmd, &state.hook_env(), None);
+        }
+    } else if let Some(cmd) = &state.args.exec_on_error {
+        hooks::run("exec-on-error", cmd, &state.hook_env(), Some(exit_code));
     }
 
-    exit(quit_flag.load(Ordering::SeqCst) as i32)
+    exit(exit_code)
 }
@@ -0,0 +1,169 @@
+use std::{
+    fmt::Write as _,
+    io::{self, BufRead, BufReader, Write},
+    net::{SocketAddr, TcpListener, TcpStream},
+    sync::{
+        atomic::{AtomicU64, Ordering::Relaxed},
+        Arc,
+    },
+    thread,
+    time::Duration,
+};
+
+use log::{debug, info, warn};
+
+// Counters for --metrics-listen, shared with the thread serving them. They're kept whether or not
+// anything is listening, and count across --reconnect
+#[derive(Default)]
+pub struct Metrics {
+    frames_captured: AtomicU64,
+    frames_dropped: AtomicU64,
+    frames_encoded: AtomicU64,
+    bytes_written: AtomicU64,
+    history_bytes: AtomicU64,
+    restarts: AtomicU64,
+}
+
+impl Metrics {
+    pub fn frame_captured(&self) {
+        self.frames_captured.fetch_add(1, Relaxed);
+    }
+
+    pub fn frame_dropped(&self) {
+        self.frames_dropped.fetch_add(1, Relaxed);
+    }
+
+    pub fn frame_encoded(&self) {
+        self.frames_encoded.fetch_add(1, Relaxed);
+    }
+
+    pub fn wrote(&self, bytes: usize) {
+        self.bytes_written.fetch_add(bytes as u64, Relaxed);
+    }
+
+    // how much --history is holding in memory right now
+    pub fn set_history_bytes(&self, bytes: usize) {
+        self.history_bytes.store(bytes as u64, Relaxed);
+    }
+
+    // the compositor connection (--reconnect) or the encoder was restarted
+    pub fn restarted(&self) {
+        self.restarts.fetch_add(1, Relaxed);
+    }
+
+    // Prometheus' text exposition format
+    fn render(&self) -> String {
+        let metrics = [
+            (
+                "frames_captured_total",
+                "counter",
+                "Frames copied from the compositor",
+                &self.frames_captured,
+            ),
+            (
+                "frames_dropped_total",
+                "counter",
+                "Captured frames that weren't encoded, or copies that failed",
+                &self.frames_dropped,
+            ),
+            (
+                "frames_encoded_total",
+                "counter",
+                "Video packets that came out of the encoder",
+                &self.frames_encoded,
+            ),
+            (
+                "bytes_written_total",
+                "counter",
+                "Bytes of audio and video written to the output",
+                &self.bytes_written,
+            ),
+            (
+                "history_bytes",
+                "gauge",
+                "Bytes of packets held in memory for --history",
+                &self.history_bytes,
+            ),
+            (
+                "restarts_total",
+                "counter",
+                "Times the compositor connection or the encoder was restarted",
+                &self.restarts,
+            ),
+        ];
+        let mut out = String::new();
+        for (name, ty, help, value) in metrics {
+            let name = format!("wl_screenrec_{name}");
+            let _ = writeln!(out, "# HELP {name} {help}");
+            let _ = writeln!(out, "# TYPE {name} {ty}");
+            let _ = writeln!(out, "{name} {}", value.load(Relaxed));
+        }
+        out
+    }
+}
+
+fn handle_client(stream: TcpStream, metrics: &Metrics) -> io::Result<()> {
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+    let mut reader = BufReader::new(&stream);
+    let mut request = String::new();
+    reader.read_line(&mut request)?;
+    // the headers don't matter, but have to be read before replying
+    let mut line = String::new();
+    while reader.read_line(&mut line)? > 2 {
+        line.clear();
+    }
+
+    let path = request.split_whitespace().nth(1).unwrap_or("");
+    let (status, body) = match path {
+        "/metrics" | "/" => ("200 OK", metrics.render()),
+        _ => ("404 Not Found", "not found, try /metrics\n".to_owned()),
+    };
+    write!(
+        &stream,
+        "HTTP/1.1 {status}\r\nContent-Type: text/plain; version=0.0.4; charset=utf-8\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    )
+}
+
+// serves `metrics` over http on `addr` until the process exits
+pub fn serve(addr: SocketAddr, metrics: Arc<Metrics>) -> io::Result<()> {
+    let listener = TcpListener::bind(addr)?;
+    info!(
+        "serving metrics on http://{}/metrics",
+        listener.local_addr()?
+    );
+
+    thread::Builder::new()
+        .name("metrics".to_owned())
+        .spawn(move || {
+            for stream in listener.incoming() {
+                match stream {
+                    Ok(stream) => {
+                        if let Err(e) = handle_client(stream, &metrics) {
+                            debug!("metrics client error: {e}");
+                        }
+                    }
+                    Err(e) => warn!("failed to accept metrics connection: {e}"),
+                }
+            }
+        })?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::Metrics;
+
+    #[test]
+    fn render() {
+        let metrics = Metrics::default();
+        metrics.frame_captured();
+        metrics.frame_captured();
+        metrics.wrote(1000);
+        let text = metrics.render();
+        assert!(text.contains("# TYPE wl_screenrec_frames_captured_total counter\n"));
+        assert!(text.contains("\nwl_screenrec_frames_captured_total 2\n"));
+        assert!(text.contains("\nwl_screenrec_bytes_written_total 1000\n"));
+        assert!(text.contains("\nwl_screenrec_restarts_total 0\n"));
+    }
+}
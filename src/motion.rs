@@ -0,0 +1,62 @@
+// --adaptive-quality: estimates how much of the screen is changing from the damage the compositor
+// reports, and lowers the quality of frames while the screen is mostly static. Static frames are
+// cheap already, but this also makes the occasional small change (a blinking cursor, a clock)
+// nearly free
+
+// how quickly the estimate follows the damage, per frame
+const SMOOTHING: f64 = 0.1;
+
+// this much of the frame changing each frame counts as full motion, and gets full quality
+const FULL_MOTION: f64 = 0.25;
+
+pub struct MotionEstimate {
+    strength: f64,
+    level: f64, // smoothed fraction of the frame damaged per frame
+}
+
+impl MotionEstimate {
+    // `strength` is the quantizer offset (0 to 1) applied to a completely static screen
+    pub fn new(strength: f64) -> Self {
+        MotionEstimate {
+            strength,
+            level: 1.,
+        }
+    }
+
+    // `damaged` is the fraction of this frame the compositor reported as damaged. Returns the
+    // quantizer offset for it
+    pub fn on_frame(&mut self, damaged: f64) -> f64 {
+        self.level += (damaged.clamp(0., 1.) - self.level) * SMOOTHING;
+        self.strength * (1. - (self.level / FULL_MOTION).min(1.))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::MotionEstimate;
+
+    #[test]
+    fn follows_damage() {
+        let mut m = MotionEstimate::new(0.4);
+        // starts out assuming full motion
+        assert_eq!(m.on_frame(1.), 0.);
+
+        let mut q = 0.;
+        for _ in 0..100 {
+            q = m.on_frame(0.);
+        }
+        assert!((q - 0.4).abs() < 0.01, "{q}");
+
+        // a burst of full screen changes brings quality back quickly
+        for _ in 0..10 {
+            q = m.on_frame(1.);
+        }
+        assert_eq!(q, 0.);
+
+        // a small, constantly changing area doesn't count as motion
+        for _ in 0..100 {
+            q = m.on_frame(0.01);
+        }
+        assert!(q > 0.35, "{q}");
+    }
+}
@@ -0,0 +1,533 @@
+use std::{
+    collections::HashMap,
+    ffi::CString,
+    fmt,
+    os::fd::BorrowedFd,
+    sync::{
+        atomic::{AtomicBool, Ordering::SeqCst},
+        Arc, Mutex,
+    },
+    thread::{self, sleep},
+};
+
+use anyhow::Context;
+use drm::buffer::DrmFourcc;
+use ffmpeg::{ffi::av_frame_ref, filter, frame};
+use log::{debug, warn};
+use wayland_client::{
+    globals::GlobalList,
+    protocol::{
+        wl_buffer::WlBuffer,
+        wl_callback::WlCallback,
+        wl_output::{Transform, WlOutput},
+    },
+    Connection, Dispatch, Proxy, QueueHandle,
+};
+use wayland_protocols::wp::linux_dmabuf::zv1::client::{
+    zwp_linux_buffer_params_v1::{self, ZwpLinuxBufferParamsV1},
+    zwp_linux_dmabuf_v1::ZwpLinuxDmabufV1,
+};
+use wayland_protocols_wlr::screencopy::v1::client::{
+    zwlr_screencopy_frame_v1::{self, ZwlrScreencopyFrameV1},
+    zwlr_screencopy_manager_v1::ZwlrScreencopyManagerV1,
+};
+
+use crate::{
+    avhw::{AvHwDevCtx, AvHwFrameCtx, Usage},
+    capture_error,
+    composite::Composite,
+    dmabuf_to_av, log_spec, map_drm, surface_id,
+    transform::transpose_if_transform_transposed,
+    transpose_vaapi, DrmModifier,
+};
+
+// what an output shows until its first frame is captured
+const PLACEHOLDER_SIZE: i32 = 16;
+
+// --outputs besides the first: each is captured whole on a thread of its own, and its latest frame
+// is stacked next to the first output's every time that one is encoded
+pub struct OtherOutput {
+    pub name: String,
+    pub size: (i32, i32), // pixels, in screen coordinates
+    pub transform: Transform,
+    pub zoom: f64, // scale to the pixel density of the most scaled output
+    output: WlOutput,
+    source: Option<AvHwFrameCtx>, // the filter graphs take its frames, None while they're black
+    current: Option<frame::Video>, // from `source`
+    handle: Arc<Handle>,
+}
+
+// `current` is referenced rather than copied
+impl Clone for OtherOutput {
+    fn clone(&self) -> Self {
+        OtherOutput {
+            name: self.name.clone(),
+            size: self.size,
+            transform: self.transform,
+            zoom: self.zoom,
+            output: self.output.clone(),
+            source: self.source.clone(),
+            current: self.current.as_ref().map(new_ref),
+            handle: Arc::clone(&self.handle),
+        }
+    }
+}
+
+// shared with the capture thread
+#[derive(Default)]
+struct Shared {
+    latest: Mutex<Option<frame::Video>>,
+    fresh: AtomicBool, // captured since it was last encoded
+    new_device: Mutex<Option<AvHwDevCtx>>,
+    stop: AtomicBool,
+}
+
+// stops the capture thread once the last copy of the OtherOutput is gone
+#[derive(Default)]
+struct Handle {
+    shared: Arc<Shared>,
+    wake_thread: Mutex<Option<Box<dyn Fn() + Send>>>,
+}
+
+impl Drop for Handle {
+    fn drop(&mut self) {
+        self.shared.stop.store(true, SeqCst);
+        if let Some(wake) = self.wake_thread.get_mut().unwrap() {
+            wake();
+        }
+    }
+}
+
+impl fmt::Debug for OtherOutput {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("OtherOutput")
+            .field("name", &self.name)
+            .field("size", &self.size)
+            .field("transform", &self.transform)
+            .field("zoom", &self.zoom)
+            .finish()
+    }
+}
+
+impl PartialEq for OtherOutput {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.handle, &other.handle) && self.source == other.source
+    }
+}
+
+// the graph source the `i`th other output's frames go into, "in" being the first output's
+pub fn source_name(i: usize) -> String {
+    format!("in{}", i + 1)
+}
+
+// another reference to a frame's buffers, `Clone` would copy them
+pub fn new_ref(frame: &frame::Video) -> frame::Video {
+    let mut r = frame::Video::empty();
+    unsafe { av_frame_ref(r.as_mut_ptr(), frame.as_ptr()) };
+    r
+}
+
+// pushes `surf` into a graph built by video_filter, along with the latest frame of each other
+// output. `surf` itself is left as it was
+pub fn add_frames(graph: &mut filter::Graph, surf: &frame::Video, composite: Option<&Composite>) {
+    for (i, other) in composite.iter().flat_map(|c| &c.others).enumerate() {
+        let mut frame = other.frame();
+        frame.set_pts(surf.pts());
+        unsafe { (*frame.as_mut_ptr()).time_base = (*surf.as_ptr()).time_base };
+        let mut source = graph.get(&source_name(i)).unwrap();
+        source.source().add(&frame).unwrap();
+    }
+    graph
+        .get("in")
+        .unwrap()
+        .source()
+        .add(&new_ref(surf))
+        .unwrap();
+}
+
+// flushes every source of a graph built by video_filter
+pub fn flush_sources(graph: &mut filter::Graph) {
+    graph.get("in").unwrap().source().flush().unwrap();
+    for i in 0.. {
+        let Some(mut source) = graph.get(&source_name(i)) else {
+            break;
+        };
+        source.source().flush().unwrap();
+    }
+}
+
+impl OtherOutput {
+    pub fn new(
+        name: String,
+        output: WlOutput,
+        size: (i32, i32),
+        transform: Transform,
+        zoom: f64,
+    ) -> Self {
+        OtherOutput {
+            name,
+            size,
+            transform,
+            zoom,
+            output,
+            source: None,
+            current: None,
+            handle: Arc::default(),
+        }
+    }
+
+    // starts copying the output into surfaces on `device`, waking the event loop with `wake` when
+    // a frame is in
+    pub fn start(
+        &self,
+        conn: &Connection,
+        gm: &GlobalList,
+        device: &AvHwDevCtx,
+        damage: bool,
+        paint_cursor: bool,
+        wake: impl Fn() + Send + 'static,
+    ) -> anyhow::Result<()> {
+        let mut queue = conn.new_event_queue();
+        let qh = queue.handle();
+        let manager: ZwlrScreencopyManagerV1 = gm
+            .bind(&qh, 3..=ZwlrScreencopyManagerV1::interface().version, ())
+            .context("--outputs needs zwlr-screencopy-manager to capture the other outputs, which your compositor does not support")?;
+        let dma: ZwpLinuxDmabufV1 = gm
+            .bind(&qh, 4..=ZwpLinuxDmabufV1::interface().version, ())
+            .context("your compositor does not support zwp-linux-dmabuf")?;
+
+        let wake_conn = conn.clone();
+        let wake_qh = qh.clone();
+        *self.handle.wake_thread.lock().unwrap() = Some(Box::new(move || {
+            wake_conn.display().sync(&wake_qh, ());
+            let _ = wake_conn.flush();
+        }));
+
+        let mut capture = Capture {
+            name: self.name.clone(),
+            manager,
+            dma,
+            output: self.output.clone(),
+            paint_cursor,
+            damage,
+            device: device.clone(),
+            frames: None,
+            buffers: HashMap::new(),
+            in_flight: None,
+            failures: 0,
+            shared: Arc::clone(&self.handle.shared),
+            wake: Box::new(wake),
+        };
+        capture.request(&qh);
+        thread::Builder::new()
+            .name("other output".to_owned())
+            .spawn(move || {
+                while !capture.shared.stop.load(SeqCst) {
+                    if let Err(e) = queue.blocking_dispatch(&mut capture) {
+                        warn!(target: log_spec::WAYLAND, "stopped capturing {}: {e}", capture.name);
+                        break;
+                    }
+                }
+                capture.clear_buffers();
+            })?;
+        Ok(())
+    }
+
+    // after the device was reopened: frames are captured onto `device`, and the output is black
+    // until the first one is in
+    pub fn set_device(&self, device: &AvHwDevCtx) {
+        *self.handle.shared.new_device.lock().unwrap() = Some(device.clone());
+        *self.handle.shared.latest.lock().unwrap() = None;
+    }
+
+    // captured since this was last called
+    pub fn take_fresh(&self) -> bool {
+        self.handle.shared.fresh.swap(false, SeqCst)
+    }
+
+    // takes the latest captured frame to be pushed with the first output's next ones. Returns true
+    // if it's from other surfaces than before, the filter graphs have to be built again for them
+    pub fn update(&mut self) -> bool {
+        self.current = self
+            .handle
+            .shared
+            .latest
+            .lock()
+            .unwrap()
+            .as_ref()
+            .map(new_ref);
+        let source = self.current.as_ref().and_then(AvHwFrameCtx::of_frame);
+        if source == self.source {
+            return false;
+        }
+        self.source = source;
+        true
+    }
+
+    // what goes into the graph: the frame taken by update, or black before there was one
+    fn frame(&self) -> frame::Video {
+        match &self.current {
+            Some(f) => new_ref(f),
+            None => {
+                let mut black = frame::Video::new(
+                    ffmpeg::format::Pixel::BGRZ,
+                    PLACEHOLDER_SIZE as u32,
+                    PLACEHOLDER_SIZE as u32,
+                );
+                black.data_mut(0).fill(0);
+                black
+            }
+        }
+    }
+
+    // adds this output's source to a graph, named `name`
+    pub fn add_source(&self, g: &mut filter::Graph, name: &str) {
+        match &self.source {
+            Some(frames) => {
+                crate::add_hw_source(g, &CString::new(name).unwrap(), frames, frames.size())
+            }
+            None => {
+                g.add(
+                    &filter::find("buffer").unwrap(),
+                    name,
+                    &format!("video_size={PLACEHOLDER_SIZE}x{PLACEHOLDER_SIZE}:pix_fmt=bgr0:time_base=1/1000000000"),
+                )
+                .unwrap();
+            }
+        }
+    }
+
+    // the chain from this output's source to its tile of `size` (screen coordinates) in `format`
+    pub fn filter(&self, (w, h): (i32, i32), format: &str) -> String {
+        match self.source {
+            Some(_) => {
+                let (w, h) = transpose_if_transform_transposed((w, h), self.transform);
+                format!(
+                    "scale_vaapi=format={format}:w={w}:h={h}{}",
+                    transpose_vaapi(self.transform)
+                )
+            }
+            None => format!("hwupload,scale_vaapi=format={format}:w={w}:h={h}"),
+        }
+    }
+}
+
+// the capture thread's state
+struct Capture {
+    name: String,
+    manager: ZwlrScreencopyManagerV1,
+    dma: ZwpLinuxDmabufV1,
+    output: WlOutput,
+    paint_cursor: bool,
+    damage: bool,
+    device: AvHwDevCtx,
+    frames: Option<(AvHwFrameCtx, (DrmFourcc, u32, u32))>,
+    buffers: HashMap<u32, WlBuffer>, // for the frames' surfaces, by VASurfaceID
+    in_flight: Option<frame::Video>,
+    failures: u32, // copies failed in a row
+    shared: Arc<Shared>,
+    wake: Box<dyn Fn() + Send>,
+}
+
+impl Capture {
+    fn request(&self, qh: &QueueHandle<Self>) {
+        // the frame's buffer events are where the copy is queued
+        let _frame = self
+            .manager
+            .capture_output(self.paint_cursor.into(), &self.output, qh, ());
+    }
+
+    fn clear_buffers(&mut self) {
+        for (_, wl_buffer) in self.buffers.drain() {
+            wl_buffer.destroy();
+        }
+    }
+
+    // allocates a surface in the format the compositor asked for and queues the copy into it
+    fn copy(
+        &mut self,
+        frame: &ZwlrScreencopyFrameV1,
+        format: (DrmFourcc, u32, u32),
+        qh: &QueueHandle<Self>,
+    ) -> anyhow::Result<()> {
+        if let Some(device) = self.shared.new_device.lock().unwrap().take() {
+            self.device = device;
+            self.frames = None;
+        }
+        if self.frames.as_ref().map(|(_, f)| *f) != Some(format) {
+            let (fourcc, w, h) = format;
+            debug!(target: log_spec::WAYLAND, "capturing {} as {w}x{h} {fourcc}", self.name);
+            self.clear_buffers();
+            let frames = self
+                .device
+                .create_frame_ctx(
+                    dmabuf_to_av(fourcc),
+                    w as i32,
+                    h as i32,
+                    DrmModifier::LINEAR,
+                    Usage::Capture,
+                )
+                .with_context(|| format!("failed to create capture surfaces for {}", self.name))?;
+            self.frames = Some((frames, format));
+        }
+        let (frames, (fourcc, w, h)) = self.frames.as_mut().unwrap();
+        let surf = frames.alloc()?;
+
+        let wl_buffer = self.buffers.entry(surface_id(&surf)).or_insert_with(|| {
+            let (desc, av_mapping) = map_drm(&surf);
+
+            let modifier = desc.objects[0].format_modifier.to_be_bytes();
+            let stride = desc.layers[0].planes[0].pitch as u32;
+            let fd = unsafe { BorrowedFd::borrow_raw(desc.objects[0].fd) };
+
+            let params = self.dma.create_params(qh, ());
+            params.add(
+                fd,
+                0,
+                0,
+                stride,
+                u32::from_be_bytes(modifier[..4].try_into().unwrap()),
+                u32::from_be_bytes(modifier[4..].try_into().unwrap()),
+            );
+            let wl_buffer = params.create_immed(
+                *w as i32,
+                *h as i32,
+                *fourcc as u32,
+                zwp_linux_buffer_params_v1::Flags::empty(),
+                qh,
+                (),
+            );
+            drop(av_mapping);
+            wl_buffer
+        });
+        if self.damage {
+            frame.copy_with_damage(wl_buffer);
+        } else {
+            frame.copy(wl_buffer);
+        }
+        self.in_flight = Some(surf);
+        Ok(())
+    }
+
+    fn on_ready(&mut self, qh: &QueueHandle<Self>) {
+        self.failures = 0;
+        if let Some(surf) = self.in_flight.take() {
+            *self.shared.latest.lock().unwrap() = Some(surf);
+            self.shared.fresh.store(true, SeqCst);
+            (self.wake)();
+        }
+        self.request(qh);
+    }
+
+    // the output keeps what it showed last, copying is tried again after a while
+    fn on_failed(&mut self, qh: &QueueHandle<Self>) {
+        self.in_flight = None;
+        self.failures += 1;
+        if self.failures == 5 {
+            warn!(
+                target: log_spec::WAYLAND,
+                "capturing {} keeps failing, it's recorded as it was last captured", self.name
+            );
+        }
+        sleep(capture_error::backoff(self.failures));
+        self.request(qh);
+    }
+}
+
+impl Dispatch<ZwlrScreencopyFrameV1, ()> for Capture {
+    fn event(
+        state: &mut Self,
+        frame: &ZwlrScreencopyFrameV1,
+        event: <ZwlrScreencopyFrameV1 as Proxy>::Event,
+        _data: &(),
+        _conn: &Connection,
+        qhandle: &QueueHandle<Self>,
+    ) {
+        match event {
+            zwlr_screencopy_frame_v1::Event::LinuxDmabuf {
+                format,
+                width,
+                height,
+            } => {
+                let res = DrmFourcc::try_from(format)
+                    .map_err(|e| anyhow::format_err!("unknown format: {e:?}"))
+                    .and_then(|fourcc| state.copy(frame, (fourcc, width, height), qhandle));
+                if let Err(e) = res {
+                    debug!(target: log_spec::WAYLAND, "failed to copy {}: {e:?}", state.name);
+                    frame.destroy();
+                    state.on_failed(qhandle);
+                }
+            }
+            zwlr_screencopy_frame_v1::Event::Ready { .. } => {
+                frame.destroy();
+                state.on_ready(qhandle);
+            }
+            zwlr_screencopy_frame_v1::Event::Failed => {
+                frame.destroy();
+                state.on_failed(qhandle);
+            }
+            _ => {}
+        }
+    }
+}
+
+impl Dispatch<ZwlrScreencopyManagerV1, ()> for Capture {
+    fn event(
+        _state: &mut Self,
+        _proxy: &ZwlrScreencopyManagerV1,
+        _event: <ZwlrScreencopyManagerV1 as Proxy>::Event,
+        _data: &(),
+        _conn: &Connection,
+        _qhandle: &QueueHandle<Self>,
+    ) {
+    }
+}
+
+impl Dispatch<ZwpLinuxDmabufV1, ()> for Capture {
+    fn event(
+        _state: &mut Self,
+        _proxy: &ZwpLinuxDmabufV1,
+        _event: <ZwpLinuxDmabufV1 as Proxy>::Event,
+        _data: &(),
+        _conn: &Connection,
+        _qhandle: &QueueHandle<Self>,
+    ) {
+    }
+}
+
+impl Dispatch<ZwpLinuxBufferParamsV1, ()> for Capture {
+    fn event(
+        _state: &mut Self,
+        _proxy: &ZwpLinuxBufferParamsV1,
+        _event: <ZwpLinuxBufferParamsV1 as Proxy>::Event,
+        _data: &(),
+        _conn: &Connection,
+        _qhandle: &QueueHandle<Self>,
+    ) {
+    }
+}
+
+impl Dispatch<WlBuffer, ()> for Capture {
+    fn event(
+        _state: &mut Self,
+        _proxy: &WlBuffer,
+        _event: <WlBuffer as Proxy>::Event,
+        _data: &(),
+        _conn: &Connection,
+        _qhandle: &QueueHandle<Self>,
+    ) {
+    }
+}
+
+// the sync callback that wakes the thread to stop
+impl Dispatch<WlCallback, ()> for Capture {
+    fn event(
+        _state: &mut Self,
+        _proxy: &WlCallback,
+        _event: <WlCallback as Proxy>::Event,
+        _data: &(),
+        _conn: &Connection,
+        _qhandle: &QueueHandle<Self>,
+    ) {
+    }
+}
@@ -0,0 +1,40 @@
+// --output DP-3,HDMI-A-1,any: the first of these outputs that's enabled is recorded, so the same
+// keybinding works docked and undocked. `any` matches any enabled output
+
+pub fn is_list(output: &str) -> bool {
+    output.contains(',') || output == "any"
+}
+
+// `enabled` are the names of the enabled outputs. `any` picks the first of them by name, so it
+// doesn't depend on the order the compositor announced them in
+pub fn pick<'a>(output: &str, enabled: &[&'a str]) -> Option<&'a str> {
+    output
+        .split(',')
+        .map(str::trim)
+        .find_map(|name| match name {
+            "any" => enabled.iter().min().copied(),
+            name => enabled.iter().find(|e| **e == name).copied(),
+        })
+}
+
+#[cfg(test)]
+mod test {
+    use super::{is_list, pick};
+
+    #[test]
+    fn fallback() {
+        let docked = ["eDP-1", "DP-3", "HDMI-A-1"];
+        let undocked = ["eDP-1"];
+
+        assert!(is_list("DP-3,eDP-1"));
+        assert!(is_list("any"));
+        assert!(!is_list("DP-3"));
+
+        assert_eq!(pick("DP-3,eDP-1", &docked), Some("DP-3"));
+        assert_eq!(pick("DP-3,eDP-1", &undocked), Some("eDP-1"));
+        assert_eq!(pick("DP-3, HDMI-A-1", &undocked), None);
+        assert_eq!(pick("DP-3,any", &undocked), Some("eDP-1"));
+        assert_eq!(pick("any", &docked), Some("DP-3"));
+        assert_eq!(pick("any", &[]), None);
+    }
+}
@@ -0,0 +1,173 @@
+use std::collections::HashMap;
+
+use ffmpeg::Rational;
+use log::{debug, warn};
+use wayland_client::{
+    backend::ObjectId, event_created_child, globals::GlobalList, Connection, Dispatch, Proxy,
+    QueueHandle, WEnum,
+};
+use wayland_protocols_wlr::output_management::v1::client::{
+    zwlr_output_head_v1::{self, AdaptiveSyncState, ZwlrOutputHeadV1},
+    zwlr_output_manager_v1::{self, ZwlrOutputManagerV1},
+    zwlr_output_mode_v1::{self, ZwlrOutputModeV1},
+};
+
+use crate::{CaptureSource, State};
+
+// What wlr-output-management knows about an output, which unlike wl_output includes whether it's
+// enabled and whether adaptive sync is on
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct HeadInfo {
+    pub enabled: bool,
+    pub refresh: Option<Rational>,
+    pub adaptive_sync: bool,
+}
+
+#[derive(Default)]
+struct Head {
+    name: Option<String>,
+    enabled: bool,
+    current_mode: Option<ObjectId>,
+    adaptive_sync: bool,
+}
+
+// Tracks the heads advertised by zwlr-output-manager. Events are applied as they arrive, and the
+// manager's done event (which carries the whole configuration atomically) is forwarded to
+// `State::on_output_configuration_changed`
+pub struct OutputManagement {
+    _manager: ZwlrOutputManagerV1,
+    heads: HashMap<ObjectId, Head>,
+    mode_refresh: HashMap<ObjectId, i32>, // mHz
+}
+
+impl OutputManagement {
+    // None if the compositor doesn't support wlr-output-management, wl_output is used alone then
+    pub fn bind<S: CaptureSource + 'static>(
+        gm: &GlobalList,
+        eq: &QueueHandle<State<S>>,
+    ) -> Option<Self> {
+        match gm.bind(eq, 1..=ZwlrOutputManagerV1::interface().version, ()) {
+            Ok(manager) => Some(OutputManagement {
+                _manager: manager,
+                heads: HashMap::new(),
+                mode_refresh: HashMap::new(),
+            }),
+            Err(e) => {
+                debug!("not using wlr-output-management: {e}");
+                None
+            }
+        }
+    }
+
+    pub fn head(&self, name: &str) -> Option<HeadInfo> {
+        let head = self
+            .heads
+            .values()
+            .find(|h| h.name.as_deref() == Some(name))?;
+        Some(HeadInfo {
+            enabled: head.enabled,
+            refresh: head
+                .current_mode
+                .as_ref()
+                .and_then(|m| self.mode_refresh.get(m))
+                .filter(|r| **r > 0)
+                .map(|r| Rational(*r, 1000)),
+            adaptive_sync: head.adaptive_sync,
+        })
+    }
+}
+
+impl<S: CaptureSource + 'static> Dispatch<ZwlrOutputManagerV1, ()> for State<S> {
+    fn event(
+        state: &mut Self,
+        _proxy: &ZwlrOutputManagerV1,
+        event: <ZwlrOutputManagerV1 as Proxy>::Event,
+        _data: &(),
+        _conn: &Connection,
+        _qhandle: &QueueHandle<Self>,
+    ) {
+        debug!("zwlr-output-manager event: {event:?}");
+        match event {
+            zwlr_output_manager_v1::Event::Head { head } => {
+                if let Some(om) = &mut state.output_management {
+                    om.heads.insert(head.id(), Head::default());
+                }
+            }
+            zwlr_output_manager_v1::Event::Done { .. } => state.on_output_configuration_changed(),
+            zwlr_output_manager_v1::Event::Finished => {
+                warn!("compositor stopped sending output configuration");
+                state.output_management = None;
+            }
+            _ => {}
+        }
+    }
+
+    event_created_child!(State<S>, ZwlrOutputManagerV1, [
+        zwlr_output_manager_v1::EVT_HEAD_OPCODE => (ZwlrOutputHeadV1, ()),
+    ]);
+}
+
+impl<S: CaptureSource + 'static> Dispatch<ZwlrOutputHeadV1, ()> for State<S> {
+    fn event(
+        state: &mut Self,
+        proxy: &ZwlrOutputHeadV1,
+        event: <ZwlrOutputHeadV1 as Proxy>::Event,
+        _data: &(),
+        _conn: &Connection,
+        _qhandle: &QueueHandle<Self>,
+    ) {
+        debug!("zwlr-output-head event: {:?} {event:?}", proxy.id());
+        let Some(om) = &mut state.output_management else {
+            return;
+        };
+        if let zwlr_output_head_v1::Event::Finished = event {
+            om.heads.remove(&proxy.id());
+            return;
+        }
+        let Some(head) = om.heads.get_mut(&proxy.id()) else {
+            return;
+        };
+        match event {
+            zwlr_output_head_v1::Event::Name { name } => head.name = Some(name),
+            zwlr_output_head_v1::Event::Enabled { enabled } => {
+                head.enabled = enabled != 0;
+                if !head.enabled {
+                    head.current_mode = None;
+                }
+            }
+            zwlr_output_head_v1::Event::CurrentMode { mode } => head.current_mode = Some(mode.id()),
+            zwlr_output_head_v1::Event::AdaptiveSync { state } => {
+                head.adaptive_sync = state == WEnum::Value(AdaptiveSyncState::Enabled)
+            }
+            _ => {}
+        }
+    }
+
+    event_created_child!(State<S>, ZwlrOutputHeadV1, [
+        zwlr_output_head_v1::EVT_MODE_OPCODE => (ZwlrOutputModeV1, ()),
+    ]);
+}
+
+impl<S: CaptureSource + 'static> Dispatch<ZwlrOutputModeV1, ()> for State<S> {
+    fn event(
+        state: &mut Self,
+        proxy: &ZwlrOutputModeV1,
+        event: <ZwlrOutputModeV1 as Proxy>::Event,
+        _data: &(),
+        _conn: &Connection,
+        _qhandle: &QueueHandle<Self>,
+    ) {
+        let Some(om) = &mut state.output_management else {
+            return;
+        };
+        match event {
+            zwlr_output_mode_v1::Event::Refresh { refresh } => {
+                om.mode_refresh.insert(proxy.id(), refresh);
+            }
+            zwlr_output_mode_v1::Event::Finished => {
+                om.mode_refresh.remove(&proxy.id());
+            }
+            _ => {}
+        }
+    }
+}
@@ -0,0 +1,75 @@
+use std::ffi::CStr;
+
+use ffmpeg::{ffi::av_hwframe_transfer_data, filter, format::Pixel, frame};
+
+use crate::{
+    avhw::{AvHwFrameCtx, Usage},
+    multi_output::new_ref,
+    DrmModifier,
+};
+
+// images drawn on the CPU (--exclude-region's black, --overlay-text) that overlay_vaapi blends
+// onto the recorded frames, so those never have to be downloaded. An image is uploaded to a
+// surface of its own when it changes, and each frame only pushes another reference to it
+pub struct OverlaySurface {
+    frames: AvHwFrameCtx,
+    surface: Option<frame::Video>,
+}
+
+impl OverlaySurface {
+    // for images of `size` in `format`, on the same device as `like`'s surfaces
+    pub fn new(
+        like: &AvHwFrameCtx,
+        format: Pixel,
+        (w, h): (i32, i32),
+    ) -> Result<Self, ffmpeg::Error> {
+        let frames =
+            like.device()
+                .create_frame_ctx(format, w, h, DrmModifier::LINEAR, Usage::Overlay)?;
+        Ok(OverlaySurface {
+            frames,
+            surface: None,
+        })
+    }
+
+    pub fn size(&self) -> (i32, i32) {
+        self.frames.size()
+    }
+
+    // `image` is what's pushed from now on. It has to be the size and format given to `new`. The
+    // graph may still hold the old surface, so it's uploaded to a new one
+    pub fn upload(&mut self, image: &frame::Video) -> Result<(), ffmpeg::Error> {
+        let mut surface = self.frames.alloc()?;
+        match unsafe { av_hwframe_transfer_data(surface.as_mut_ptr(), image.as_ptr(), 0) } {
+            0 => {
+                self.surface = Some(surface);
+                Ok(())
+            }
+            e => Err(ffmpeg::Error::from(e)),
+        }
+    }
+
+    // adds a source named `name` to `g` that this can be pushed to
+    pub fn add_source(&self, g: &mut filter::Graph, name: &CStr) {
+        crate::add_hw_source(g, name, &self.frames, self.size());
+    }
+
+    // feeds the uploaded image to the source `name` for the frame at `pts`
+    pub fn push(
+        &self,
+        g: &mut filter::Graph,
+        name: &str,
+        pts: Option<i64>,
+    ) -> Result<(), ffmpeg::Error> {
+        let Some(surface) = &self.surface else {
+            return Err(ffmpeg::Error::InvalidData);
+        };
+        let mut frame = new_ref(surface);
+        frame.set_pts(pts);
+        unsafe {
+            (*frame.as_mut_ptr()).time_base.num = 1;
+            (*frame.as_mut_ptr()).time_base.den = 1_000_000_000;
+        }
+        g.get(name).unwrap().source().add(&frame)
+    }
+}
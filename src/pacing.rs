@@ -0,0 +1,155 @@
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    thread::{self, sleep},
+    time::Duration,
+};
+
+// frames per second every second, and how evenly spaced the frames were for the summary at exit:
+// the intervals between captured frames (as the compositor timestamped them) and between the
+// frames that made it to the encoder, after --max-fps, dedupe and pausing drop theirs
+
+const BUCKET_NS: i64 = 100_000; // 0.1ms
+const BUCKETS: usize = 10_000; // up to 1s, longer intervals all land in the last one
+
+struct Intervals {
+    buckets: Vec<u64>,
+    count: u64,
+    max_ns: i64,
+    last_ns: Option<i64>,
+}
+
+impl Intervals {
+    fn new() -> Self {
+        Intervals {
+            buckets: vec![0; BUCKETS],
+            count: 0,
+            max_ns: 0,
+            last_ns: None,
+        }
+    }
+
+    fn push(&mut self, ns: i64) {
+        if let Some(last) = self.last_ns.replace(ns) {
+            let interval = (ns - last).max(0);
+            let bucket = ((interval / BUCKET_NS) as usize).min(BUCKETS - 1);
+            self.buckets[bucket] += 1;
+            self.count += 1;
+            self.max_ns = self.max_ns.max(interval);
+        }
+    }
+
+    // upper end of the bucket the percentile falls in, in ms
+    fn percentile(&self, p: f64) -> f64 {
+        let target = ((self.count as f64 * p).ceil() as u64).max(1);
+        let mut seen = 0;
+        for (i, n) in self.buckets.iter().enumerate() {
+            seen += n;
+            if seen >= target {
+                return ((i as i64 + 1) * BUCKET_NS).min(self.max_ns) as f64 / 1e6;
+            }
+        }
+        self.max_ns as f64 / 1e6
+    }
+
+    fn summary(&self, name: &str) -> Option<String> {
+        (self.count > 0).then(|| {
+            format!(
+                "{name} frame intervals: p50 {:.1}ms, p95 {:.1}ms, max {:.1}ms over {} frames",
+                self.percentile(0.5),
+                self.percentile(0.95),
+                self.max_ns as f64 / 1e6,
+                self.count + 1,
+            )
+        })
+    }
+}
+
+pub struct Pacing {
+    ct: Arc<AtomicU64>,
+    capture: Intervals,
+    encode: Intervals,
+}
+
+impl Pacing {
+    pub fn new() -> Self {
+        let ct = Arc::new(AtomicU64::new(0));
+        let ct_weak = Arc::<AtomicU64>::downgrade(&ct);
+
+        thread::Builder::new()
+            .name("FpsCounter".to_owned())
+            .spawn(move || {
+                let mut last_ct = 0;
+                loop {
+                    sleep(Duration::from_millis(1000));
+
+                    if let Some(ct_ptr) = ct_weak.upgrade() {
+                        let ct = ct_ptr.load(Ordering::SeqCst);
+                        println!("{} fps", ct - last_ct);
+                        last_ct = ct;
+                    } else {
+                        return;
+                    }
+                }
+            })
+            .unwrap();
+
+        Self {
+            ct,
+            capture: Intervals::new(),
+            encode: Intervals::new(),
+        }
+    }
+
+    // `pts_ns` is the compositor's timestamp of the copied frame
+    pub fn on_capture(&mut self, pts_ns: i64) {
+        self.ct.fetch_add(1, Ordering::SeqCst);
+        self.capture.push(pts_ns);
+    }
+
+    // `pts_ns` is the frame's timestamp in the recording
+    pub fn on_encode(&mut self, pts_ns: i64) {
+        self.encode.push(pts_ns);
+    }
+
+    pub fn summary(&self) -> Vec<String> {
+        [
+            self.capture.summary("captured"),
+            self.encode.summary("encoded"),
+        ]
+        .into_iter()
+        .flatten()
+        .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Intervals;
+
+    const MS: i64 = 1_000_000;
+
+    #[test]
+    fn percentiles() {
+        let mut intervals = Intervals::new();
+        let mut t = 0;
+        intervals.push(t);
+        // 19 frames 16.6ms apart and one 50ms stutter
+        for _ in 0..19 {
+            t += 16_600_000;
+            intervals.push(t);
+        }
+        t += 50 * MS;
+        intervals.push(t);
+
+        assert_eq!(intervals.percentile(0.5), 16.7);
+        assert_eq!(intervals.percentile(0.95), 16.7);
+        assert_eq!(intervals.percentile(1.), 50.);
+        assert_eq!(
+            intervals.summary("captured").unwrap(),
+            "captured frame intervals: p50 16.7ms, p95 16.7ms, max 50.0ms over 21 frames"
+        );
+    }
+}
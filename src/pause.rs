@@ -0,0 +1,108 @@
+use std::collections::HashSet;
+
+use crate::audio::AudioHandle;
+
+// why the recording is paused
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum PauseReason {
+    Locked,         // --on-lock
+    OtherWorkspace, // --workspace
+    Unfocused,      // --only-while-focused
+    Idle,           // --pause-after-idle
+    CaptureError,   // --on-capture-error pause
+}
+
+// time while any reason holds is cut out of the recording, once even if several overlap. Audio is
+// paused along with it. All times are CLOCK_MONOTONIC ns, like the presentation timestamps of
+// captured frames
+#[derive(Default)]
+pub struct Pauses {
+    reasons: HashSet<PauseReason>,
+    since: Option<i64>, // while paused
+    start: Option<i64>, // of the recording, time paused before it isn't cut
+    cut_ns: i64,
+}
+
+impl Pauses {
+    pub fn started(&mut self, now: i64) {
+        self.start = Some(now);
+    }
+
+    // returns true if the recording wasn't paused before, for any reason
+    pub fn pause(
+        &mut self,
+        reason: PauseReason,
+        now: i64,
+        audio: Option<&mut AudioHandle>,
+    ) -> bool {
+        let was_paused = !self.reasons.is_empty();
+        if !self.reasons.insert(reason) || was_paused {
+            return false;
+        }
+        self.since = Some(now);
+        if let Some(audio) = audio {
+            audio.set_paused(true);
+        }
+        true
+    }
+
+    pub fn resume(&mut self, reason: PauseReason, now: i64, audio: Option<&mut AudioHandle>) {
+        if !self.reasons.remove(&reason) || !self.reasons.is_empty() {
+            return;
+        }
+        if let (Some(since), Some(start)) = (self.since.take(), self.start) {
+            self.cut_ns += (now - since.max(start)).max(0);
+        }
+        if let Some(audio) = audio {
+            audio.set_paused(false);
+        }
+    }
+
+    pub fn is_paused(&self) -> bool {
+        !self.reasons.is_empty()
+    }
+
+    // in total
+    pub fn cut_ns(&self) -> i64 {
+        self.cut_ns
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{PauseReason::*, Pauses};
+
+    #[test]
+    fn overlapping() {
+        let mut p = Pauses::default();
+        p.started(0);
+        assert!(p.pause(Locked, 10, None));
+        assert!(!p.pause(Unfocused, 20, None));
+        assert!(!p.pause(Locked, 25, None));
+        p.resume(Locked, 30, None);
+        assert_eq!(p.cut_ns(), 0);
+        assert!(p.reasons.contains(&Unfocused));
+        p.resume(Unfocused, 50, None);
+        assert_eq!(p.cut_ns(), 40);
+
+        // resuming what wasn't paused does nothing
+        p.resume(Idle, 60, None);
+        assert_eq!(p.cut_ns(), 40);
+    }
+
+    #[test]
+    fn before_start() {
+        let mut p = Pauses::default();
+        assert!(p.pause(CaptureError, 10, None));
+        p.started(30);
+        p.resume(CaptureError, 50, None);
+        assert_eq!(p.cut_ns(), 20);
+
+        // paused and resumed before the recording started, nothing to cut
+        let mut p = Pauses::default();
+        p.pause(Idle, 10, None);
+        p.resume(Idle, 20, None);
+        p.started(30);
+        assert_eq!(p.cut_ns(), 0);
+    }
+}
@@ -0,0 +1,95 @@
+use std::{
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+use anyhow::anyhow;
+use ffmpeg::frame;
+use log::warn;
+
+use crate::screenshot;
+
+// --poster: a frame of the recording attached as cover.png, which file managers and players show
+// as the thumbnail of a mkv. Attachments are written with the header, before any frame is
+// captured, so the file is remuxed with it once it's finished (see attachment::embed)
+
+pub const FILENAME: &str = "cover.png";
+
+enum State {
+    Waiting(i64), // until this pts, in ns since the start of the recording
+    Encoding(JoinHandle<anyhow::Result<Vec<u8>>>),
+    Done,
+}
+
+pub struct Poster(State);
+
+impl Poster {
+    pub fn new(at: Duration) -> Self {
+        Poster(State::Waiting(at.as_nanos() as i64))
+    }
+
+    // every frame on its way to the encoder, the first one at or after the time is taken
+    pub fn push(&mut self, frame: &frame::Video) {
+        let State::Waiting(at) = self.0 else {
+            return;
+        };
+        if frame.pts().is_none_or(|pts| pts < at) {
+            return;
+        }
+        let frame = match screenshot::download(frame) {
+            Ok(frame) => frame,
+            Err(e) => {
+                warn!("failed to download frame for --poster: {e}");
+                self.0 = State::Done;
+                return;
+            }
+        };
+        match thread::Builder::new()
+            .name("poster".to_owned())
+            .spawn(move || screenshot::encode_png(&frame))
+        {
+            Ok(handle) => self.0 = State::Encoding(handle),
+            Err(e) => {
+                warn!("failed to start encoding --poster: {e}");
+                self.0 = State::Done;
+            }
+        }
+    }
+
+    // once `path` is finished, `last` if the recording is over. The poster goes into the file it
+    // was taken from, so with --split-at only one file gets it
+    pub fn take(&mut self, path: &str, last: bool) -> Option<PendingPoster> {
+        match std::mem::replace(&mut self.0, State::Done) {
+            State::Encoding(handle) => Some(PendingPoster(handle)),
+            State::Waiting(_) if last => {
+                warn!("the recording ended before the --poster frame, {path} has no poster");
+                None
+            }
+            State::Waiting(at) => {
+                self.0 = State::Waiting(at);
+                None
+            }
+            State::Done => None,
+        }
+    }
+}
+
+// the poster of a finished file, which may still be being encoded
+pub struct PendingPoster(JoinHandle<anyhow::Result<Vec<u8>>>);
+
+impl PendingPoster {
+    pub fn png(self, path: &str) -> Option<Vec<u8>> {
+        let res = self
+            .0
+            .join()
+            .map_err(|_| anyhow!("encoding the poster panicked"))
+            .and_then(|png| png);
+        match res {
+            Ok(png) => Some(png),
+            Err(e) => {
+                warn!("failed to encode the poster for {path}: {e:?}");
+                None
+            }
+        }
+    }
+}
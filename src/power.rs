@@ -0,0 +1,183 @@
+use std::{
+    fs,
+    path::Path,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, Ordering::SeqCst},
+        Arc,
+    },
+    thread::{self, sleep},
+    time::Duration,
+};
+
+use human_size::Size;
+use log::{debug, info};
+use thiserror::Error;
+
+const POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+// Settings from --on-battery, applied while running on battery power
+#[derive(Debug, Clone, PartialEq)]
+pub struct BatteryOverrides {
+    pub max_fps: Option<f64>,
+    pub bitrate: Option<Size>,
+}
+
+#[derive(Error, Debug, PartialEq)]
+pub enum ParseOverridesError {
+    #[error("expected key=value, got {0}")]
+    Structure(String),
+    #[error("unknown setting {0}, expected max-fps or bitrate")]
+    UnknownKey(String),
+    #[error("invalid value for {0}")]
+    Value(String),
+}
+
+impl FromStr for BatteryOverrides {
+    type Err = ParseOverridesError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        use ParseOverridesError::*;
+        let mut overrides = BatteryOverrides {
+            max_fps: None,
+            bitrate: None,
+        };
+        for kv in s.split(',') {
+            let (key, value) = kv.split_once('=').ok_or_else(|| Structure(kv.into()))?;
+            match key.trim() {
+                "max-fps" => {
+                    overrides.max_fps = Some(
+                        value
+                            .parse()
+                            .ok()
+                            .filter(|fps: &f64| *fps > 0.)
+                            .ok_or_else(|| Value(key.into()))?,
+                    )
+                }
+                "bitrate" => {
+                    overrides.bitrate = Some(value.parse().map_err(|_| Value(key.into()))?)
+                }
+                _ => return Err(UnknownKey(key.into())),
+            }
+        }
+        Ok(overrides)
+    }
+}
+
+// Some(true) if there is a battery and no external power supply is online, None if there's no
+// way to tell (no battery, or not linux)
+fn on_battery(power_supply_dir: &Path) -> Option<bool> {
+    let mut has_battery = false;
+    let mut has_external = false;
+    let mut external_online = false;
+
+    for entry in fs::read_dir(power_supply_dir).ok()?.flatten() {
+        let read = |f: &str| {
+            fs::read_to_string(entry.path().join(f))
+                .map(|s| s.trim().to_string())
+                .unwrap_or_default()
+        };
+        match read("type").as_str() {
+            "Battery" => has_battery = true,
+            "Mains" | "USB" => {
+                has_external = true;
+                external_online |= read("online") == "1";
+            }
+            _ => {}
+        }
+    }
+
+    if has_battery && has_external {
+        Some(!external_online)
+    } else {
+        None
+    }
+}
+
+// polls sysfs in the background, the returned flag is true while running on battery
+fn spawn_battery_watcher() -> Arc<AtomicBool> {
+    let flag = Arc::new(AtomicBool::new(false));
+    let flag_weak = Arc::downgrade(&flag);
+
+    thread::Builder::new()
+        .name("battery-watcher".to_owned())
+        .spawn(move || {
+            let dir = Path::new("/sys/class/power_supply");
+            while let Some(flag) = flag_weak.upgrade() {
+                let battery = on_battery(dir);
+                debug!("on battery: {battery:?}");
+                flag.store(battery.unwrap_or(false), SeqCst);
+                drop(flag);
+                sleep(POLL_INTERVAL);
+            }
+        })
+        .unwrap();
+
+    flag
+}
+
+// Switches between the normal settings and the --on-battery overrides as the power source changes
+pub struct BatteryPolicy {
+    overrides: BatteryOverrides,
+    ac_max_fps: Option<f64>,
+    ac_bitrate: Size,
+    on_battery: Arc<AtomicBool>,
+    applied: bool,
+}
+
+impl BatteryPolicy {
+    pub fn new(overrides: BatteryOverrides, max_fps: Option<f64>, bitrate: Size) -> Self {
+        BatteryPolicy {
+            overrides,
+            ac_max_fps: max_fps,
+            ac_bitrate: bitrate,
+            on_battery: spawn_battery_watcher(),
+            applied: false,
+        }
+    }
+
+    // the (max fps, bitrate) to switch to, if the power source changed since the last call
+    pub fn poll(&mut self) -> Option<(Option<f64>, Size)> {
+        let on_battery = self.on_battery.load(SeqCst);
+        if on_battery == self.applied {
+            return None;
+        }
+        self.applied = on_battery;
+
+        if on_battery {
+            info!("running on battery, applying --on-battery settings");
+            Some((
+                self.overrides.max_fps.or(self.ac_max_fps),
+                self.overrides.bitrate.unwrap_or(self.ac_bitrate),
+            ))
+        } else {
+            info!("running on external power, restoring normal settings");
+            Some((self.ac_max_fps, self.ac_bitrate))
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{BatteryOverrides, ParseOverridesError};
+
+    #[test]
+    fn parse() {
+        let o: BatteryOverrides = "max-fps=30,bitrate=2MB".parse().unwrap();
+        assert_eq!(o.max_fps, Some(30.));
+        assert_eq!(o.bitrate, Some("2MB".parse().unwrap()));
+
+        let o: BatteryOverrides = "bitrate=500 kB".parse().unwrap();
+        assert_eq!(o.max_fps, None);
+
+        assert_eq!(
+            "fps=30".parse::<BatteryOverrides>(),
+            Err(ParseOverridesError::UnknownKey("fps".into()))
+        );
+        assert_eq!(
+            "max-fps=0".parse::<BatteryOverrides>(),
+            Err(ParseOverridesError::Value("max-fps".into()))
+        );
+        assert!("max-fps".parse::<BatteryOverrides>().is_err());
+    }
+}
@@ -0,0 +1,98 @@
+use std::{
+    ffi::CStr,
+    fs::{self, OpenOptions},
+    io,
+    os::unix::fs::MetadataExt,
+    path::{Path, PathBuf},
+};
+
+use anyhow::bail;
+use log::warn;
+
+// renderD128 -> 128
+fn node_number(name: &str) -> Option<u32> {
+    name.strip_prefix("renderD")?.parse().ok()
+}
+
+// every render node on the system, in order
+fn render_nodes() -> Vec<PathBuf> {
+    let Ok(entries) = fs::read_dir("/dev/dri") else {
+        return Vec::new();
+    };
+    let mut nodes: Vec<_> = entries
+        .flatten()
+        .filter_map(|e| Some((node_number(e.file_name().to_str()?)?, e.path())))
+        .collect();
+    nodes.sort();
+    nodes.into_iter().map(|(_, path)| path).collect()
+}
+
+fn open(node: &Path) -> io::Result<()> {
+    OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(node)
+        .map(drop)
+}
+
+// name of the group owning `node`, which the user needs to be in to use it
+fn owning_group(node: &Path) -> Option<String> {
+    let gid = fs::metadata(node).ok()?.gid();
+    let group = unsafe { libc::getgrgid(gid) };
+    if group.is_null() {
+        return None;
+    }
+    Some(
+        unsafe { CStr::from_ptr((*group).gr_name) }
+            .to_string_lossy()
+            .into_owned(),
+    )
+}
+
+// Checks `node` can be opened before handing it to vaapi, which only reports a generic failure
+// when it can't. If it's a permissions problem and the node was guessed rather than passed with
+// --dri-device, another render node that can be opened is used instead
+pub fn usable(node: &Path, may_substitute: bool) -> anyhow::Result<PathBuf> {
+    let e = match open(node) {
+        Ok(()) => return Ok(node.to_owned()),
+        Err(e) if e.kind() == io::ErrorKind::PermissionDenied => e,
+        // vaapi will report anything else
+        Err(_) => return Ok(node.to_owned()),
+    };
+
+    let group = owning_group(node).unwrap_or_else(|| "render".to_owned());
+    let guidance = format!(
+        "Add yourself to the {group} group (`sudo usermod -aG {group} $USER`) and log in again"
+    );
+
+    if may_substitute {
+        if let Some(other) = render_nodes()
+            .into_iter()
+            .find(|n| n != node && open(n).is_ok())
+        {
+            warn!(
+                "no permission to open {} ({e}), using {} instead, which may be a different GPU. {guidance}, or pass --dri-device to pick one",
+                node.display(),
+                other.display()
+            );
+            return Ok(other);
+        }
+    }
+    bail!(
+        "no permission to open the render node {}: {e}. {guidance}",
+        node.display()
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::node_number;
+
+    #[test]
+    fn node_numbers() {
+        assert_eq!(node_number("renderD128"), Some(128));
+        assert_eq!(node_number("renderD129"), Some(129));
+        assert_eq!(node_number("card0"), None);
+        assert_eq!(node_number("by-path"), None);
+    }
+}
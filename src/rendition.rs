@@ -0,0 +1,456 @@
+use std::{mem, str::FromStr};
+
+use anyhow::Context;
+use ffmpeg::{dictionary, encoder, filter, format, frame, picture, Packet, Rational};
+use human_size::Size;
+use log::{info, warn};
+use thiserror::Error;
+use wayland_client::protocol::wl_output::Transform;
+
+use crate::{
+    avhw::{AvHwDevCtx, AvHwFrameCtx, Usage},
+    composite::Composite,
+    exclude::Exclusions,
+    get_encoder, make_video_params, multi_output, open_output, parse_size,
+    transform::Rect,
+    video_filter, Args, DmabufFormat, DrmModifier, EncodePixelFormat,
+};
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum RenditionSize {
+    Height(u32),     // 720p, width is picked to keep the aspect ratio of the recording
+    Exact(u32, u32), // 1280x720
+}
+
+impl RenditionSize {
+    // the size to encode at, given the encode size of the full quality recording
+    fn resolve(self, (full_w, full_h): (i32, i32)) -> (i32, i32) {
+        match self {
+            RenditionSize::Height(h) => {
+                let w = i64::from(full_w) * i64::from(h) / i64::from(full_h);
+                // most encoders only take even sizes
+                (w as i32 & !1, h as i32 & !1)
+            }
+            RenditionSize::Exact(w, h) => (w as i32, h as i32),
+        }
+    }
+}
+
+// --extra-stream SIZE:BITRATE
+#[derive(Debug, Clone, PartialEq)]
+pub struct RenditionSpec {
+    size: RenditionSize,
+    bitrate: Size,
+}
+
+// --proxy FILENAME:SIZE:BITRATE
+#[derive(Debug, Clone, PartialEq)]
+pub struct ProxySpec {
+    filename: String,
+    rendition: RenditionSpec,
+}
+
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum ParseRenditionError {
+    #[error("expected [FILENAME:]SIZE:BITRATE, for example proxy.mp4:720p:1MB")]
+    Structure,
+    #[error("invalid size, expected something like 720p or 1280x720")]
+    Size,
+    #[error("invalid bitrate")]
+    Bitrate,
+}
+
+impl RenditionSpec {
+    fn parse(size: &str, bitrate: &str) -> Result<Self, ParseRenditionError> {
+        use ParseRenditionError::*;
+        let size = match size.strip_suffix('p') {
+            Some(h) => RenditionSize::Height(h.parse().map_err(|_| Size)?),
+            None => {
+                let (w, h) = parse_size(size).map_err(|_| Size)?;
+                RenditionSize::Exact(w, h)
+            }
+        };
+        if matches!(
+            size,
+            RenditionSize::Height(0) | RenditionSize::Exact(0, _) | RenditionSize::Exact(_, 0)
+        ) {
+            return Err(Size);
+        }
+
+        Ok(RenditionSpec {
+            size,
+            bitrate: bitrate.parse().map_err(|_| Bitrate)?,
+        })
+    }
+}
+
+impl FromStr for RenditionSpec {
+    type Err = ParseRenditionError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (size, bitrate) = s.split_once(':').ok_or(ParseRenditionError::Structure)?;
+        RenditionSpec::parse(size, bitrate)
+    }
+}
+
+impl FromStr for ProxySpec {
+    type Err = ParseRenditionError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        // split from the right, so the filename can contain colons
+        let mut it = s.rsplitn(3, ':');
+        let (Some(bitrate), Some(size), Some(filename)) = (it.next(), it.next(), it.next()) else {
+            return Err(ParseRenditionError::Structure);
+        };
+        if filename.is_empty() {
+            return Err(ParseRenditionError::Structure);
+        }
+
+        Ok(ProxySpec {
+            filename: filename.to_owned(),
+            rendition: RenditionSpec::parse(size, bitrate)?,
+        })
+    }
+}
+
+// What a rendition needs from the full quality encode to build its own filter and encoder
+pub struct RenditionSource<'a> {
+    pub hw_device_ctx: &'a mut AvHwDevCtx,
+    pub cross_device: bool, // --encode-device, hw_device_ctx isn't the one frames_rgb is on
+    pub frames_rgb: &'a mut AvHwFrameCtx,
+    pub capture_format: DmabufFormat,
+    pub roi_screen_coord: Rect,
+    pub composite: Option<&'a Composite>,
+    pub exclusions: &'a Exclusions,
+    pub letterbox: bool,
+    pub transform: Transform,
+    pub enc_pixfmt: EncodePixelFormat,
+    pub enc_video: &'a encoder::Video,
+    pub enc_video_options: &'a dictionary::Owned<'static>,
+}
+
+enum RenditionOutput {
+    File(format::context::Output),
+    // an extra stream in the main recording. Packets are collected here and written by EncState,
+    // so they go through history and file splitting like everything else
+    Stream { idx: usize, packets: Vec<Packet> },
+}
+
+// A second, smaller encode of the same captured frames, either written to its own file (--proxy)
+// or as an extra stream in the main recording (--extra-stream)
+pub struct Rendition {
+    args: Args, // with the rendition's filename and bitrate
+    size: RenditionSize,
+    video_filter: filter::Graph,
+    filter_output_timebase: Rational,
+    enc_video: encoder::Video,
+    enc_video_has_been_fed_any_frames: bool,
+    force_keyframe: bool,
+    global_header: bool,
+    output: RenditionOutput,
+}
+
+impl Rendition {
+    pub fn new_file(args: &Args, spec: &ProxySpec, src: RenditionSource) -> anyhow::Result<Self> {
+        let args = Args {
+            filename: spec.filename.clone(),
+            bitrate: spec.rendition.bitrate,
+            ffmpeg_muxer: None,
+            ffmpeg_muxer_options: None,
+            ..args.clone()
+        };
+
+        let mut octx = open_output(&args.filename, None, None)?;
+        let codec = get_encoder(&args, &octx.format())?;
+        let global_header = octx.format().flags().contains(format::Flags::GLOBAL_HEADER);
+
+        let (video_filter, filter_output_timebase, enc_video) =
+            open_encoder(&args, spec.rendition.size, codec, global_header, src)?;
+
+        let mut ost = octx.add_stream(codec)?;
+        ost.set_parameters(&enc_video);
+        octx.write_header()
+            .with_context(|| format!("failed to write header of {}", args.filename))?;
+
+        info!(
+            "writing {}x{} proxy to {}",
+            enc_video.width(),
+            enc_video.height(),
+            args.filename
+        );
+
+        Ok(Rendition {
+            args,
+            size: spec.rendition.size,
+            video_filter,
+            filter_output_timebase,
+            enc_video,
+            enc_video_has_been_fed_any_frames: false,
+            force_keyframe: false,
+            global_header,
+            output: RenditionOutput::File(octx),
+        })
+    }
+
+    // adds the stream to `octx`, so this must be called before its header is written
+    pub fn new_stream(
+        args: &Args,
+        spec: &RenditionSpec,
+        octx: &mut format::context::Output,
+        src: RenditionSource,
+    ) -> anyhow::Result<Self> {
+        let args = Args {
+            bitrate: spec.bitrate,
+            ..args.clone()
+        };
+
+        let codec = src.enc_video.codec().unwrap();
+        let global_header = octx.format().flags().contains(format::Flags::GLOBAL_HEADER);
+
+        let (video_filter, filter_output_timebase, enc_video) =
+            open_encoder(&args, spec.size, codec, global_header, src)?;
+
+        let mut ost = octx.add_stream(codec)?;
+        ost.set_parameters(&enc_video);
+        let idx = ost.index();
+
+        info!(
+            "adding {}x{} extra stream",
+            enc_video.width(),
+            enc_video.height()
+        );
+
+        Ok(Rendition {
+            args,
+            size: spec.size,
+            video_filter,
+            filter_output_timebase,
+            enc_video,
+            enc_video_has_been_fed_any_frames: false,
+            force_keyframe: false,
+            global_header,
+            output: RenditionOutput::Stream {
+                idx,
+                packets: Vec::new(),
+            },
+        })
+    }
+
+    pub fn push(&mut self, surf: &frame::Video, composite: Option<&Composite>) {
+        multi_output::add_frames(&mut self.video_filter, surf, composite);
+        self.process_ready();
+    }
+
+    // make the next frame a keyframe, so a new file can start with it
+    pub fn force_keyframe(&mut self) {
+        self.force_keyframe = true;
+    }
+
+    // packets encoded for --extra-stream since the last call, rescaled to `octx`'s time base
+    pub fn take_packets(&mut self, octx: &format::context::Output) -> Vec<Packet> {
+        let RenditionOutput::Stream { idx, packets } = &mut self.output else {
+            return Vec::new();
+        };
+        let time_base = octx.stream(*idx).unwrap().time_base();
+        let mut packets = mem::take(packets);
+        for p in &mut packets {
+            p.rescale_ts(self.filter_output_timebase, time_base);
+        }
+        packets
+    }
+
+    fn process_ready(&mut self) {
+        let mut yuv_frame = frame::Video::empty();
+        while self
+            .video_filter
+            .get("out")
+            .unwrap()
+            .sink()
+            .frame(&mut yuv_frame)
+            .is_ok()
+        {
+            if self.force_keyframe {
+                yuv_frame.set_kind(picture::Type::I);
+                self.force_keyframe = false;
+            }
+            self.enc_video.send_frame(&yuv_frame).unwrap();
+            self.enc_video_has_been_fed_any_frames = true;
+        }
+
+        let mut encoded = Packet::empty();
+        while self.enc_video.receive_packet(&mut encoded).is_ok() {
+            match &mut self.output {
+                RenditionOutput::File(octx) => {
+                    encoded.set_stream(0);
+                    encoded.rescale_ts(
+                        self.filter_output_timebase,
+                        octx.stream(0).unwrap().time_base(),
+                    );
+                    if let Err(e) = encoded.write_interleaved(octx) {
+                        warn!("failed to write to {}: {e}", self.args.filename);
+                    }
+                }
+                RenditionOutput::Stream { idx, packets } => {
+                    encoded.set_stream(*idx);
+                    packets.push(encoded);
+                }
+            }
+            encoded = Packet::empty();
+        }
+    }
+
+    fn drain(&mut self) {
+        multi_output::flush_sources(&mut self.video_filter);
+        self.process_ready();
+        if self.enc_video_has_been_fed_any_frames {
+            // ffmpeg bug--if you call send_eof before feeding any frames it will crash
+            self.enc_video.send_eof().unwrap();
+        }
+        self.process_ready();
+    }
+
+    // the capture format changed, so the filter and encoder need to be recreated like the main ones
+    pub fn rebuild(&mut self, src: RenditionSource) -> anyhow::Result<()> {
+        self.drain();
+
+        let codec = self.enc_video.codec().unwrap();
+        (
+            self.video_filter,
+            self.filter_output_timebase,
+            self.enc_video,
+        ) = open_encoder(&self.args, self.size, codec, self.global_header, src)?;
+        self.enc_video_has_been_fed_any_frames = false;
+        Ok(())
+    }
+
+    // the region or the other --outputs' surfaces changed, recreate the filter but keep the encoder
+    pub fn rebuild_filter(&mut self, src: RenditionSource) {
+        multi_output::flush_sources(&mut self.video_filter);
+        self.process_ready();
+
+        (self.video_filter, self.filter_output_timebase) = video_filter(
+            src.frames_rgb,
+            src.enc_pixfmt,
+            src.cross_device.then_some(&*src.hw_device_ctx),
+            (src.capture_format.width, src.capture_format.height),
+            src.roi_screen_coord,
+            src.composite,
+            src.exclusions,
+            None,
+            None,
+            (
+                self.enc_video.width() as i32,
+                self.enc_video.height() as i32,
+            ),
+            src.letterbox,
+            src.transform,
+        );
+    }
+
+    // encodes everything that's left. For --proxy this also finishes the file, for --extra-stream
+    // the remaining packets still need to be taken with take_packets
+    pub fn flush(&mut self) {
+        self.drain();
+        if let RenditionOutput::File(octx) = &mut self.output {
+            if let Err(e) = octx.write_trailer() {
+                warn!("failed to finish {}: {e}", self.args.filename);
+            }
+        }
+    }
+}
+
+fn open_encoder(
+    args: &Args,
+    size: RenditionSize,
+    codec: ffmpeg::Codec,
+    global_header: bool,
+    src: RenditionSource,
+) -> anyhow::Result<(filter::Graph, Rational, encoder::Video)> {
+    let (w, h) = size.resolve((src.enc_video.width() as i32, src.enc_video.height() as i32));
+
+    let enc_pixfmt_av = src.enc_pixfmt.surface_format();
+    let mut frames_yuv = src
+        .hw_device_ctx
+        .create_frame_ctx(enc_pixfmt_av, w, h, DrmModifier::LINEAR, Usage::Encode)
+        .with_context(|| {
+            format!("Failed to create a vaapi frame context for {w}x{h} {enc_pixfmt_av:?} surfaces for {}", args.filename)
+        })?;
+
+    let (video_filter, filter_timebase) = video_filter(
+        src.frames_rgb,
+        src.enc_pixfmt,
+        src.cross_device.then_some(&*src.hw_device_ctx),
+        (src.capture_format.width, src.capture_format.height),
+        src.roi_screen_coord,
+        src.composite,
+        src.exclusions,
+        None,
+        None,
+        (w, h),
+        src.letterbox,
+        src.transform,
+    );
+
+    let enc = make_video_params(
+        args,
+        src.enc_pixfmt,
+        &codec,
+        (w, h),
+        src.enc_video.frame_rate(),
+        global_header,
+        src.hw_device_ctx,
+        &mut frames_yuv,
+    )?
+    .open_with(src.enc_video_options.clone())
+    .with_context(|| format!("failed to open {w}x{h} encoder"))?;
+
+    Ok((video_filter, filter_timebase, enc))
+}
+
+#[cfg(test)]
+mod test {
+    use human_size::Size;
+
+    use super::{ParseRenditionError, ProxySpec, RenditionSize, RenditionSpec};
+
+    #[test]
+    fn parse() {
+        let spec: ProxySpec = "proxy.mp4:720p:1MB".parse().unwrap();
+        assert_eq!(spec.filename, "proxy.mp4");
+        assert_eq!(spec.rendition.size, RenditionSize::Height(720));
+        assert_eq!(spec.rendition.bitrate, "1MB".parse::<Size>().unwrap());
+
+        let spec: ProxySpec = "a:b.mkv:640x360:500kB".parse().unwrap();
+        assert_eq!(spec.filename, "a:b.mkv");
+        assert_eq!(spec.rendition.size, RenditionSize::Exact(640, 360));
+
+        let spec: RenditionSpec = "360p:500kB".parse().unwrap();
+        assert_eq!(spec.size, RenditionSize::Height(360));
+
+        assert_eq!(
+            "proxy.mp4:720p".parse::<ProxySpec>(),
+            Err(ParseRenditionError::Structure)
+        );
+        assert_eq!(
+            "proxy.mp4:0p:1MB".parse::<ProxySpec>(),
+            Err(ParseRenditionError::Size)
+        );
+        assert_eq!(
+            "720p:fast".parse::<RenditionSpec>(),
+            Err(ParseRenditionError::Bitrate)
+        );
+    }
+
+    #[test]
+    fn resolve() {
+        assert_eq!(
+            RenditionSize::Height(720).resolve((3840, 2160)),
+            (1280, 720)
+        );
+        assert_eq!(RenditionSize::Height(360).resolve((1366, 768)), (640, 360));
+        assert_eq!(
+            RenditionSize::Exact(320, 200).resolve((1920, 1080)),
+            (320, 200)
+        );
+    }
+}
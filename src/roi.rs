@@ -0,0 +1,170 @@
+use std::mem::size_of;
+
+use ffmpeg::{
+    ffi::{av_frame_new_side_data, AVFrameSideDataType, AVRegionOfInterest},
+    frame, Rational,
+};
+use log_once::warn_once;
+
+use crate::transform::Rect;
+
+// Region of interest hints for the encoder. Each region has a quantizer offset from -1 (best
+// quality) to 1 (worst), which vaapi scales by the codec's QP range
+
+// for --roi-cursor, roughly 10 QP better for h264
+pub const CURSOR_QOFFSET: Rational = Rational(-1, 5);
+
+// for --damage-hints, what changed gets a little better quality than what didn't
+pub const DAMAGED_QOFFSET: Rational = Rational(-1, 10);
+pub const UNDAMAGED_QOFFSET: Rational = Rational(1, 10);
+
+// vaapi drivers only take a few regions, and drop the rest
+const MAX_DAMAGE_REGIONS: usize = 4;
+
+// the --roi-cursor box around the pointer, in encoded frame coordinates. `cursor` and `roi` are in
+// screen coordinates, `roi` being the part of the screen that is scaled to `enc_size`. None if the
+// box is entirely outside of the recording
+pub fn cursor_region(
+    (cursor_x, cursor_y): (i32, i32),
+    (box_w, box_h): (u32, u32),
+    roi: Rect,
+    enc_size: (i32, i32),
+) -> Option<Rect> {
+    let cursor_box = Rect::new(
+        (cursor_x - box_w as i32 / 2, cursor_y - box_h as i32 / 2),
+        (box_w as i32 / 2 * 2, box_h as i32 / 2 * 2),
+    );
+    to_encoded(cursor_box, roi, enc_size)
+}
+
+// damaged regions in encoded frame coordinates, from `damage` in screen coordinates. Many small
+// regions are merged into their bounding box
+pub fn damage_regions(damage: &[Rect], roi: Rect, enc_size: (i32, i32)) -> Vec<Rect> {
+    let regions: Vec<_> = damage
+        .iter()
+        .filter_map(|d| to_encoded(*d, roi, enc_size))
+        .collect();
+    if regions.len() <= MAX_DAMAGE_REGIONS {
+        return regions;
+    }
+    let left = regions.iter().map(|r| r.x).min().unwrap();
+    let top = regions.iter().map(|r| r.y).min().unwrap();
+    let right = regions.iter().map(|r| r.x + r.w).max().unwrap();
+    let bottom = regions.iter().map(|r| r.y + r.h).max().unwrap();
+    vec![Rect::new((left, top), (right - left, bottom - top))]
+}
+
+// clips `region` (screen coordinates) to `roi` and scales it to `enc_size`. None if it's entirely
+// outside of the recording
+pub fn to_encoded(region: Rect, roi: Rect, (enc_w, enc_h): (i32, i32)) -> Option<Rect> {
+    let left = region.x.max(roi.x);
+    let top = region.y.max(roi.y);
+    let right = (region.x + region.w).min(roi.x + roi.w);
+    let bottom = (region.y + region.h).min(roi.y + roi.h);
+    if left >= right || top >= bottom {
+        return None;
+    }
+
+    let scale = |v: i32, origin: i32, from: i32, to: i32| {
+        (i64::from(v - origin) * i64::from(to) / i64::from(from)) as i32
+    };
+    let (x0, x1) = (
+        scale(left, roi.x, roi.w, enc_w),
+        scale(right, roi.x, roi.w, enc_w),
+    );
+    let (y0, y1) = (
+        scale(top, roi.y, roi.h, enc_h),
+        scale(bottom, roi.y, roi.h, enc_h),
+    );
+    Some(Rect::new((x0, y0), (x1 - x0, y1 - y0)))
+}
+
+// attaches quantizer offsets for regions of this frame. Where regions overlap the first one
+// wins. Encoders without region of interest support ignore them
+pub fn attach(frame: &mut frame::Video, regions: &[(Rect, Rational)]) {
+    if regions.is_empty() {
+        return;
+    }
+    unsafe {
+        let sd = av_frame_new_side_data(
+            frame.as_mut_ptr(),
+            AVFrameSideDataType::AV_FRAME_DATA_REGIONS_OF_INTEREST,
+            size_of::<AVRegionOfInterest>() * regions.len(),
+        );
+        if sd.is_null() {
+            warn_once!("failed to allocate region of interest side data");
+            return;
+        }
+        let out = (*sd).data as *mut AVRegionOfInterest;
+        for (i, (region, qoffset)) in regions.iter().enumerate() {
+            out.add(i).write(AVRegionOfInterest {
+                self_size: size_of::<AVRegionOfInterest>() as u32,
+                top: region.y,
+                bottom: region.y + region.h,
+                left: region.x,
+                right: region.x + region.w,
+                qoffset: (*qoffset).into(),
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{cursor_region, damage_regions};
+    use crate::transform::Rect;
+
+    #[test]
+    fn region() {
+        let full = Rect::new((0, 0), (1920, 1080));
+        assert_eq!(
+            cursor_region((960, 540), (400, 200), full, (1920, 1080)),
+            Some(Rect::new((760, 440), (400, 200)))
+        );
+        // clipped at the edge of the screen
+        assert_eq!(
+            cursor_region((10, 1070), (400, 200), full, (1920, 1080)),
+            Some(Rect::new((0, 970), (210, 110)))
+        );
+        // scaled to the encode size
+        assert_eq!(
+            cursor_region((960, 540), (400, 200), full, (960, 540)),
+            Some(Rect::new((380, 220), (200, 100)))
+        );
+        // relative to a --geometry region
+        let roi = Rect::new((100, 100), (800, 600));
+        assert_eq!(
+            cursor_region((150, 150), (100, 100), roi, (800, 600)),
+            Some(Rect::new((0, 0), (100, 100)))
+        );
+        assert_eq!(
+            cursor_region((1500, 150), (100, 100), roi, (800, 600)),
+            None
+        );
+    }
+
+    #[test]
+    fn damage() {
+        let roi = Rect::new((100, 100), (800, 600));
+        let damage = [
+            Rect::new((0, 0), (50, 50)), // outside
+            Rect::new((150, 150), (100, 100)),
+            Rect::new((850, 650), (100, 100)), // clipped
+        ];
+        assert_eq!(
+            damage_regions(&damage, roi, (400, 300)),
+            vec![
+                Rect::new((25, 25), (50, 50)),
+                Rect::new((375, 275), (25, 25))
+            ]
+        );
+
+        let scattered: Vec<_> = (0..6)
+            .map(|i| Rect::new((100 + i * 100, 100 + i * 50), (10, 10)))
+            .collect();
+        assert_eq!(
+            damage_regions(&scattered, roi, (800, 600)),
+            vec![Rect::new((0, 0), (510, 260))]
+        );
+    }
+}
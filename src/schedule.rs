@@ -0,0 +1,186 @@
+use std::{
+    ffi::{CStr, CString},
+    mem,
+    num::ParseIntError,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicUsize, Ordering::SeqCst},
+        Arc,
+    },
+    thread::{self, sleep},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use log::info;
+use thiserror::Error;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct TimeOfDay {
+    hour: u32,
+    minute: u32,
+    second: u32,
+}
+
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum ParseTimeOfDayError {
+    #[error("invalid integer")]
+    Int(#[from] ParseIntError),
+    #[error("expected HH:MM or HH:MM:SS")]
+    Structure,
+    #[error("time out of range")]
+    Range,
+}
+
+impl FromStr for TimeOfDay {
+    type Err = ParseTimeOfDayError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        use ParseTimeOfDayError::*;
+        let mut it = s.split(':');
+        let hour = it.next().ok_or(Structure)?.parse()?;
+        let minute = it.next().ok_or(Structure)?.parse()?;
+        let second = it.next().map(str::parse).transpose()?.unwrap_or(0);
+        if it.next().is_some() {
+            return Err(Structure);
+        }
+        if hour > 23 || minute > 59 || second > 59 {
+            return Err(Range);
+        }
+
+        Ok(TimeOfDay {
+            hour,
+            minute,
+            second,
+        })
+    }
+}
+
+impl TimeOfDay {
+    // the first time strictly after `after` that the local clock reads this time of day
+    pub fn next_after(&self, after: SystemTime) -> SystemTime {
+        let after_secs = after.duration_since(UNIX_EPOCH).unwrap().as_secs() as libc::time_t;
+        unsafe {
+            let mut tm = mem::zeroed::<libc::tm>();
+            libc::localtime_r(&after_secs, &mut tm);
+            tm.tm_hour = self.hour as i32;
+            tm.tm_min = self.minute as i32;
+            tm.tm_sec = self.second as i32;
+            tm.tm_isdst = -1;
+
+            let mut t = libc::mktime(&mut tm);
+            if t <= after_secs {
+                // mktime normalizes the day overflow, and handles DST changes
+                tm.tm_mday += 1;
+                tm.tm_isdst = -1;
+                t = libc::mktime(&mut tm);
+            }
+            UNIX_EPOCH + Duration::from_secs(t as u64)
+        }
+    }
+}
+
+// --start-at/--stop-at
+pub struct Schedule {
+    start: Option<SystemTime>,
+    stop: Option<SystemTime>,
+}
+
+impl Schedule {
+    pub fn new(start_at: Option<TimeOfDay>, stop_at: Option<TimeOfDay>) -> Self {
+        let now = SystemTime::now();
+        let start = start_at.map(|s| s.next_after(now));
+        let stop = stop_at.map(|s| s.next_after(start.unwrap_or(now)));
+        Schedule { start, stop }
+    }
+
+    pub fn started(&self) -> bool {
+        self.start.is_none_or(|s| SystemTime::now() >= s)
+    }
+
+    // sets `quit_flag` to 0 (clean exit) at the stop time, and calls `wake` so the event loop
+    // notices even if no frames are arriving
+    pub fn spawn_stop_timer(&self, quit_flag: Arc<AtomicUsize>, wake: impl Fn() + Send + 'static) {
+        if let Some(start) = self.start {
+            info!("waiting until {} to start recording", format_local(start));
+        }
+        let Some(stop) = self.stop else {
+            return;
+        };
+        info!("recording will stop at {}", format_local(stop));
+
+        thread::Builder::new()
+            .name("stop-timer".to_owned())
+            .spawn(move || {
+                while let Ok(remaining) = stop.duration_since(SystemTime::now()) {
+                    // sleep in chunks so suspend/clock changes don't push the stop time out
+                    sleep(remaining.min(Duration::from_secs(1)));
+                }
+                info!("--stop-at time reached, stopping");
+                let _ = quit_flag.compare_exchange(usize::MAX, 0, SeqCst, SeqCst);
+                wake();
+            })
+            .unwrap();
+    }
+}
+
+// the earliest of `times` strictly after `after`, for --split-at
+pub fn next_boundary(times: &[TimeOfDay], after: SystemTime) -> Option<SystemTime> {
+    times.iter().map(|t| t.next_after(after)).min()
+}
+
+// formats `t` in local time with strftime(3)
+pub fn strftime_local(fmt: &str, t: SystemTime) -> String {
+    let secs = t.duration_since(UNIX_EPOCH).unwrap().as_secs() as libc::time_t;
+    let fmt = CString::new(fmt).unwrap();
+    let mut buf = [0u8; 1024];
+    unsafe {
+        let mut tm = mem::zeroed::<libc::tm>();
+        libc::localtime_r(&secs, &mut tm);
+        let len = libc::strftime(buf.as_mut_ptr().cast(), buf.len(), fmt.as_ptr(), &tm);
+        if len == 0 {
+            return String::new();
+        }
+        CStr::from_bytes_until_nul(&buf)
+            .unwrap()
+            .to_string_lossy()
+            .into_owned()
+    }
+}
+
+fn format_local(t: SystemTime) -> String {
+    strftime_local("%Y-%m-%d %H:%M:%S", t)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{ParseTimeOfDayError, TimeOfDay};
+
+    #[test]
+    fn parse() {
+        assert_eq!(
+            "14:00".parse(),
+            Ok(TimeOfDay {
+                hour: 14,
+                minute: 0,
+                second: 0
+            })
+        );
+        assert_eq!(
+            "09:30:15".parse(),
+            Ok(TimeOfDay {
+                hour: 9,
+                minute: 30,
+                second: 15
+            })
+        );
+        assert_eq!(
+            "14".parse::<TimeOfDay>(),
+            Err(ParseTimeOfDayError::Structure)
+        );
+        assert_eq!(
+            "24:00".parse::<TimeOfDay>(),
+            Err(ParseTimeOfDayError::Range)
+        );
+        assert!("1:2:3:4".parse::<TimeOfDay>().is_err());
+    }
+}
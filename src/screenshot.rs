@@ -0,0 +1,75 @@
+use std::{fs, path::PathBuf, thread};
+
+use anyhow::Context;
+use ffmpeg::{
+    codec, encoder, ffi::av_hwframe_transfer_data, format::Pixel, frame, software::scaling, Packet,
+};
+use log::warn;
+
+// `wl-screenrec ctl screenshot` and SIGUSR2 save a frame as it's being recorded, cropped, scaled
+// and with overlays, so stills can be grabbed without a second capture client. Only the download
+// happens on the calling thread, converting and compressing is done in the background
+
+pub fn encode_png(frame: &frame::Video) -> anyhow::Result<Vec<u8>> {
+    let (w, h) = (frame.width(), frame.height());
+    let mut rgb = frame::Video::empty();
+    scaling::Context::get(
+        frame.format(),
+        w,
+        h,
+        Pixel::RGB24,
+        w,
+        h,
+        scaling::Flags::BILINEAR,
+    )?
+    .run(frame, &mut rgb)?;
+
+    let codec = encoder::find(codec::Id::PNG).context("ffmpeg has no png encoder")?;
+    let mut enc = codec::Context::new_with_codec(codec).encoder().video()?;
+    enc.set_width(w);
+    enc.set_height(h);
+    enc.set_format(Pixel::RGB24);
+    enc.set_time_base((1, 1));
+    let mut enc = enc.open()?;
+
+    enc.send_frame(&rgb)?;
+    enc.send_eof()?;
+    let mut packet = Packet::empty();
+    enc.receive_packet(&mut packet)?;
+    Ok(packet.data().unwrap_or_default().to_vec())
+}
+
+// `frame` is what's about to be sent to the encoder, a vaapi surface or a frame in memory
+pub fn download(frame: &frame::Video) -> Result<frame::Video, ffmpeg::Error> {
+    if frame.format() != Pixel::VAAPI {
+        return Ok(frame.clone());
+    }
+    let mut downloaded = frame::Video::empty();
+    let sts = unsafe { av_hwframe_transfer_data(downloaded.as_mut_ptr(), frame.as_ptr(), 0) };
+    if sts != 0 {
+        return Err(ffmpeg::Error::from(sts));
+    }
+    Ok(downloaded)
+}
+
+pub fn save(frame: &frame::Video, path: PathBuf) {
+    let frame = match download(frame) {
+        Ok(frame) => frame,
+        Err(e) => {
+            warn!("failed to download frame for screenshot: {e}");
+            return;
+        }
+    };
+
+    let spawned = thread::Builder::new()
+        .name("screenshot".to_owned())
+        .spawn(
+            move || match encode_png(&frame).and_then(|png| Ok(fs::write(&path, png)?)) {
+                Ok(()) => eprintln!("saved screenshot to {}", path.display()),
+                Err(e) => warn!("failed to save screenshot to {}: {e:?}", path.display()),
+            },
+        );
+    if let Err(e) = spawned {
+        warn!("failed to start saving screenshot: {e}");
+    }
+}
@@ -0,0 +1,97 @@
+use std::{
+    env, process,
+    sync::{
+        atomic::{AtomicBool, Ordering::SeqCst},
+        Arc, Weak,
+    },
+    thread,
+    time::Duration,
+};
+
+use log::{debug, info, warn};
+use zbus::{
+    blocking::{Connection, Proxy},
+    zvariant::OwnedObjectPath,
+};
+
+// how long the lock screen is shown for with --on-lock slate
+pub const SLATE_DURATION: Duration = Duration::from_secs(1);
+
+const LOGIND: &str = "org.freedesktop.login1";
+
+// Wayland has no way for a regular client to observe the session being locked
+// (ext-session-lock is only for the locker itself), so this listens to logind instead. It sends
+// Lock and Unlock when something asks for the session to be locked (`loginctl lock-session`, which
+// is what idle daemons do), and screen lockers that are started some other way set LockedHint
+fn session_proxy() -> zbus::Result<Proxy<'static>> {
+    let conn = Connection::system()?;
+    let manager = Proxy::new(
+        &conn,
+        LOGIND,
+        "/org/freedesktop/login1",
+        "org.freedesktop.login1.Manager",
+    )?;
+    let path: OwnedObjectPath = match env::var("XDG_SESSION_ID") {
+        Ok(id) => manager.call("GetSession", &(id,))?,
+        Err(_) => manager.call("GetSessionByPID", &(process::id(),))?,
+    };
+    Proxy::new_owned(conn, LOGIND, path, "org.freedesktop.login1.Session")
+}
+
+fn set(flag: &Weak<AtomicBool>, locked: bool) -> bool {
+    let Some(flag) = flag.upgrade() else {
+        return false;
+    };
+    if flag.swap(locked, SeqCst) != locked {
+        info!("session {}", if locked { "locked" } else { "unlocked" });
+    }
+    true
+}
+
+fn watch(session: Proxy<'static>, flag: Weak<AtomicBool>) -> zbus::Result<()> {
+    let signals = session.receive_all_signals()?;
+    let hints = session.receive_property_changed::<bool>("LockedHint");
+    set(&flag, session.get_property("LockedHint")?);
+
+    let hint_flag = flag.clone();
+    thread::Builder::new()
+        .name("lock-hint-watcher".to_owned())
+        .spawn(move || {
+            for hint in hints {
+                match hint.get() {
+                    Ok(locked) if !set(&hint_flag, locked) => return,
+                    Ok(_) => {}
+                    Err(e) => debug!("failed to read LockedHint: {e}"),
+                }
+            }
+        })
+        .unwrap();
+
+    thread::Builder::new()
+        .name("lock-watcher".to_owned())
+        .spawn(move || {
+            for signal in signals {
+                let locked = match signal.header().member().map(|m| m.as_str()) {
+                    Some("Lock") => true,
+                    Some("Unlock") => false,
+                    _ => continue,
+                };
+                if !set(&flag, locked) {
+                    return;
+                }
+            }
+            warn!("lost connection to logind, --on-lock will have no effect");
+        })
+        .unwrap();
+    Ok(())
+}
+
+// follows logind's lock signals in the background, the returned flag is true while the session is
+// locked
+pub fn spawn_lock_watcher() -> Arc<AtomicBool> {
+    let flag = Arc::new(AtomicBool::new(false));
+    if let Err(e) = session_proxy().and_then(|session| watch(session, Arc::downgrade(&flag))) {
+        warn!("could not subscribe to logind's lock signals, --on-lock will have no effect: {e}");
+    }
+    flag
+}
@@ -0,0 +1,162 @@
+use std::{
+    ffi::{c_int, c_void},
+    process, ptr,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use ffmpeg::ffi::AVIOInterruptCB;
+
+// --shutdown-timeout. Finishing a recording can block forever, a network sink that stopped
+// accepting data blocks every write. Once the timeout passes, or Ctrl-C is pressed a second time,
+// output I/O is interrupted so what's left is dropped and the trailer is written if that's still
+// possible, and if even that doesn't return, the process exits without it
+
+// after the interrupt, how long the flush gets to notice it before exiting
+const EXIT_GRACE: Duration = Duration::from_secs(5);
+
+static ABORT_IO: AtomicBool = AtomicBool::new(false);
+
+unsafe extern "C" fn interrupt(_: *mut c_void) -> c_int {
+    c_int::from(ABORT_IO.load(Ordering::Relaxed))
+}
+
+// for the outputs' avio contexts, so blocking writes return AVERROR_EXIT once shutdown gives up
+pub fn interrupt_callback() -> AVIOInterruptCB {
+    AVIOInterruptCB {
+        callback: Some(interrupt),
+        opaque: ptr::null_mut(),
+    }
+}
+
+// the flush was given up on, writes are failing on purpose
+pub fn aborted() -> bool {
+    ABORT_IO.load(Ordering::Relaxed)
+}
+
+// another SIGINT while the recording is being finished, see `interrupted`. Only tells the
+// watchdog to say so and move on to exiting, the I/O is aborted by the signal handler itself
+static FORCE: AtomicBool = AtomicBool::new(false);
+
+// Ctrl-Cs after the first, never reset as the process is on its way out
+static REPEATED: AtomicUsize = AtomicUsize::new(0);
+
+// uploads are waited for after the watchdog is gone, so they have their own flag. Like FORCE it's
+// set by another SIGINT, but it stays set
+static ABORT_UPLOADS: AtomicBool = AtomicBool::new(false);
+
+// uploads still going stop and the ones queued are skipped, the files are kept
+pub fn uploads_aborted() -> bool {
+    ABORT_UPLOADS.load(Ordering::Relaxed)
+}
+
+// how often the watchdog checks for FORCE, the signal handler can't wake it
+const POLL: Duration = Duration::from_millis(100);
+
+// when finishing takes this long, say that Ctrl-C can be pressed again
+const HINT_AFTER: Duration = Duration::from_secs(1);
+
+// called from the SIGINT handler, so it only touches atomics. `quit_flag` is set if this is the
+// first request to stop. The second Ctrl-C cuts the flush short like after --shutdown-timeout,
+// without waiting on the watchdog's thread, and gives up on uploads. The third exits right away
+pub fn interrupted(quit_flag: &AtomicUsize) {
+    if quit_flag
+        .compare_exchange(usize::MAX, 0, Ordering::SeqCst, Ordering::SeqCst)
+        .is_ok()
+    {
+        return;
+    }
+    if REPEATED.fetch_add(1, Ordering::SeqCst) == 0 {
+        ABORT_IO.store(true, Ordering::SeqCst);
+        ABORT_UPLOADS.store(true, Ordering::SeqCst);
+        FORCE.store(true, Ordering::SeqCst);
+    } else {
+        let code = match quit_flag.load(Ordering::SeqCst) {
+            0 => 1,
+            code => code as c_int,
+        };
+        unsafe { libc::_exit(code) }
+    }
+}
+
+enum Wake {
+    Done,
+    Timeout,
+    Forced,
+}
+
+fn wait(done: &AtomicBool, d: Duration) -> Wake {
+    let until = Instant::now() + d;
+    loop {
+        if done.load(Ordering::SeqCst) {
+            return Wake::Done;
+        }
+        if FORCE.swap(false, Ordering::SeqCst) {
+            return Wake::Forced;
+        }
+        let Some(left) = until.checked_duration_since(Instant::now()) else {
+            return Wake::Timeout;
+        };
+        thread::park_timeout(left.min(POLL));
+    }
+}
+
+// aborts output and then exits unless it's dropped (the recording was finished) first
+pub struct Watchdog {
+    done: Arc<AtomicBool>,
+    thread: thread::Thread,
+}
+
+impl Watchdog {
+    pub fn start(timeout: Duration, filename: &str, exit_code: i32) -> Watchdog {
+        let done = Arc::new(AtomicBool::new(false));
+        let done2 = Arc::clone(&done);
+        let filename = filename.to_owned();
+        let exit_code = if exit_code == 0 { 1 } else { exit_code };
+        let handle = thread::Builder::new()
+            .name("shutdown-watchdog".to_owned())
+            .spawn(move || {
+                let started = Instant::now();
+                let mut wake = wait(&done2, HINT_AFTER.min(timeout));
+                if matches!(wake, Wake::Timeout) && started.elapsed() < timeout {
+                    eprintln!("finishing {filename}, press Ctrl-C again to stop without waiting");
+                    wake = wait(&done2, timeout.saturating_sub(started.elapsed()));
+                }
+                match wake {
+                    Wake::Done => return,
+                    Wake::Timeout => eprintln!(
+                        "finishing {filename} is taking longer than {timeout:?}, dropping what hasn't been written yet"
+                    ),
+                    Wake::Forced => eprintln!(
+                        "interrupted again, dropping what hasn't been written to {filename} yet. Ctrl-C once more to exit immediately"
+                    ),
+                }
+                ABORT_IO.store(true, Ordering::SeqCst);
+                match wait(&done2, EXIT_GRACE) {
+                    Wake::Done => return,
+                    Wake::Timeout => eprintln!("still stuck, exiting without finishing {filename}"),
+                    Wake::Forced => eprintln!("exiting without finishing {filename}"),
+                }
+                process::exit(exit_code);
+            })
+            .unwrap();
+        Watchdog {
+            done,
+            thread: handle.thread().clone(),
+        }
+    }
+}
+
+impl Drop for Watchdog {
+    fn drop(&mut self) {
+        self.done.store(true, Ordering::SeqCst);
+        self.thread.unpark();
+        // with --reconnect, the next file is written normally
+        ABORT_IO.store(false, Ordering::SeqCst);
+        FORCE.store(false, Ordering::SeqCst);
+    }
+}
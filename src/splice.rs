@@ -0,0 +1,238 @@
+use std::{str::FromStr, time::Duration};
+
+use anyhow::bail;
+use ffmpeg::{codec, dict, ffi::AVMediaType, format, packet, Packet, Rational};
+
+use crate::parse_duration_with_unit;
+
+// --splice-points: cues for downstream tools to cut a live stream at, in a timed ID3 metadata
+// stream, which is how mpegts (and so hls) carries metadata. `splice out` and `splice in` are
+// SCTE-35 splice_insert commands in a PRIV frame, `splice text` is a TXXX frame. Cues go on the
+// next frame to be encoded, which is made a keyframe so the stream can be cut right there
+
+// the scheme id binary SCTE-35 is carried with in DASH, used as the PRIV frame's owner
+const SCTE35_OWNER: &str = "urn:scte:scte35:2013:bin";
+const TEXT_DESCRIPTION: &str = "splice";
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum SpliceCue {
+    Out(Option<Duration>), // to a break, of this length if it's known
+    In,                    // back from the break
+    Text(String),
+}
+
+impl FromStr for SpliceCue {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (kind, rest) = s.split_once(' ').unwrap_or((s, ""));
+        let rest = rest.trim();
+        match kind {
+            "out" if rest.is_empty() => Ok(SpliceCue::Out(None)),
+            "out" => parse_duration_with_unit(rest).map(|d| SpliceCue::Out(Some(d))),
+            "in" if rest.is_empty() => Ok(SpliceCue::In),
+            "text" if !rest.is_empty() => Ok(SpliceCue::Text(rest.to_owned())),
+            _ => Err("expected out [DURATION], in or text <TEXT>".to_owned()),
+        }
+    }
+}
+
+pub struct SpliceTrack {
+    idx: usize,
+    event_id: u32, // of the last splice out, splice in returns from it
+    pending: Vec<SpliceCue>,
+}
+
+impl SpliceTrack {
+    // has to be created before the header is written
+    pub fn new(octx: &mut format::context::Output) -> anyhow::Result<Self> {
+        let format_name = octx.format().name().to_owned();
+        if !matches!(&format_name[..], "mpegts" | "hls") {
+            bail!("{format_name} can't hold --splice-points, use a .ts or .m3u8 output")
+        }
+
+        let mut st = octx.add_stream(None::<ffmpeg::Codec>)?;
+        unsafe {
+            let par = (*st.as_mut_ptr()).codecpar;
+            (*par).codec_type = AVMediaType::AVMEDIA_TYPE_DATA;
+            (*par).codec_id = codec::Id::TIMED_ID3.into();
+        }
+        st.set_time_base(Rational(1, 90_000));
+        st.set_metadata(dict! { "title" => "splice points" });
+
+        Ok(SpliceTrack {
+            idx: st.index(),
+            event_id: 0,
+            pending: Vec::new(),
+        })
+    }
+
+    pub fn push(&mut self, cue: SpliceCue) {
+        self.pending.push(cue);
+    }
+
+    pub fn has_pending(&self) -> bool {
+        !self.pending.is_empty()
+    }
+
+    // the cues pushed since the last call, as one ID3 tag at pts_ns. In the stream's time base like
+    // the packets coming out of the encoders
+    pub fn packet(&mut self, octx: &format::context::Output, pts_ns: i64) -> Packet {
+        let mut frames = Vec::new();
+        for cue in self.pending.drain(..) {
+            match cue {
+                SpliceCue::Out(duration) => {
+                    self.event_id = self.event_id.wrapping_add(1);
+                    frames.push(priv_frame(&splice_insert(self.event_id, true, duration)));
+                }
+                SpliceCue::In => {
+                    frames.push(priv_frame(&splice_insert(self.event_id, false, None)))
+                }
+                SpliceCue::Text(text) => frames.push(txxx_frame(&text)),
+            }
+        }
+
+        let time_base = octx.stream(self.idx).unwrap().time_base();
+        let mut packet = Packet::copy(&id3_tag(&frames));
+        packet.set_stream(self.idx);
+        packet.set_pts(Some(pts_ns));
+        packet.set_dts(Some(pts_ns));
+        packet.rescale_ts(Rational(1, 1_000_000_000), time_base);
+        // every cue stands alone, which --history relies on to trim old ones
+        packet.set_flags(packet::Flags::KEY);
+        packet
+    }
+}
+
+// a splice_info_section holding a splice_insert for the whole program, immediately (where the
+// ID3 tag is)
+fn splice_insert(event_id: u32, out_of_network: bool, duration: Option<Duration>) -> Vec<u8> {
+    let mut command = event_id.to_be_bytes().to_vec();
+    command.push(0x7f); // not cancelled
+    command.push(
+        u8::from(out_of_network) << 7
+            | 1 << 6 // program_splice_flag
+            | u8::from(duration.is_some()) << 5
+            | 1 << 4 // splice_immediate_flag
+            | 0x0f,
+    );
+    if let Some(duration) = duration {
+        let ticks = (duration.as_secs_f64() * 90_000.).round() as u64 & ((1 << 33) - 1);
+        command.push(0x80 | 0x7e | (ticks >> 32) as u8); // auto_return
+        command.extend_from_slice(&(ticks as u32).to_be_bytes());
+    }
+    command.extend_from_slice(&[0, 0, 0, 0]); // unique_program_id, avail_num, avails_expected
+
+    // everything after section_length, the CRC included
+    let section_length = 17 + command.len();
+    let mut section = vec![
+        0xfc,
+        0x30 | (section_length >> 8) as u8, // sap_type 3, not specified
+        section_length as u8,
+        0, // protocol_version
+        0, // not encrypted, pts_adjustment 0
+        0,
+        0,
+        0,
+        0,
+        0,    // cw_index
+        0xff, // tier 0xfff, all tiers
+        0xf0 | (command.len() >> 8) as u8,
+        command.len() as u8,
+        0x05, // splice_insert
+    ];
+    section.extend_from_slice(&command);
+    section.extend_from_slice(&[0, 0]); // no descriptors
+    let crc = crc32(&section);
+    section.extend_from_slice(&crc.to_be_bytes());
+    section
+}
+
+// the MPEG-2 CRC sections end with
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xffff_ffffu32;
+    for b in data {
+        crc ^= u32::from(*b) << 24;
+        for _ in 0..8 {
+            crc = if crc & 0x8000_0000 != 0 {
+                (crc << 1) ^ 0x04c1_1db7
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
+
+fn priv_frame(data: &[u8]) -> ([u8; 4], Vec<u8>) {
+    let mut body = SCTE35_OWNER.as_bytes().to_vec();
+    body.push(0);
+    body.extend_from_slice(data);
+    (*b"PRIV", body)
+}
+
+fn txxx_frame(text: &str) -> ([u8; 4], Vec<u8>) {
+    let mut body = vec![3]; // utf-8
+    body.extend_from_slice(TEXT_DESCRIPTION.as_bytes());
+    body.push(0);
+    body.extend_from_slice(text.as_bytes());
+    (*b"TXXX", body)
+}
+
+// an ID3v2.4 tag, which is what each timed ID3 packet is
+fn id3_tag(frames: &[([u8; 4], Vec<u8>)]) -> Vec<u8> {
+    let mut body = Vec::new();
+    for (id, data) in frames {
+        body.extend_from_slice(id);
+        body.extend_from_slice(&syncsafe(data.len()));
+        body.extend_from_slice(&[0, 0]); // flags
+        body.extend_from_slice(data);
+    }
+    let mut tag = b"ID3\x04\x00\x00".to_vec();
+    tag.extend_from_slice(&syncsafe(body.len()));
+    tag.extend_from_slice(&body);
+    tag
+}
+
+// 7 bits per byte
+fn syncsafe(n: usize) -> [u8; 4] {
+    [21, 14, 7, 0].map(|shift| (n >> shift) as u8 & 0x7f)
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use super::{crc32, id3_tag, splice_insert, syncsafe, txxx_frame};
+
+    #[test]
+    fn sections() {
+        // the splice_insert example from SCTE-35, without its CRC
+        let example = [
+            0xfc, 0x30, 0x2f, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xff, 0xff, 0xf0, 0x14, 0x05,
+            0x48, 0x00, 0x00, 0x8f, 0x7f, 0xef, 0xfe, 0x73, 0x69, 0xc0, 0x2e, 0xfe, 0x00, 0x52,
+            0xcc, 0xf5, 0x00, 0x00, 0x00, 0x00, 0x00, 0x0a, 0x00, 0x08, 0x43, 0x55, 0x45, 0x49,
+            0x00, 0x00, 0x01, 0x35,
+        ];
+        assert_eq!(crc32(&example), 0x62dba30a);
+
+        let out = splice_insert(7, true, Some(Duration::from_secs(30)));
+        assert_eq!(out.len(), 3 + out[2] as usize);
+        assert_eq!(
+            out[..out.len() - 4],
+            [
+                0xfc, 0x30, 0x20, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xff, 0xf0, 0x0f, 0x05,
+                0x00, 0x00, 0x00, 0x07, 0x7f, 0xff, 0xfe, 0x00, 0x29, 0x32, 0xe0, 0x00, 0x00, 0x00,
+                0x00, 0x00, 0x00
+            ][..]
+        );
+        // a section's CRC covers it to a remainder of 0
+        assert_eq!(crc32(&out), 0);
+
+        assert_eq!(syncsafe(300), [0, 0, 2, 44]);
+        assert_eq!(
+            id3_tag(&[txxx_frame("ad")]),
+            b"ID3\x04\x00\x00\x00\x00\x00\x14TXXX\x00\x00\x00\x0a\x00\x00\x03splice\x00ad"
+        );
+    }
+}
@@ -0,0 +1,143 @@
+use std::time::{Duration, Instant};
+
+use log::{debug, log_enabled, warn, Level};
+
+const REPORT_INTERVAL: Duration = Duration::from_secs(1);
+
+// encoding is synchronous and the next frame is only captured once it's done, so a frame that
+// takes longer than the frame interval to filter and encode makes the next refresh get missed.
+// The encoder can't keep up once this many frames in a row were late
+const BACKPRESSURE_FRAMES: u64 = 8;
+const BACKPRESSURE_WARN_INTERVAL: Duration = Duration::from_secs(30);
+
+// Counters for each stage of the capture -> filter -> encode pipeline, so it's
+// possible to tell which stage is falling behind when performance is bad
+pub struct PipelineStats {
+    pub surfaces_allocd: u64,
+    pub copies_queued: u64,
+    pub copies_completed: u64,
+    pub copies_failed: u64,
+    pub frames_into_filter: u64,
+    pub frames_into_encoder: u64,
+    pub packets_from_encoder: u64,
+    pub frames_late: u64, // frames that took longer than the frame interval to encode
+    late_streak: u64,
+    last_backpressure_warning: Option<Instant>,
+    last_report: Instant,
+}
+
+impl PipelineStats {
+    pub fn new() -> Self {
+        PipelineStats {
+            surfaces_allocd: 0,
+            copies_queued: 0,
+            copies_completed: 0,
+            copies_failed: 0,
+            frames_into_filter: 0,
+            frames_into_encoder: 0,
+            packets_from_encoder: 0,
+            frames_late: 0,
+            late_streak: 0,
+            last_backpressure_warning: None,
+            last_report: Instant::now(),
+        }
+    }
+
+    pub fn copies_in_flight(&self) -> u64 {
+        self.copies_queued
+            .saturating_sub(self.copies_completed + self.copies_failed)
+    }
+
+    pub fn filter_queue_depth(&self) -> u64 {
+        self.frames_into_filter
+            .saturating_sub(self.frames_into_encoder)
+    }
+
+    pub fn encoder_lag(&self) -> u64 {
+        self.frames_into_encoder
+            .saturating_sub(self.packets_from_encoder)
+    }
+
+    // called after each frame went through the filter and the encoder, which `took` that long.
+    // Warns at most once every BACKPRESSURE_WARN_INTERVAL while it can't keep up. A zero
+    // `interval` is unknown
+    pub fn frame_encoded(&mut self, took: Duration, interval: Duration) {
+        if interval.is_zero() || took <= interval {
+            self.late_streak = 0;
+            return;
+        }
+        self.frames_late += 1;
+        self.late_streak += 1;
+        if self.late_streak < BACKPRESSURE_FRAMES
+            || self
+                .last_backpressure_warning
+                .is_some_and(|t| t.elapsed() < BACKPRESSURE_WARN_INTERVAL)
+        {
+            return;
+        }
+        self.last_backpressure_warning = Some(Instant::now());
+        warn!(
+            "the encoder can't keep up, frames take {}ms to encode but come every {}ms, so the recording will stutter. Try a lower --max-fps, a smaller --encode-resolution, or a faster --codec",
+            took.as_millis(),
+            interval.as_millis()
+        );
+    }
+
+    // for the end of the recording
+    pub fn backpressure_summary(&self) -> Option<String> {
+        (self.frames_late > 0).then(|| {
+            format!(
+                "{} of {} frames took longer than the frame interval to encode, and the next one was captured late",
+                self.frames_late, self.frames_into_encoder
+            )
+        })
+    }
+
+    // called from the encode path, logs at most once every REPORT_INTERVAL
+    pub fn maybe_report(&mut self) {
+        if !log_enabled!(Level::Debug) || self.last_report.elapsed() < REPORT_INTERVAL {
+            return;
+        }
+        self.last_report = Instant::now();
+
+        debug!(
+            "pipeline: {} capture surfaces allocated, {} copies in flight ({} failed total), filter queue depth {}, encoder lag {} frames ({} submitted, {} packets)",
+            self.surfaces_allocd,
+            self.copies_in_flight(),
+            self.copies_failed,
+            self.filter_queue_depth(),
+            self.encoder_lag(),
+            self.frames_into_encoder,
+            self.packets_from_encoder,
+        );
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use super::{PipelineStats, BACKPRESSURE_FRAMES};
+
+    #[test]
+    fn backpressure_when_late_in_a_row() {
+        let mut stats = PipelineStats::new();
+        let interval = Duration::from_millis(16);
+        let slow = Duration::from_millis(20);
+        stats.frame_encoded(Duration::from_millis(5), interval);
+        assert_eq!(stats.backpressure_summary(), None);
+
+        // the odd slow frame doesn't warn
+        stats.frame_encoded(slow, interval);
+        stats.frame_encoded(Duration::from_millis(5), interval);
+        assert_eq!(stats.frames_late, 1);
+        assert!(stats.last_backpressure_warning.is_none());
+
+        for _ in 0..BACKPRESSURE_FRAMES {
+            stats.frame_encoded(slow, interval);
+        }
+        assert_eq!(stats.frames_late, BACKPRESSURE_FRAMES + 1);
+        assert!(stats.last_backpressure_warning.is_some());
+        assert!(stats.backpressure_summary().is_some());
+    }
+}
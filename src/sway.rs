@@ -0,0 +1,197 @@
+use std::{
+    env,
+    io::{self, Read, Write},
+    os::{fd::AsRawFd, unix::net::UnixStream},
+    process,
+    sync::mpsc::Receiver,
+};
+
+use anyhow::{bail, format_err};
+use log::debug;
+use serde_json::Value;
+
+use crate::window::{self, find_sway, WindowGeometry};
+
+// message types of the i3/sway IPC protocol, see sway-ipc(7)
+const RUN_COMMAND: u32 = 0;
+const GET_WORKSPACES: u32 = 1;
+const SUBSCRIBE: u32 = 2;
+const GET_OUTPUTS: u32 = 3;
+const GET_TREE: u32 = 4;
+const EVENT: u32 = 1 << 31;
+
+const MAGIC: &[u8] = b"i3-ipc";
+
+// what moves or resizes windows. binding catches resize commands from key bindings, output
+// catches outputs being moved or rescaled
+pub const WINDOW_EVENTS: &str = r#"["window", "workspace", "binding", "output"]"#;
+
+// dragging a window or its border with the mouse sends no event at all, only that needs this. The
+// tree is checked this often while nothing else happens, so a dragged window is followed within
+// half a second
+pub const RECHECK_MS: i32 = 500;
+
+pub struct SwayIpc(UnixStream);
+
+impl SwayIpc {
+    pub fn connect() -> anyhow::Result<Self> {
+        let path = env::var_os("SWAYSOCK").ok_or_else(|| format_err!("SWAYSOCK is not set"))?;
+        Ok(SwayIpc(UnixStream::connect(path)?))
+    }
+
+    fn send(&mut self, ty: u32, payload: &str) -> io::Result<()> {
+        let mut msg = MAGIC.to_vec();
+        msg.extend((payload.len() as u32).to_ne_bytes());
+        msg.extend(ty.to_ne_bytes());
+        msg.extend(payload.as_bytes());
+        self.0.write_all(&msg)
+    }
+
+    // the next message, which is a reply to the last request or an event
+    fn recv(&mut self) -> anyhow::Result<(u32, Value)> {
+        let mut header = [0; 14];
+        self.0.read_exact(&mut header)?;
+        if &header[..6] != MAGIC {
+            bail!("invalid sway ipc message");
+        }
+        let len = u32::from_ne_bytes(header[6..10].try_into().unwrap());
+        let ty = u32::from_ne_bytes(header[10..14].try_into().unwrap());
+        let mut payload = vec![0; len as usize];
+        self.0.read_exact(&mut payload)?;
+        Ok((ty, serde_json::from_slice(&payload)?))
+    }
+
+    fn request(&mut self, ty: u32, payload: &str) -> anyhow::Result<Value> {
+        self.send(ty, payload)?;
+        let (_, reply) = self.recv()?;
+        Ok(reply)
+    }
+
+    pub fn tree(&mut self) -> anyhow::Result<Value> {
+        self.request(GET_TREE, "")
+    }
+
+    pub fn outputs(&mut self) -> anyhow::Result<Value> {
+        self.request(GET_OUTPUTS, "")
+    }
+
+    pub fn workspaces(&mut self) -> anyhow::Result<Value> {
+        self.request(GET_WORKSPACES, "")
+    }
+
+    // `events` is a JSON list like `["window"]`. Afterwards this connection only receives events,
+    // requests go on another one
+    pub fn subscribe(&mut self, events: &str) -> anyhow::Result<()> {
+        let reply = self.request(SUBSCRIBE, events)?;
+        if reply["success"].as_bool() != Some(true) {
+            bail!("failed to subscribe to sway events: {reply}");
+        }
+        Ok(())
+    }
+
+    // waits up to `timeout_ms` for an event, forever if it's negative
+    pub fn wait_event(&mut self, timeout_ms: i32) -> anyhow::Result<()> {
+        let mut fd = libc::pollfd {
+            fd: self.0.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        if unsafe { libc::poll(&mut fd, 1, timeout_ms) } <= 0 {
+            return Ok(());
+        }
+        let (ty, event) = self.recv()?;
+        if ty & EVENT != 0 {
+            debug!("sway event {}: {}", ty & !EVENT, event["change"]);
+        }
+        Ok(())
+    }
+}
+
+// sway can match windows by criteria, but not report which window matched. The window is marked
+// instead, and found again by its mark. Marks starting with an underscore aren't shown in title
+// bars
+fn mark() -> String {
+    format!("_wl-screenrec-{}", process::id())
+}
+
+fn has_mark(node: &Value, mark: &str) -> bool {
+    node["marks"]
+        .as_array()
+        .is_some_and(|marks| marks.iter().any(|m| m.as_str() == Some(mark)))
+}
+
+// removes the mark `window_geometry` left, which would otherwise stay on the window after the
+// recording. exit doesn't run destructors, so this is called before it
+pub fn unmark() {
+    let unmarked = SwayIpc::connect()
+        .and_then(|mut ipc| ipc.request(RUN_COMMAND, &format!("unmark {}", mark())));
+    if let Err(e) = unmarked {
+        debug!("failed to remove the sway mark: {e}");
+    }
+}
+
+// the window matching `criteria`, like `[app_id="foot" title="vim"]` (the brackets are optional).
+// Once a window has been picked it is kept until it closes, even if others match too
+pub fn window_geometry(
+    ipc: &mut SwayIpc,
+    criteria: &str,
+) -> anyhow::Result<Option<WindowGeometry>> {
+    let mark = mark();
+    let matches = |n: &Value| has_mark(n, &mark) && n["visible"].as_bool() != Some(false);
+
+    let tree = ipc.tree()?;
+    if let Some(window) = find_sway(&tree, None, &matches) {
+        return Ok(Some(window));
+    }
+    if find_sway(&tree, None, &|n: &Value| has_mark(n, &mark)).is_some() {
+        return Ok(None); // on a workspace that isn't visible
+    }
+
+    let criteria = criteria.trim();
+    let criteria = if criteria.starts_with('[') {
+        criteria.to_owned()
+    } else {
+        format!("[{criteria}]")
+    };
+    let reply = ipc.request(RUN_COMMAND, &format!("{criteria} mark --add {mark}"))?;
+    if reply[0]["success"].as_bool() != Some(true) {
+        debug!("no window matches {criteria}: {}", reply[0]["error"]);
+        return Ok(None);
+    }
+    let tree = ipc.tree()?;
+    Ok(find_sway(&tree, None, &matches))
+}
+
+// follows the window matching `criteria`, sending its geometry whenever it moves or resizes.
+// `wake` is called after each change so the event loop notices
+pub fn spawn_window_watcher(
+    criteria: String,
+    last: Option<WindowGeometry>,
+    wake: impl Fn() + Send + 'static,
+) -> anyhow::Result<Receiver<WindowGeometry>> {
+    let mut ipc = SwayIpc::connect()?;
+    let mut events = SwayIpc::connect()?;
+    events.subscribe(WINDOW_EVENTS)?;
+    Ok(window::watch(
+        criteria.clone(),
+        last,
+        wake,
+        move || window_geometry(&mut ipc, &criteria),
+        move || events.wait_event(RECHECK_MS),
+    ))
+}
+
+#[cfg(test)]
+mod test {
+    use serde_json::json;
+
+    use super::has_mark;
+
+    #[test]
+    fn marks() {
+        let node = json!({"marks": ["a", "_wl-screenrec-1"]});
+        assert!(has_mark(&node, "_wl-screenrec-1"));
+        assert!(!has_mark(&node, "_wl-screenrec-2"));
+        assert!(!has_mark(&json!({}), "a"));
+    }
+}
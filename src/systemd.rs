@@ -0,0 +1,94 @@
+use std::{
+    env,
+    os::unix::net::{SocketAddr, UnixDatagram},
+    time::{Duration, Instant},
+};
+
+use log::{debug, warn};
+
+const STATUS_INTERVAL: Duration = Duration::from_secs(1);
+
+// Minimal implementation of sd_notify(3), so wl-screenrec can be run as a
+// Type=notify service without linking libsystemd. Does nothing if NOTIFY_SOCKET
+// is not set (i.e. not running under systemd)
+pub struct SdNotify {
+    sock: Option<(UnixDatagram, SocketAddr)>,
+    sent_ready: bool,
+    last_status: Option<Instant>,
+}
+
+impl SdNotify {
+    pub fn from_env() -> Self {
+        let sock = env::var_os("NOTIFY_SOCKET").and_then(|path| {
+            let path = path.to_string_lossy().into_owned();
+            match Self::connect(&path) {
+                Ok(s) => Some(s),
+                Err(e) => {
+                    warn!("NOTIFY_SOCKET is set to {path}, but failed to open it: {e}");
+                    None
+                }
+            }
+        });
+
+        SdNotify {
+            sock,
+            sent_ready: false,
+            last_status: None,
+        }
+    }
+
+    fn connect(path: &str) -> std::io::Result<(UnixDatagram, SocketAddr)> {
+        let addr = if let Some(abstract_name) = path.strip_prefix('@') {
+            #[cfg(target_os = "linux")]
+            {
+                use std::os::linux::net::SocketAddrExt;
+                SocketAddr::from_abstract_name(abstract_name)?
+            }
+            #[cfg(not(target_os = "linux"))]
+            {
+                let _ = abstract_name;
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::Unsupported,
+                    "abstract sockets are only supported on linux",
+                ));
+            }
+        } else {
+            SocketAddr::from_pathname(path)?
+        };
+        Ok((UnixDatagram::unbound()?, addr))
+    }
+
+    fn notify(&self, msg: &str) {
+        if let Some((sock, addr)) = &self.sock {
+            debug!("sd_notify: {msg}");
+            if let Err(e) = sock.send_to_addr(msg.as_bytes(), addr) {
+                warn!("failed to notify systemd ({msg}): {e}");
+            }
+        }
+    }
+
+    // call once the first frame has made it through the encoder
+    pub fn ready(&mut self) {
+        if !self.sent_ready {
+            self.sent_ready = true;
+            self.notify("READY=1");
+        }
+    }
+
+    // rate limited, so it's fine to call this for every packet
+    pub fn status(&mut self, status: impl FnOnce() -> String) {
+        if self.sock.is_none()
+            || self
+                .last_status
+                .is_some_and(|l| l.elapsed() < STATUS_INTERVAL)
+        {
+            return;
+        }
+        self.last_status = Some(Instant::now());
+        self.notify(&format!("STATUS={}", status()));
+    }
+
+    pub fn stopping(&mut self) {
+        self.notify("STOPPING=1");
+    }
+}
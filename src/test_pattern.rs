@@ -0,0 +1,171 @@
+// --capture-backend test-pattern: encodes synthesized frames instead of capturing a compositor,
+// so the encode, mux and history logic can be run without a Wayland session and benchmarked with
+// the same input every time. The frames still go through vaapi like captured ones, so a render
+// node is needed
+
+use std::{
+    path::Path,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::bail;
+use drm::buffer::DrmFourcc;
+use ffmpeg::{ffi::av_hwframe_transfer_data, filter, frame, Rational};
+use log::{info, warn};
+use wayland_client::protocol::wl_output::Transform;
+
+use crate::{
+    composite::Composite, exclude::Exclusions, metrics::Metrics, transform::Rect, Args,
+    DmabufFormat, DrmModifier, EncState,
+};
+
+// the output being pretended to be recorded. --geometry is relative to it
+const SIZE: (i32, i32) = (1920, 1080);
+const FPS: i32 = 60;
+
+fn pattern(spec: &str) -> Result<filter::Graph, ffmpeg::Error> {
+    let mut g = filter::Graph::new();
+    g.add(&filter::find("buffersink").unwrap(), "out", "")?;
+    g.input("out", 0)?.parse(spec)?;
+    g.validate()?;
+    Ok(g)
+}
+
+// SMPTE bars with the time burnt in, or ffmpeg's own moving test source if it was built without
+// drawtext (or has no fonts)
+fn pattern_graph() -> anyhow::Result<filter::Graph> {
+    let (w, h) = SIZE;
+    let bars = format!(
+        "smptehdbars=size={w}x{h}:rate={FPS},drawtext=text='%{{pts\\:hms}}':fontsize={}:fontcolor=white:box=1:boxcolor=black:x=(w-tw)/2:y=h/8,format=bgr0",
+        h / 10
+    );
+    match pattern(&bars) {
+        Ok(g) => Ok(g),
+        Err(e) => {
+            warn!("failed to set up the SMPTE bars test pattern ({e}), using testsrc2 instead");
+            Ok(pattern(&format!(
+                "testsrc2=size={w}x{h}:rate={FPS},format=bgr0"
+            ))?)
+        }
+    }
+}
+
+fn record(
+    args: &Args,
+    quit_flag: &AtomicUsize,
+    sigusr1_flag: Arc<AtomicBool>,
+) -> anyhow::Result<()> {
+    let mut regions = Vec::new();
+    for geometry in &args.geometry {
+        let (x, y, w, h) = geometry.resolve((0, 0), SIZE);
+        let region = Rect::new((x, y), (w as i32, h as i32));
+        if region.fit_inside_bounds(SIZE.0, SIZE.1) != region {
+            bail!(
+                "--geometry {geometry} is not within the {}x{} test pattern",
+                SIZE.0,
+                SIZE.1
+            );
+        }
+        regions.push(region);
+    }
+    let (roi, composite) = match regions[..] {
+        [] => (Rect::new((0, 0), SIZE), None),
+        [roi] => (roi, None),
+        _ => {
+            let composite = Composite {
+                regions,
+                layout: args.layout,
+                others: Vec::new(),
+                zoom: 1.,
+            };
+            (composite.bounds(), Some(composite))
+        }
+    };
+
+    let mut enc = EncState::new(
+        args,
+        DmabufFormat {
+            width: SIZE.0,
+            height: SIZE.1,
+            fourcc: DrmFourcc::Xrgb8888,
+            modifier: DrmModifier::LINEAR,
+        },
+        Rational(FPS, 1),
+        Transform::Normal,
+        roi,
+        composite,
+        Exclusions {
+            regions: args
+                .exclude_region
+                .iter()
+                .map(|g| {
+                    let (x, y, w, h) = g.resolve((0, 0), SIZE);
+                    Rect::new((x, y), (w as i32, h as i32))
+                })
+                .collect(),
+            style: args.exclude_style,
+        },
+        sigusr1_flag,
+        Arc::new(Metrics::default()),
+        Path::new(args.dri_device.as_deref().unwrap_or("/dev/dri/renderD128")),
+    )?;
+    let mut pattern = pattern_graph()?;
+    info!("{}", pattern.dump());
+
+    if let Some(audio) = &mut enc.audio {
+        audio.start();
+    }
+
+    let start = Instant::now();
+    let mut sw_frame = frame::Video::empty();
+    for n in 0.. {
+        if quit_flag.load(Ordering::SeqCst) != usize::MAX {
+            break;
+        }
+
+        pattern.get("out").unwrap().sink().frame(&mut sw_frame)?;
+        let mut surf = enc.frames_rgb.alloc()?;
+        let sts = unsafe { av_hwframe_transfer_data(surf.as_mut_ptr(), sw_frame.as_ptr(), 0) };
+        if sts != 0 {
+            bail!(
+                "failed to upload test pattern: {}",
+                ffmpeg::Error::from(sts)
+            );
+        }
+
+        let pts = n * 1_000_000_000 / i64::from(FPS);
+        if let Some((prev_pts, duration)) = enc.frame_timing.advance(pts)? {
+            enc.on_frame_duration(prev_pts, duration);
+        }
+        surf.set_pts(Some(pts));
+        unsafe {
+            (*surf.as_mut_ptr()).time_base.num = 1;
+            (*surf.as_mut_ptr()).time_base.den = 1_000_000_000;
+        }
+        enc.push(surf);
+        enc.recover_encoder(args)?;
+
+        // as fast as a compositor would deliver them
+        let next = start + Duration::from_nanos((pts + 1_000_000_000 / i64::from(FPS)) as u64);
+        thread::sleep(next.saturating_duration_since(Instant::now()));
+    }
+
+    enc.flush();
+    Ok(())
+}
+
+// records until `quit_flag` is set, returns the exit code
+pub fn run(args: &Args, quit_flag: Arc<AtomicUsize>, sigusr1_flag: Arc<AtomicBool>) -> i32 {
+    match record(args, &quit_flag, sigusr1_flag) {
+        Ok(()) => quit_flag.load(Ordering::SeqCst) as i32,
+        Err(e) => {
+            eprintln!("{e:?}");
+            1
+        }
+    }
+}
@@ -0,0 +1,261 @@
+use std::{
+    env,
+    ffi::{CString, OsStr},
+    fs,
+    io::{self, Read},
+    mem::size_of,
+    os::{fd::FromRawFd, unix::ffi::OsStrExt},
+    path::{Path, PathBuf},
+    process,
+    sync::{
+        atomic::{AtomicBool, AtomicU32, Ordering},
+        Arc, Weak,
+    },
+    thread,
+};
+
+use ffmpeg::{filter, format::Pixel, frame};
+use log::{debug, info, warn};
+use log_once::warn_once;
+
+use crate::{avhw::AvHwFrameCtx, overlay_surface::OverlaySurface};
+
+// --overlay-text FILE: FILE's contents are drawn in the top left corner of the recording, and
+// redrawn whenever it changes, for scoreboards or status lines that a script keeps up to date.
+// drawtext can reread its file on every frame, but fails the whole filter graph if the file is
+// missing or half written at that moment, which is how most programs save files. So FILE is
+// watched with inotify and copied to a private file that's only ever replaced atomically, and
+// drawtext reads that one. When the frames stay on the GPU, drawtext draws onto a transparent
+// image instead, only when the text changed, and that's uploaded for overlay_vaapi
+
+const POLL_MS: i32 = 100;
+
+// for a path inside a filter graph description: escaped once for the option value and once more
+// for the graph parser
+fn escape_filter_path(path: &Path) -> String {
+    path.to_string_lossy()
+        .replace('\\', "\\\\\\\\")
+        .replace(':', "\\\\:")
+        .replace('\'', "\\\\\\'")
+}
+
+// the inotify events in `buf` for a file called `name` in the watched directory
+fn names_file(buf: &[u8], name: &OsStr) -> bool {
+    let header = size_of::<libc::inotify_event>();
+    let mut rest = buf;
+    while rest.len() >= header {
+        let ev: libc::inotify_event = unsafe { std::ptr::read_unaligned(rest.as_ptr() as _) };
+        let len = ev.len as usize;
+        let Some(ev_name) = rest.get(header..header + len) else {
+            break;
+        };
+        // the name is padded with nuls
+        let ev_name = ev_name.split(|b| *b == 0).next().unwrap_or_default();
+        if ev_name == name.as_bytes() {
+            return true;
+        }
+        rest = &rest[header + len..];
+    }
+    false
+}
+
+fn copy(from: &Path, to: &Path, changed: &AtomicBool) {
+    let tmp = to.with_extension("tmp");
+    match fs::read(from).and_then(|text| fs::write(&tmp, text)) {
+        Ok(()) => match fs::rename(&tmp, to) {
+            Ok(()) => changed.store(true, Ordering::SeqCst),
+            Err(e) => warn!("failed to update {}: {e}", to.display()),
+        },
+        // keeps showing the old text
+        Err(e) => debug!("can't read {}: {e}", from.display()),
+    }
+}
+
+// watches the directory rather than the file, so it still works when the file is replaced
+fn watch(
+    file: PathBuf,
+    copied: PathBuf,
+    changed: Arc<AtomicBool>,
+    alive: Weak<()>,
+) -> io::Result<()> {
+    let dir = match file.parent() {
+        Some(d) if !d.as_os_str().is_empty() => d.to_owned(),
+        _ => PathBuf::from("."),
+    };
+    let name = file.file_name().unwrap_or_default().to_owned();
+
+    let fd = unsafe { libc::inotify_init1(libc::IN_CLOEXEC) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let mut inotify = unsafe { fs::File::from_raw_fd(fd) };
+    let c_dir = CString::new(dir.as_os_str().as_bytes())?;
+    let mask = libc::IN_CLOSE_WRITE | libc::IN_MOVED_TO | libc::IN_CREATE;
+    if unsafe { libc::inotify_add_watch(fd, c_dir.as_ptr(), mask) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    info!("watching {} for --overlay-text", file.display());
+
+    let mut buf = vec![0u8; 4096];
+    while alive.upgrade().is_some() {
+        let mut pfd = libc::pollfd {
+            fd,
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        if unsafe { libc::poll(&mut pfd, 1, POLL_MS) } <= 0 {
+            continue;
+        }
+        let n = inotify.read(&mut buf)?;
+        if names_file(&buf[..n], &name) {
+            debug!("{} changed, reloading overlay text", file.display());
+            copy(&file, &copied, &changed);
+        }
+    }
+    let _ = fs::remove_file(&copied);
+    Ok(())
+}
+
+pub struct TextOverlay {
+    path: PathBuf,                   // the private copy drawtext reads
+    changed: Arc<AtomicBool>,        // the copy was replaced since the text was last drawn
+    surface: Option<OverlaySurface>, // the drawn text, when it's blended with overlay_vaapi
+    _alive: Arc<()>,
+}
+
+impl TextOverlay {
+    pub fn spawn(file: &Path) -> io::Result<Self> {
+        let text = fs::read(file)?;
+        let dir = env::var_os("XDG_RUNTIME_DIR").map_or_else(env::temp_dir, PathBuf::from);
+        // numbered, a rebuilt filter graph gets a new overlay and the old one removes its copy
+        // when it stops
+        static NEXT: AtomicU32 = AtomicU32::new(0);
+        let n = NEXT.fetch_add(1, Ordering::Relaxed);
+        let path = dir.join(format!("wl-screenrec-{}-text-{n}.txt", process::id()));
+        fs::write(&path, text)?;
+
+        let alive = Arc::new(());
+        let weak = Arc::downgrade(&alive);
+        let changed = Arc::new(AtomicBool::new(false));
+        let (file, copied, watch_changed) = (file.to_owned(), path.clone(), changed.clone());
+        thread::Builder::new()
+            .name("text-overlay".to_owned())
+            .spawn(move || {
+                if let Err(e) = watch(file, copied, watch_changed, weak) {
+                    warn!("stopped watching --overlay-text for changes: {e}");
+                }
+            })?;
+        Ok(TextOverlay {
+            path,
+            changed,
+            surface: None,
+            _alive: alive,
+        })
+    }
+
+    pub fn filter(&self) -> String {
+        format!(
+            "drawtext@text=textfile={}:reload=1:expansion=none:fontsize=h/30:fontcolor=white:box=1:boxcolor=black@0.6:boxborderw=8:x=h/40:y=h/40",
+            escape_filter_path(&self.path)
+        )
+    }
+
+    // the text on a transparent image of `size`, premultiplied like overlay_vaapi expects
+    fn draw(&self, (w, h): (i32, i32)) -> Result<frame::Video, ffmpeg::Error> {
+        let mut g = filter::Graph::new();
+        g.add(&filter::find("buffersink").unwrap(), "out", "")?;
+        g.input("out", 0)?.parse(&format!(
+            "color=c=black@0:s={w}x{h}:r=1,format=gbrap,{},premultiply=inplace=1,format=bgra",
+            self.filter()
+        ))?;
+        g.validate()?;
+        let mut image = frame::Video::empty();
+        g.get("out").unwrap().sink().frame(&mut image)?;
+        Ok(image)
+    }
+
+    // gets the text ready to be blended over surfaces from `like`'s device, of `size`, with
+    // `filter_vaapi`
+    pub fn prepare_vaapi(
+        &mut self,
+        like: &AvHwFrameCtx,
+        size: (i32, i32),
+    ) -> Result<(), ffmpeg::Error> {
+        if self.surface.as_ref().is_none_or(|s| s.size() != size) {
+            self.surface = Some(OverlaySurface::new(like, Pixel::BGRA, size)?);
+        }
+        self.changed.store(false, Ordering::SeqCst);
+        let image = self.draw(size)?;
+        self.surface.as_mut().unwrap().upload(&image)
+    }
+
+    // blends the graph's `text` source over the chain it's appended to
+    pub fn filter_vaapi(&self) -> &'static str {
+        "[txbase];[txbase][text]overlay_vaapi"
+    }
+
+    // adds the `text` source `filter_vaapi` reads from, once it's prepared
+    pub fn add_source(&self, g: &mut filter::Graph) {
+        self.surface.as_ref().unwrap().add_source(g, c"text");
+    }
+
+    // feeds the text for the frame with `pts` to a graph using `filter_vaapi`, redrawing it first
+    // if it changed. Like the cursor, it has to go in before the frame
+    pub fn push(&mut self, graph: &mut filter::Graph, pts: Option<i64>) {
+        if graph.get("text").is_none() {
+            return;
+        }
+        let Some(surface) = &self.surface else {
+            return;
+        };
+        if self.changed.swap(false, Ordering::SeqCst) {
+            let size = surface.size();
+            let drawn = self.draw(size);
+            if let Err(e) = drawn.and_then(|image| self.surface.as_mut().unwrap().upload(&image)) {
+                // keeps showing the old text
+                warn_once!("failed to draw --overlay-text: {e}");
+            }
+        }
+        if let Err(e) = self.surface.as_ref().unwrap().push(graph, "text", pts) {
+            warn_once!("failed to push --overlay-text into the filter graph: {e}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{ffi::OsStr, mem::size_of};
+
+    use super::names_file;
+
+    fn event(name: &str, padded_len: usize) -> Vec<u8> {
+        let header = libc::inotify_event {
+            wd: 1,
+            mask: libc::IN_CLOSE_WRITE,
+            cookie: 0,
+            len: padded_len as u32,
+        };
+        let mut buf = unsafe {
+            std::slice::from_raw_parts(
+                &header as *const _ as *const u8,
+                size_of::<libc::inotify_event>(),
+            )
+        }
+        .to_vec();
+        let mut name = name.as_bytes().to_vec();
+        name.resize(padded_len, 0);
+        buf.extend(name);
+        buf
+    }
+
+    #[test]
+    fn events() {
+        let mut buf = event(".score.txt.swp", 16);
+        assert!(!names_file(&buf, OsStr::new("score.txt")));
+        buf.extend(event("score.txt", 16));
+        assert!(names_file(&buf, OsStr::new("score.txt")));
+        assert!(!names_file(&buf, OsStr::new("score")));
+        // cut off in the middle of a name
+        assert!(!names_file(&buf[..buf.len() - 10], OsStr::new("score.txt")));
+    }
+}
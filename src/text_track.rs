@@ -0,0 +1,65 @@
+use anyhow::bail;
+use ffmpeg::{codec, dict, ffi::AVMediaType, format, packet, Packet, Rational};
+
+// A subtitle stream in the recording where each packet is one text cue. Has to be created before
+// the header is written
+pub struct TextTrack {
+    idx: usize,
+    codec_id: codec::Id,
+}
+
+impl TextTrack {
+    pub fn new(octx: &mut format::context::Output, title: &str) -> anyhow::Result<Self> {
+        let format_name = octx.format().name().to_owned();
+        let codec_id = if format_name.contains("matroska") {
+            codec::Id::SUBRIP
+        } else if format_name.contains("mp4") || format_name.contains("mov") {
+            codec::Id::MOV_TEXT
+        } else {
+            bail!("{format_name} can't hold a text track, use mkv or mp4")
+        };
+
+        let mut st = octx.add_stream(None::<ffmpeg::Codec>)?;
+        unsafe {
+            let par = (*st.as_mut_ptr()).codecpar;
+            (*par).codec_type = AVMediaType::AVMEDIA_TYPE_SUBTITLE;
+            (*par).codec_id = codec_id.into();
+        }
+        st.set_time_base(Rational(1, 1000));
+        st.set_metadata(dict! { "title" => title });
+
+        Ok(TextTrack {
+            idx: st.index(),
+            codec_id,
+        })
+    }
+
+    // a cue showing `text` from pts_ns for duration_ns, in the stream's time base like the
+    // packets coming out of the encoders
+    pub fn cue(
+        &self,
+        octx: &format::context::Output,
+        text: &str,
+        pts_ns: i64,
+        duration_ns: i64,
+    ) -> Packet {
+        let mut payload = Vec::with_capacity(text.len() + 2);
+        if self.codec_id == codec::Id::MOV_TEXT {
+            // tx3g samples are prefixed with the text length
+            payload.extend_from_slice(&(text.len() as u16).to_be_bytes());
+        }
+        payload.extend_from_slice(text.as_bytes());
+
+        let time_base = octx.stream(self.idx).unwrap().time_base();
+        let ns = Rational(1, 1_000_000_000);
+        let mut packet = Packet::copy(&payload);
+        packet.set_stream(self.idx);
+        packet.set_pts(Some(pts_ns));
+        packet.set_dts(Some(pts_ns));
+        packet.set_duration(duration_ns);
+        packet.rescale_ts(ns, time_base);
+        // every cue stands alone, which --history relies on to trim old ones
+        packet.set_flags(packet::Flags::KEY);
+        packet
+    }
+}
@@ -0,0 +1,99 @@
+use std::{
+    sync::{Arc, Condvar, Mutex},
+    thread,
+    time::Duration,
+};
+
+use crate::capture_error::monotonic_ns;
+
+// Wakes the event loop at the times it's asked to, so waiting for the next capture doesn't mean
+// sleeping in a Wayland event handler, which holds up Ctrl-C, control commands and audio. The
+// loop checks what's due itself after every dispatch, a wakeup only makes sure that happens.
+// Times are CLOCK_MONOTONIC ns, like the presentation timestamps of captured frames
+
+#[derive(Default)]
+struct Deadlines {
+    pending: Vec<i64>,
+    stopped: bool,
+}
+
+pub struct Timer(Arc<(Mutex<Deadlines>, Condvar)>);
+
+impl Timer {
+    pub fn new(wake: impl Fn() + Send + 'static) -> Self {
+        let shared = Arc::new((Mutex::new(Deadlines::default()), Condvar::new()));
+        let shared2 = Arc::clone(&shared);
+        thread::Builder::new()
+            .name("timer".to_owned())
+            .spawn(move || {
+                let (deadlines, cond) = &*shared2;
+                let mut d = deadlines.lock().unwrap();
+                loop {
+                    if d.stopped {
+                        return;
+                    }
+                    let now = monotonic_ns();
+                    if d.pending.iter().any(|at| *at <= now) {
+                        d.pending.retain(|at| *at > now);
+                        drop(d);
+                        wake();
+                        d = deadlines.lock().unwrap();
+                        continue;
+                    }
+                    d = match d.pending.iter().min() {
+                        Some(next) => {
+                            let left = Duration::from_nanos((next - now) as u64);
+                            cond.wait_timeout(d, left).unwrap().0
+                        }
+                        None => cond.wait(d).unwrap(),
+                    };
+                }
+            })
+            .unwrap();
+        Timer(shared)
+    }
+
+    pub fn wake_at(&self, at: i64) {
+        let (deadlines, cond) = &*self.0;
+        deadlines.lock().unwrap().pending.push(at);
+        cond.notify_one();
+    }
+}
+
+impl Drop for Timer {
+    fn drop(&mut self) {
+        let (deadlines, cond) = &*self.0;
+        deadlines.lock().unwrap().stopped = true;
+        cond.notify_one();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        sync::mpsc::channel,
+        time::{Duration, Instant},
+    };
+
+    use super::Timer;
+    use crate::capture_error::monotonic_ns;
+
+    #[test]
+    fn wakes_in_order() {
+        let (tx, rx) = channel();
+        let timer = Timer::new(move || tx.send(Instant::now()).unwrap());
+        let start = Instant::now();
+        let now = monotonic_ns();
+        timer.wake_at(now + 60_000_000);
+        timer.wake_at(now + 20_000_000);
+
+        let first = rx.recv_timeout(Duration::from_secs(1)).unwrap() - start;
+        let second = rx.recv_timeout(Duration::from_secs(1)).unwrap() - start;
+        assert!(first >= Duration::from_millis(20), "{first:?}");
+        assert!(second >= Duration::from_millis(60), "{second:?}");
+
+        // already due
+        timer.wake_at(now);
+        rx.recv_timeout(Duration::from_secs(1)).unwrap();
+    }
+}
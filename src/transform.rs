@@ -20,6 +20,16 @@ pub fn transpose_if_transform_transposed((w, h): (i32, i32), transform: Transfor
     }
 }
 
+fn screen_origin_frame_coord(capture_w: i32, capture_h: i32, transform: Transform) -> (i32, i32) {
+    match transform {
+        Transform::Flipped180 | Transform::_90 => (0, capture_h),
+        Transform::Flipped270 | Transform::_180 => (capture_w, capture_h),
+        Transform::Flipped | Transform::_270 => (capture_w, 0),
+        Transform::Flipped90 | Transform::Normal => (0, 0),
+        _ => (0, 0),
+    }
+}
+
 fn screen_point_to_frame(
     capture_w: i32,
     capture_h: i32,
@@ -27,13 +37,7 @@ fn screen_point_to_frame(
     x: i32,
     y: i32,
 ) -> (i32, i32) {
-    let screen_origin_frame_coord = match transform {
-        Transform::Flipped180 | Transform::_90 => (0, capture_h),
-        Transform::Flipped270 | Transform::_180 => (capture_w, capture_h),
-        Transform::Flipped | Transform::_270 => (capture_w, 0),
-        Transform::Flipped90 | Transform::Normal => (0, 0),
-        _ => (0, 0),
-    };
+    let screen_origin_frame_coord = screen_origin_frame_coord(capture_w, capture_h, transform);
 
     let screen_basis_frame_coord = transform_basis(transform);
 
@@ -47,6 +51,24 @@ fn screen_point_to_frame(
     )
 }
 
+fn frame_point_to_screen(
+    capture_w: i32,
+    capture_h: i32,
+    transform: Transform,
+    x: i32,
+    y: i32,
+) -> (i32, i32) {
+    let (origin_x, origin_y) = screen_origin_frame_coord(capture_w, capture_h, transform);
+    let (x_basis, y_basis) = transform_basis(transform);
+
+    // the basis vectors are orthonormal, so the inverse is the transpose
+    let (dx, dy) = (x - origin_x, y - origin_y);
+    (
+        dx * x_basis[0] + dy * x_basis[1],
+        dx * y_basis[0] + dy * y_basis[1],
+    )
+}
+
 // for each (x, y) in screen space, how many steps in frame space
 fn transform_basis(transform: Transform) -> ([i32; 2], [i32; 2]) {
     match transform {
@@ -61,6 +83,34 @@ fn transform_basis(transform: Transform) -> ([i32; 2], [i32; 2]) {
     }
 }
 
+// wp-fractional-scale-v1 expresses scales in 120ths. Compositors derive an output's logical size
+// by rounding its pixel size divided by the scale, so the exact scale is recovered by rounding the
+// ratio back to the nearest 120th
+pub fn scale_120(size_pixels: i32, logical_size: i32) -> i64 {
+    let (pixels, logical) = (i64::from(size_pixels), i64::from(logical_size));
+    (pixels * 120 + logical / 2) / logical
+}
+
+// rounds halves up, matching how compositors place logical coordinates on the pixel grid
+pub fn logical_to_pixel(logical: i32, scale_120: i64) -> i32 {
+    (i64::from(logical) * scale_120 + 60).div_euclid(120) as i32
+}
+
+// where a `src` sized image goes in a `w`x`h` frame when it's scaled to fit without being
+// stretched, centred. Sizes and offsets are even, which subsampled pixel formats need
+pub fn letterbox((src_w, src_h): (i32, i32), (w, h): (i32, i32)) -> Rect {
+    let scaled = |v: i32, to: i32, from: i32| {
+        let v = i64::from(v) * i64::from(to) / i64::from(from);
+        ((v + 1) / 2 * 2).max(2) as i32
+    };
+    let (fit_w, fit_h) = if i64::from(src_w) * i64::from(h) > i64::from(src_h) * i64::from(w) {
+        (w, scaled(src_h, w, src_w).min(h))
+    } else {
+        (scaled(src_w, h, src_h).min(w), h)
+    };
+    Rect::new(((w - fit_w) / 4 * 2, (h - fit_h) / 4 * 2), (fit_w, fit_h))
+}
+
 impl Rect {
     pub fn new((x, y): (i32, i32), (w, h): (i32, i32)) -> Self {
         Rect { x, y, w, h }
@@ -84,10 +134,35 @@ impl Rect {
         }
     }
 
+    // the inverse of screen_to_frame, for things like damage that are reported in buffer
+    // coordinates
+    pub fn frame_to_screen(&self, capture_w: i32, capture_h: i32, transform: Transform) -> Rect {
+        let (x1, y1) = frame_point_to_screen(capture_w, capture_h, transform, self.x, self.y);
+        let (x2, y2) = frame_point_to_screen(
+            capture_w,
+            capture_h,
+            transform,
+            self.x + self.w,
+            self.y + self.h,
+        );
+
+        Rect {
+            x: x1.min(x2),
+            y: y1.min(y2),
+            w: (x1 - x2).abs(),
+            h: (y1 - y2).abs(),
+        }
+    }
+
     pub fn bottom_right(&self) -> (i32, i32) {
         (self.x + self.w, self.y + self.h)
     }
 
+    #[must_use]
+    pub fn translate(&self, (dx, dy): (i32, i32)) -> Rect {
+        Rect::new((self.x + dx, self.y + dy), (self.w, self.h))
+    }
+
     #[must_use]
     pub fn fit_inside_bounds(&self, w: i32, h: i32) -> Rect {
         let mut r = *self;
@@ -105,7 +180,29 @@ mod test {
 
     use crate::transform::transform_is_transposed;
 
-    use super::Rect;
+    use super::{letterbox, logical_to_pixel, scale_120, Rect};
+
+    #[test]
+    fn fractional_scale() {
+        // (pixel width, logical width as the compositor rounds it, scale in 120ths)
+        for (pixels, logical, scale) in [
+            (1920, 1920, 120),
+            (1920, 1536, 150),
+            (2560, 1707, 180),
+            (3840, 2194, 210),
+            (3840, 1920, 240),
+            (2560, 1920, 160),
+        ] {
+            assert_eq!(scale_120(pixels, logical), scale, "{pixels}/{logical}");
+        }
+
+        assert_eq!(logical_to_pixel(1536, 150), 1920);
+        assert_eq!(logical_to_pixel(960, 180), 1440);
+        assert_eq!(logical_to_pixel(101, 180), 152);
+        assert_eq!(logical_to_pixel(100, 150), 125);
+        assert_eq!(logical_to_pixel(3, 210), 5);
+        assert_eq!(logical_to_pixel(0, 180), 0);
+    }
 
     #[test]
     fn fit_inside_bounds() {
@@ -117,6 +214,33 @@ mod test {
         assert_eq!(r.fit_inside_bounds(10, 5), Rect::new((0, 0), (10, 5)));
     }
 
+    #[test]
+    fn letterbox_fit() {
+        // the same shape, give or take rounding to even sizes
+        assert_eq!(
+            letterbox((1366, 768), (682, 384)),
+            Rect::new((0, 0), (682, 384))
+        );
+        assert_eq!(
+            letterbox((1920, 1080), (1920, 1080)),
+            Rect::new((0, 0), (1920, 1080))
+        );
+        // narrower, bars on the sides
+        assert_eq!(
+            letterbox((800, 600), (1920, 1080)),
+            Rect::new((240, 0), (1440, 1080))
+        );
+        // wider, bars on the top and bottom
+        assert_eq!(
+            letterbox((1000, 200), (640, 480)),
+            Rect::new((0, 176), (640, 128))
+        );
+        assert_eq!(
+            letterbox((5000, 1), (640, 480)),
+            Rect::new((0, 238), (640, 2))
+        );
+    }
+
     #[test]
     fn screen_to_frame_normal() {
         assert_eq!(
@@ -206,6 +330,29 @@ mod test {
         );
     }
 
+    #[test]
+    fn frame_to_screen_inverse() {
+        let r = Rect::new((743, 1359), (312, 264));
+        for t in [
+            Transform::Normal,
+            Transform::_90,
+            Transform::_180,
+            Transform::_270,
+            Transform::Flipped,
+            Transform::Flipped90,
+            Transform::Flipped180,
+            Transform::Flipped270,
+        ] {
+            let (capture_w, capture_h) = if transform_is_transposed(t) {
+                (1920, 1200)
+            } else {
+                (1200, 1920)
+            };
+            let frame = r.screen_to_frame(capture_w, capture_h, t);
+            assert_eq!(frame.frame_to_screen(capture_w, capture_h, t), r, "{t:?}");
+        }
+    }
+
     #[test]
     fn transform_transposed() {
         assert!(!transform_is_transposed(Transform::Normal));
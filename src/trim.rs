@@ -0,0 +1,216 @@
+use std::{fs, path::Path, time::Duration};
+
+use anyhow::{bail, Context};
+use ffmpeg::{format, media, Rational, Rescale};
+use log::{info, warn};
+
+use crate::{finish::Finisher, open_output};
+
+// --trim-start and --trim-end: cut the beginning and end off the recording once it's finished.
+// The start is moved forward to the next video keyframe so nothing has to be re-encoded, the end
+// can be cut anywhere. With file splitting the start is cut from the first file and the end from
+// the last one
+
+const NS: Rational = Rational(1, 1_000_000_000);
+
+pub struct Trim {
+    start: i64, // ns
+    end: i64,
+    first_file: bool,
+}
+
+impl Trim {
+    pub fn new(start: Duration, end: Duration) -> Option<Self> {
+        if start.is_zero() && end.is_zero() {
+            return None;
+        }
+        Some(Trim {
+            start: start.as_nanos() as i64,
+            end: end.as_nanos() as i64,
+            first_file: true,
+        })
+    }
+
+    // once `path` is finished, `last` if the recording is over. The file is trimmed on
+    // `finisher`'s thread
+    pub fn finish_file(
+        &mut self,
+        finisher: &mut Finisher,
+        path: &str,
+        muxer: Option<&str>,
+        muxer_options: Option<&str>,
+        last: bool,
+    ) {
+        let start = if self.first_file { self.start } else { 0 };
+        let end = if last { self.end } else { 0 };
+        self.first_file = false;
+        if start == 0 && end == 0 {
+            return;
+        }
+
+        let path = path.to_owned();
+        let muxer = muxer.map(str::to_owned);
+        let muxer_options = muxer_options.map(str::to_owned);
+        finisher.run(move || {
+            let tmp = trimming_filename(&path);
+            match trim(
+                &path,
+                &tmp,
+                start,
+                end,
+                muxer.as_deref(),
+                muxer_options.as_deref(),
+            ) {
+                Ok(()) => info!("trimmed {path}"),
+                Err(e) => {
+                    warn!("failed to trim {path}, it was left as is: {e:?}");
+                    let _ = fs::remove_file(&tmp);
+                }
+            }
+        });
+    }
+}
+
+// next to the recording with the same extension, so the muxer is guessed the same way
+fn trimming_filename(path: &str) -> String {
+    let p = Path::new(path);
+    match (p.file_stem(), p.extension()) {
+        (Some(stem), Some(ext)) => p
+            .with_file_name(format!(
+                "{}.trimming.{}",
+                stem.to_string_lossy(),
+                ext.to_string_lossy()
+            ))
+            .to_string_lossy()
+            .into_owned(),
+        _ => format!("{path}.trimming"),
+    }
+}
+
+// where to cut, in ns from the start of the recording. `keyframes` are the video keyframe
+// timestamps in order, `duration` where the last packet ends
+fn cut_points(keyframes: &[i64], duration: i64, start: i64, end: i64) -> Option<(i64, i64)> {
+    let from = if start == 0 {
+        0
+    } else {
+        *keyframes.iter().find(|k| **k >= start)?
+    };
+    let to = duration - end;
+    (to > from).then_some((from, to))
+}
+
+fn trim(
+    path: &str,
+    tmp: &str,
+    start: i64,
+    end: i64,
+    muxer: Option<&str>,
+    muxer_options: Option<&str>,
+) -> anyhow::Result<()> {
+    let mut ictx = format::input(path).with_context(|| format!("failed to open {path}"))?;
+    let video_idx = ictx
+        .streams()
+        .best(media::Type::Video)
+        .context("no video stream")?
+        .index();
+
+    let mut keyframes = Vec::new();
+    let mut duration = 0;
+    for (ist, packet) in ictx.packets() {
+        let Some(pts) = packet.pts() else {
+            continue;
+        };
+        let tb = ist.time_base();
+        duration = duration.max((pts + packet.duration()).rescale(tb, NS));
+        if ist.index() == video_idx && packet.is_key() {
+            keyframes.push(pts.rescale(tb, NS));
+        }
+    }
+    keyframes.sort_unstable();
+    let Some((from, to)) = cut_points(&keyframes, duration, start, end) else {
+        bail!(
+            "the recording is {:.1}s long, too short for --trim-start and --trim-end",
+            duration as f64 / 1e9
+        );
+    };
+
+    let mut ictx = format::input(path).with_context(|| format!("failed to open {path}"))?;
+    let mut octx = open_output(tmp, muxer, muxer_options)?;
+    for ist in ictx.streams() {
+        let mut ost = octx.add_stream(None::<ffmpeg::Codec>)?;
+        ost.set_parameters(ist.parameters());
+        ost.set_metadata(ist.metadata().to_owned());
+        unsafe {
+            (*ost.as_mut_ptr()).disposition = ist.disposition().bits();
+            (*ost.parameters().as_mut_ptr()).codec_tag = 0;
+        }
+    }
+    octx.write_header()?;
+
+    // video streams start at their first keyframe from the cut on, so the first packets can be
+    // decoded
+    let mut started: Vec<_> = ictx
+        .streams()
+        .map(|st| st.parameters().medium() != media::Type::Video)
+        .collect();
+    for (ist, mut packet) in ictx.packets() {
+        let (Some(pts), idx, tb) = (packet.pts(), ist.index(), ist.time_base()) else {
+            continue;
+        };
+        let pts_ns = pts.rescale(tb, NS);
+        // in decode order, so no kept frame refers to one that was cut
+        let dts_ns = packet.dts().map_or(pts_ns, |dts| dts.rescale(tb, NS));
+        if pts_ns < from || dts_ns >= to {
+            continue;
+        }
+        if !started[idx] {
+            if !packet.is_key() {
+                continue;
+            }
+            started[idx] = true;
+        }
+
+        let offset = from.rescale(NS, tb);
+        packet.set_pts(Some(pts - offset));
+        packet.set_dts(packet.dts().map(|dts| dts - offset));
+        let ost_tb = octx.stream(idx).unwrap().time_base();
+        packet.rescale_ts(tb, ost_tb);
+        packet.set_position(-1);
+        packet.write_interleaved(&mut octx)?;
+    }
+    octx.write_trailer()?;
+    drop(octx);
+
+    fs::rename(tmp, path).with_context(|| format!("failed to replace {path}"))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::{cut_points, trimming_filename};
+
+    const S: i64 = 1_000_000_000;
+
+    #[test]
+    fn cuts() {
+        let keyframes = [0, 4 * S, 8 * S];
+        assert_eq!(cut_points(&keyframes, 10 * S, 0, S), Some((0, 9 * S)));
+        // the start moves to the next keyframe
+        assert_eq!(
+            cut_points(&keyframes, 10 * S, 2 * S, 0),
+            Some((4 * S, 10 * S))
+        );
+        assert_eq!(
+            cut_points(&keyframes, 10 * S, 4 * S, 0),
+            Some((4 * S, 10 * S))
+        );
+        assert_eq!(cut_points(&keyframes, 10 * S, 9 * S, 0), None);
+        assert_eq!(cut_points(&keyframes, 10 * S, 5 * S, 2 * S), None);
+    }
+
+    #[test]
+    fn tmp_name() {
+        assert_eq!(trimming_filename("/tmp/rec.mkv"), "/tmp/rec.trimming.mkv");
+        assert_eq!(trimming_filename("rec"), "rec.trimming");
+    }
+}
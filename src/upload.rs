@@ -0,0 +1,211 @@
+use std::{
+    fs::{self, File},
+    io::{self, Read},
+    path::Path,
+    thread::sleep,
+    time::{Duration, Instant},
+};
+
+#[cfg(feature = "s3")]
+use std::sync::Arc;
+
+use anyhow::{bail, Context};
+use log::{info, warn};
+
+use crate::{shutdown, Args};
+
+// --upload-url (and --s3-url when built with the s3 feature): each finished file is PUT to an
+// http(s) endpoint. It only counts as uploaded once the server answers with a 2xx, anything else
+// is retried. Uploads run on the Finisher's thread while recording continues, after the file is
+// trimmed, and are waited for at exit
+
+const ATTEMPTS: u32 = 5;
+const FIRST_RETRY: Duration = Duration::from_secs(2);
+
+// a server that stops answering fails the attempt rather than hanging it
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(30);
+const IO_TIMEOUT: Duration = Duration::from_secs(60);
+
+// how often a retry waiting to happen checks whether uploads were given up on
+const POLL: Duration = Duration::from_millis(100);
+
+#[derive(Clone)]
+pub enum Destination {
+    Http(String),
+    #[cfg(feature = "s3")]
+    S3(Arc<crate::s3::Bucket>),
+}
+
+impl Destination {
+    pub fn from_args(args: &Args) -> anyhow::Result<Option<Self>> {
+        #[cfg(feature = "s3")]
+        if let Some(url) = &args.s3_url {
+            let bucket =
+                crate::s3::Bucket::new(url, &args.s3_region, &args.s3_key, args.s3_keep_local)?;
+            return Ok(Some(Destination::S3(Arc::new(bucket))));
+        }
+        let Some(url) = &args.upload_url else {
+            return Ok(None);
+        };
+        // any recording can be split with `wl-screenrec ctl split`, so there's always a chance
+        // of more than one file
+        #[cfg(not(feature = "tls"))]
+        if url.starts_with("https://") {
+            bail!(
+                "--upload-url {url} is https, but wl-screenrec was built without the tls feature"
+            );
+        }
+        if !url.ends_with('/') && !url.contains("{filename}") {
+            bail!(
+                "--upload-url {url} would have every file overwrite the last one. End it with / to add the file's name to it, or put {{filename}} where the name goes"
+            );
+        }
+        Ok(Some(Destination::Http(url.clone())))
+    }
+
+    fn put(&self, path: &str) -> anyhow::Result<String> {
+        match self {
+            Destination::Http(base) => {
+                let url = target_url(base, path);
+                let file = File::open(path).with_context(|| format!("failed to open {path}"))?;
+                let len = file.metadata()?.len();
+                put(&url, file, len, &[])?;
+                Ok(url)
+            }
+            #[cfg(feature = "s3")]
+            Destination::S3(bucket) => bucket.put(path),
+        }
+    }
+
+    // the local copy isn't needed once it's uploaded
+    fn deletes_uploaded(&self) -> bool {
+        match self {
+            Destination::Http(_) => false,
+            #[cfg(feature = "s3")]
+            Destination::S3(bucket) => !bucket.keep_local(),
+        }
+    }
+}
+
+// blocks until `path` is uploaded or every attempt failed. Failures are only warned about, the
+// file is still there to upload by hand
+pub fn upload(destination: &Destination, path: &str) {
+    let mut wait = FIRST_RETRY;
+    for attempt in 1..=ATTEMPTS {
+        if shutdown::uploads_aborted() {
+            warn!("not uploading {path}, interrupted");
+            return;
+        }
+        match destination.put(path) {
+            Ok(url) => {
+                info!("uploaded {path} to {url}");
+                if destination.deletes_uploaded() {
+                    if let Err(e) = fs::remove_file(path) {
+                        warn!("failed to delete {path} after uploading it: {e}");
+                    }
+                }
+                return;
+            }
+            Err(e) if attempt == ATTEMPTS || shutdown::uploads_aborted() => {
+                warn!("giving up on uploading {path}: {e:?}");
+                return;
+            }
+            Err(e) => {
+                warn!(
+                    "failed to upload {path}, retrying in {}s: {e:?}",
+                    wait.as_secs()
+                );
+                let until = Instant::now() + wait;
+                while Instant::now() < until && !shutdown::uploads_aborted() {
+                    sleep(POLL);
+                }
+                wait *= 2;
+            }
+        }
+    }
+}
+
+// `len` bytes of `body` are sent with a Content-Length, along with `headers`. Only a 2xx response
+// is a success
+pub fn put(url: &str, body: impl Read, len: u64, headers: &[(&str, String)]) -> anyhow::Result<()> {
+    let agent = ureq::AgentBuilder::new()
+        .timeout_connect(CONNECT_TIMEOUT)
+        .timeout_read(IO_TIMEOUT)
+        .timeout_write(IO_TIMEOUT)
+        .build();
+    let mut request = agent.put(url).set("Content-Length", &len.to_string());
+    for (name, value) in headers {
+        request = request.set(name, value);
+    }
+    match request.send(Interruptible(body)) {
+        Ok(response) if (200..300).contains(&response.status()) => Ok(()),
+        Ok(response) => bail!("{} {}", response.status(), response.status_text()),
+        Err(ureq::Error::Status(code, response)) => {
+            let status = format!("{code} {}", response.status_text());
+            // S3 says what's wrong in the body
+            let body = response.into_string().unwrap_or_default();
+            bail!(
+                "{status}: {}",
+                body.trim().chars().take(500).collect::<String>()
+            )
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+// stops the body of an upload partway once uploads are given up on
+struct Interruptible<R>(R);
+
+impl<R: Read> Read for Interruptible<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if shutdown::uploads_aborted() {
+            return Err(io::Error::other("interrupted"));
+        }
+        self.0.read(buf)
+    }
+}
+
+// {filename} in the url is replaced with the file's name, and a url ending in / is a directory
+// it's added to
+fn target_url(base: &str, path: &str) -> String {
+    let name = Path::new(path)
+        .file_name()
+        .unwrap_or_default()
+        .to_string_lossy();
+    let name = percent_encode(&name, false);
+    if base.contains("{filename}") {
+        base.replace("{filename}", &name)
+    } else {
+        base.to_owned() + &name
+    }
+}
+
+// everything but unreserved characters, and / if `keep_slash`
+pub fn percent_encode(s: &str, keep_slash: bool) -> String {
+    let mut encoded = String::new();
+    for b in s.bytes() {
+        if b.is_ascii_alphanumeric() || b"-._~".contains(&b) || (keep_slash && b == b'/') {
+            encoded.push(b as char);
+        } else {
+            encoded += &format!("%{b:02X}");
+        }
+    }
+    encoded
+}
+
+#[cfg(test)]
+mod test {
+    use super::target_url;
+
+    #[test]
+    fn urls() {
+        assert_eq!(
+            target_url("https://example.com/recordings/", "/tmp/rec 12:00.mkv"),
+            "https://example.com/recordings/rec%2012%3A00.mkv"
+        );
+        assert_eq!(
+            target_url("https://example.com/upload?name={filename}", "/tmp/rec.mkv"),
+            "https://example.com/upload?name=rec.mkv"
+        );
+    }
+}
@@ -0,0 +1,137 @@
+use std::ffi::{c_char, c_int, c_void, CStr};
+
+use crate::avhw::AvHwDevCtx;
+
+// just enough of libva to list what the driver can encode, like `vainfo` does, and to give
+// frame contexts surface attributes
+
+type VAStatus = c_int;
+const VA_STATUS_SUCCESS: VAStatus = 0;
+
+// VAEntrypointEncSlice, VAEntrypointEncPicture, VAEntrypointEncSliceLP
+const ENCODE_ENTRYPOINTS: [c_int; 3] = [6, 7, 8];
+
+// VA_SURFACE_ATTRIB_USAGE_HINT_*
+pub const USAGE_HINT_ENCODER: i32 = 0x2;
+pub const USAGE_HINT_VPP_READ: i32 = 0x4;
+pub const USAGE_HINT_VPP_WRITE: i32 = 0x8;
+pub const USAGE_HINT_EXPORT: i32 = 0x20;
+
+// libva's VASurfaceAttrib, with the VAGenericValue inlined
+#[repr(C)]
+pub struct VASurfaceAttrib {
+    pub type_: c_int, // VASurfaceAttribType
+    pub flags: u32,
+    pub value_type: c_int, // VAGenericValueType
+    pub value: VAGenericValue,
+}
+
+#[repr(C)]
+pub union VAGenericValue {
+    pub i: i32,
+    _p: *mut c_void, // not used, but sets the size and alignment
+}
+
+pub const VA_SURFACE_ATTRIB_USAGE_HINT: c_int = 8;
+pub const VA_SURFACE_ATTRIB_SETTABLE: u32 = 0x2;
+pub const VA_GENERIC_VALUE_TYPE_INTEGER: c_int = 1;
+
+#[link(name = "va")]
+extern "C" {
+    fn vaMaxNumProfiles(dpy: *mut c_void) -> c_int;
+    fn vaMaxNumEntrypoints(dpy: *mut c_void) -> c_int;
+    fn vaQueryConfigProfiles(
+        dpy: *mut c_void,
+        profile_list: *mut c_int,
+        num_profiles: *mut c_int,
+    ) -> VAStatus;
+    fn vaQueryConfigEntrypoints(
+        dpy: *mut c_void,
+        profile: c_int,
+        entrypoint_list: *mut c_int,
+        num_entrypoints: *mut c_int,
+    ) -> VAStatus;
+    fn vaProfileStr(profile: c_int) -> *const c_char;
+    fn vaEntrypointStr(entrypoint: c_int) -> *const c_char;
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct EncodeProfile {
+    pub profile: String,
+    pub entrypoints: Vec<String>,
+}
+
+fn va_str(s: *const c_char) -> String {
+    if s.is_null() {
+        "<unknown>".to_owned()
+    } else {
+        unsafe { CStr::from_ptr(s) }.to_string_lossy().into_owned()
+    }
+}
+
+// the profiles the driver can encode, with their encode entrypoints
+pub fn encode_profiles(dev: &AvHwDevCtx) -> Vec<EncodeProfile> {
+    let dpy = dev.va_display();
+    unsafe {
+        let mut profiles = vec![0; vaMaxNumProfiles(dpy).max(0) as usize];
+        let mut num_profiles = 0;
+        if vaQueryConfigProfiles(dpy, profiles.as_mut_ptr(), &mut num_profiles) != VA_STATUS_SUCCESS
+        {
+            return Vec::new();
+        }
+        profiles.truncate(num_profiles.max(0) as usize);
+
+        let mut entrypoints = vec![0; vaMaxNumEntrypoints(dpy).max(0) as usize];
+        profiles
+            .into_iter()
+            .filter_map(|profile| {
+                let mut num = 0;
+                if vaQueryConfigEntrypoints(dpy, profile, entrypoints.as_mut_ptr(), &mut num)
+                    != VA_STATUS_SUCCESS
+                {
+                    return None;
+                }
+                let encode: Vec<_> = entrypoints[..num.max(0) as usize]
+                    .iter()
+                    .filter(|e| ENCODE_ENTRYPOINTS.contains(e))
+                    .map(|e| va_str(vaEntrypointStr(*e)))
+                    .collect();
+                (!encode.is_empty()).then(|| EncodeProfile {
+                    profile: va_str(vaProfileStr(profile)),
+                    entrypoints: encode,
+                })
+            })
+            .collect()
+    }
+}
+
+pub fn describe(profiles: &[EncodeProfile]) -> String {
+    if profiles.is_empty() {
+        return "the vaapi driver does not support encoding at all".to_owned();
+    }
+    let mut s = "the vaapi driver can encode with (EncSliceLP is --low-power):".to_owned();
+    for p in profiles {
+        s += &format!("\n  {}: {}", p.profile, p.entrypoints.join(", "));
+    }
+    s
+}
+
+#[cfg(test)]
+mod test {
+    use super::{describe, EncodeProfile};
+
+    #[test]
+    fn describe_profiles() {
+        assert_eq!(
+            describe(&[]),
+            "the vaapi driver does not support encoding at all"
+        );
+        assert_eq!(
+            describe(&[EncodeProfile {
+                profile: "VAProfileHEVCMain".into(),
+                entrypoints: vec!["VAEntrypointEncSlice".into(), "VAEntrypointEncSliceLP".into()],
+            }]),
+            "the vaapi driver can encode with (EncSliceLP is --low-power):\n  VAProfileHEVCMain: VAEntrypointEncSlice, VAEntrypointEncSliceLP"
+        );
+    }
+}
@@ -0,0 +1,84 @@
+use std::{ffi::CStr, fmt::Write};
+
+use ffmpeg::{encoder, ffi::av_version_info};
+
+// --version --verbose, everything an issue report needs to know about the build
+
+const VAAPI_ENCODERS: [&str; 5] = [
+    "h264_vaapi",
+    "hevc_vaapi",
+    "vp8_vaapi",
+    "vp9_vaapi",
+    "av1_vaapi",
+];
+
+// the optional cargo features, and whether this build has them
+const FEATURES: &[(&str, bool)] = &[("tls", cfg!(feature = "tls")), ("s3", cfg!(feature = "s3"))];
+
+// libav* encode their versions as major << 16 | minor << 8 | micro
+fn lib_version(v: u32) -> String {
+    format!("{}.{}.{}", v >> 16, (v >> 8) & 0xff, v & 0xff)
+}
+
+pub fn verbose() -> String {
+    let mut s = String::new();
+    let ffmpeg = unsafe { CStr::from_ptr(av_version_info()) }.to_string_lossy();
+    writeln!(s, "ffmpeg {ffmpeg}").unwrap();
+    for (lib, version) in [
+        ("libavutil", ffmpeg::util::version()),
+        ("libavcodec", ffmpeg::codec::version()),
+        ("libavformat", ffmpeg::format::version()),
+        ("libavfilter", ffmpeg::filter::version()),
+        ("libavdevice", ffmpeg::device::version()),
+    ] {
+        writeln!(s, "  {lib} {}", lib_version(version)).unwrap();
+    }
+    writeln!(s, "  configuration: {}", ffmpeg::util::configuration()).unwrap();
+
+    let vaapi: Vec<_> = VAAPI_ENCODERS
+        .into_iter()
+        .filter(|name| encoder::find_by_name(name).is_some())
+        .collect();
+    writeln!(
+        s,
+        "vaapi encoders: {}",
+        if vaapi.is_empty() {
+            "none".to_owned()
+        } else {
+            vaapi.join(" ")
+        }
+    )
+    .unwrap();
+
+    let features: Vec<_> = FEATURES
+        .iter()
+        .filter(|(_, enabled)| *enabled)
+        .map(|(name, _)| *name)
+        .collect();
+    writeln!(
+        s,
+        "cargo features: {}",
+        if features.is_empty() {
+            "none".to_owned()
+        } else {
+            features.join(" ")
+        }
+    )
+    .unwrap();
+    writeln!(
+        s,
+        "capture backends: wlr-screencopy (default), ext-image-copy-capture (--experimental-ext-image-copy-capture), test-pattern (--capture-backend test-pattern)"
+    )
+    .unwrap();
+    s
+}
+
+#[cfg(test)]
+mod test {
+    use super::lib_version;
+
+    #[test]
+    fn versions() {
+        assert_eq!(lib_version((61 << 16) | (19 << 8) | 100), "61.19.100");
+    }
+}
@@ -0,0 +1,260 @@
+use std::{
+    env,
+    path::Path,
+    sync::mpsc::{channel, Receiver},
+};
+
+use log::{debug, info, warn};
+use serde_json::Value;
+
+use crate::{
+    hyprland, ipc_watch,
+    sway::{self, SwayIpc},
+};
+
+// where a window is, in the same global logical coordinates as --geometry
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WindowGeometry {
+    pub output: String,
+    pub region: (i32, i32, u32, u32),
+}
+
+fn rect(v: &Value) -> Option<(i32, i32, u32, u32)> {
+    Some((
+        v["x"].as_i64()? as i32,
+        v["y"].as_i64()? as i32,
+        v["width"].as_u64()? as u32,
+        v["height"].as_u64()? as u32,
+    ))
+}
+
+// searches a sway tree (GET_TREE) for the first node `matches` accepts. `rect`
+// includes the border and title bar, `window_rect` is the window itself relative to it
+pub fn find_sway(
+    node: &Value,
+    output: Option<&str>,
+    matches: &impl Fn(&Value) -> bool,
+) -> Option<WindowGeometry> {
+    let output = match node["type"].as_str() {
+        Some("output") => node["name"].as_str(),
+        _ => output,
+    };
+    if matches(node) {
+        let (x, y, _, _) = rect(&node["rect"])?;
+        let (wx, wy, w, h) = rect(&node["window_rect"])?;
+        return Some(WindowGeometry {
+            output: output?.to_owned(),
+            region: (x + wx, y + wy, w, h),
+        });
+    }
+
+    ["nodes", "floating_nodes"]
+        .iter()
+        .filter_map(|key| node[key].as_array())
+        .flatten()
+        .find_map(|child| find_sway(child, output, matches))
+}
+
+// a sway window with `app_id`, or that X11 class for Xwayland windows
+pub fn has_app_id(node: &Value, app_id: &str) -> bool {
+    node["app_id"].as_str() == Some(app_id)
+        || node["window_properties"]["class"].as_str() == Some(app_id)
+}
+
+// Hyprland's `clients` has the monitor of each window by id, `monitors` has the names
+fn find_hyprland(clients: &Value, monitors: &Value, class: &str) -> Option<WindowGeometry> {
+    let client = clients.as_array()?.iter().find(|c| {
+        (c["class"].as_str() == Some(class) || c["initialClass"].as_str() == Some(class))
+            && c["mapped"].as_bool() != Some(false)
+            && c["hidden"].as_bool() != Some(true)
+    })?;
+    hyprland_geometry(client, monitors)
+}
+
+// where a window from Hyprland's `clients` or `activewindow` is
+pub fn hyprland_geometry(client: &Value, monitors: &Value) -> Option<WindowGeometry> {
+    let monitor = monitors
+        .as_array()?
+        .iter()
+        .find(|m| m["id"] == client["monitor"])?;
+    Some(WindowGeometry {
+        output: monitor["name"].as_str()?.to_owned(),
+        region: (
+            client["at"][0].as_i64()? as i32,
+            client["at"][1].as_i64()? as i32,
+            client["size"][0].as_u64()? as u32,
+            client["size"][1].as_u64()? as u32,
+        ),
+    })
+}
+
+fn hyprland_window(dir: &Path, app_id: &str) -> anyhow::Result<Option<WindowGeometry>> {
+    let clients = hyprland::request(dir, "clients")?;
+    let monitors = hyprland::request(dir, "monitors")?;
+    Ok(find_hyprland(&clients, &monitors, app_id))
+}
+
+fn sway_window(ipc: &mut SwayIpc, app_id: &str) -> anyhow::Result<Option<WindowGeometry>> {
+    let matches = |n: &Value| has_app_id(n, app_id) && n["visible"].as_bool() != Some(false);
+    Ok(find_sway(&ipc.tree()?, None, &matches))
+}
+
+// wlr-foreign-toplevel-management lists windows but not where they are, so this asks the
+// compositor directly. Only sway and Hyprland are supported
+pub fn window_geometry(app_id: &str) -> Option<WindowGeometry> {
+    let window = if env::var_os("HYPRLAND_INSTANCE_SIGNATURE").is_some() {
+        hyprland::socket_dir().and_then(|dir| hyprland_window(&dir, app_id))
+    } else if env::var_os("SWAYSOCK").is_some() {
+        SwayIpc::connect().and_then(|mut ipc| sway_window(&mut ipc, app_id))
+    } else {
+        return None;
+    };
+    window
+        .map_err(|e| debug!("failed to ask the compositor where {app_id} is: {e}"))
+        .ok()
+        .flatten()
+}
+
+// follows the window over the compositor's IPC in the background, sending its geometry whenever
+// it moves or resizes. `wake` is called after each change so the event loop notices
+pub fn spawn_window_watcher(
+    app_id: String,
+    last: Option<WindowGeometry>,
+    wake: impl Fn() + Send + 'static,
+) -> anyhow::Result<Receiver<WindowGeometry>> {
+    if env::var_os("HYPRLAND_INSTANCE_SIGNATURE").is_some() {
+        let dir = hyprland::socket_dir()?;
+        let mut events = hyprland::event_socket(&dir)?;
+        Ok(watch(
+            app_id.clone(),
+            last,
+            wake,
+            move || hyprland_window(&dir, &app_id),
+            move || Ok(hyprland::wait_event(&mut events, hyprland::RECHECK_MS)?),
+        ))
+    } else if env::var_os("SWAYSOCK").is_some() {
+        let mut ipc = SwayIpc::connect()?;
+        let mut events = SwayIpc::connect()?;
+        events.subscribe(sway::WINDOW_EVENTS)?;
+        Ok(watch(
+            app_id.clone(),
+            last,
+            wake,
+            move || sway_window(&mut ipc, &app_id),
+            move || events.wait_event(sway::RECHECK_MS),
+        ))
+    } else {
+        warn!(
+            "--follow-window only works on sway and Hyprland, the recording will not follow {app_id}"
+        );
+        Ok(channel().1)
+    }
+}
+
+// sends the geometry `window` says `name` is at whenever it's not the `last` one. `wait` also
+// returns after a while without an event, as neither compositor sends one for every move or
+// resize
+pub fn watch(
+    name: String,
+    mut last: Option<WindowGeometry>,
+    wake: impl Fn() + Send + 'static,
+    window: impl FnMut() -> anyhow::Result<Option<WindowGeometry>> + Send + 'static,
+    wait: impl FnMut() -> anyhow::Result<()> + Send + 'static,
+) -> Receiver<WindowGeometry> {
+    let (tx, rx) = channel();
+    let mut missing = false;
+    ipc_watch::watch("window-watcher", window, wait, move |res| {
+        match res {
+            Ok(Some(geometry)) if last.as_ref() != Some(&geometry) => {
+                debug!("{name} is now at {geometry:?}");
+                if tx.send(geometry.clone()).is_err() {
+                    return false;
+                }
+                wake();
+                last = Some(geometry);
+                missing = false;
+            }
+            Ok(Some(_)) => missing = false,
+            Ok(None) if !missing => {
+                info!("window {name} is gone or hidden, recording its last position");
+                missing = true;
+            }
+            Ok(None) => {}
+            Err(e) => {
+                warn!("lost connection to the compositor, no longer following {name}: {e}");
+                return false;
+            }
+        }
+        true
+    });
+    rx
+}
+
+#[cfg(test)]
+mod test {
+    use serde_json::json;
+
+    use super::{find_hyprland, find_sway, has_app_id, hyprland_geometry, WindowGeometry};
+
+    #[test]
+    fn sway() {
+        let tree = json!({
+            "type": "root",
+            "nodes": [{
+                "type": "output",
+                "name": "DP-1",
+                "nodes": [{
+                    "type": "workspace",
+                    "nodes": [{
+                        "type": "con",
+                        "app_id": "foot",
+                        "visible": true,
+                        "rect": {"x": 1920, "y": 0, "width": 960, "height": 1080},
+                        "window_rect": {"x": 2, "y": 24, "width": 956, "height": 1054},
+                        "nodes": []
+                    }],
+                    "floating_nodes": [{
+                        "type": "floating_con",
+                        "app_id": null,
+                        "window_properties": {"class": "steam"},
+                        "visible": true,
+                        "rect": {"x": 2000, "y": 100, "width": 400, "height": 300},
+                        "window_rect": {"x": 0, "y": 0, "width": 400, "height": 300},
+                        "nodes": []
+                    }]
+                }]
+            }]
+        });
+        assert_eq!(
+            find_sway(&tree, None, &|n| has_app_id(n, "foot")),
+            Some(WindowGeometry {
+                output: "DP-1".into(),
+                region: (1922, 24, 956, 1054)
+            })
+        );
+        assert_eq!(
+            find_sway(&tree, None, &|n| has_app_id(n, "steam")).map(|g| g.region),
+            Some((2000, 100, 400, 300))
+        );
+        assert_eq!(find_sway(&tree, None, &|n| has_app_id(n, "firefox")), None);
+    }
+
+    #[test]
+    fn hyprland() {
+        let clients = json!([
+            {"class": "kitty", "initialClass": "kitty", "mapped": true, "hidden": false,
+             "at": [10, 40], "size": [1900, 1030], "monitor": 1}
+        ]);
+        let monitors = json!([{"id": 0, "name": "eDP-1"}, {"id": 1, "name": "DP-2"}]);
+        assert_eq!(
+            find_hyprland(&clients, &monitors, "kitty"),
+            Some(WindowGeometry {
+                output: "DP-2".into(),
+                region: (10, 40, 1900, 1030)
+            })
+        );
+        assert_eq!(find_hyprland(&clients, &monitors, "firefox"), None);
+        // the `activewindow` reply when nothing has focus
+        assert_eq!(hyprland_geometry(&json!({}), &monitors), None);
+    }
+}
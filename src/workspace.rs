@@ -0,0 +1,150 @@
+use std::{
+    env,
+    path::Path,
+    sync::{Arc, Mutex},
+};
+
+use anyhow::bail;
+use log::{info, warn};
+use serde_json::Value;
+
+use crate::{hyprland, ipc_watch, sway::SwayIpc};
+
+// workspaces can be given by name or number. On sway a workspace named `2:web` is number 2
+fn is_workspace(name: &Value, num: &Value, workspace: &str) -> bool {
+    name.as_str() == Some(workspace) || num.as_i64().is_some_and(|n| n.to_string() == workspace)
+}
+
+// sway's GET_WORKSPACES
+fn sway_output(workspaces: &Value, workspace: &str) -> Option<String> {
+    let ws = workspaces.as_array()?.iter().find(|w| {
+        w["visible"].as_bool() == Some(true) && is_workspace(&w["name"], &w["num"], workspace)
+    })?;
+    Some(ws["output"].as_str()?.to_owned())
+}
+
+// Hyprland's `monitors`. A special workspace (scratchpad) shown on top hides the workspace too
+fn hyprland_output(monitors: &Value, workspace: &str) -> Option<String> {
+    let monitor = monitors.as_array()?.iter().find(|m| {
+        let active = &m["activeWorkspace"];
+        is_workspace(&active["name"], &active["id"], workspace)
+            && m["specialWorkspace"]["id"].as_i64().unwrap_or(0) == 0
+    })?;
+    Some(monitor["name"].as_str()?.to_owned())
+}
+
+fn hyprland_shown_on(dir: &Path, workspace: &str) -> anyhow::Result<Option<String>> {
+    Ok(hyprland_output(
+        &hyprland::request(dir, "monitors")?,
+        workspace,
+    ))
+}
+
+fn sway_shown_on(ipc: &mut SwayIpc, workspace: &str) -> anyhow::Result<Option<String>> {
+    Ok(sway_output(&ipc.workspaces()?, workspace))
+}
+
+pub fn supported() -> bool {
+    env::var_os("HYPRLAND_INSTANCE_SIGNATURE").is_some() || env::var_os("SWAYSOCK").is_some()
+}
+
+// follows workspaces over the compositor's IPC in the background, the returned value is the
+// output showing `workspace`, or None while no output is
+pub fn spawn_workspace_watcher(workspace: String) -> anyhow::Result<Arc<Mutex<Option<String>>>> {
+    if env::var_os("HYPRLAND_INSTANCE_SIGNATURE").is_some() {
+        let dir = hyprland::socket_dir()?;
+        let mut events = hyprland::event_socket(&dir)?;
+        watch(
+            workspace,
+            move |workspace| hyprland_shown_on(&dir, workspace),
+            move || Ok(hyprland::wait_event(&mut events, -1)?),
+        )
+    } else if env::var_os("SWAYSOCK").is_some() {
+        let mut ipc = SwayIpc::connect()?;
+        let mut events = SwayIpc::connect()?;
+        // moving a workspace to another output sends a workspace event too
+        events.subscribe(r#"["workspace"]"#)?;
+        watch(
+            workspace,
+            move |workspace| sway_shown_on(&mut ipc, workspace),
+            move || events.wait_event(-1),
+        )
+    } else {
+        bail!("--workspace only works on sway and Hyprland")
+    }
+}
+
+// keeps what `shown_on` says about `workspace` up to date
+fn watch(
+    workspace: String,
+    mut shown_on: impl FnMut(&str) -> anyhow::Result<Option<String>> + Send + 'static,
+    wait: impl FnMut() -> anyhow::Result<()> + Send + 'static,
+) -> anyhow::Result<Arc<Mutex<Option<String>>>> {
+    let output = Arc::new(Mutex::new(shown_on(&workspace)?));
+    let output_weak = Arc::downgrade(&output);
+
+    let name = workspace.clone();
+    ipc_watch::watch(
+        "workspace-watcher",
+        move || shown_on(&name),
+        wait,
+        move |res| {
+            let Some(output) = output_weak.upgrade() else {
+                return false;
+            };
+            match res {
+                Ok(now) => {
+                    let mut output = output.lock().unwrap();
+                    if *output != now {
+                        match &now {
+                            Some(o) => info!("workspace {workspace} is shown on {o}"),
+                            None => info!("workspace {workspace} is not shown"),
+                        }
+                        *output = now;
+                    }
+                    true
+                }
+                Err(e) => {
+                    // the last known output is kept, which is where the recording stays
+                    warn!("lost connection to the compositor, no longer following workspace {workspace}: {e}");
+                    false
+                }
+            }
+        },
+    );
+
+    Ok(output)
+}
+
+#[cfg(test)]
+mod test {
+    use serde_json::json;
+
+    use super::{hyprland_output, sway_output};
+
+    #[test]
+    fn sway() {
+        let workspaces = json!([
+            {"name": "1", "num": 1, "visible": true, "output": "DP-1"},
+            {"name": "2:web", "num": 2, "visible": false, "output": "DP-1"},
+            {"name": "3", "num": 3, "visible": true, "output": "DP-2"}
+        ]);
+        assert_eq!(sway_output(&workspaces, "1").as_deref(), Some("DP-1"));
+        assert_eq!(sway_output(&workspaces, "3").as_deref(), Some("DP-2"));
+        assert_eq!(sway_output(&workspaces, "2"), None);
+        assert_eq!(sway_output(&workspaces, "2:web"), None);
+    }
+
+    #[test]
+    fn hyprland() {
+        let monitors = json!([
+            {"name": "DP-1", "activeWorkspace": {"id": 1, "name": "1"},
+             "specialWorkspace": {"id": 0, "name": ""}},
+            {"name": "DP-2", "activeWorkspace": {"id": 4, "name": "code"},
+             "specialWorkspace": {"id": -98, "name": "special:scratch"}}
+        ]);
+        assert_eq!(hyprland_output(&monitors, "1").as_deref(), Some("DP-1"));
+        assert_eq!(hyprland_output(&monitors, "code"), None); // scratchpad on top
+        assert_eq!(hyprland_output(&monitors, "2"), None);
+    }
+}
@@ -1,7 +1,11 @@
 use std::{
     env::{consts::EXE_SUFFIX, current_exe, temp_dir},
+    fs::{self, Permissions},
+    io::ErrorKind,
+    os::unix::fs::PermissionsExt,
     path::{Path, PathBuf},
-    process::Command,
+    process::{self, Child, Command, Stdio},
+    sync::atomic::{AtomicUsize, Ordering},
     thread::sleep,
     time::{Duration, Instant},
 };
@@ -108,6 +112,234 @@ fn basic() {
     assert!(dur < Duration::from_secs_f64(3.5), "{:?} > 3.5s", dur);
 }
 
+// a headless sway to record, so tests don't depend on the session they're run from and can
+// change outputs around
+struct HeadlessSway {
+    sway: Child,
+    runtime_dir: PathBuf,
+    wayland_display: String,
+    ipc_socket: PathBuf,
+}
+
+impl HeadlessSway {
+    // None if sway isn't installed, in which case the test should be skipped
+    fn start(config: &str) -> Option<Self> {
+        static COUNT: AtomicUsize = AtomicUsize::new(0);
+        let runtime_dir = temp_dir().join(format!(
+            "wl-screenrec-test-{}-{}",
+            process::id(),
+            COUNT.fetch_add(1, Ordering::SeqCst)
+        ));
+        fs::create_dir_all(&runtime_dir).unwrap();
+        fs::set_permissions(&runtime_dir, Permissions::from_mode(0o700)).unwrap();
+        let config_path = runtime_dir.join("config");
+        fs::write(&config_path, config).unwrap();
+
+        let sway = match Command::new("sway")
+            .arg("-c")
+            .arg(&config_path)
+            .env("XDG_RUNTIME_DIR", &runtime_dir)
+            .env("WLR_BACKENDS", "headless")
+            .env("WLR_LIBINPUT_NO_DEVICES", "1")
+            .env_remove("WAYLAND_DISPLAY")
+            .env_remove("DISPLAY")
+            .env_remove("SWAYSOCK")
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+        {
+            Ok(sway) => sway,
+            Err(e) if e.kind() == ErrorKind::NotFound => {
+                println!("sway is not installed, skipping");
+                fs::remove_dir_all(&runtime_dir).unwrap();
+                return None;
+            }
+            Err(e) => panic!("failed to start sway: {e}"),
+        };
+
+        // sway picks the names of its sockets itself, wait for them to show up
+        let start = Instant::now();
+        let (wayland_display, ipc_socket) = loop {
+            let names: Vec<_> = fs::read_dir(&runtime_dir)
+                .unwrap()
+                .map(|e| e.unwrap().file_name().into_string().unwrap())
+                .collect();
+            let wayland = names
+                .iter()
+                .find(|n| n.starts_with("wayland-") && !n.ends_with(".lock"));
+            let ipc = names.iter().find(|n| n.starts_with("sway-ipc."));
+            if let (Some(wayland), Some(ipc)) = (wayland, ipc) {
+                break (wayland.clone(), runtime_dir.join(ipc));
+            }
+            assert!(
+                start.elapsed() < Duration::from_secs(10),
+                "headless sway didn't start"
+            );
+            sleep(Duration::from_millis(100));
+        };
+
+        Some(HeadlessSway {
+            sway,
+            runtime_dir,
+            wayland_display,
+            ipc_socket,
+        })
+    }
+
+    // wl-screenrec, connected to this sway
+    fn wl_screenrec(&self) -> Command {
+        let mut cmd = Command::new(wl_screenrec());
+        cmd.env("XDG_RUNTIME_DIR", &self.runtime_dir)
+            .env("WAYLAND_DISPLAY", &self.wayland_display);
+        cmd
+    }
+
+    fn swaymsg(&self, msg: &str) {
+        let status = Command::new("swaymsg")
+            .arg("-s")
+            .arg(&self.ipc_socket)
+            .args(msg.split(' '))
+            .stdout(Stdio::null())
+            .status()
+            .unwrap();
+        assert!(status.success(), "swaymsg {msg} failed");
+    }
+}
+
+impl Drop for HeadlessSway {
+    fn drop(&mut self) {
+        let _ = self.sway.kill();
+        let _ = self.sway.wait();
+        let _ = fs::remove_dir_all(&self.runtime_dir);
+    }
+}
+
+// sends SIGINT and checks the recording is finished promptly
+fn stop(mut cmd: Child) {
+    kill(Pid::from_raw(cmd.id() as i32), SIGINT).unwrap();
+
+    let wait_start = Instant::now();
+    assert!(cmd.wait().unwrap().success());
+    assert!(wait_start.elapsed() < Duration::from_secs(1));
+}
+
+#[test]
+fn headless_basic() {
+    let Some(sway) = HeadlessSway::start("output HEADLESS-1 mode 1280x720") else {
+        return;
+    };
+    let filename = temp_dir().join("headless_basic.mp4");
+
+    let cmd = sway
+        .wl_screenrec()
+        .arg("--no-damage")
+        .arg("-f")
+        .arg(&filename)
+        .spawn()
+        .unwrap();
+    sleep(Duration::from_secs(3));
+    stop(cmd);
+
+    assert_eq!(file_resolution(&filename), (1280, 720));
+    let dur = file_duration(&filename);
+    assert!(dur > Duration::from_secs_f64(2.5), "{:?} < 2.5s", dur);
+    assert!(dur < Duration::from_secs_f64(3.5), "{:?} > 3.5s", dur);
+}
+
+#[test]
+fn headless_transform() {
+    let Some(sway) = HeadlessSway::start("output HEADLESS-1 mode 1280x720 transform 90") else {
+        return;
+    };
+    let filename = temp_dir().join("headless_transform.mp4");
+
+    let cmd = sway
+        .wl_screenrec()
+        .arg("--no-damage")
+        .arg("-g=0,0 360x640") // the top left quarter of the rotated, 720x1280 output
+        .arg("-f")
+        .arg(&filename)
+        .spawn()
+        .unwrap();
+    sleep(Duration::from_secs(2));
+    stop(cmd);
+
+    assert_eq!(file_resolution(&filename), (360, 640));
+}
+
+// a 10 bit output, so the capture format has to be negotiated away from the usual 8 bit XRGB8888
+#[test]
+fn headless_format_negotiation() {
+    let Some(sway) = HeadlessSway::start("output HEADLESS-1 mode 1280x720 render_bit_depth 10")
+    else {
+        return;
+    };
+    let filename = temp_dir().join("headless_format_negotiation.mp4");
+
+    let cmd = sway
+        .wl_screenrec()
+        .arg("--no-damage")
+        .arg("-f")
+        .arg(&filename)
+        .spawn()
+        .unwrap();
+    sleep(Duration::from_secs(2));
+    stop(cmd);
+
+    assert_eq!(file_resolution(&filename), (1280, 720));
+    let dur = file_duration(&filename);
+    assert!(dur > Duration::from_secs_f64(1.5), "{:?} < 1.5s", dur);
+}
+
+#[test]
+fn headless_output_went_away() {
+    let Some(sway) = HeadlessSway::start("output HEADLESS-1 mode 1280x720") else {
+        return;
+    };
+    let filename = temp_dir().join("headless_output_went_away.mp4");
+
+    let cmd = sway
+        .wl_screenrec()
+        .arg("--no-damage")
+        .arg("-o")
+        .arg("HEADLESS-1")
+        .arg("-f")
+        .arg(&filename)
+        .spawn()
+        .unwrap();
+    sleep(Duration::from_secs(2));
+    sway.swaymsg("output HEADLESS-1 disable");
+    sleep(Duration::from_secs(1));
+    sway.swaymsg("output HEADLESS-1 enable");
+    sleep(Duration::from_secs(2));
+    stop(cmd);
+
+    // recording continues in the same file once the output is back
+    assert_eq!(file_resolution(&filename), (1280, 720));
+    let dur = file_duration(&filename);
+    assert!(dur > Duration::from_secs_f64(3.5), "{:?} < 3.5s", dur);
+}
+
+#[test]
+fn test_pattern() {
+    let filename = temp_dir().join("test_pattern.mp4");
+
+    let cmd = Command::new(wl_screenrec())
+        .arg("--capture-backend=test-pattern")
+        .arg("-g=center 50%x50%")
+        .arg("-f")
+        .arg(&filename)
+        .spawn()
+        .unwrap();
+    sleep(Duration::from_secs(2));
+    stop(cmd);
+
+    assert_eq!(file_resolution(&filename), (960, 540));
+    let dur = file_duration(&filename);
+    assert!(dur > Duration::from_secs_f64(1.5), "{:?} < 1.5s", dur);
+    assert!(dur < Duration::from_secs_f64(2.5), "{:?} > 2.5s", dur);
+}
+
 fn file_metadata(filename: &Path) -> Value {
     serde_json::from_str(
         &String::from_utf8(